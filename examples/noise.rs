@@ -0,0 +1,60 @@
+//! A Noise NNpsk0 handshake keyed by an OPAQUE login, between two in-process peers.
+extern crate opaque_rust;
+
+use opaque_rust::ake::{ClientState, ServerState};
+use opaque_rust::config::{CipherSuite, OpaqueConfig};
+use opaque_rust::envelope::EnvelopeMode;
+use opaque_rust::messages::registration::{RegistrationRequest, RegistrationResponse, RegistrationUpload};
+use opaque_rust::noise;
+use opaque_rust::server_setup::ServerSetup;
+use rand::rngs::OsRng;
+use sha2::Sha512;
+
+static PATTERN: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+
+fn main() {
+    let config = OpaqueConfig::new(CipherSuite::Ristretto255Sha512, EnvelopeMode::Internal, Vec::new(), None, None);
+    let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+
+    // Registration.
+    let (request, blind) = RegistrationRequest::create_registration_request::<Sha512, _>(&config, b"password", &mut OsRng).unwrap();
+    let response = RegistrationResponse::create_registration_response::<Sha512>(&config, &request, &setup, "alice").unwrap();
+    let (record, _) =
+        RegistrationUpload::finalize_request::<Sha512, _>(&config, b"password", &blind, &response, None, None, &mut OsRng).unwrap();
+
+    // Login.
+    let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+    let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+        &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+    ).unwrap();
+    let (ke3, client_session, _, _, _) =
+        client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None).unwrap();
+    let (server_session, _, _) = server_state.server_finish::<Sha512>(&config, &setup, &ke3, None).unwrap();
+
+    // Noise handshake keyed by each side's session.
+    let mut initiator = snow::Builder::new(PATTERN.parse().unwrap())
+        .psk(0, &noise::psk::<Sha512>(&client_session).unwrap())
+        .prologue(&noise::prologue(&client_session))
+        .build_initiator()
+        .unwrap();
+    let mut responder = snow::Builder::new(PATTERN.parse().unwrap())
+        .psk(0, &noise::psk::<Sha512>(&server_session).unwrap())
+        .prologue(&noise::prologue(&server_session))
+        .build_responder()
+        .unwrap();
+
+    let mut message = [0u8; 1024];
+    let mut payload = [0u8; 1024];
+    // -> psk, e
+    let len = initiator.write_message(&[], &mut message).unwrap();
+    responder.read_message(&message[..len], &mut payload).unwrap();
+    // <- e, ee
+    let len = responder.write_message(&[], &mut message).unwrap();
+    initiator.read_message(&message[..len], &mut payload).unwrap();
+
+    let mut initiator = initiator.into_transport_mode().unwrap();
+    let mut responder = responder.into_transport_mode().unwrap();
+    let len = initiator.write_message(b"hello over noise", &mut message).unwrap();
+    let len = responder.read_message(&message[..len], &mut payload).unwrap();
+    println!("{}", String::from_utf8_lossy(&payload[..len]));
+}
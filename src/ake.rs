@@ -1,4 +1,4 @@
-//! AKE protocol - 3DH
+//! AKE protocol - 3DH / HMQV / SIGMA-I / KEM
 //!
 //! The protocol consists of three messages sent between client and server, and runs as follows:
 //!
@@ -13,12 +13,14 @@
 //! Note: export_key MUST NOT be used in any way before the protocol completes.
 //!
 //! The server inputs:
-//! - server_pri_key: server private key.
-//! - server_pub_key: server public key.
+//! - server_setup: the server's [`crate::server_setup::ServerSetup`], holding:
+//!   - server_private_key: server private key, encoded as described on [`crate::group`] (i.e. a key from an
+//!     existing P-256 PKI can be used directly);
+//!   - server_public_key: server public key, encoded as described on [`crate::group`];
+//!   - oprf_seed: seed used to derive per-client OPRF keys.
 //! - server_identity: server identity as defined during registration.
 //! - record: [`crate::messages::registration::RegistrationUpload`] stored during registration.
 //! - credential_identifier: client credential identifier.
-//! - oprf_seed: seed used to derive per-client OPRF keys.
 //!
 //! The server receives:
 //! - session_secret: a secret matching that one of the client.
@@ -33,11 +35,9 @@
 //!
 //!             ke2 = ServerInit(
 //!                         server_identity,
-//!                         server_private_key,
-//!                         server_public_key,
+//!                         server_setup,
 //!                         record,
 //!                         credential_identifier,
-//!                         oprf_seed,
 //!                         ke1
 //!                   )
 //!                  <----------ke2------------
@@ -64,16 +64,315 @@
 //! Both `ClientInit` and `ServerInit` implicitly return internal state objects `client_state` ([`ClientState`]) and `server_state` ([`ServerState`]).
 //!
 //! Before the execution of any function related to client-server communication, both client and server MUST agree
-//! on a configuration (more on that later).
+//! on a configuration, see [`crate::config::OpaqueConfig`]. Every entry point receives it, and its `context` is
+//! mixed into the preamble, its `server_identity`/`client_identity` are used whenever no identity is given.
+//!
+//! # Application information
+//!
+//! Applications can exchange data within the handshake, without another round trip:
+//! - `client_info` on `KE1`: plaintext, authenticated by both MACs (i.e. a device identifier);
+//! - `server_extensions` on `KE2`: sealed with `Ke2` and also covered by `server_mac` (i.e. an MFA challenge);
+//! - `client_extensions` on `KE3`: sealed with `Ke3`.
+//!
+//! `Ke2` and `Ke3` are derived by `DeriveKeys` alongside the MAC keys, and the extensions are encrypted and
+//! authenticated with [`crate::kdf::seal`]/[`crate::kdf::open`]. `client_info` MUST NOT be trusted before
+//! `ServerFinish` succeeds. Every protocol below applies the same steps to the extensions as 3DH, except KEM
+//! which has no `server_extensions` (see the "KEM" section).
+//!
+//! # Session outputs
+//!
+//! `ClientFinish` and `ServerFinish` output the session key as a [`crate::session::SessionKey`], together with
+//! the transcript hash of the handshake: `Hash(concat(preamble, server_mac))` with 3DH, HMQV and KEM, and
+//! `transcript_hash` with SIGMA-I. Both sides compute the same transcript hash, which identifies the session
+//! (i.e. as a Noise prologue, see [`crate::noise`]). The server can also issue a ticket from it, so the
+//! client can reconnect without a full login (see [`crate::resumption`]).
+//!
+//! # Channel binding
+//!
+//! When OPAQUE runs inside another secure channel (i.e. TLS), both sides can bind the handshake to it with a
+//! `channel_binding` value taken from that channel (i.e. a `tls-exporter` value, RFC 9266), given to
+//! `ClientInit` and `ServerInit`. The value is appended to the preamble as
+//! `concat("ChannelBinding", I2OSP(len(channel_binding), 2), channel_binding)`, so it's covered by every key and
+//! MAC of the handshake, and a relay between two different channels makes the MAC checks fail. Without a
+//! channel binding the preamble is unchanged.
+//!
+//! Each side also confirms the binding alone, with a MAC keyed by the binding value (`server_binding_mac` on
+//! `KE2`, `client_binding_mac` on `KE3`) and checked before any other MAC:
+//!
+//! ```txt
+//!     binding_key = Extract("ChannelBinding", channel_binding)
+//!     server_binding_mac = MAC(binding_key, Hash(concat("ServerBinding", ke1, server_nonce)))
+//!     client_binding_mac = MAC(binding_key, Hash(concat("ClientBinding", ke1, server_nonce)))
+//! ```
+//!
+//! A binding MAC that doesn't match, or is missing while a binding is in use, is reported as an `InvalidData`
+//! error holding a [`ChannelBindingError`] (see [`is_channel_binding_error`]); any later failure (i.e. a wrong
+//! password) is a plain `HandshakeError`. The binding MACs only tell the failures apart: a relay terminating
+//! both channels knows both values and can forge them, but the handshake still fails on the MACs covering the
+//! preamble. Both values must be computed the same way on each side (same exporter label and length), and every
+//! protocol below applies the same steps as 3DH.
+//!
+//! # Server key rotation
+//!
+//! Every record is bound to the server keypair it was registered with (`record.server_key_id`, see
+//! [`crate::server_setup`]), since the server's public key is covered by the envelope. `ServerInit` uses that
+//! keypair, and when it isn't the current one, `KE2` also carries the ids the record is re-bound to and the
+//! current public key (`server_key_update`), covered by `server_mac`. After verifying `server_mac`, the client
+//! re-registers right away: the OPRF output doesn't depend on the server keypair, so the client builds a new
+//! envelope for the new key from the same `randomized_pwd`, and sends the new record sealed with `Ke3` on `KE3`
+//! (`record_update`). `ServerFinish` checks the ids of the new record and outputs it, to be stored in place of
+//! the old one. No extra round trip is needed, and the client's password stays the same, but the new envelope
+//! has its own nonce, so `ClientFinish` also outputs the `export_key` of the new record. Every protocol below
+//! applies the same steps as 3DH, except KEM whose records are never re-bound (see the "KEM" section).
+//!
+//! # OPRF seed rotation
+//!
+//! Records also store the id of the OPRF seed they were registered with (`record.oprf_seed_id`), and
+//! `CreateCredentialResponse` evaluates the OPRF with the key derived from that seed. When it isn't the current
+//! seed, `ServerInit` also evaluates the client's blinded element with the key derived from the current seed, and
+//! `KE2` carries that evaluation sealed with `Ke2` (`oprf_seed_update`), covered by `server_mac`, along with a
+//! `server_key_update` holding the ids of the current key and seed. This is the upgrade registration round:
+//! after verifying `server_mac`, the client finalizes that evaluation into a new `randomized_pwd` (the password
+//! is still at hand), and builds the new record from it, sent on `KE3` as `record_update` (for the current
+//! server key as well). No extra round trip is needed.
+//!
+//! Fake records (`RegistrationUpload::fake_record`) are spread over every key and seed still in use, so the
+//! `KE2` of an unknown client carries the same updates (and costs the same extra evaluation) as the one of a
+//! record that wasn't upgraded yet, or has the length of the one of an up-to-date record. Since the `export_key` derives from
+//! `randomized_pwd`, it changes with the upgrade: `ClientFinish` outputs the new one along with the old one, so
+//! applications can migrate whatever they protected with it.
+//!
+//! # Versions
+//!
+//! `KE1`, `KE2` and `KE3` have the fixed layouts of RFC 9807 and carry no version: the stored record carries the
+//! [`crate::config::ProtocolVersion`] it was registered with, and `ServerInit` answers it as long as that version
+//! is listed on `config.accepted_versions`. The optional fields of this crate (extensions, hybrid keyshares,
+//! signatures, key updates, ...) follow each message as a trailer, which is left out when none of them is used,
+//! so a plain 3DH handshake is byte for byte the one of the RFC (including its preamble, prefixed by
+//! `"OPAQUEv1-"`, and the keyshares derived from random seeds by `DeriveDiffieHellmanKeyPair`).
+//!
+//! # Groups
+//!
+//! Keyshares and long-term keys belong to `config.ake_group` ([`crate::group::AkeGroup`]), either ristretto255,
+//! X25519 or P-256 (compressed SEC1 on the wire). `GenerateAuthKeyPair` and the Diffie-Hellman operations of `TripleDHIKM` are the ones of that
+//! group, and every received keyshare is validated with [`crate::group::AkeGroup::validate_public_key`].
+//!
+//! # HMQV
+//!
+//! When `config.ake_protocol` is [`AkeProtocol::Hmqv`], long-term and ephemeral keys are the same as with 3DH
+//! (so records registered for 3DH can be used with HMQV and vice versa, as long as `config.ake_group` is the
+//! same), but the IKM is the HMQV shared secret ([`crate::group::AkeGroup::hmqv`]), which saves one scalar
+//! multiplication per side. HMQV has its own preamble and key schedule labels (`"HMQV-"`), and needs a prime order
+//! group (ristretto255 or P-256).
+//!
+//! ```txt
+//!     d = HashToScalar(concat(I2OSP(len(client_keyshare), 2), client_keyshare,
+//!                             I2OSP(len(server_identity), 2), server_identity))
+//!     e = HashToScalar(concat(I2OSP(len(server_keyshare), 2), server_keyshare,
+//!                             I2OSP(len(client_identity), 2), client_identity))
+//!     ikm = (client_secret + d * client_private_key) * (server_keyshare + e * server_public_key)   (client)
+//!         = (server_secret + e * server_private_key) * (client_keyshare + d * client_public_key)   (server)
+//! ```
+//!
+//! Since the labels differ, a client running 3DH and a server running HMQV (or the other way around) never
+//! agree on the keys, and the login fails on the MAC checks.
+//!
+//! # Hybrid mode
+//!
+//! When `config.hybrid_kem` is set, 3DH and HMQV handshakes also carry an ephemeral KEM keyshare:
+//! `client_kem_keyshare` on `KE1` and a ciphertext to it, `server_kem_keyshare`, on `KE2`. Its shared secret is
+//! appended to the Diffie-Hellman IKM, so the session key stays secure as long as either primitive holds. Both
+//! keyshares are part of the preamble, and a peer omitting them is rejected, so the hybrid mode can't be
+//! downgraded. Long-term keys and records are unchanged.
+//!
+//! # SIGMA-I
+//!
+//! When `config.ake_protocol` is [`AkeProtocol::SigmaI`], the same three messages are exchanged, but both
+//! sides authenticate with signatures: `KE2` carries the server's signature over the transcript and `KE3` the
+//! client's. Only the ephemeral keyshares are combined by Diffie-Hellman, and long-term keys are signing keys.
+//! Clients holding an existing signing-only key register it with the external envelope mode.
+//!
+//! ```txt
+//!     ikm = DH(client_secret, server_keyshare)
+//!     server_signature = Sign(server_private_key, Hash(preamble))
+//!     server_mac = MAC(Km2, Hash(server_identity))
+//!     transcript_hash = Hash(concat(preamble, server_signature, server_mac))
+//!     client_signature = Sign(client_private_key, transcript_hash)
+//!     client_mac = MAC(Km3, client_identity)
+//! ```
+//!
+//! # KEM
+//!
+//! When `config.ake_protocol` is [`AkeProtocol::Kem`], every Diffie-Hellman operation is replaced by a KEM
+//! encapsulation ([`crate::kem::Kem`], i.e. ML-KEM), so session keys are protected against "harvest now,
+//! decrypt later" attacks. Long-term keys are KEM keypairs: the server's encapsulation key is the
+//! `server_public_key` covered by the envelope's `CleartextCredentials`, and the client's one is stored on the
+//! record. The OPRF and the envelope are the same as with the other protocols.
+//!
+//! - `KE1` carries an ephemeral encapsulation key as `client_keyshare`;
+//! - `KE2` carries a ciphertext to that ephemeral key (as `server_keyshare`) and a ciphertext to the client's
+//!   long-term key (`server_kem_ciphertext`);
+//! - `KE3` carries a ciphertext to the server's long-term key (`client_kem_ciphertext`), which the client can only
+//!   compute after recovering the server's key from the envelope.
+//!
+//! The keys are derived in two stages:
+//!
+//! ```txt
+//!     ikm = concat(Decaps(client_secret, server_keyshare), Decaps(client_private_key, server_kem_ciphertext))
+//!     Km2, Ke2 = DeriveKeys(ikm, Hash(preamble))
+//!     server_mac = MAC(Km2, Hash(preamble))
+//!     transcript_hash = Hash(concat(preamble, server_mac))
+//!     Km3, Ke3, session_key = DeriveKeys(concat(ikm, Decaps(server_private_key, client_kem_ciphertext)),
+//!                                        transcript_hash)
+//!     client_mac = MAC(Km3, transcript_hash)
+//! ```
+//!
+//! Unlike the other protocols, `server_mac` doesn't authenticate the server: `Km2` only depends on the
+//! encapsulations to the client's keys, which anyone knowing the client's public key (i.e. from a leaked
+//! record) can compute. The server proves the knowledge of its long-term key implicitly, since the session key
+//! and `client_mac` depend on the shared secret encapsulated on `KE3`: the client only knows that whoever holds
+//! the session key is the server, and the server is never explicitly authenticated within the three messages.
+//! So nothing the client would act on is sent on `KE2`: `ServerInit` refuses `server_extensions` and never sends
+//! a `server_key_update` nor an `oprf_seed_update`, and `ClientFinish` rejects a `KE2` carrying any of them.
+//! Records of an older server key or OPRF seed keep logging in with them, and are moved to the current ones
+//! when the client registers again.
+//!
+//! # Stateless servers
+//!
+//! Between `KE2` and `KE3` the server keeps its [`ServerState`] (`expected_client_mac`, `session_key`, ...). When
+//! `KE3` may reach another server than the one that sent `KE2` (i.e. behind a load balancer without sticky
+//! sessions), the state can be sealed into a token with [`crate::stateless::StateSealer`], sent alongside `KE2`
+//! and presented back with `KE3`, so any server holding the sealing key can run `ServerFinish`. Servers that do
+//! keep it in memory can use [`crate::sessions::LoginSessions`].
+//!
+//! # Throttling
+//!
+//! `ServerInit` and `ServerFinish` optionally take a [`crate::throttle::Throttle`], checked with the credential
+//! identifier before the OPRF evaluation, so online guessing is limited in the same way for every login,
+//! including those of unknown identifiers answered with a fake record. `ServerInit` records every attempt as a
+//! failure, and only a successful `ServerFinish` clears it: a guesser learns the outcome of a guess from `KE2`
+//! alone, so an attempt that never sends `KE3` still counts towards the lockout.
 
-use crate::messages::ake::{KE1, KE2, KE3};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use crate::kdf::SuiteHash;
+use rand::{CryptoRng, RngCore};
+use crate::envelope::{randomized_password, EnvelopeMode};
+use crate::config::{
+    invalid_data, read_field, read_optional_field, read_u8, write_field, write_optional_field, OpaqueConfig,
+    NONCE_SIZE,
+};
+use crate::group::AkeGroup;
+use crate::kdf;
+use crate::oprf;
+use crate::messages::ake::{InnerKE2, KE1, KE2, KE3};
 use crate::messages::credential::{CredentialRequest, CredentialResponse};
 use crate::messages::registration::RegistrationUpload;
+use crate::replay::ReplayCache;
+use crate::session::SessionKey;
+use crate::throttle::Throttle;
+use crate::kem::Kem;
+use crate::server_setup::ServerSetup;
+use crate::signature::SignatureScheme;
+
+static STR_OPAQUE_V1: &[u8] = b"OPAQUEv1-";
+static STR_CHANNEL_BINDING: &[u8] = b"ChannelBinding";
+static STR_HANDSHAKE_SECRET: &[u8] = b"HandshakeSecret";
+static STR_SESSION_KEY: &[u8] = b"SessionKey";
+static STR_SERVER_MAC: &[u8] = b"ServerMAC";
+static STR_CLIENT_MAC: &[u8] = b"ClientMAC";
+static STR_SERVER_ENCRYPTION: &[u8] = b"ServerEncryption";
+static STR_CLIENT_ENCRYPTION: &[u8] = b"ClientEncryption";
+static STR_SERVER_EXTENSIONS: &[u8] = b"ServerExtensions";
+static STR_CLIENT_EXTENSIONS: &[u8] = b"ClientExtensions";
+static STR_RECORD_UPDATE: &[u8] = b"RecordUpdate";
+static STR_OPRF_SEED_UPDATE: &[u8] = b"OprfSeedUpdate";
+static STR_SERVER_BINDING: &[u8] = b"ServerBinding";
+static STR_CLIENT_BINDING: &[u8] = b"ClientBinding";
 
-struct ClientState {
+/// The error held by the `InvalidData` error of a binding MAC check that failed, because both sides aren't on the
+/// same channel (or only one of them bound the handshake).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelBindingError;
+
+impl fmt::Display for ChannelBindingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("channel binding mismatch")
+    }
+}
+
+impl Error for ChannelBindingError {}
+
+/// Whether `error` reports a channel binding mismatch (i.e. a relay between two channels).
+pub fn is_channel_binding_error(error: &io::Error) -> bool {
+    matches!(error.get_ref(), Some(inner) if inner.is::<ChannelBindingError>())
+}
+
+/// AKE protocols that can run within the KE1/KE2/KE3 flow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AkeProtocol {
+    /// 3DH: long-term and ephemeral keys of `config.ake_group` are combined by three Diffie-Hellman operations.
+    TripleDh,
+    /// HMQV: same keys as 3DH, combined by the HMQV shared secret computation.
+    Hmqv,
+    /// SIGMA-I: the session keys come from a single ephemeral Diffie-Hellman, and each side authenticates by
+    /// signing the transcript with its long-term signing key and MACing its own identity. Long-term keys (the
+    /// server keypair and the client keypair stored in/derived by the envelope) are keys of the given
+    /// [`SignatureScheme`].
+    SigmaI(SignatureScheme),
+    /// KEM: post-quantum variant where the Diffie-Hellman operations are replaced by encapsulations of the
+    /// given [`Kem`], and long-term keys are keypairs of that KEM.
+    Kem(Kem),
+}
+
+impl AkeProtocol {
+    /// Two bytes identifier of the protocol (`protocol || signature_scheme`, zero when not used).
+    pub fn id(&self) -> [u8; 2] {
+        match self {
+            AkeProtocol::TripleDh => [0x01, 0x00],
+            AkeProtocol::SigmaI(scheme) => [0x02, scheme.id()],
+            AkeProtocol::Hmqv => [0x03, 0x00],
+            AkeProtocol::Kem(kem) => [0x04, kem.id()],
+        }
+    }
+
+    /// Returns the protocol matching `id`, if any.
+    pub fn from_id(id: [u8; 2]) -> Option<Self> {
+        match id {
+            [0x01, 0x00] => Some(AkeProtocol::TripleDh),
+            [0x02, scheme] => SignatureScheme::from_id(scheme).map(AkeProtocol::SigmaI),
+            [0x03, 0x00] => Some(AkeProtocol::Hmqv),
+            [0x04, kem] => Kem::from_id(kem).map(AkeProtocol::Kem),
+            _ => None,
+        }
+    }
+
+    /// Label of the protocol on the preamble and the key schedule (empty for 3DH, so its handshakes are the
+    /// ones of the draft).
+    fn label(&self) -> &'static [u8] {
+        match self {
+            AkeProtocol::TripleDh => b"",
+            AkeProtocol::Hmqv => b"HMQV-",
+            AkeProtocol::SigmaI(_) => b"SIGMA-I-",
+            AkeProtocol::Kem(_) => b"KEM-",
+        }
+    }
+}
+
+/// The client's state between `KE1` and `KE2`.
+pub struct ClientState {
     blind: Vec<u8>,
     client_secret: Vec<u8>,
-    ke1: KE1,
+    /// The decapsulation key of `client_kem_keyshare`. Only used in hybrid mode, otherwise it will be ```None```.
+    client_kem_secret: Option<Vec<u8>>,
+    /// Only set when the handshake is bound to an outer channel, otherwise it will be ```None```.
+    channel_binding: Option<Vec<u8>>,
+    /// The client's ephemeral keyshare, bound to the HMQV exponent `d`.
+    client_keyshare: Vec<u8>,
+    /// The serialized `KE1`, part of the preamble.
+    ke1: Vec<u8>,
 }
 
 impl ClientState {
@@ -81,207 +380,2304 @@ impl ClientState {
     ///
     /// # Arguments
     ///
+    /// * `config`: the [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
     /// * `pwd`: client's password.
-    /// * `client_identity`: optional encoded client identity.
+    /// * `client_info`: optional application information, sent in plaintext.
+    /// * `channel_binding`: optional binding to the outer channel (i.e. a `tls-exporter` value).
     ///
     /// # Returns
     ///
+    /// * `state`: The [`ClientState`], holding the OPRF blind and the client's secret share for the session,
+    ///   to be given to [`ClientState::client_finish`].
     /// * `ke1`: A [`KE1`] structure.
-    /// * `blind`: A OPRF blinding scalar.
-    /// * `client_secret`: The client's Diffie-Hellman secret share for the session.
-    pub fn client_init(
-        &self,
-        _pwd: String,
-        _client_identity: Option<Vec<String>>,
-    ) { // -> (KE1, Vec<u8>, Vec<u8>)
-        /*
-        1. request, blind = CreateCredentialRequest(password)
-        2. state.blind = blind
-        3. ke1 = Start(request)
-        4. Output ke1
-        */
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When `D` isn't the hash function of `config.suite`.
+    /// * `Unsupported`: When `config.ake_group` doesn't support `config.ake_protocol`, or `config.hybrid_kem` is
+    ///   set with SIGMA-I or KEM.
+    pub fn client_init<D, R>(
+        config: &OpaqueConfig,
+        rng: &mut R,
+        pwd: &[u8],
+        client_info: Option<&[u8]>,
+        channel_binding: Option<&[u8]>,
+    ) -> io::Result<(Self, KE1)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let (request, blind) = CredentialRequest::create_credential_request::<D, R>(config, pwd, rng)?;
+        let (ke1, client_secret, client_kem_secret) = ClientState::start::<D, R>(config, rng, request, client_info)?;
+
+        let state = ClientState {
+            blind,
+            client_secret,
+            client_kem_secret,
+            channel_binding: channel_binding.map(<[u8]>::to_vec),
+            client_keyshare: ke1.client_keyshare.clone(),
+            ke1: ke1.serialize()?,
+        };
+        Ok((state, ke1))
     }
 
     /// Finish client events
     ///
     /// # Arguments
     ///
+    /// * `config`: the [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
     /// * `pwd`: client's password.
-    /// * `ke1`: a [`KE1`] structure.
     /// * `ke2`: a [`KE2`] structure.
-    /// * `client_identity`: optional encoded client_identity.
-    /// * `server_identity`: optional encoded server_identity.
+    /// * `client_identity`: optional encoded client_identity (defaults to `config.client_identity`).
+    /// * `client_extensions`: optional application extensions, sent encrypted.
     ///
     /// # Returns
     ///
     /// * `ke3`: KE3 message structure
-    /// * `session_key`: session's shared secret
-    pub fn client_finish(
-        &self,
-        _pwd: String,
-        _ke1: KE1,
-        _ke2: KE2,
-        _client_identity: Option<Vec<String>>,
-        _server_identity: Option<Vec<String>>,
-    ) { // -> (KE3, Vec<u8>)
-        /*
-        1. (client_private_key, server_public_key, export_key) = RecoverCredentials(password, state.blind, ke2.CredentialResponse, server_identity, client_identity)
-        2. (ke3, session_key) = ClientFinalize(client_identity, client_private_key, server_identity, server_public_key, ke1, ke2)
-        3. Output (ke3, session_key)
-        */
+    /// * `session_key`: session's shared secret, see [`crate::session::SessionKey`]
+    /// * `export_key`: an additional client key, the same one output by the registration
+    /// * `server_extensions`: the decrypted server extensions, if any
+    /// * `new_export_key`: the `export_key` of the record sent on `ke3` as `record_update`, only set when `ke2`
+    ///   asked to re-bind the record, otherwise it will be ```None```
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData` (```EnvelopeRecoveryError```/```HandshakeError```): When the envelope can't be recovered
+    ///   (i.e. a wrong password), or the server can't be authenticated. The error holds a
+    ///   [`ChannelBindingError`] when `server_binding_mac` doesn't match the channel binding (or is missing).
+    #[allow(clippy::type_complexity)]
+    pub fn client_finish<D, R>(
+        self,
+        config: &OpaqueConfig,
+        rng: &mut R,
+        pwd: &[u8],
+        ke2: &KE2,
+        client_identity: Option<&[u8]>,
+        client_extensions: Option<&[u8]>,
+    ) -> io::Result<(KE3, SessionKey, Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        // Before any other check, so a binding mismatch isn't reported as a wrong password and vice versa.
+        let server_nonce = &ke2.inner_ke2.server_nonce;
+        let expected_binding_mac = self
+            .channel_binding
+            .as_deref()
+            .map(|channel_binding| binding_mac::<D>(channel_binding, STR_SERVER_BINDING, &self.ke1, server_nonce));
+        check_binding_mac(expected_binding_mac.as_deref(), ke2.server_binding_mac.as_deref())?;
+
+        let (client_pri_key, client_pub_key, server_pub_key, export_key, randomized_pwd) =
+            ke2.inner_ke2.response.recover_credentials::<D>(config, pwd, &self.blind, client_identity)?;
+        let resolved_client_identity = config.client_identity_or(client_identity, &client_pub_key);
+        let server_identity = config.server_identity_or(&server_pub_key);
+
+        let (mut ke3, session_key, ke2_key, ke3_key) = match config.ake_protocol {
+            AkeProtocol::TripleDh | AkeProtocol::Hmqv => self.finalize::<D>(
+                config,
+                &client_pri_key,
+                &server_pub_key,
+                &resolved_client_identity,
+                &server_identity,
+                ke2,
+            )?,
+            AkeProtocol::SigmaI(scheme) => self.sigma_i_finalize::<D>(
+                config,
+                scheme,
+                &client_pri_key,
+                &server_pub_key,
+                &resolved_client_identity,
+                &server_identity,
+                ke2,
+            )?,
+            AkeProtocol::Kem(kem) => self.kem_finalize::<D, R>(
+                config,
+                rng,
+                kem,
+                &client_pri_key,
+                &server_pub_key,
+                &resolved_client_identity,
+                &server_identity,
+                ke2,
+            )?,
+        };
+        let server_extensions = open_optional::<D>(&ke2_key, STR_SERVER_EXTENSIONS, &ke2.server_extensions)?;
+
+        // The server's MAC was verified, so the record can be re-bound as asked, from the evaluation under the
+        // current OPRF seed when the seed changes.
+        let mut new_export_key = None;
+        if let Some(server_key_update) = &ke2.server_key_update {
+            let randomized_pwd = match open_optional::<D>(&ke2_key, STR_OPRF_SEED_UPDATE, &ke2.oprf_seed_update)? {
+                Some(evaluated_element) => {
+                    let oprf_output = oprf::finalize::<D>(config.suite, pwd, &self.blind, &evaluated_element)?;
+                    randomized_password::<D>(&oprf_output)
+                }
+                None => randomized_pwd,
+            };
+            let (record, record_export_key) = ClientState::record_update::<D, R>(
+                config,
+                rng,
+                &randomized_pwd,
+                &client_pri_key,
+                client_identity,
+                server_key_update,
+            )?;
+            ke3.record_update = Some(kdf::seal::<D>(&ke3_key, STR_RECORD_UPDATE, &record.serialize()?)?);
+            new_export_key = Some(record_export_key);
+        }
+        ke3.client_extensions = seal_optional::<D>(&ke3_key, STR_CLIENT_EXTENSIONS, client_extensions)?;
+        ke3.client_binding_mac = self
+            .channel_binding
+            .as_deref()
+            .map(|channel_binding| binding_mac::<D>(channel_binding, STR_CLIENT_BINDING, &self.ke1, server_nonce));
+        Ok((ke3, session_key, export_key, server_extensions, new_export_key))
     }
 
     /// Start client requests
     ///
     /// # Arguments
     ///
+    /// * `config`: the [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
     /// * `credential_request`: a [`CredentialRequest`] structure.
+    /// * `client_info`: optional application information.
     ///
     /// # Returns
     ///
     /// * `ke1`: a [`KE1`] structure.
-    ///
-    fn start(&self, _credential_request: CredentialRequest) { // -> KE1
-        /*
-        1. client_nonce = random(Nn)
-        2. client_secret, client_keyshare = GenerateAuthKeyPair()
-        3. Create KE1 ke1 with (credential_request, client_nonce, client_keyshare)
-        4. state.client_secret = client_secret
-        5. Output (ke1, client_secret)
-        */
+    /// * `client_secret`: The client's secret share for the session.
+    /// * `client_kem_secret`: The decapsulation key of the hybrid KEM keyshare, if any.
+    #[allow(clippy::type_complexity)]
+    fn start<D, R>(
+        config: &OpaqueConfig,
+        rng: &mut R,
+        credential_request: CredentialRequest,
+        client_info: Option<&[u8]>,
+    ) -> io::Result<(KE1, Vec<u8>, Option<Vec<u8>>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        check_hybrid_kem(config)?;
+
+        let mut client_nonce = vec![0u8; NONCE_SIZE];
+        rng.fill_bytes(&mut client_nonce);
+        let (client_secret, client_keyshare) = match config.ake_protocol {
+            AkeProtocol::TripleDh | AkeProtocol::SigmaI(_) => generate_keyshare::<D, R>(config, rng)?,
+            AkeProtocol::Hmqv if config.ake_group.supports_hmqv() => generate_keyshare::<D, R>(config, rng)?,
+            AkeProtocol::Kem(kem) => kem.generate_keypair(rng),
+            _ => return Err(io::Error::from(io::ErrorKind::Unsupported)),
+        };
+        let (client_kem_secret, client_kem_keyshare) = match config.hybrid_kem {
+            Some(kem) => {
+                let (decapsulation_key, encapsulation_key) = kem.generate_keypair(rng);
+                (Some(decapsulation_key), Some(encapsulation_key))
+            }
+            None => (None, None),
+        };
+
+        let ke1 = KE1 {
+            request: credential_request,
+            client_nonce,
+            client_keyshare,
+            client_kem_keyshare,
+            client_info: client_info.map(<[u8]>::to_vec),
+        };
+        Ok((ke1, client_secret, client_kem_secret))
     }
 
-    /// Finish client requests
+    /// Finish client requests with 3DH or HMQV, which only differ on the IKM.
     ///
     /// # Arguments
     ///
+    /// * `config`: the [`OpaqueConfig`] agreed by client and server.
     /// * `client_pri_key`: Client's private key.
     /// * `server_pub_key`: Server's public key.
+    /// * `client_identity`: The resolved client identity.
+    /// * `server_identity`: The resolved server identity.
     /// * `ke2`: a KE2 message structure.
-    /// * `client_identity`: Optional client identity.
-    /// * `server_identity`: Optional server identity.
     ///
     /// # Returns
     ///
-    /// * `ke3`: a KE3 structure.
-    /// * `session_key`: the shared session secret.
-    fn finalize(
+    /// * `ke3`: a KE3 structure, without client extensions nor record update.
+    /// * `session_key`: the shared session secret, with the hash of the handshake transcript.
+    /// * `ke2_key`: `Ke2`, the key of the server extensions.
+    /// * `ke3_key`: `Ke3`, the key of the client extensions and the record update.
+    #[allow(clippy::too_many_arguments)]
+    fn finalize<D>(
         &self,
-        _client_pri_key: Vec<u8>,
-        _server_pub_key: Vec<u8>,
-        _ke2: KE2,
-        _client_identity: Option<String>,
-        _server_identity: Option<String>,
-    ) { // -> (KE3, Vec<u8>)
-        /*
-        1. ikm = TripleDHIKM(state.client_secret, ke2.server_keyshare, state.client_secret, server_public_key, client_private_key, ke2.server_keyshare)
-        2. preamble = Preamble(client_identity, state.ke1, server_identity, ke2.inner_ke2)
-        3. Km2, Km3, session_key = DeriveKeys(ikm, preamble)
-        4. expected_server_mac = MAC(Km2, Hash(preamble))
-        5. If !ct_equal(ke2.server_mac, expected_server_mac),
-            raise HandshakeError
-        6. client_mac = MAC(Km3, Hash(concat(preamble, expected_server_mac))
-        7. Create KE3 ke3 with client_mac
-        8. Output (ke3, session_key)
-        */
+        config: &OpaqueConfig,
+        client_pri_key: &[u8],
+        server_pub_key: &[u8],
+        client_identity: &[u8],
+        server_identity: &[u8],
+        ke2: &KE2,
+    ) -> io::Result<(KE3, SessionKey, Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        let group = config.ake_group;
+        let server_keyshare = &ke2.inner_ke2.server_keyshare;
+        group.validate_public_key(server_keyshare).map_err(|_| invalid_data())?;
+
+        let ikm = match config.ake_protocol {
+            AkeProtocol::Hmqv => {
+                let d = group.hmqv_exponent::<D>(&self.client_keyshare, server_identity)?;
+                let e = group.hmqv_exponent::<D>(server_keyshare, client_identity)?;
+                group.hmqv(&self.client_secret, client_pri_key, &d, server_keyshare, server_pub_key, &e)?
+            }
+            _ => [
+                diffie_hellman(group, &self.client_secret, server_keyshare)?,
+                diffie_hellman(group, &self.client_secret, server_pub_key)?,
+                diffie_hellman(group, client_pri_key, server_keyshare)?,
+            ].concat(),
+        };
+        // Hybrid mode: a peer omitting its KEM keyshare is rejected, so the mode can't be downgraded.
+        let kem_shared_secret = match (config.hybrid_kem, &self.client_kem_secret, &ke2.inner_ke2.server_kem_keyshare) {
+            (Some(kem), Some(secret), Some(ciphertext)) => kem.decapsulate(secret, ciphertext)?,
+            (None, None, None) => Vec::new(),
+            _ => return Err(invalid_data()),
+        };
+        let ikm = [ikm, kem_shared_secret].concat();
+        let preamble = preamble(
+            config,
+            client_identity,
+            &self.ke1,
+            server_identity,
+            &ke2.inner_ke2,
+            self.channel_binding.as_deref(),
+        )?;
+        let keys = HandshakeKeys::derive::<D>(config.ake_protocol, &ikm, &kdf::hash::<D>(&preamble))?;
+
+        let server_mac_message = server_mac_message::<D>(&preamble, ke2)?;
+        kdf::verify::<D>(&keys.km2, &server_mac_message, &ke2.server_mac)
+            .map_err(|_| invalid_data())?;
+
+        let transcript_hash = kdf::hash::<D>(&[&preamble[..], &ke2.server_mac].concat());
+        let ke3 = KE3 {
+            client_extensions: None,
+            client_mac: kdf::mac::<D>(&keys.km3, &transcript_hash),
+            client_signature: None,
+            client_kem_ciphertext: None,
+            record_update: None,
+            client_binding_mac: None,
+        };
+        Ok((ke3, SessionKey::new(keys.session_key, transcript_hash), keys.ke2, keys.ke3))
+    }
+
+    /// Finish client requests with the SIGMA-I protocol.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: the [`OpaqueConfig`] agreed by client and server.
+    /// * `scheme`: the [`SignatureScheme`] of `config.ake_protocol`.
+    /// * `client_pri_key`: Client's signing key.
+    /// * `server_pub_key`: Server's verifying key.
+    /// * `client_identity`: The resolved client identity.
+    /// * `server_identity`: The resolved server identity.
+    /// * `ke2`: a KE2 message structure.
+    ///
+    /// # Returns
+    ///
+    /// * `ke3`: a KE3 structure, without client extensions nor record update.
+    /// * `session_key`: the shared session secret, with the hash of the handshake transcript.
+    /// * `ke2_key`: `Ke2`, the key of the server extensions.
+    /// * `ke3_key`: `Ke3`, the key of the client extensions and the record update.
+    #[allow(clippy::too_many_arguments)]
+    fn sigma_i_finalize<D>(
+        &self,
+        config: &OpaqueConfig,
+        scheme: SignatureScheme,
+        client_pri_key: &[u8],
+        server_pub_key: &[u8],
+        client_identity: &[u8],
+        server_identity: &[u8],
+        ke2: &KE2,
+    ) -> io::Result<(KE3, SessionKey, Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        let group = config.ake_group;
+        let server_keyshare = &ke2.inner_ke2.server_keyshare;
+        group.validate_public_key(server_keyshare).map_err(|_| invalid_data())?;
+
+        let ikm = diffie_hellman(group, &self.client_secret, server_keyshare)?;
+        let preamble = preamble(
+            config,
+            client_identity,
+            &self.ke1,
+            server_identity,
+            &ke2.inner_ke2,
+            self.channel_binding.as_deref(),
+        )?;
+        let keys = HandshakeKeys::derive::<D>(config.ake_protocol, &ikm, &kdf::hash::<D>(&preamble))?;
+
+        let server_signature = ke2.server_signature.as_deref().ok_or_else(invalid_data)?;
+        scheme
+            .verify(server_pub_key, &kdf::hash::<D>(&preamble), server_signature)
+            .map_err(|_| invalid_data())?;
+        kdf::verify::<D>(&keys.km2, &server_mac_message::<D>(server_identity, ke2)?, &ke2.server_mac)
+            .map_err(|_| invalid_data())?;
+
+        let transcript_hash = kdf::hash::<D>(&[&preamble[..], server_signature, &ke2.server_mac].concat());
+        let ke3 = KE3 {
+            client_extensions: None,
+            client_mac: kdf::mac::<D>(&keys.km3, client_identity),
+            client_signature: Some(scheme.sign(client_pri_key, &transcript_hash)?),
+            client_kem_ciphertext: None,
+            record_update: None,
+            client_binding_mac: None,
+        };
+        Ok((ke3, SessionKey::new(keys.session_key, transcript_hash), keys.ke2, keys.ke3))
+    }
+
+    /// Finish client requests with the KEM protocol.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: the [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `kem`: the [`Kem`] of `config.ake_protocol`.
+    /// * `client_pri_key`: Client's decapsulation key.
+    /// * `server_pub_key`: Server's encapsulation key.
+    /// * `client_identity`: The resolved client identity.
+    /// * `server_identity`: The resolved server identity.
+    /// * `ke2`: a KE2 message structure.
+    ///
+    /// # Returns
+    ///
+    /// * `ke3`: a KE3 structure, without client extensions nor record update.
+    /// * `session_key`: the shared session secret, with the hash of the handshake transcript.
+    /// * `ke2_key`: `Ke2`, the key of the server extensions.
+    /// * `ke3_key`: `Ke3`, the key of the client extensions and the record update.
+    #[allow(clippy::too_many_arguments)]
+    fn kem_finalize<D, R>(
+        &self,
+        config: &OpaqueConfig,
+        rng: &mut R,
+        kem: Kem,
+        client_pri_key: &[u8],
+        server_pub_key: &[u8],
+        client_identity: &[u8],
+        server_identity: &[u8],
+        ke2: &KE2,
+    ) -> io::Result<(KE3, SessionKey, Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        // Nothing on KE2 can be trusted before the server is authenticated, see the "KEM" section.
+        if ke2.server_extensions.is_some() || ke2.server_key_update.is_some() || ke2.oprf_seed_update.is_some() {
+            return Err(invalid_data());
+        }
+        let server_kem_ciphertext = ke2.server_kem_ciphertext.as_deref().ok_or_else(invalid_data)?;
+        let ikm = [
+            kem.decapsulate(&self.client_secret, &ke2.inner_ke2.server_keyshare)?,
+            kem.decapsulate(client_pri_key, server_kem_ciphertext)?,
+        ].concat();
+        let preamble = preamble(
+            config,
+            client_identity,
+            &self.ke1,
+            server_identity,
+            &ke2.inner_ke2,
+            self.channel_binding.as_deref(),
+        )?;
+        let keys = HandshakeKeys::derive::<D>(config.ake_protocol, &ikm, &kdf::hash::<D>(&preamble))?;
+
+        kdf::verify::<D>(&keys.km2, &server_mac_message::<D>(&preamble, ke2)?, &ke2.server_mac)
+            .map_err(|_| invalid_data())?;
+
+        // The second stage, keyed by the encapsulation to the server's long-term key.
+        let transcript_hash = kdf::hash::<D>(&[&preamble[..], &ke2.server_mac].concat());
+        let (client_kem_ciphertext, shared_secret) = kem.encapsulate(rng, server_pub_key)?;
+        let ke2_key = keys.ke2;
+        let keys = HandshakeKeys::derive::<D>(config.ake_protocol, &[&ikm[..], &shared_secret].concat(), &transcript_hash)?;
+
+        let ke3 = KE3 {
+            client_extensions: None,
+            client_mac: kdf::mac::<D>(&keys.km3, &transcript_hash),
+            client_signature: None,
+            client_kem_ciphertext: Some(client_kem_ciphertext),
+            record_update: None,
+            client_binding_mac: None,
+        };
+        Ok((ke3, SessionKey::new(keys.session_key, transcript_hash), ke2_key, keys.ke3))
+    }
+
+    /// Re-registers with `randomized_pwd`, for the server key and OPRF seed of `server_key_update` (see the "Server
+    /// key rotation" and "OPRF seed rotation" sections).
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: the [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `randomized_pwd`: The randomized password of the login, or the one of the evaluation under the current
+    ///   OPRF seed.
+    /// * `client_pri_key`: Client's private key, kept in external mode.
+    /// * `client_identity`: optional encoded client_identity, as given to `ClientFinish`.
+    /// * `server_key_update`: The `server_key_update` of [`KE2`].
+    ///
+    /// # Returns
+    ///
+    /// * `record`: The new [`RegistrationUpload`].
+    /// * `export_key`: The `export_key` of the new record.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When `server_key_update` doesn't hold a public key of `config`.
+    fn record_update<D, R>(
+        config: &OpaqueConfig,
+        rng: &mut R,
+        randomized_pwd: &[u8],
+        client_pri_key: &[u8],
+        client_identity: Option<&[u8]>,
+        server_key_update: &[u8],
+    ) -> io::Result<(RegistrationUpload, Vec<u8>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        if server_key_update.len() != 4 + config.public_key_size() {
+            return Err(invalid_data());
+        }
+        let server_key_id = u16::from_be_bytes([server_key_update[0], server_key_update[1]]);
+        let oprf_seed_id = u16::from_be_bytes([server_key_update[2], server_key_update[3]]);
+        let client_pri_key = match config.mode {
+            EnvelopeMode::Internal => None,
+            EnvelopeMode::External => Some(client_pri_key),
+        };
+
+        RegistrationUpload::create::<D, R>(
+            config,
+            rng,
+            randomized_pwd,
+            &server_key_update[4..],
+            client_pri_key,
+            client_identity,
+            server_key_id,
+            oprf_seed_id,
+        )
     }
 }
 
-struct ServerState {
+/// The server's state between `KE2` and `KE3`, see the "Stateless servers" section to hold it outside the server.
+pub struct ServerState {
+    /// The protocol the handshake runs, which selects the checks of `ServerFinish`.
+    ake_protocol: AkeProtocol,
+    /// Empty with KEM, where it's derived by `ServerFinish` once the client's encapsulation is received.
     expected_client_mac: Vec<u8>,
+    /// Empty with KEM, see `expected_client_mac`.
     session_key: Vec<u8>,
+    /// `Ke3`, used to decrypt the client extensions on [`KE3`]. Empty with KEM, see `expected_client_mac`.
+    client_extensions_key: Vec<u8>,
+    /// The transcript hash output with the session key. With SIGMA-I the client also signs it on [`KE3`].
+    transcript_hash: Vec<u8>,
+    /// Only set with SIGMA-I: the client's verifying key (`record.client_public_key`).
+    client_verifying_key: Option<Vec<u8>>,
+    /// Only set with KEM: the IKM gathered before the client's encapsulation on [`KE3`].
+    kem_ikm: Option<Vec<u8>>,
+    /// Only set with KEM: the id of the server's keypair, whose decapsulation key is looked up in the
+    /// [`ServerSetup`] by `ServerFinish` to decapsulate the client's ciphertext on [`KE3`], so the state (and a
+    /// token sealing it) never holds a long-term key.
+    server_key_id: Option<u16>,
+    /// The `client_binding_mac` expected on [`KE3`]. Only set when the handshake is bound to an outer channel,
+    /// otherwise it will be ```None```.
+    expected_client_binding_mac: Option<Vec<u8>>,
+    /// The `server_key_update` of [`KE2`], whose ids the `record_update` of [`KE3`] must be bound to. Only set
+    /// when the record was registered with an older server key or OPRF seed, otherwise it will be ```None```.
+    server_key_update: Option<Vec<u8>>,
+    /// The credential identifier of the login, given to the throttle by `ServerFinish`.
+    credential_identifier: String,
 }
 
 impl ServerState {
+    /// Serializes the state, to be sealed by [`crate::stateless::StateSealer`].
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When a field is longer than 65535 bytes (i.e. a huge channel binding or identifier).
+    pub(crate) fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut output = self.ake_protocol.id().to_vec();
+        write_field(&mut output, &self.expected_client_mac)?;
+        write_field(&mut output, &self.session_key)?;
+        write_field(&mut output, &self.client_extensions_key)?;
+        write_field(&mut output, &self.transcript_hash)?;
+        write_optional_field(&mut output, &self.client_verifying_key)?;
+        write_optional_field(&mut output, &self.kem_ikm)?;
+        write_optional_field(&mut output, &self.server_key_id.map(|key_id| key_id.to_be_bytes().to_vec()))?;
+        write_optional_field(&mut output, &self.expected_client_binding_mac)?;
+        write_optional_field(&mut output, &self.server_key_update)?;
+        write_field(&mut output, self.credential_identifier.as_bytes())?;
+        Ok(output)
+    }
+
+    /// Deserializes a state created by [`ServerState::serialize`].
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is truncated, has trailing bytes or an unknown protocol.
+    pub(crate) fn deserialize(input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+        let state = ServerState {
+            ake_protocol: AkeProtocol::from_id([read_u8(&mut reader)?, read_u8(&mut reader)?]).ok_or_else(invalid_data)?,
+            expected_client_mac: read_field(&mut reader)?,
+            session_key: read_field(&mut reader)?,
+            client_extensions_key: read_field(&mut reader)?,
+            transcript_hash: read_field(&mut reader)?,
+            client_verifying_key: read_optional_field(&mut reader)?,
+            kem_ikm: read_optional_field(&mut reader)?,
+            server_key_id: read_optional_field(&mut reader)?
+                .map(|key_id| <[u8; 2]>::try_from(&key_id[..]).map(u16::from_be_bytes).map_err(|_| invalid_data()))
+                .transpose()?,
+            expected_client_binding_mac: read_optional_field(&mut reader)?,
+            server_key_update: read_optional_field(&mut reader)?,
+            credential_identifier: String::from_utf8(read_field(&mut reader)?).map_err(|_| invalid_data())?,
+        };
+
+        if !reader.is_empty() {
+            return Err(invalid_data());
+        }
+        Ok(state)
+    }
+
     /// Init server response
     ///
     /// # Arguments
     ///
-    /// * `server_pri_key`: Server's private key.
-    /// * `server_pub_key`: Server's public key.
-    /// * `record`: A [`RegistrationUpload`] structure.
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `server_setup`: The server's [`ServerSetup`].
+    /// * `record`: A [`RegistrationUpload`] structure, or the one of [`RegistrationUpload::fake_record`] when
+    ///   `identifier` has no record.
     /// * `identifier`: The user's identifier.
-    /// * `oprf_seed`: The server-side seed.
+    /// * `client_identity`: Optional encoded client identity (defaults to `config.client_identity`). It MUST be
+    ///   the one the client gives to `ClientFinish`.
     /// * `ke1`: A [`KE1`] structure.
-    /// * `server_identity`: Optional server identity.
+    /// * `server_extensions`: Optional application extensions, sent encrypted.
+    /// * `channel_binding`: Optional binding to the outer channel (i.e. a `tls-exporter` value).
+    /// * `replay_cache`: Optional [`ReplayCache`], to reject replayed `KE1`s before any computation.
+    /// * `throttle`: Optional [`Throttle`], checked with `identifier` before the OPRF evaluation, and given the
+    ///   attempt as a failure until `ServerFinish` succeeds. It MUST be the same one given to `ServerFinish`.
     ///
     /// # Returns
     ///
+    /// * `state`: The [`ServerState`], to be given to [`ServerState::server_finish`].
     /// * `ke2`: A [`KE2`] structure.
-    pub fn server_init(
-        &self,
-        _server_pri_key: Vec<u8>,
-        _server_pub_key: Vec<u8>,
-        _record: RegistrationUpload,
-        _identifier: String,
-        _oprf_seed: Vec<u8>,
-        _ke1: KE1,
-        _server_identity: Option<String>,
-    ) { // -> KE2
-        /*
-        1. response = CreateCredentialResponse(ke1.request, server_public_key, record, credential_identifier, oprf_seed)
-        2. ke2 = Response(server_identity, server_private_key, client_identity, record.client_public_key, ke1, response)
-        3. Output ke2
-        */
-    }
-
-    /// Finish server response
+    /// * `client_info`: The client's application information, if any. It MUST NOT be trusted before
+    ///   `ServerFinish` succeeds.
+    ///
+    /// # Exceptions
+    ///
+    /// * `AlreadyExists` (```ReplayedKe1```): When `replay_cache` saw `ke1` already.
+    /// * `PermissionDenied` (```Throttled```): When `throttle` rejects `identifier`.
+    /// * `Unsupported` (```UnsupportedVersion```): When the version of the record isn't accepted by `config`
+    ///   (the client must register again), or `config.ake_group` doesn't support `config.ake_protocol`, or
+    ///   `config.hybrid_kem` is set with SIGMA-I or KEM.
+    /// * `NotFound` (```UnknownServerKey```/```UnknownOprfSeed```): When the key or the seed of the record was
+    ///   retired (the client must register again).
+    /// * `InvalidData` (```HandshakeError```): When `ke1` holds an invalid element or keyshare, or its hybrid KEM
+    ///   keyshare doesn't match `config.hybrid_kem`.
+    /// * `InvalidInput`: When `server_extensions` are given with KEM, which can't authenticate them.
+    #[allow(clippy::too_many_arguments)]
+    pub fn server_init<D, R>(
+        config: &OpaqueConfig,
+        rng: &mut R,
+        server_setup: &ServerSetup,
+        record: &RegistrationUpload,
+        identifier: &str,
+        client_identity: Option<&[u8]>,
+        ke1: &KE1,
+        server_extensions: Option<&[u8]>,
+        channel_binding: Option<&[u8]>,
+        replay_cache: Option<&ReplayCache>,
+        throttle: Option<&dyn Throttle>,
+    ) -> io::Result<(Self, KE2, Option<Vec<u8>>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        // KE2 isn't authenticated by the server's long-term key with KEM, see the "KEM" section.
+        let authenticated_ke2 = !matches!(config.ake_protocol, AkeProtocol::Kem(_));
+        if !authenticated_ke2 && server_extensions.is_some() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        if let Some(replay_cache) = replay_cache {
+            replay_cache.check(ke1)?;
+        }
+        if let Some(throttle) = throttle {
+            // The same for fake records, so unknown identifiers are throttled like registered ones. The attempt
+            // is a failure until ServerFinish authenticates the client, since KE2 is enough to check a guess.
+            throttle.check(identifier)?;
+            throttle.record_failure(identifier)?;
+        }
+        check_hybrid_kem(config)?;
+        if config.hybrid_kem.is_none() && ke1.client_kem_keyshare.is_some() {
+            return Err(invalid_data());
+        }
+        config.negotiate(record.version())?;
+        let server_keypair = server_setup
+            .server_keypair(record.server_key_id())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+        let response = CredentialResponse::create_credential_response::<D, R>(
+            config,
+            &ke1.request,
+            server_setup,
+            record,
+            identifier,
+            rng,
+        )?;
+
+        // A record of an older server key or OPRF seed is re-bound to the current ones on KE3, the client
+        // getting the evaluation under the current seed when the seed changes.
+        let oprf_seed_update = if authenticated_ke2 && record.oprf_seed_id() != server_setup.current_seed_id() {
+            let seed_id = server_setup.current_seed_id();
+            Some(ke1.request.evaluate::<D>(config, server_setup, seed_id, identifier)?)
+        } else {
+            None
+        };
+        let server_key_update = if authenticated_ke2
+            && (record.server_key_id() != server_setup.current_key_id() || oprf_seed_update.is_some())
+        {
+            let mut update = server_setup.current_key_id().to_be_bytes().to_vec();
+            update.extend_from_slice(&server_setup.current_seed_id().to_be_bytes());
+            update.extend_from_slice(server_setup.server_public_key());
+            Some(update)
+        } else {
+            None
+        };
+
+        let client_identity = config.client_identity_or(client_identity, record.client_pub_key());
+        let server_identity = config.server_identity_or(server_keypair.1);
+        let (mut state, mut ke2) = match config.ake_protocol {
+            AkeProtocol::TripleDh | AkeProtocol::Hmqv => ServerState::response::<D, R>(
+                config,
+                rng,
+                server_keypair,
+                record.client_pub_key(),
+                &client_identity,
+                &server_identity,
+                ke1,
+                response,
+                server_extensions,
+                channel_binding,
+                server_key_update.as_deref(),
+                oprf_seed_update.as_deref(),
+            )?,
+            AkeProtocol::SigmaI(scheme) => ServerState::sigma_i_response::<D, R>(
+                config,
+                rng,
+                scheme,
+                server_keypair.0,
+                record.client_pub_key(),
+                &client_identity,
+                &server_identity,
+                ke1,
+                response,
+                server_extensions,
+                channel_binding,
+                server_key_update.as_deref(),
+                oprf_seed_update.as_deref(),
+            )?,
+            AkeProtocol::Kem(kem) => ServerState::kem_response::<D, R>(
+                config,
+                rng,
+                kem,
+                record.server_key_id(),
+                record.client_pub_key(),
+                &client_identity,
+                &server_identity,
+                ke1,
+                response,
+                server_extensions,
+                channel_binding,
+                server_key_update.as_deref(),
+                oprf_seed_update.as_deref(),
+            )?,
+        };
+        if let Some(channel_binding) = channel_binding {
+            let ke1 = ke1.serialize()?;
+            let server_nonce = &ke2.inner_ke2.server_nonce;
+            ke2.server_binding_mac = Some(binding_mac::<D>(channel_binding, STR_SERVER_BINDING, &ke1, server_nonce));
+            state.expected_client_binding_mac =
+                Some(binding_mac::<D>(channel_binding, STR_CLIENT_BINDING, &ke1, server_nonce));
+        }
+        state.credential_identifier = identifier.to_string();
+        Ok((state, ke2, ke1.client_info.clone()))
+    }
+
+    /// Finish server response. The state is either the one kept since `ServerInit`, or the one opened from a
+    /// token by [`crate::stateless::StateSealer::open`].
     ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`] given to `ServerInit`.
+    /// * `server_setup`: The server's [`ServerSetup`], holding the KEM decapsulation key of the login (the state
+    ///   only keeps its key id).
     /// * `ke3`: A [`KE3`] structure.
+    /// * `throttle`: Optional [`Throttle`], given the success of the login (`ServerInit` already recorded it as a
+    ///   failure). It MUST be the same one given to `ServerInit`.
     ///
     /// # Returns
     ///
-    /// * `session_key`: Shared session secret.
+    /// * `session_key`: Shared session secret, see [`crate::session::SessionKey`].
+    /// * `client_extensions`: The decrypted client extensions, if any.
+    /// * `record_update`: The record re-bound to the current server key and OPRF seed, if any. It replaces the
+    ///   stored record.
+    ///
+    /// # Exceptions
     ///
-    pub fn server_finish(&self, _ke3: KE3) { // -> Vec<u8>
-        /*
-         1. if !ct_equal(ke3.client_mac, state.expected_client_mac):
-                raise HandshakeError
-         2. Output state.session_key
-         */
+    /// * `InvalidData` (```HandshakeError```): When the client can't be authenticated (i.e. a wrong password). The
+    ///   error holds a [`ChannelBindingError`] when `client_binding_mac` doesn't match the channel binding (or is
+    ///   missing).
+    /// * `NotFound` (```UnknownServerKey```): With KEM, when the server key of the login was retired since
+    ///   `ServerInit`.
+    pub fn server_finish<D>(
+        self,
+        config: &OpaqueConfig,
+        server_setup: &ServerSetup,
+        ke3: &KE3,
+        throttle: Option<&dyn Throttle>,
+    ) -> io::Result<(SessionKey, Option<Vec<u8>>, Option<RegistrationUpload>)>
+    where
+        D: SuiteHash,
+    {
+        let result = self.verify::<D>(config, server_setup, ke3);
+        if let (Some(throttle), Ok(_)) = (throttle, &result) {
+            // A failure was recorded by ServerInit already.
+            throttle.record_success(&self.credential_identifier)?;
+        }
+
+        let (session_key, client_extensions, record_update) = result?;
+        Ok((SessionKey::new(session_key, self.transcript_hash), client_extensions, record_update))
     }
 
-    /// Build response message.
+    /// Authenticates the client on [`KE3`], and opens the client extensions.
+    ///
+    /// # Returns
+    ///
+    /// * `session_key`: The session key (only derived here with KEM).
+    /// * `client_extensions`: The decrypted client extensions, if any.
+    /// * `record_update`: The new record, if asked on `KE2` and sent on `KE3`.
+    #[allow(clippy::type_complexity)]
+    fn verify<D>(&self, config: &OpaqueConfig, server_setup: &ServerSetup, ke3: &KE3) -> io::Result<(Vec<u8>, Option<Vec<u8>>, Option<RegistrationUpload>)>
+    where
+        D: SuiteHash,
+    {
+        // Before any other check, so a binding mismatch isn't reported as a wrong password and vice versa.
+        check_binding_mac(self.expected_client_binding_mac.as_deref(), ke3.client_binding_mac.as_deref())?;
+        let (session_key, client_extensions_key) = match self.ake_protocol {
+            AkeProtocol::TripleDh | AkeProtocol::Hmqv => {
+                if !kdf::ct_equal(&ke3.client_mac, &self.expected_client_mac) {
+                    return Err(invalid_data());
+                }
+                (self.session_key.clone(), self.client_extensions_key.clone())
+            }
+            AkeProtocol::SigmaI(scheme) => {
+                if !kdf::ct_equal(&ke3.client_mac, &self.expected_client_mac) {
+                    return Err(invalid_data());
+                }
+                let client_verifying_key = self.client_verifying_key.as_deref().ok_or_else(invalid_data)?;
+                let client_signature = ke3.client_signature.as_deref().ok_or_else(invalid_data)?;
+                scheme
+                    .verify(client_verifying_key, &self.transcript_hash, client_signature)
+                    .map_err(|_| invalid_data())?;
+                (self.session_key.clone(), self.client_extensions_key.clone())
+            }
+            AkeProtocol::Kem(kem) => {
+                let ikm = self.kem_ikm.as_deref().ok_or_else(invalid_data)?;
+                let server_key_id = self.server_key_id.ok_or_else(invalid_data)?;
+                let (server_decapsulation_key, _) = server_setup
+                    .server_keypair(server_key_id)
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+                let client_kem_ciphertext = ke3.client_kem_ciphertext.as_deref().ok_or_else(invalid_data)?;
+                let shared_secret = kem.decapsulate(server_decapsulation_key, client_kem_ciphertext)?;
+
+                let keys = HandshakeKeys::derive::<D>(self.ake_protocol, &[ikm, &shared_secret].concat(), &self.transcript_hash)?;
+                if !kdf::ct_equal(&ke3.client_mac, &kdf::mac::<D>(&keys.km3, &self.transcript_hash)) {
+                    return Err(invalid_data());
+                }
+                (keys.session_key, keys.ke3)
+            }
+        };
+
+        let client_extensions =
+            open_optional::<D>(&client_extensions_key, STR_CLIENT_EXTENSIONS, &ke3.client_extensions)?;
+        let record_update = match &self.server_key_update {
+            Some(server_key_update) => open_optional::<D>(&client_extensions_key, STR_RECORD_UPDATE, &ke3.record_update)?
+                .map(|record| check_record_update::<D>(config, &record, server_key_update))
+                .transpose()?,
+            None => None,
+        };
+        Ok((session_key, client_extensions, record_update))
+    }
+
+    /// Build response message with 3DH or HMQV, which only differ on the IKM.
     ///
     /// # Arguments
     ///
-    /// * `server_pub_key`: Server's public key.
-    /// * `server_pri_key`: Server's private key.
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `server_keypair`: The server's private and public keys for the record.
     /// * `client_pub_key`: Client's public key.
-    /// * `client_pri_key`: Client's private key.
+    /// * `client_identity`: The resolved client identity.
+    /// * `server_identity`: The resolved server identity.
     /// * `ke1`: A [`KE1`] structure.
     /// * `credential_response`: A [`CredentialResponse`] structure.
-    /// * `server_identity`: Optional server identity.
-    /// * `client_identity`: Optional client identity.
+    /// * `server_extensions`: Optional application extensions.
+    /// * `channel_binding`: Optional binding to the outer channel.
+    /// * `server_key_update`: Only used when the record was registered with an older server key or OPRF seed,
+    ///   otherwise it will be ```None```.
+    /// * `oprf_seed_update`: The evaluation under the current OPRF seed. Only used when the record was registered
+    ///   with an older seed, otherwise it will be ```None```.
     ///
     /// # Returns
     ///
+    /// * `state`: The [`ServerState`] of the handshake.
     /// * `ke2`: A [`KE2`] structure.
-    fn response(
-        &self,
-        _server_pub_key: Vec<u8>,
-        _server_pri_key: Vec<u8>,
-        _client_pub_key: Vec<u8>,
-        _client_pri_key: Vec<u8>,
-        _ke1: KE1,
-        _credential_response: CredentialResponse,
-        _server_identity: Option<String>,
-        _client_identity: Option<String>,
-    ) { // -> KE2
-        /*
-        1. server_nonce = random(Nn)
-        2. server_secret, server_keyshare = GenerateAuthKeyPair()
-        3. Create inner_ke2 ike2 with (credential_response, server_nonce, server_keyshare)
-        4. preamble = Preamble(client_identity, ke1, server_identity, ike2)
-        5. ikm = TripleDHIKM(server_secret, ke1.client_keyshare, server_private_key, ke1.client_keyshare, server_secret, client_public_key)
-        6. Km2, Km3, session_key = DeriveKeys(ikm, preamble)
-        7. server_mac = MAC(Km2, Hash(preamble))
-        8. expected_client_mac = MAC(Km3, Hash(concat(preamble, server_mac))
-        9. Populate state with ServerState(expected_client_mac, session_key)
-        10. Create KE2 ke2 with (ike2, server_mac)
-        11. Output ke2
-        */
+    #[allow(clippy::too_many_arguments)]
+    fn response<D, R>(
+        config: &OpaqueConfig,
+        rng: &mut R,
+        server_keypair: (&[u8], &[u8]),
+        client_pub_key: &[u8],
+        client_identity: &[u8],
+        server_identity: &[u8],
+        ke1: &KE1,
+        credential_response: CredentialResponse,
+        server_extensions: Option<&[u8]>,
+        channel_binding: Option<&[u8]>,
+        server_key_update: Option<&[u8]>,
+        oprf_seed_update: Option<&[u8]>,
+    ) -> io::Result<(Self, KE2)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let group = config.ake_group;
+        group.validate_public_key(&ke1.client_keyshare).map_err(|_| invalid_data())?;
+
+        let mut server_nonce = vec![0u8; NONCE_SIZE];
+        rng.fill_bytes(&mut server_nonce);
+        let (server_secret, server_keyshare) = generate_keyshare::<D, R>(config, rng)?;
+        // Hybrid mode: a peer omitting its KEM keyshare is rejected, so the mode can't be downgraded.
+        let (server_kem_keyshare, kem_shared_secret) = match (config.hybrid_kem, &ke1.client_kem_keyshare) {
+            (Some(kem), Some(client_kem_keyshare)) => {
+                let (ciphertext, shared_secret) = kem.encapsulate(rng, client_kem_keyshare)?;
+                (Some(ciphertext), shared_secret)
+            }
+            (None, None) => (None, Vec::new()),
+            _ => return Err(invalid_data()),
+        };
+        let inner_ke2 = InnerKE2 {
+            response: credential_response,
+            server_nonce,
+            server_keyshare,
+            server_kem_keyshare,
+        };
+
+        let preamble = preamble(
+            config,
+            client_identity,
+            &ke1.serialize()?,
+            server_identity,
+            &inner_ke2,
+            channel_binding,
+        )?;
+        let (server_pri_key, _) = server_keypair;
+        let ikm = match config.ake_protocol {
+            AkeProtocol::Hmqv => {
+                let d = group.hmqv_exponent::<D>(&ke1.client_keyshare, server_identity)?;
+                let e = group.hmqv_exponent::<D>(&inner_ke2.server_keyshare, client_identity)?;
+                group.hmqv(&server_secret, server_pri_key, &e, &ke1.client_keyshare, client_pub_key, &d)?
+            }
+            _ => [
+                diffie_hellman(group, &server_secret, &ke1.client_keyshare)?,
+                diffie_hellman(group, server_pri_key, &ke1.client_keyshare)?,
+                diffie_hellman(group, &server_secret, client_pub_key)?,
+            ].concat(),
+        };
+        let ikm = [ikm, kem_shared_secret].concat();
+        let keys = HandshakeKeys::derive::<D>(config.ake_protocol, &ikm, &kdf::hash::<D>(&preamble))?;
+
+        let mut ke2 = KE2 {
+            inner_ke2,
+            server_extensions: seal_optional::<D>(&keys.ke2, STR_SERVER_EXTENSIONS, server_extensions)?,
+            server_mac: Vec::new(),
+            server_signature: None,
+            server_kem_ciphertext: None,
+            server_key_update: server_key_update.map(<[u8]>::to_vec),
+            oprf_seed_update: seal_optional::<D>(&keys.ke2, STR_OPRF_SEED_UPDATE, oprf_seed_update)?,
+            server_binding_mac: None,
+        };
+        ke2.server_mac = kdf::mac::<D>(&keys.km2, &server_mac_message::<D>(&preamble, &ke2)?);
+        let transcript_hash = kdf::hash::<D>(&[&preamble[..], &ke2.server_mac].concat());
+
+        let state = ServerState {
+            ake_protocol: config.ake_protocol,
+            expected_client_mac: kdf::mac::<D>(&keys.km3, &transcript_hash),
+            session_key: keys.session_key,
+            client_extensions_key: keys.ke3,
+            transcript_hash,
+            client_verifying_key: None,
+            kem_ikm: None,
+            server_key_id: None,
+            expected_client_binding_mac: None,
+            server_key_update: server_key_update.map(<[u8]>::to_vec),
+            credential_identifier: String::new(),
+        };
+        Ok((state, ke2))
+    }
+
+    /// Build response message with the SIGMA-I protocol.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `scheme`: The [`SignatureScheme`] of `config.ake_protocol`.
+    /// * `server_pri_key`: Server's signing key.
+    /// * `client_pub_key`: Client's verifying key.
+    /// * `client_identity`: The resolved client identity.
+    /// * `server_identity`: The resolved server identity.
+    /// * `ke1`: A [`KE1`] structure.
+    /// * `credential_response`: A [`CredentialResponse`] structure.
+    /// * `server_extensions`: Optional application extensions.
+    /// * `channel_binding`: Optional binding to the outer channel.
+    /// * `server_key_update`: Only used when the record was registered with an older server key or OPRF seed,
+    ///   otherwise it will be ```None```.
+    /// * `oprf_seed_update`: The evaluation under the current OPRF seed. Only used when the record was registered
+    ///   with an older seed, otherwise it will be ```None```.
+    ///
+    /// # Returns
+    ///
+    /// * `state`: The [`ServerState`] of the handshake.
+    /// * `ke2`: A [`KE2`] structure.
+    #[allow(clippy::too_many_arguments)]
+    fn sigma_i_response<D, R>(
+        config: &OpaqueConfig,
+        rng: &mut R,
+        scheme: SignatureScheme,
+        server_pri_key: &[u8],
+        client_pub_key: &[u8],
+        client_identity: &[u8],
+        server_identity: &[u8],
+        ke1: &KE1,
+        credential_response: CredentialResponse,
+        server_extensions: Option<&[u8]>,
+        channel_binding: Option<&[u8]>,
+        server_key_update: Option<&[u8]>,
+        oprf_seed_update: Option<&[u8]>,
+    ) -> io::Result<(Self, KE2)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let group = config.ake_group;
+        group.validate_public_key(&ke1.client_keyshare).map_err(|_| invalid_data())?;
+
+        let mut server_nonce = vec![0u8; NONCE_SIZE];
+        rng.fill_bytes(&mut server_nonce);
+        let (server_secret, server_keyshare) = generate_keyshare::<D, R>(config, rng)?;
+        let inner_ke2 = InnerKE2 {
+            response: credential_response,
+            server_nonce,
+            server_keyshare,
+            server_kem_keyshare: None,
+        };
+
+        let preamble = preamble(
+            config,
+            client_identity,
+            &ke1.serialize()?,
+            server_identity,
+            &inner_ke2,
+            channel_binding,
+        )?;
+        let ikm = diffie_hellman(group, &server_secret, &ke1.client_keyshare)?;
+        let keys = HandshakeKeys::derive::<D>(config.ake_protocol, &ikm, &kdf::hash::<D>(&preamble))?;
+
+        let server_signature = scheme.sign(server_pri_key, &kdf::hash::<D>(&preamble))?;
+        let mut ke2 = KE2 {
+            inner_ke2,
+            server_extensions: seal_optional::<D>(&keys.ke2, STR_SERVER_EXTENSIONS, server_extensions)?,
+            server_mac: Vec::new(),
+            server_signature: None,
+            server_kem_ciphertext: None,
+            server_key_update: server_key_update.map(<[u8]>::to_vec),
+            oprf_seed_update: seal_optional::<D>(&keys.ke2, STR_OPRF_SEED_UPDATE, oprf_seed_update)?,
+            server_binding_mac: None,
+        };
+        ke2.server_mac = kdf::mac::<D>(&keys.km2, &server_mac_message::<D>(server_identity, &ke2)?);
+        let transcript_hash = kdf::hash::<D>(&[&preamble[..], &server_signature, &ke2.server_mac].concat());
+        ke2.server_signature = Some(server_signature);
+
+        let state = ServerState {
+            ake_protocol: config.ake_protocol,
+            expected_client_mac: kdf::mac::<D>(&keys.km3, client_identity),
+            session_key: keys.session_key,
+            client_extensions_key: keys.ke3,
+            transcript_hash,
+            client_verifying_key: Some(client_pub_key.to_vec()),
+            kem_ikm: None,
+            server_key_id: None,
+            expected_client_binding_mac: None,
+            server_key_update: server_key_update.map(<[u8]>::to_vec),
+            credential_identifier: String::new(),
+        };
+        Ok((state, ke2))
+    }
+
+    /// Build response message with the KEM protocol.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `kem`: The [`Kem`] of `config.ake_protocol`.
+    /// * `server_key_id`: The id of the server's keypair, whose decapsulation key `ServerFinish` uses.
+    /// * `client_pub_key`: Client's encapsulation key.
+    /// * `client_identity`: The resolved client identity.
+    /// * `server_identity`: The resolved server identity.
+    /// * `ke1`: A [`KE1`] structure.
+    /// * `credential_response`: A [`CredentialResponse`] structure.
+    /// * `server_extensions`: Optional application extensions.
+    /// * `channel_binding`: Optional binding to the outer channel.
+    /// * `server_key_update`: Only used when the record was registered with an older server key or OPRF seed,
+    ///   otherwise it will be ```None```.
+    /// * `oprf_seed_update`: The evaluation under the current OPRF seed. Only used when the record was registered
+    ///   with an older seed, otherwise it will be ```None```.
+    ///
+    /// # Returns
+    ///
+    /// * `state`: The [`ServerState`] of the handshake.
+    /// * `ke2`: A [`KE2`] structure.
+    #[allow(clippy::too_many_arguments)]
+    fn kem_response<D, R>(
+        config: &OpaqueConfig,
+        rng: &mut R,
+        kem: Kem,
+        server_key_id: u16,
+        client_pub_key: &[u8],
+        client_identity: &[u8],
+        server_identity: &[u8],
+        ke1: &KE1,
+        credential_response: CredentialResponse,
+        server_extensions: Option<&[u8]>,
+        channel_binding: Option<&[u8]>,
+        server_key_update: Option<&[u8]>,
+        oprf_seed_update: Option<&[u8]>,
+    ) -> io::Result<(Self, KE2)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let (server_keyshare, ephemeral_secret) = kem.encapsulate(rng, &ke1.client_keyshare)?;
+        let (server_kem_ciphertext, static_secret) = kem.encapsulate(rng, client_pub_key)?;
+
+        let mut server_nonce = vec![0u8; NONCE_SIZE];
+        rng.fill_bytes(&mut server_nonce);
+        let inner_ke2 = InnerKE2 {
+            response: credential_response,
+            server_nonce,
+            server_keyshare,
+            server_kem_keyshare: None,
+        };
+
+        let preamble = preamble(
+            config,
+            client_identity,
+            &ke1.serialize()?,
+            server_identity,
+            &inner_ke2,
+            channel_binding,
+        )?;
+        let ikm = [ephemeral_secret, static_secret].concat();
+        let keys = HandshakeKeys::derive::<D>(config.ake_protocol, &ikm, &kdf::hash::<D>(&preamble))?;
+
+        let mut ke2 = KE2 {
+            inner_ke2,
+            server_extensions: seal_optional::<D>(&keys.ke2, STR_SERVER_EXTENSIONS, server_extensions)?,
+            server_mac: Vec::new(),
+            server_signature: None,
+            server_kem_ciphertext: Some(server_kem_ciphertext),
+            server_key_update: server_key_update.map(<[u8]>::to_vec),
+            oprf_seed_update: seal_optional::<D>(&keys.ke2, STR_OPRF_SEED_UPDATE, oprf_seed_update)?,
+            server_binding_mac: None,
+        };
+        ke2.server_mac = kdf::mac::<D>(&keys.km2, &server_mac_message::<D>(&preamble, &ke2)?);
+        let transcript_hash = kdf::hash::<D>(&[&preamble[..], &ke2.server_mac].concat());
+
+        let state = ServerState {
+            ake_protocol: config.ake_protocol,
+            expected_client_mac: Vec::new(),
+            session_key: Vec::new(),
+            client_extensions_key: Vec::new(),
+            transcript_hash,
+            client_verifying_key: None,
+            kem_ikm: Some(ikm),
+            server_key_id: Some(server_key_id),
+            expected_client_binding_mac: None,
+            server_key_update: server_key_update.map(<[u8]>::to_vec),
+            credential_identifier: String::new(),
+        };
+        Ok((state, ke2))
+    }
+}
+
+/// The keys derived from the IKM of a handshake (`DeriveKeys`).
+struct HandshakeKeys {
+    /// `Km2`, the key of `server_mac`.
+    km2: Vec<u8>,
+    /// `Km3`, the key of `client_mac`.
+    km3: Vec<u8>,
+    /// `Ke2`, the key of the server extensions.
+    ke2: Vec<u8>,
+    /// `Ke3`, the key of the client extensions.
+    ke3: Vec<u8>,
+    session_key: Vec<u8>,
+}
+
+impl HandshakeKeys {
+    /// Derives the keys of a handshake from its IKM and `preamble_hash = Hash(preamble)`, as `DeriveKeys` on the
+    /// draft:
+    ///
+    /// ```txt
+    ///     prk = Extract("", ikm)
+    ///     handshake_secret = Expand-Label(prk, "HandshakeSecret", Hash(preamble), Nh)
+    ///     session_key = Expand-Label(prk, "SessionKey", Hash(preamble), Nh)
+    ///     Km2 = Expand-Label(handshake_secret, "ServerMAC", "", Nh)
+    ///     Km3 = Expand-Label(handshake_secret, "ClientMAC", "", Nh)
+    ///     Ke2 = Expand-Label(handshake_secret, "ServerEncryption", "", Nh)
+    ///     Ke3 = Expand-Label(handshake_secret, "ClientEncryption", "", Nh)
+    /// ```
+    ///
+    /// The first two labels are prefixed by the label of `protocol` (i.e. `"HMQV-HandshakeSecret"`), so two
+    /// protocols never share keys.
+    fn derive<D>(protocol: AkeProtocol, ikm: &[u8], preamble_hash: &[u8]) -> io::Result<Self>
+    where
+        D: SuiteHash,
+    {
+        let prk = kdf::extract::<D>(&[], ikm);
+        let size = kdf::output_size::<D>();
+
+        let label = protocol.label();
+        let handshake_secret =
+            kdf::expand_label::<D>(&prk, &[label, STR_HANDSHAKE_SECRET].concat(), preamble_hash, size)?;
+        let session_key = kdf::expand_label::<D>(&prk, &[label, STR_SESSION_KEY].concat(), preamble_hash, size)?;
+        let key = |label: &[u8]| kdf::expand_label::<D>(&handshake_secret, label, &[], size);
+
+        Ok(HandshakeKeys {
+            km2: key(STR_SERVER_MAC)?,
+            km3: key(STR_CLIENT_MAC)?,
+            ke2: key(STR_SERVER_ENCRYPTION)?,
+            ke3: key(STR_CLIENT_ENCRYPTION)?,
+            session_key,
+        })
+    }
+}
+
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+/// Builds the preamble of a handshake:
+///
+/// ```txt
+///     preamble = concat("OPAQUEv1-", protocol_label,
+///                       I2OSP(len(context), 2), context,
+///                       I2OSP(len(client_identity), 2), client_identity,
+///                       ke1,
+///                       I2OSP(len(server_identity), 2), server_identity,
+///                       inner_ke2)
+/// ```
+///
+/// followed by `concat("ChannelBinding", I2OSP(len(channel_binding), 2), channel_binding)` when the handshake
+/// is bound to an outer channel.
+#[allow(clippy::too_many_arguments)]
+fn preamble(
+    config: &OpaqueConfig,
+    client_identity: &[u8],
+    ke1: &[u8],
+    server_identity: &[u8],
+    inner_ke2: &InnerKE2,
+    channel_binding: Option<&[u8]>,
+) -> io::Result<Vec<u8>> {
+    let mut preamble = STR_OPAQUE_V1.to_vec();
+    preamble.extend_from_slice(config.ake_protocol.label());
+    write_field(&mut preamble, &config.context)?;
+    write_field(&mut preamble, client_identity)?;
+    preamble.extend_from_slice(ke1);
+    write_field(&mut preamble, server_identity)?;
+    inner_ke2.serialize(&mut preamble)?;
+
+    if let Some(channel_binding) = channel_binding {
+        preamble.extend_from_slice(STR_CHANNEL_BINDING);
+        write_field(&mut preamble, channel_binding)?;
+    }
+    Ok(preamble)
+}
+
+/// Generates an ephemeral keyshare of `config.ake_group` from a random seed (`DeriveDiffieHellmanKeyPair` of a
+/// `Nseed` bytes seed, as on RFC 9807).
+fn generate_keyshare<D, R>(config: &OpaqueConfig, rng: &mut R) -> io::Result<(Vec<u8>, Vec<u8>)>
+where
+    D: SuiteHash,
+    R: RngCore + CryptoRng,
+{
+    let mut seed = vec![0u8; 32];
+    rng.fill_bytes(&mut seed);
+    config.ake_group.derive_keypair::<D>(config.suite, &seed)
+}
+
+/// The message of `server_mac`: `Hash(preamble)`, or
+/// `Hash(concat(preamble, server_extensions, server_key_update, oprf_seed_update))` (each field with its presence
+/// flag and length) when any of them is set. With SIGMA-I the server identity takes the place of the preamble,
+/// which is covered by `server_signature` instead.
+fn server_mac_message<D>(preamble: &[u8], ke2: &KE2) -> io::Result<Vec<u8>>
+where
+    D: SuiteHash,
+{
+    let mut message = preamble.to_vec();
+    if ke2.server_extensions.is_some() || ke2.server_key_update.is_some() || ke2.oprf_seed_update.is_some() {
+        write_optional_field(&mut message, &ke2.server_extensions)?;
+        write_optional_field(&mut message, &ke2.server_key_update)?;
+        write_optional_field(&mut message, &ke2.oprf_seed_update)?;
+    }
+    Ok(kdf::hash::<D>(&message))
+}
+
+/// Decodes the `record_update` of [`KE3`], checking it's bound to the key and seed ids of `server_key_update`.
+///
+/// # Exceptions
+///
+/// * `InvalidData`: When the record can't be decoded, or it's bound to other ids.
+fn check_record_update<D: SuiteHash>(
+    config: &OpaqueConfig,
+    record: &[u8],
+    server_key_update: &[u8],
+) -> io::Result<RegistrationUpload> {
+    let record = RegistrationUpload::deserialize::<D>(config, record)?;
+    let ids = [record.server_key_id().to_be_bytes(), record.oprf_seed_id().to_be_bytes()].concat();
+    if server_key_update.get(..4) != Some(&ids[..]) {
+        return Err(invalid_data());
+    }
+    Ok(record)
+}
+
+/// Checks that `config.hybrid_kem` is only set with 3DH and HMQV.
+///
+/// # Exceptions
+///
+/// * `Unsupported`: When `config.hybrid_kem` is set with another protocol.
+fn check_hybrid_kem(config: &OpaqueConfig) -> io::Result<()> {
+    match (config.hybrid_kem, config.ake_protocol) {
+        (None, _) | (Some(_), AkeProtocol::TripleDh) | (Some(_), AkeProtocol::Hmqv) => Ok(()),
+        _ => Err(io::Error::from(io::ErrorKind::Unsupported)),
+    }
+}
+
+/// The MAC confirming the channel binding alone (`label` is ```ServerBinding``` on `KE2` and ```ClientBinding```
+/// on `KE3`):
+///
+/// ```txt
+///     binding_key = Extract("ChannelBinding", channel_binding)
+///     binding_mac = MAC(binding_key, Hash(concat(label, ke1, server_nonce)))
+/// ```
+fn binding_mac<D>(channel_binding: &[u8], label: &[u8], ke1: &[u8], server_nonce: &[u8]) -> Vec<u8>
+where
+    D: SuiteHash,
+{
+    let binding_key = kdf::extract::<D>(STR_CHANNEL_BINDING, channel_binding);
+    kdf::mac::<D>(&binding_key, &kdf::hash::<D>(&[label, ke1, server_nonce].concat()))
+}
+
+/// Checks the binding MAC received from the peer against the expected one, before any other MAC.
+///
+/// # Exceptions
+///
+/// * `InvalidData` (```ChannelBindingError```): When a binding is in use and the peer's MAC is missing or doesn't
+///   match (the peer used another binding, or none).
+/// * `InvalidData` (```HandshakeError```): When no binding is in use but the peer sent a MAC.
+fn check_binding_mac(expected: Option<&[u8]>, received: Option<&[u8]>) -> io::Result<()> {
+    match (expected, received) {
+        (None, None) => Ok(()),
+        (Some(expected), Some(received)) if kdf::ct_equal(expected, received) => Ok(()),
+        (Some(_), _) => Err(io::Error::new(io::ErrorKind::InvalidData, ChannelBindingError)),
+        (None, Some(_)) => Err(invalid_data()),
+    }
+}
+
+/// Diffie-Hellman on `group`, failing with `InvalidData` (```HandshakeError```) on invalid keys.
+fn diffie_hellman(group: AkeGroup, private_key: &[u8], public_key: &[u8]) -> io::Result<Vec<u8>> {
+    group.diffie_hellman(private_key, public_key).map_err(|_| invalid_data())
+}
+
+fn seal_optional<D>(key: &[u8], info: &[u8], plaintext: Option<&[u8]>) -> io::Result<Option<Vec<u8>>>
+where
+    D: SuiteHash,
+{
+    plaintext.map(|plaintext| kdf::seal::<D>(key, info, plaintext)).transpose()
+}
+
+fn open_optional<D>(key: &[u8], info: &[u8], sealed: &Option<Vec<u8>>) -> io::Result<Option<Vec<u8>>>
+where
+    D: SuiteHash,
+{
+    sealed.as_ref().map(|sealed| kdf::open::<D>(key, info, sealed)).transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use rand::rngs::OsRng;
+    use sha2::{Sha256, Sha384, Sha512};
+    use sha3::Sha3_512;
+    use crate::config::{CipherSuite, ProtocolVersion};
+    use crate::envelope::EnvelopeMode;
+    use crate::messages::registration::{RegistrationRequest, RegistrationResponse};
+    use crate::replay::is_replay;
+    use crate::test_support::{login, register, server_extensions, Login};
+    use crate::throttle::{ThrottleConfig, Throttled, TokenBucketThrottle};
+    use hex_literal::hex;
+
+    fn config(mode: EnvelopeMode) -> OpaqueConfig {
+        OpaqueConfig::new(CipherSuite::Ristretto255Sha512, mode, b"ake tests".to_vec(), None, None)
+    }
+
+    fn protocols() -> [AkeProtocol; 4] {
+        [AkeProtocol::TripleDh, AkeProtocol::Hmqv, AkeProtocol::SigmaI(SignatureScheme::Ed25519), AkeProtocol::Kem(Kem::MlKem768)]
+    }
+
+    fn assert_login(login: &Login, export_key: &[u8]) {
+        assert_eq!(login.client_session_key.as_bytes(), login.server_session_key.as_bytes());
+        assert_eq!(login.client_session_key.transcript_hash(), login.server_session_key.transcript_hash());
+        assert_eq!(login.export_key, export_key);
+        assert_eq!(login.server_extensions.as_deref(), server_extensions(login.ake_protocol));
+        assert_eq!(login.client_extensions.as_deref(), Some(&b"answer"[..]));
+        assert_eq!(login.client_info.as_deref(), Some(&b"device"[..]));
+    }
+
+    #[test]
+    fn login_round_trip() {
+        let config = config(EnvelopeMode::Internal);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+
+        let first = login::<Sha512>(&config, &setup, &record, b"password").unwrap();
+        assert_login(&first, &export_key);
+        let second = login::<Sha512>(&config, &setup, &record, b"password").unwrap();
+        assert_ne!(first.client_session_key.as_bytes(), second.client_session_key.as_bytes());
+    }
+
+    #[test]
+    fn login_round_trip_with_external_keys() {
+        let config = config(EnvelopeMode::External);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (client_pri_key, _) = config.generate_long_term_keypair(&mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha512>(&config, &setup, b"password", Some(&client_pri_key));
+
+        assert_login(&login::<Sha512>(&config, &setup, &record, b"password").unwrap(), &export_key);
+    }
+
+    #[test]
+    fn login_round_trip_on_p256() {
+        let config = OpaqueConfig::new(CipherSuite::P256Sha256, EnvelopeMode::Internal, Vec::new(), Some(b"example.com".to_vec()), None)
+            .with_ake_group(AkeGroup::P256);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha256>(&config, &setup, b"password", None);
+
+        assert_login(&login::<Sha256>(&config, &setup, &record, b"password").unwrap(), &export_key);
+    }
+
+    #[test]
+    fn login_round_trip_on_p384_and_sha3() {
+        let config = OpaqueConfig::new(CipherSuite::P384Sha384, EnvelopeMode::Internal, Vec::new(), None, None)
+            .with_ake_group(AkeGroup::P384);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha384>(&config, &setup, b"password", None);
+        assert_login(&login::<Sha384>(&config, &setup, &record, b"password").unwrap(), &export_key);
+
+        let config = OpaqueConfig::new(CipherSuite::Ristretto255Sha3_512, EnvelopeMode::Internal, Vec::new(), None, None);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha3_512>(&config, &setup, b"password", None);
+        assert_login(&login::<Sha3_512>(&config, &setup, &record, b"password").unwrap(), &export_key);
+
+        // SHA-512 has the output size of SHA3-512, but isn't the suite's hash function.
+        assert_eq!(
+            login::<Sha512>(&config, &setup, &record, b"password").err().unwrap().kind(),
+            io::ErrorKind::InvalidInput,
+        );
+    }
+
+    #[test]
+    fn login_round_trip_on_draft18() {
+        let config = config(EnvelopeMode::Internal).with_versions(ProtocolVersion::Draft18, &[]);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+        assert_eq!(record.version(), ProtocolVersion::Draft18);
+
+        assert_login(&login::<Sha512>(&config, &setup, &record, b"password").unwrap(), &export_key);
+    }
+
+    #[test]
+    fn wrong_password_fails() {
+        let config = config(EnvelopeMode::Internal);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+
+        let result = login::<Sha512>(&config, &setup, &record, b"wrong password");
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fake_record_fails_like_a_wrong_password() {
+        let config = config(EnvelopeMode::Internal);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let fake = RegistrationUpload::fake_record::<Sha512>(&config, &setup, "alice").unwrap();
+
+        let result = login::<Sha512>(&config, &setup, &fake, b"password");
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fake_and_real_ke2_have_the_same_length() {
+        let ke2_len = |config: &OpaqueConfig, setup: &ServerSetup, record: &RegistrationUpload| {
+            let (_, ke1) = ClientState::client_init::<Sha512, _>(config, &mut OsRng, b"password", None, None).unwrap();
+            let (_, ke2, _) = ServerState::server_init::<Sha512, _>(
+                config, &mut OsRng, setup, record, "alice", None, &ke1, None, None, None, None,
+            ).unwrap();
+            ke2.serialize().unwrap().len()
+        };
+
+        for protocol in protocols().iter() {
+            let config = config(EnvelopeMode::Internal).with_ake_protocol(*protocol);
+            let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+            let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+            let fake = RegistrationUpload::fake_record::<Sha512>(&config, &setup, "mallory").unwrap();
+            assert_eq!(ke2_len(&config, &setup, &fake), ke2_len(&config, &setup, &record));
+
+            // While a rotation is in progress, fake records look like either the records that were already
+            // upgraded or the ones that weren't.
+            setup.rotate_server_key(&config, &mut OsRng).unwrap();
+            setup.rotate_oprf_seed(&mut OsRng).unwrap();
+            // Records aren't re-bound with KEM, so every KE2 has the same length.
+            let updated = login::<Sha512>(&config, &setup, &record, b"password").unwrap().record_update;
+            let updated = updated.as_ref().unwrap_or(&record);
+            for identifier in ["mallory", "trudy", "eve", "oscar"].iter() {
+                let fake = RegistrationUpload::fake_record::<Sha512>(&config, &setup, identifier).unwrap();
+                let real = if fake.oprf_seed_id() == updated.oprf_seed_id() { updated } else { &record };
+                assert_eq!(ke2_len(&config, &setup, &fake), ke2_len(&config, &setup, real));
+            }
+        }
+    }
+
+    #[test]
+    fn fakes_get_updates_like_records_that_were_not_upgraded() {
+        let ke2 = |setup: &ServerSetup, record: &RegistrationUpload| {
+            let config = config(EnvelopeMode::Internal);
+            let (_, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+            let (_, ke2, _) = ServerState::server_init::<Sha512, _>(
+                &config, &mut OsRng, setup, record, "alice", None, &ke1, None, None, None, None,
+            ).unwrap();
+            ke2
+        };
+
+        let config = config(EnvelopeMode::Internal);
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (old_record, _) = register::<Sha512>(&config, &setup, b"password", None);
+        setup.rotate_server_key(&config, &mut OsRng).unwrap();
+        setup.rotate_oprf_seed(&mut OsRng).unwrap();
+        let old_ke2 = ke2(&setup, &old_record);
+        assert!(old_ke2.server_key_update.is_some() && old_ke2.oprf_seed_update.is_some());
+
+        // Some unknown identifiers are answered like the record that wasn't upgraded, with both updates.
+        let fakes: Vec<RegistrationUpload> = (0..32)
+            .map(|index| RegistrationUpload::fake_record::<Sha512>(&config, &setup, &format!("user{}", index)).unwrap())
+            .collect();
+        let old_fake = fakes
+            .iter()
+            .find(|fake| (fake.server_key_id(), fake.oprf_seed_id()) == (old_record.server_key_id(), old_record.oprf_seed_id()))
+            .unwrap();
+        let fake_ke2 = ke2(&setup, old_fake);
+        assert!(fake_ke2.server_key_update.is_some() && fake_ke2.oprf_seed_update.is_some());
+        assert_eq!(fake_ke2.serialize().unwrap().len(), old_ke2.serialize().unwrap().len());
+        assert!(fakes.iter().any(|fake| fake.oprf_seed_id() == setup.current_seed_id()));
+    }
+
+    #[test]
+    fn fake_and_real_ke2_match_for_every_accepted_version() {
+        let ke2 = |config: &OpaqueConfig, setup: &ServerSetup, record: &RegistrationUpload| {
+            let (_, ke1) = ClientState::client_init::<Sha512, _>(config, &mut OsRng, b"password", None, None).unwrap();
+            let (_, ke2, _) = ServerState::server_init::<Sha512, _>(
+                config, &mut OsRng, setup, record, "alice", None, &ke1, None, None, None, None,
+            ).unwrap();
+            ke2
+        };
+
+        let config = config(EnvelopeMode::Internal)
+            .with_versions(ProtocolVersion::Rfc9807, &[ProtocolVersion::Draft18]);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let fake = RegistrationUpload::fake_record::<Sha512>(&config, &setup, "mallory").unwrap();
+        assert_eq!(fake.version(), config.negotiate(config.version).unwrap());
+        let fake_ke2 = ke2(&config, &setup, &fake);
+
+        for version in config.accepted_versions.iter() {
+            let registration = config.clone().with_versions(*version, &[]);
+            let (record, _) = register::<Sha512>(&registration, &setup, b"password", None);
+            assert_eq!(record.version(), *version);
+
+            let real_ke2 = ke2(&config, &setup, &record);
+            assert_eq!(real_ke2.serialize().unwrap().len(), fake_ke2.serialize().unwrap().len());
+            let updates = |ke2: &KE2| (ke2.server_key_update.is_some(), ke2.oprf_seed_update.is_some());
+            assert_eq!(updates(&real_ke2), updates(&fake_ke2));
+        }
+    }
+
+    #[test]
+    fn tampered_messages_fail() {
+        let config = config(EnvelopeMode::Internal);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+
+        // A server_mac that doesn't match.
+        let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+        let (_, mut ke2, _) = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+        ).unwrap();
+        ke2.server_mac[0] ^= 1;
+        let error = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(!is_channel_binding_error(&error));
+
+        // A client_mac that doesn't match.
+        let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+        let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+        ).unwrap();
+        let (mut ke3, _, _, _, _) = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None).unwrap();
+        ke3.client_mac[0] ^= 1;
+        let result = server_state.server_finish::<Sha512>(&config, &setup, &ke3, None);
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn mismatched_identities_fail() {
+        let config = config(EnvelopeMode::Internal);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+
+        let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+        let (_, ke2, _) = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", Some(b"bob"), &ke1, None, None, None, None,
+        ).unwrap();
+        let result = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None);
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn versions_are_checked() {
+        let config = config(EnvelopeMode::Internal)
+            .with_versions(ProtocolVersion::Rfc9807, &[ProtocolVersion::Draft18]);
+        let old = config.clone().with_versions(ProtocolVersion::Draft18, &[]);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (old_record, export_key) = register::<Sha512>(&old, &setup, b"password", None);
+
+        // An accepted version, the record is answered as is.
+        assert_login(&login::<Sha512>(&config, &setup, &old_record, b"password").unwrap(), &export_key);
+
+        // A version that isn't accepted.
+        let current = config.clone().with_versions(ProtocolVersion::Rfc9807, &[]);
+        let (_, ke1) = ClientState::client_init::<Sha512, _>(&current, &mut OsRng, b"password", None, None).unwrap();
+        let result = ServerState::server_init::<Sha512, _>(
+            &current, &mut OsRng, &setup, &old_record, "alice", None, &ke1, None, None, None, None,
+        );
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn replays_and_throttling_are_enforced() {
+        let config = config(EnvelopeMode::Internal);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+        let replay_cache = ReplayCache::new(Duration::from_secs(60), 16);
+        let throttle = TokenBucketThrottle::new(
+            ThrottleConfig { burst: 1, refill: Duration::from_secs(3600), ..ThrottleConfig::default() },
+            16,
+        );
+
+        let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"wrong", None, None).unwrap();
+        let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, Some(&replay_cache), Some(&throttle),
+        ).unwrap();
+        let result = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, Some(&replay_cache), None,
+        );
+        assert!(is_replay(&result.err().unwrap()));
+
+        // The wrong password fails on the client, which answers anyway with a made up KE3.
+        assert!(client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"wrong", &ke2, None, None).is_err());
+        let ke3 = KE3 {
+            client_extensions: None,
+            client_mac: vec![0; 64],
+            client_signature: None,
+            client_kem_ciphertext: None,
+            record_update: None,
+            client_binding_mac: None,
+        };
+        assert!(server_state.server_finish::<Sha512>(&config, &setup, &ke3, Some(&throttle)).is_err());
+
+        let (_, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+        let result = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, Some(&throttle),
+        );
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn logins_without_ke3_end_in_lockout() {
+        let config = config(EnvelopeMode::Internal);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+        let throttle = TokenBucketThrottle::new(
+            ThrottleConfig {
+                burst: 100,
+                backoff: Duration::from_secs(0),
+                max_backoff: Duration::from_secs(0),
+                lockout_threshold: 3,
+                ..ThrottleConfig::default()
+            },
+            16,
+        );
+        let server_init = |password: &[u8]| {
+            let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, password, None, None).unwrap();
+            ServerState::server_init::<Sha512, _>(
+                &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, Some(&throttle),
+            ).map(|(server_state, ke2, _)| (client_state, server_state, ke2))
+        };
+
+        // A successful login clears the attempts recorded before it.
+        server_init(b"wrong").unwrap();
+        server_init(b"wrong").unwrap();
+        let (client_state, server_state, ke2) = server_init(b"password").unwrap();
+        let (ke3, _, _, _, _) = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None).unwrap();
+        server_state.server_finish::<Sha512>(&config, &setup, &ke3, Some(&throttle)).unwrap();
+
+        // A guesser checking each guess against KE2 never sends KE3, and is locked out anyway.
+        for _ in 0..3 {
+            let (client_state, _, ke2) = server_init(b"wrong").unwrap();
+            assert!(client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"wrong", &ke2, None, None).is_err());
+        }
+        let error = server_init(b"password").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(error.get_ref().unwrap().downcast_ref::<Throttled>().unwrap().locked_out);
+    }
+
+    #[test]
+    fn hmqv_login_round_trip() {
+        let ristretto255 = config(EnvelopeMode::Internal).with_ake_protocol(AkeProtocol::Hmqv);
+        let setup = ServerSetup::new(&ristretto255, &mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha512>(&ristretto255, &setup, b"password", None);
+        assert_login(&login::<Sha512>(&ristretto255, &setup, &record, b"password").unwrap(), &export_key);
+        let result = login::<Sha512>(&ristretto255, &setup, &record, b"wrong password");
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let p256 = OpaqueConfig::new(CipherSuite::P256Sha256, EnvelopeMode::Internal, Vec::new(), None, None)
+            .with_ake_group(AkeGroup::P256)
+            .with_ake_protocol(AkeProtocol::Hmqv);
+        let setup = ServerSetup::new(&p256, &mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha256>(&p256, &setup, b"password", None);
+        assert_login(&login::<Sha256>(&p256, &setup, &record, b"password").unwrap(), &export_key);
+    }
+
+    #[test]
+    fn hmqv_and_3dh_share_records() {
+        let triple_dh = config(EnvelopeMode::Internal);
+        let hmqv = triple_dh.clone().with_ake_protocol(AkeProtocol::Hmqv);
+        let setup = ServerSetup::new(&triple_dh, &mut OsRng).unwrap();
+
+        let (record, export_key) = register::<Sha512>(&triple_dh, &setup, b"password", None);
+        assert_login(&login::<Sha512>(&hmqv, &setup, &record, b"password").unwrap(), &export_key);
+        let (record, export_key) = register::<Sha512>(&hmqv, &setup, b"password", None);
+        assert_login(&login::<Sha512>(&triple_dh, &setup, &record, b"password").unwrap(), &export_key);
+    }
+
+    #[test]
+    fn mixed_protocols_are_rejected() {
+        let triple_dh = config(EnvelopeMode::Internal);
+        let hmqv = triple_dh.clone().with_ake_protocol(AkeProtocol::Hmqv);
+        let setup = ServerSetup::new(&triple_dh, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&triple_dh, &setup, b"password", None);
+
+        for (client, server) in [(&triple_dh, &hmqv), (&hmqv, &triple_dh)].iter() {
+            let (client_state, ke1) = ClientState::client_init::<Sha512, _>(client, &mut OsRng, b"password", None, None).unwrap();
+            let (_, ke2, _) = ServerState::server_init::<Sha512, _>(
+                server, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+            ).unwrap();
+            let result = client_state.client_finish::<Sha512, _>(client, &mut OsRng, b"password", &ke2, None, None);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+
+        // HMQV needs a prime order group.
+        let x25519 = hmqv.with_ake_group(AkeGroup::X25519);
+        let result = ClientState::client_init::<Sha512, _>(&x25519, &mut OsRng, b"password", None, None);
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn hybrid_login_round_trip() {
+        for protocol in [AkeProtocol::TripleDh, AkeProtocol::Hmqv].iter() {
+            let config = config(EnvelopeMode::Internal).with_ake_protocol(*protocol);
+            let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+            // Records don't change with the hybrid mode.
+            let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+
+            let hybrid = config.with_hybrid_kem(Kem::MlKem768);
+            assert_login(&login::<Sha512>(&hybrid, &setup, &record, b"password").unwrap(), &export_key);
+            let result = login::<Sha512>(&hybrid, &setup, &record, b"wrong password");
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn hybrid_mode_cant_be_downgraded() {
+        let config = config(EnvelopeMode::Internal);
+        let hybrid = config.clone().with_hybrid_kem(Kem::MlKem768);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+
+        // A client or a server that isn't in hybrid mode.
+        for (client, server) in [(&config, &hybrid), (&hybrid, &config)].iter() {
+            let (_, ke1) = ClientState::client_init::<Sha512, _>(client, &mut OsRng, b"password", None, None).unwrap();
+            let result = ServerState::server_init::<Sha512, _>(
+                server, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+            );
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+
+        // A KE2 whose KEM keyshare was stripped or modified.
+        for keyshare in [None, Some(vec![0; Kem::MlKem768.ciphertext_size()])].iter() {
+            let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&hybrid, &mut OsRng, b"password", None, None).unwrap();
+            let (_, mut ke2, _) = ServerState::server_init::<Sha512, _>(
+                &hybrid, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+            ).unwrap();
+            ke2.inner_ke2.server_kem_keyshare = keyshare.clone();
+            let result = client_state.client_finish::<Sha512, _>(&hybrid, &mut OsRng, b"password", &ke2, None, None);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+
+        // Only 3DH and HMQV have a hybrid mode.
+        let sigma_i = hybrid.with_ake_protocol(AkeProtocol::SigmaI(SignatureScheme::Ed25519));
+        let result = ClientState::client_init::<Sha512, _>(&sigma_i, &mut OsRng, b"password", None, None);
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn sigma_i_login_round_trip() {
+        for scheme in [SignatureScheme::Ed25519, SignatureScheme::EcdsaP256].iter() {
+            let config = config(EnvelopeMode::Internal).with_ake_protocol(AkeProtocol::SigmaI(*scheme));
+            let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+            let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+            assert_eq!(record.client_pub_key().len(), scheme.verifying_key_size());
+
+            assert_login(&login::<Sha512>(&config, &setup, &record, b"password").unwrap(), &export_key);
+            let result = login::<Sha512>(&config, &setup, &record, b"wrong password");
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn sigma_i_login_with_an_existing_signing_key() {
+        let scheme = SignatureScheme::Ed25519;
+        let config = config(EnvelopeMode::External).with_ake_protocol(AkeProtocol::SigmaI(scheme));
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (signing_key, verifying_key) = scheme.generate_keypair(&mut OsRng);
+        let (record, export_key) = register::<Sha512>(&config, &setup, b"password", Some(&signing_key));
+        assert_eq!(record.client_pub_key(), &verifying_key[..]);
+
+        assert_login(&login::<Sha512>(&config, &setup, &record, b"password").unwrap(), &export_key);
+    }
+
+    #[test]
+    fn sigma_i_signatures_are_checked() {
+        let config = config(EnvelopeMode::Internal).with_ake_protocol(AkeProtocol::SigmaI(SignatureScheme::Ed25519));
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+
+        // A server signature that doesn't match, then a missing one.
+        for signature in [Some(vec![0; 64]), None].iter() {
+            let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+            let (_, mut ke2, _) = ServerState::server_init::<Sha512, _>(
+                &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+            ).unwrap();
+            ke2.server_signature = signature.clone();
+            let result = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+
+        // A client signature that doesn't match, then a missing one.
+        for signature in [Some(vec![0; 64]), None].iter() {
+            let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+            let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+                &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+            ).unwrap();
+            let (mut ke3, _, _, _, _) = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None).unwrap();
+            ke3.client_signature = signature.clone();
+            let result = server_state.server_finish::<Sha512>(&config, &setup, &ke3, None);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn kem_login_round_trip() {
+        let config = config(EnvelopeMode::Internal).with_ake_protocol(AkeProtocol::Kem(Kem::MlKem768));
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+        assert_eq!(record.client_pub_key().len(), Kem::MlKem768.encapsulation_key_size());
+
+        assert_login(&login::<Sha512>(&config, &setup, &record, b"password").unwrap(), &export_key);
+        let result = login::<Sha512>(&config, &setup, &record, b"wrong password");
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let external = OpaqueConfig { mode: EnvelopeMode::External, ..config };
+        let (decapsulation_key, _) = Kem::MlKem768.generate_keypair(&mut OsRng);
+        let (record, export_key) = register::<Sha512>(&external, &setup, b"password", Some(&decapsulation_key));
+        assert_login(&login::<Sha512>(&external, &setup, &record, b"password").unwrap(), &export_key);
+    }
+
+    #[test]
+    fn kem_ke2_carries_nothing_unauthenticated() {
+        let config = config(EnvelopeMode::Internal).with_ake_protocol(AkeProtocol::Kem(Kem::MlKem768));
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+
+        let (_, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+        let result = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", None, &ke1, Some(b"challenge"), None, None, None,
+        );
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+
+        // Records aren't re-bound on a rotation, and keep logging in with their key and seed.
+        setup.rotate_server_key(&config, &mut OsRng).unwrap();
+        setup.rotate_oprf_seed(&mut OsRng).unwrap();
+        let login = login::<Sha512>(&config, &setup, &record, b"password").unwrap();
+        assert_login(&login, &export_key);
+        assert!(login.record_update.is_none() && login.new_export_key.is_none());
+
+        // A KE2 carrying extensions or updates (i.e. from someone knowing the client's public key) is rejected.
+        let updates: [fn(&mut KE2); 3] = [
+            |ke2| ke2.server_extensions = Some(vec![0; 32]),
+            |ke2| ke2.server_key_update = Some(vec![0; 32]),
+            |ke2| ke2.oprf_seed_update = Some(vec![0; 32]),
+        ];
+        for update in updates.iter() {
+            let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+            let (_, mut ke2, _) = ServerState::server_init::<Sha512, _>(
+                &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+            ).unwrap();
+            update(&mut ke2);
+            let result = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn kem_states_keep_the_key_id_only() {
+        let config = config(EnvelopeMode::Internal).with_ake_protocol(AkeProtocol::Kem(Kem::MlKem768));
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+        setup.rotate_server_key(&config, &mut OsRng).unwrap();
+
+        let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+        let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+        ).unwrap();
+        let (ke3, _, _, _, _) = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None).unwrap();
+        assert_eq!(server_state.server_key_id, Some(record.server_key_id()));
+        let serialized = server_state.serialize().unwrap();
+        let (server_private_key, _) = setup.server_keypair(record.server_key_id()).unwrap();
+        assert!(!serialized.windows(server_private_key.len()).any(|window| window == server_private_key));
+
+        // The key is looked up on ServerFinish, so a key retired in between fails the login.
+        let restored = ServerState::deserialize(&serialized).unwrap();
+        setup.retire_server_key(record.server_key_id()).unwrap();
+        let result = restored.server_finish::<Sha512>(&config, &setup, &ke3, None);
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn kem_ciphertexts_are_checked() {
+        let config = config(EnvelopeMode::Internal).with_ake_protocol(AkeProtocol::Kem(Kem::MlKem768));
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+
+        // A modified or missing ciphertext to the client's long-term key.
+        for ciphertext in [Some(vec![0; Kem::MlKem768.ciphertext_size()]), None].iter() {
+            let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+            let (_, mut ke2, _) = ServerState::server_init::<Sha512, _>(
+                &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+            ).unwrap();
+            ke2.server_kem_ciphertext = ciphertext.clone();
+            let result = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+
+        // A modified or missing ciphertext to the server's long-term key.
+        for ciphertext in [Some(vec![0; Kem::MlKem768.ciphertext_size()]), None].iter() {
+            let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+            let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+                &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+            ).unwrap();
+            let (mut ke3, _, _, _, _) =
+                client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None).unwrap();
+            ke3.client_kem_ciphertext = ciphertext.clone();
+            let result = server_state.server_finish::<Sha512>(&config, &setup, &ke3, None);
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn channel_binding_is_checked_in_every_protocol() {
+        for protocol in protocols().iter() {
+            let config = config(EnvelopeMode::Internal).with_ake_protocol(*protocol);
+            let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+            let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+
+            // The same binding on both sides, then different ones, then one on a single side.
+            let (tls, other): (&[u8], &[u8]) = (b"tls", b"other");
+            let bindings = [(Some(tls), Some(tls)), (Some(tls), Some(other)), (Some(tls), None), (None, Some(tls))];
+            for (client_binding, server_binding) in bindings.iter() {
+                let (client_state, ke1) =
+                    ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, *client_binding).unwrap();
+                let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+                    &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, *server_binding, None, None,
+                ).unwrap();
+                let result = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None);
+                if client_binding != server_binding {
+                    let error = result.err().unwrap();
+                    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+                    assert_eq!(is_channel_binding_error(&error), client_binding.is_some());
+                    continue;
+                }
+                let (ke3, client_session_key, login_export_key, _, _) = result.unwrap();
+                let (server_session_key, _, _) = server_state.server_finish::<Sha512>(&config, &setup, &ke3, None).unwrap();
+                assert_eq!(client_session_key.as_bytes(), server_session_key.as_bytes());
+                assert_eq!(login_export_key, export_key);
+            }
+
+            // A client_mac that doesn't match with the same binding is a plain HandshakeError, a binding MAC that
+            // doesn't match a ChannelBindingError.
+            for binding_mismatch in [false, true].iter() {
+                let (client_state, ke1) =
+                    ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, Some(b"tls")).unwrap();
+                let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+                    &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, Some(b"tls"), None, None,
+                ).unwrap();
+                let (mut ke3, _, _, _, _) =
+                    client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None).unwrap();
+                match binding_mismatch {
+                    true => ke3.client_binding_mac.as_mut().unwrap()[0] ^= 1,
+                    false => ke3.client_mac[0] ^= 1,
+                }
+                let error = server_state.server_finish::<Sha512>(&config, &setup, &ke3, None).err().unwrap();
+                assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+                assert_eq!(is_channel_binding_error(&error), *binding_mismatch);
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_passwords_with_the_same_binding_are_not_binding_errors() {
+        for protocol in protocols().iter() {
+            let config = config(EnvelopeMode::Internal).with_ake_protocol(*protocol);
+            let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+            let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+
+            let (client_state, ke1) =
+                ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"wrong", None, Some(b"tls")).unwrap();
+            let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+                &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, Some(b"tls"), None, None,
+            ).unwrap();
+            let error = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"wrong", &ke2, None, None).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(!is_channel_binding_error(&error));
+
+            // A guesser can confirm the binding, but not the password.
+            let ke3 = KE3 {
+                client_extensions: None,
+                client_mac: vec![0; 64],
+                client_signature: Some(vec![0; 64]),
+                client_kem_ciphertext: Some(vec![0; Kem::MlKem768.ciphertext_size()]),
+                record_update: None,
+                client_binding_mac: Some(binding_mac::<Sha512>(
+                    b"tls", STR_CLIENT_BINDING, &ke1.serialize().unwrap(), &ke2.inner_ke2.server_nonce,
+                )),
+            };
+            let error = server_state.server_finish::<Sha512>(&config, &setup, &ke3, None).err().unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert!(!is_channel_binding_error(&error));
+        }
+    }
+
+    #[test]
+    fn records_are_rebound_to_the_current_server_key() {
+        for protocol in protocols().iter().filter(|protocol| !matches!(protocol, AkeProtocol::Kem(_))) {
+            let config = config(EnvelopeMode::Internal).with_ake_protocol(*protocol);
+            let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+            let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+            let key_id = setup.rotate_server_key(&config, &mut OsRng).unwrap();
+
+            // The older key still serves the record, which is re-bound to the current one.
+            let first = login::<Sha512>(&config, &setup, &record, b"password").unwrap();
+            assert_login(&first, &export_key);
+            let updated = first.record_update.unwrap();
+            assert_eq!(updated.server_key_id(), key_id);
+            assert_eq!(updated.oprf_seed_id(), record.oprf_seed_id());
+            let new_export_key = first.new_export_key.unwrap();
+            assert_ne!(new_export_key, export_key);
+
+            // Once the older key is retired, only the re-bound record can log in.
+            setup.retire_server_key(record.server_key_id()).unwrap();
+            let second = login::<Sha512>(&config, &setup, &updated, b"password").unwrap();
+            assert_login(&second, &new_export_key);
+            assert!(second.record_update.is_none() && second.new_export_key.is_none());
+            let result = login::<Sha512>(&config, &setup, &record, b"password");
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::NotFound);
+        }
+    }
+
+    #[test]
+    fn rebound_records_keep_external_keys() {
+        let config = config(EnvelopeMode::External);
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (client_pri_key, _) = config.generate_long_term_keypair(&mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", Some(&client_pri_key));
+        setup.rotate_server_key(&config, &mut OsRng).unwrap();
+
+        let updated = login::<Sha512>(&config, &setup, &record, b"password").unwrap().record_update.unwrap();
+        assert_eq!(updated.client_pub_key(), record.client_pub_key());
+        assert_eq!(updated.server_key_id(), setup.current_key_id());
+    }
+
+    #[test]
+    fn records_are_migrated_to_the_current_oprf_seed() {
+        for protocol in protocols().iter().filter(|protocol| !matches!(protocol, AkeProtocol::Kem(_))) {
+            let config = config(EnvelopeMode::Internal).with_ake_protocol(*protocol);
+            let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+            let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+            let seed_id = setup.rotate_oprf_seed(&mut OsRng).unwrap();
+            assert_eq!(setup.oprf_seed_usage(vec![record.oprf_seed_id()]), vec![(0, 1), (seed_id, 0)]);
+
+            // The older seed still serves the record, which is upgraded to the current one.
+            let first = login::<Sha512>(&config, &setup, &record, b"password").unwrap();
+            assert_login(&first, &export_key);
+            let updated = first.record_update.unwrap();
+            assert_eq!(updated.oprf_seed_id(), seed_id);
+            assert_eq!(updated.server_key_id(), record.server_key_id());
+            let new_export_key = first.new_export_key.unwrap();
+            assert_eq!(setup.oprf_seed_usage(vec![updated.oprf_seed_id()]), vec![(0, 0), (seed_id, 1)]);
+
+            // Once the older seed is retired, only the upgraded record can log in.
+            setup.retire_oprf_seed(0).unwrap();
+            let second = login::<Sha512>(&config, &setup, &updated, b"password").unwrap();
+            assert_login(&second, &new_export_key);
+            assert!(second.record_update.is_none() && second.new_export_key.is_none());
+            let result = login::<Sha512>(&config, &setup, &updated, b"wrong password");
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+            let result = login::<Sha512>(&config, &setup, &record, b"password");
+            assert_eq!(result.err().unwrap().kind(), io::ErrorKind::NotFound);
+        }
+    }
+
+    #[test]
+    fn key_and_seed_rotations_share_one_update() {
+        let config = config(EnvelopeMode::Internal);
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+        let key_id = setup.rotate_server_key(&config, &mut OsRng).unwrap();
+        let seed_id = setup.rotate_oprf_seed(&mut OsRng).unwrap();
+
+        let first = login::<Sha512>(&config, &setup, &record, b"password").unwrap();
+        let updated = first.record_update.unwrap();
+        assert_eq!((updated.server_key_id(), updated.oprf_seed_id()), (key_id, seed_id));
+
+        setup.retire_server_key(0).unwrap();
+        setup.retire_oprf_seed(0).unwrap();
+        assert_login(&login::<Sha512>(&config, &setup, &updated, b"password").unwrap(), &first.new_export_key.unwrap());
+    }
+
+    #[test]
+    fn record_updates_are_checked() {
+        let config = config(EnvelopeMode::Internal);
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+        setup.rotate_server_key(&config, &mut OsRng).unwrap();
+
+        // A record update that doesn't open.
+        let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+        let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+        ).unwrap();
+        let (mut ke3, _, _, _, _) = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None).unwrap();
+        ke3.record_update.as_mut().unwrap()[0] ^= 1;
+        let result = server_state.server_finish::<Sha512>(&config, &setup, &ke3, None);
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // A server_key_update that doesn't match server_mac, then a record update bound to other ids.
+        let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, None).unwrap();
+        let (_, mut ke2, _) = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, None, None, None,
+        ).unwrap();
+        ke2.server_key_update.as_mut().unwrap()[1] ^= 1;
+        let result = client_state.client_finish::<Sha512, _>(&config, &mut OsRng, b"password", &ke2, None, None);
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        let serialized = record.serialize().unwrap();
+        assert!(check_record_update::<Sha512>(&config, &serialized, &[0, 0, 0, 0]).is_ok());
+        assert!(check_record_update::<Sha512>(&config, &serialized, &[0, 1, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn server_state_round_trip() {
+        let config = config(EnvelopeMode::Internal);
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+
+        let (_, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut OsRng, b"password", None, Some(b"tls")).unwrap();
+        let (state, _, _) = ServerState::server_init::<Sha512, _>(
+            &config, &mut OsRng, &setup, &record, "alice", None, &ke1, None, Some(b"tls"), None, None,
+        ).unwrap();
+        let serialized = state.serialize().unwrap();
+        assert_eq!(ServerState::deserialize(&serialized).unwrap().serialize().unwrap(), serialized);
+        assert!(ServerState::deserialize(&serialized[..serialized.len() - 1]).is_err());
+        assert!(ServerState::deserialize(&[&serialized[..], &[0]].concat()).is_err());
+        assert!(ServerState::deserialize(&[&[0xff, 0xff], &serialized[2..]].concat()).is_err());
+    }
+
+    /// Hands out the given bytes, in order, as the random values of a test vector.
+    struct ScriptedRng(Vec<u8>);
+
+    impl ScriptedRng {
+        /// Ristretto255 scalars are drawn as 64 bytes reduced modulo the group order, so a scalar `s` is drawn
+        /// from `s || 0^32`.
+        fn scalar(scalar: &[u8]) -> Vec<u8> {
+            [scalar, &[0; 32]].concat()
+        }
+
+        fn assert_empty(&self) {
+            assert!(self.0.is_empty(), "{} scripted bytes left", self.0.len());
+        }
+    }
+
+    impl RngCore for ScriptedRng {
+        fn next_u32(&mut self) -> u32 {
+            let mut bytes = [0u8; 4];
+            self.fill_bytes(&mut bytes);
+            u32::from_le_bytes(bytes)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            let mut bytes = [0u8; 8];
+            self.fill_bytes(&mut bytes);
+            u64::from_le_bytes(bytes)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            assert!(dest.len() <= self.0.len(), "the test vector is out of random bytes");
+            dest.copy_from_slice(&self.0[..dest.len()]);
+            self.0.drain(..dest.len());
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for ScriptedRng {}
+
+    #[test]
+    fn rfc9807_test_vector() {
+        // RFC 9807, appendix C.1.1 (OPAQUE-3DH Real Test Vector 1: ristretto255-SHA512, Identity KSF, no
+        // identities).
+        let config = OpaqueConfig::new(CipherSuite::Ristretto255Sha512, EnvelopeMode::Internal, b"OPAQUE-POC".to_vec(), None, None);
+        let oprf_seed = hex!("f433d0227b0b9dd54f7c4422b600e764e47fb503f1f9a0f0a47c6606b054a7fdc65347f1a08f277e22358bbabe26f823fca82c7848e9a75661f4ec5d5c1989ef");
+        let password = hex!("436f7272656374486f72736542617474657279537461706c65");
+        let server_private_key = hex!("47451a85372f8b3537e249d7b54188091fb18edde78094b43e2ba42b5eb89f0d");
+        let server_public_key = hex!("b2fe7af9f48cc502d016729d2fe25cdd433f2c4bc904660b2a382c9b79df1a78");
+        let identifier = "1234";
+
+        let mut rng = ScriptedRng([ScriptedRng::scalar(&server_private_key), ScriptedRng::scalar(&server_private_key), oprf_seed.to_vec()].concat());
+        let setup = ServerSetup::new(&config, &mut rng).unwrap();
+        rng.assert_empty();
+        assert_eq!(setup.server_public_key(), server_public_key);
+
+        // Registration.
+        let mut rng = ScriptedRng([
+            ScriptedRng::scalar(&hex!("76cfbfe758db884bebb33582331ba9f159720ca8784a2a070a265d9c2d6abe01")),
+            hex!("ac13171b2f17bc2c74997f0fce1e1f35bec6b91fe2e12dbd323d23ba7a38dfec").to_vec(),
+        ].concat());
+        let (request, blind) = RegistrationRequest::create_registration_request::<Sha512, _>(&config, &password, &mut rng).unwrap();
+        assert_eq!(request.serialize().unwrap(), hex!("5059ff249eb1551b7ce4991f3336205bde44a105a032e747d21bf382e75f7a71"));
+        let response = RegistrationResponse::create_registration_response::<Sha512>(&config, &request, &setup, identifier).unwrap();
+        assert_eq!(
+            response.serialize().unwrap(),
+            [&hex!("7408a268083e03abc7097fc05b587834539065e86fb0c7b6342fcf5e01e5b019")[..], &server_public_key].concat(),
+        );
+        let (record, export_key) = RegistrationUpload::finalize_request::<Sha512, _>(
+            &config, &password, &blind, &response, None, None, &mut rng,
+        ).unwrap();
+        rng.assert_empty();
+        let upload = [
+            &hex!("76a845464c68a5d2f7e442436bb1424953b17d3e2e289ccbaccafb57ac5c3675")[..],
+            &hex!("1ac5844383c7708077dea41cbefe2fa15724f449e535dd7dd562e66f5ecfb95864eadddec9db5874959905117dad40a4524111849799281fefe3c51fa82785c5"),
+            &hex!("ac13171b2f17bc2c74997f0fce1e1f35bec6b91fe2e12dbd323d23ba7a38dfec634b0f5b96109c198a8027da51854c35bee90d1e1c781806d07d49b76de6a28b8d9e9b6c93b9f8b64d16dddd9c5bfb5fea48ee8fd2f75012a8b308605cdd8ba5"),
+        ].concat();
+        assert_eq!(record.serialize().unwrap(), upload);
+
+        // Login.
+        let mut rng = ScriptedRng([
+            ScriptedRng::scalar(&hex!("6ecc102d2e7a7cf49617aad7bbe188556792d4acd60a1a8a8d2b65d4b0790308")),
+            hex!("da7e07376d6d6f034cfa9bb537d11b8c6b4238c334333d1f0aebb380cae6a6cc").to_vec(),
+            hex!("82850a697b42a505f5b68fcdafce8c31f0af2b581f063cf1091933541936304b").to_vec(),
+        ].concat());
+        let (client_state, ke1) = ClientState::client_init::<Sha512, _>(&config, &mut rng, &password, None, None).unwrap();
+        rng.assert_empty();
+        assert_eq!(
+            ke1.serialize().unwrap(),
+            hex!("c4dedb0ba6ed5d965d6f250fbe554cd45cba5dfcce3ce836e4aee778aa3cd44dda7e07376d6d6f034cfa9bb537d11b8c6b4238c334333d1f0aebb380cae6a6cc6e29bee50701498605b2c085d7b241ca15ba5c32027dd21ba420b94ce60da326"),
+        );
+
+        let mut rng = ScriptedRng([
+            hex!("38fe59af0df2c79f57b8780278f5ae47355fe1f817119041951c80f612fdfc6d").to_vec(),
+            hex!("71cd9960ecef2fe0d0f7494986fa3d8b2bb01963537e60efb13981e138e3d4a1").to_vec(),
+            hex!("05a4f54206eef1ba2f615bc0aa285cb22f26d1153b5b40a1e85ff80da12f982f").to_vec(),
+        ].concat());
+        let (server_state, ke2, _) = ServerState::server_init::<Sha512, _>(
+            &config, &mut rng, &setup, &record, identifier, None, &ke1, None, None, None, None,
+        ).unwrap();
+        rng.assert_empty();
+        assert_eq!(
+            ke2.serialize().unwrap(),
+            hex!("7e308140890bcde30cbcea28b01ea1ecfbd077cff62c4def8efa075aabcbb47138fe59af0df2c79f57b8780278f5ae47355fe1f817119041951c80f612fdfc6dd6ec60bcdb26dc455ddf3e718f1020490c192d70dfc7e403981179d8073d1146a4f9aa1ced4e4cd984c657eb3b54ced3848326f70331953d91b02535af44d9fedc80188ca46743c52786e0382f95ad85c08f6afcd1ccfbff95e2bdeb015b166c6b20b92f832cc6df01e0b86a7efd92c1c804ff865781fa93f2f20b446c8371b671cd9960ecef2fe0d0f7494986fa3d8b2bb01963537e60efb13981e138e3d4a1c4f62198a9d6fa9170c42c3c71f1971b29eb1d5d0bd733e40816c91f7912cc4a660c48dae03e57aaa38f3d0cffcfc21852ebc8b405d15bd6744945ba1a93438a162b6111699d98a16bb55b7bdddfe0fc5608b23da246e7bd73b47369169c5c90")[..],
+        );
+
+        let (ke3, client_session_key, login_export_key, _, _) =
+            client_state.client_finish::<Sha512, _>(&config, &mut OsRng, &password, &ke2, None, None).unwrap();
+        assert_eq!(
+            ke3.serialize().unwrap(),
+            hex!("4455df4f810ac31a6748835888564b536e6da5d9944dfea9e34defb9575fe5e2661ef61d2ae3929bcf57e53d464113d364365eb7d1a57b629707ca48da18e442")[..],
+        );
+        let (server_session_key, _, _) = server_state.server_finish::<Sha512>(&config, &setup, &ke3, None).unwrap();
+
+        let session_key = hex!("42afde6f5aca0cfa5c163763fbad55e73a41db6b41bc87b8e7b62214a8eedc6731fa3cb857d657ab9b3764b89a84e91ebcb4785166fbb02cedfcbdfda215b96f");
+        let rfc_export_key = hex!("1ef15b4fa99e8a852412450ab78713aad30d21fa6966c9b8c9fb3262a970dc62950d4dd4ed62598229b1b72794fc0335199d9f7fcc6eaedde92cc04870e63f16");
+        assert_eq!(client_session_key.as_bytes(), session_key);
+        assert_eq!(server_session_key.as_bytes(), session_key);
+        assert_eq!(export_key, rfc_export_key);
+        assert_eq!(login_export_key, rfc_export_key);
     }
 }
@@ -0,0 +1,608 @@
+//! # Configuration
+//!
+//! Before running any stage of the protocol, client and server MUST agree on a configuration:
+//! - the protocol version ([`ProtocolVersion`]) used for new registrations, and the versions of the records still
+//!   answered by the server;
+//! - the cipher suite (OPRF group and hash function), the AKE protocol ([`AkeProtocol`]), the AKE group
+//!   ([`AkeGroup`]) and an optional KEM ([`Kem`]) for hybrid handshakes;
+//! - the envelope mode ([`EnvelopeMode`]);
+//! - an application context string, mixed into the AKE preamble so that two applications sharing the
+//!   same credentials can't have their handshakes confused;
+//! - the server identity, fixed for the whole deployment, and the default client identity, used when the
+//!   protocol functions (on both sides) aren't given one (when no identity is set, the draft defaults each
+//!   identity to the matching encoded public key).
+//!
+//! The configuration is serializable, so both sides can check (or store alongside the records) the exact
+//! same bytes.
+use std::any::TypeId;
+use std::io;
+use rand::{CryptoRng, RngCore};
+use sha2::{Sha256, Sha384, Sha512};
+use sha3::Sha3_512;
+use crate::ake::AkeProtocol;
+use crate::envelope::EnvelopeMode;
+use crate::group::AkeGroup;
+use crate::kdf::SuiteHash;
+use crate::kem::Kem;
+
+/// Size of every nonce of the protocol (`Nn`).
+pub static NONCE_SIZE: usize = 32;
+
+/// Protocol versions supported side by side.
+///
+/// The protocol messages have the fixed layout of RFC 9807 and carry no version: every stored record carries the
+/// version it was registered with instead (see
+/// [`crate::messages::registration::RegistrationUpload::serialize_record`]), and logins for a record run with
+/// that version, so the server can keep answering clients that weren't upgraded yet, as long as their version is
+/// listed on [`OpaqueConfig::accepted_versions`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    /// draft-irtf-cfrg-opaque-18, the last published draft. RFC 9807 kept its messages, labels and key schedule,
+    /// so both versions interoperate, and records of the draft are answered unchanged.
+    Draft18,
+    /// RFC 9807, using the RFC 9497 OPRF.
+    Rfc9807,
+}
+
+impl ProtocolVersion {
+    /// The most recent version known by this crate.
+    pub const LATEST: ProtocolVersion = ProtocolVersion::Rfc9807;
+
+    /// One byte tag of the version, as stored on records and configurations.
+    pub fn id(&self) -> u8 {
+        match self {
+            ProtocolVersion::Draft18 => 0x12,
+            ProtocolVersion::Rfc9807 => 0x01,
+        }
+    }
+
+    /// Returns the version matching `id`, if any.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x12 => Some(ProtocolVersion::Draft18),
+            0x01 => Some(ProtocolVersion::Rfc9807),
+            _ => None,
+        }
+    }
+}
+
+/// Supported cipher suites, named after the OPRF suites of RFC 9497.
+///
+/// The suite fixes the OPRF group and the hash function used by every hash-dependent routine (OPRF, KDF, MAC
+/// and hash). Every generic function takes that hash function as `D`, which MUST be the suite's one (i.e.
+/// `Sha512` for [`CipherSuite::Ristretto255Sha512`]), see [`CipherSuite::check_hash`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherSuite {
+    /// OPRF(ristretto255, SHA-512), HKDF-SHA-512, HMAC-SHA-512 and SHA-512.
+    Ristretto255Sha512,
+    /// OPRF(P-256, SHA-256), HKDF-SHA-256, HMAC-SHA-256 and SHA-256.
+    P256Sha256,
+    /// OPRF(P-384, SHA-384), HKDF-SHA-384, HMAC-SHA-384 and SHA-384.
+    P384Sha384,
+    /// OPRF(ristretto255, SHA3-512), HKDF-SHA3-512, HMAC-SHA3-512 and SHA3-512.
+    ///
+    /// Note: RFC 9497 defines no SHA-3 suite, this one follows the construction of ristretto255-SHA512 with
+    /// SHA3-512 (`expand_message_xmd` accepts any hash function), so only peers using this crate support it.
+    Ristretto255Sha3_512,
+}
+
+impl CipherSuite {
+    /// Two bytes identifier of the suite (matches the OPRF suite identifier of draft-irtf-cfrg-voprf-06, and is
+    /// outside of its registry for [`CipherSuite::Ristretto255Sha3_512`]).
+    pub fn id(&self) -> u16 {
+        match self {
+            CipherSuite::Ristretto255Sha512 => 0x0001,
+            CipherSuite::P256Sha256 => 0x0003,
+            CipherSuite::P384Sha384 => 0x0004,
+            CipherSuite::Ristretto255Sha3_512 => 0x0101,
+        }
+    }
+
+    /// Textual identifier of the suite, as used by the RFC 9497 context string.
+    pub fn identifier(&self) -> &'static str {
+        match self {
+            CipherSuite::Ristretto255Sha512 => "ristretto255-SHA512",
+            CipherSuite::P256Sha256 => "P256-SHA256",
+            CipherSuite::P384Sha384 => "P384-SHA384",
+            CipherSuite::Ristretto255Sha3_512 => "ristretto255-SHA3-512",
+        }
+    }
+
+    /// Output size of the suite's hash function (`Nh`), which is also the size of the `oprf_seed`.
+    pub fn hash_size(&self) -> usize {
+        match self {
+            CipherSuite::Ristretto255Sha512 | CipherSuite::Ristretto255Sha3_512 => 64,
+            CipherSuite::P256Sha256 => 32,
+            CipherSuite::P384Sha384 => 48,
+        }
+    }
+
+    /// The group of the OPRF.
+    pub fn oprf_group(&self) -> AkeGroup {
+        match self {
+            CipherSuite::Ristretto255Sha512 | CipherSuite::Ristretto255Sha3_512 => AkeGroup::Ristretto255,
+            CipherSuite::P256Sha256 => AkeGroup::P256,
+            CipherSuite::P384Sha384 => AkeGroup::P384,
+        }
+    }
+
+    /// Returns the suite matching `id`, if any.
+    pub fn from_id(id: u16) -> Option<Self> {
+        match id {
+            0x0001 => Some(CipherSuite::Ristretto255Sha512),
+            0x0003 => Some(CipherSuite::P256Sha256),
+            0x0004 => Some(CipherSuite::P384Sha384),
+            0x0101 => Some(CipherSuite::Ristretto255Sha3_512),
+            _ => None,
+        }
+    }
+
+    /// Checks that `D` is the suite's hash function.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When `D` isn't the suite's hash function (even if it has the same output size).
+    pub fn check_hash<D: SuiteHash>(&self) -> io::Result<()> {
+        let expected = match self {
+            CipherSuite::Ristretto255Sha512 => TypeId::of::<Sha512>(),
+            CipherSuite::P256Sha256 => TypeId::of::<Sha256>(),
+            CipherSuite::P384Sha384 => TypeId::of::<Sha384>(),
+            CipherSuite::Ristretto255Sha3_512 => TypeId::of::<Sha3_512>(),
+        };
+
+        if TypeId::of::<D>() != expected {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        Ok(())
+    }
+}
+
+/// Configuration shared by client and server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OpaqueConfig {
+    /// The [`ProtocolVersion`] of new registrations (and of the records re-bound on login).
+    pub version: ProtocolVersion,
+    /// Versions of the records the server still answers (it always includes `version`).
+    pub accepted_versions: Vec<ProtocolVersion>,
+    /// The [`CipherSuite`] in use.
+    pub suite: CipherSuite,
+    /// The [`AkeProtocol`] run within KE1/KE2/KE3.
+    pub ake_protocol: AkeProtocol,
+    /// The [`AkeGroup`] used for the AKE keyshares (and long-term keys with 3DH/HMQV).
+    pub ake_group: AkeGroup,
+    /// When set, the Diffie-Hellman based handshakes (3DH/HMQV) also exchange an ephemeral keyshare of this
+    /// [`Kem`] and mix its shared secret into the IKM (hybrid mode).
+    pub hybrid_kem: Option<Kem>,
+    /// The [`EnvelopeMode`] in use.
+    pub mode: EnvelopeMode,
+    /// Application context string, included in the AKE preamble.
+    pub context: Vec<u8>,
+    /// Encoded server identity, used by every entry point (defaults to the encoded server's public key when
+    /// `None`).
+    pub server_identity: Option<Vec<u8>>,
+    /// Default encoded client identity, used when an entry point isn't given one (defaults to the encoded
+    /// client's public key when `None`). Client and server MUST resolve the same identity for a login.
+    pub client_identity: Option<Vec<u8>>,
+}
+
+impl OpaqueConfig {
+    /// Returns a new OpaqueConfig.
+    ///
+    /// The configuration uses [`ProtocolVersion::LATEST`] and only accepts that version, use
+    /// [`OpaqueConfig::with_versions`] to answer older clients as well. The AKE runs 3DH on ristretto255 unless
+    /// another protocol/group is set through [`OpaqueConfig::with_ake_protocol`]/[`OpaqueConfig::with_ake_group`].
+    ///
+    /// # Arguments
+    ///
+    /// * `suite` - The cipher suite.
+    /// * `mode` - The envelope mode.
+    /// * `context` - The application context string.
+    /// * `server_identity` - The optional encoded server's identity.
+    /// * `client_identity` - The optional default encoded client's identity.
+    pub fn new(
+        suite: CipherSuite,
+        mode: EnvelopeMode,
+        context: Vec<u8>,
+        server_identity: Option<Vec<u8>>,
+        client_identity: Option<Vec<u8>>,
+    ) -> Self {
+        OpaqueConfig {
+            version: ProtocolVersion::LATEST,
+            accepted_versions: vec![ProtocolVersion::LATEST],
+            suite,
+            ake_protocol: AkeProtocol::TripleDh,
+            ake_group: AkeGroup::Ristretto255,
+            hybrid_kem: None,
+            mode,
+            context,
+            server_identity,
+            client_identity,
+        }
+    }
+
+    /// Sets the protocol used by the AKE.
+    ///
+    /// Note: [`AkeProtocol::Hmqv`] needs a group supporting it (see [`AkeGroup::supports_hmqv`]).
+    pub fn with_ake_protocol(mut self, ake_protocol: AkeProtocol) -> Self {
+        self.ake_protocol = ake_protocol;
+        self
+    }
+
+    /// Sets the group used by the AKE.
+    pub fn with_ake_group(mut self, ake_group: AkeGroup) -> Self {
+        self.ake_group = ake_group;
+        self
+    }
+
+    /// Enables the hybrid mode, in which 3DH/HMQV handshakes also mix the shared secret of an ephemeral `kem`
+    /// keyshare into the IKM. Session keys then stay secure as long as either the Diffie-Hellman group or the
+    /// KEM holds, and records remain the same (long-term keys are still keys of `ake_group`).
+    ///
+    /// Note: only 3DH and HMQV support the hybrid mode, the handshakes of the other protocols fail with
+    /// `Unsupported`.
+    pub fn with_hybrid_kem(mut self, kem: Kem) -> Self {
+        self.hybrid_kem = Some(kem);
+        self
+    }
+
+    /// Sets the version used for new registrations and the versions of the records still answered by the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `version` - The version of new registrations.
+    /// * `accepted_versions` - Older versions the server still answers.
+    pub fn with_versions(mut self, version: ProtocolVersion, accepted_versions: &[ProtocolVersion]) -> Self {
+        let mut accepted = accepted_versions.to_vec();
+        accepted.push(version);
+        accepted.sort();
+        accepted.dedup();
+
+        self.version = version;
+        self.accepted_versions = accepted;
+        self
+    }
+
+    /// Negotiates the version of a login for a record tagged with `version`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `Unsupported`: When `version` isn't accepted by this configuration.
+    pub fn negotiate(&self, version: ProtocolVersion) -> io::Result<ProtocolVersion> {
+        if self.accepted_versions.contains(&version) {
+            Ok(version)
+        } else {
+            Err(io::Error::from(io::ErrorKind::Unsupported))
+        }
+    }
+
+    /// Generates a random long-term keypair (i.e. the server's one): a keypair of `ake_group` with 3DH/HMQV, a
+    /// signing keypair with SIGMA-I and a KEM keypair with KEM.
+    pub fn generate_long_term_keypair<R: RngCore + CryptoRng>(&self, rng: &mut R) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        match self.ake_protocol {
+            AkeProtocol::TripleDh | AkeProtocol::Hmqv => Ok(self.ake_group.generate_keypair(rng)),
+            AkeProtocol::SigmaI(scheme) => Ok(scheme.generate_keypair(rng)),
+            AkeProtocol::Kem(kem) => Ok(kem.generate_keypair(rng)),
+        }
+    }
+
+    /// Derives the client's long-term keypair from `seed` (internal envelope mode): a keypair of `ake_group`
+    /// with 3DH/HMQV, a signing keypair with SIGMA-I and a KEM keypair with KEM.
+    pub fn derive_long_term_keypair<D: SuiteHash>(&self, seed: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        match self.ake_protocol {
+            AkeProtocol::TripleDh | AkeProtocol::Hmqv => self.ake_group.derive_keypair::<D>(self.suite, seed),
+            AkeProtocol::SigmaI(scheme) => scheme.derive_keypair::<D>(self.suite, seed),
+            AkeProtocol::Kem(kem) => kem.derive_keypair(seed),
+        }
+    }
+
+    /// Recovers the public key matching a long-term private key (external envelope mode): a public key of
+    /// `ake_group` with 3DH/HMQV, a verifying key with SIGMA-I and an encapsulation key with KEM.
+    pub fn recover_long_term_public_key(&self, private_key: &[u8]) -> io::Result<Vec<u8>> {
+        match self.ake_protocol {
+            AkeProtocol::TripleDh | AkeProtocol::Hmqv => self.ake_group.recover_public_key(private_key),
+            AkeProtocol::SigmaI(scheme) => scheme.recover_verifying_key(private_key),
+            AkeProtocol::Kem(kem) => kem.recover_encapsulation_key(private_key),
+        }
+    }
+
+    /// Size of an encoded long-term private key (`Nsk`): a private key of `ake_group` with 3DH/HMQV, a signing key
+    /// with SIGMA-I and a decapsulation key with KEM.
+    pub fn private_key_size(&self) -> usize {
+        match self.ake_protocol {
+            AkeProtocol::TripleDh | AkeProtocol::Hmqv => self.ake_group.private_key_size(),
+            AkeProtocol::SigmaI(scheme) => scheme.signing_key_size(),
+            AkeProtocol::Kem(kem) => kem.decapsulation_key_size(),
+        }
+    }
+
+    /// Size of an encoded long-term public key (`Npk`): a public key of `ake_group` with 3DH/HMQV, a verifying key
+    /// with SIGMA-I and an encapsulation key with KEM.
+    pub fn public_key_size(&self) -> usize {
+        match self.ake_protocol {
+            AkeProtocol::TripleDh | AkeProtocol::Hmqv => self.ake_group.public_key_size(),
+            AkeProtocol::SigmaI(scheme) => scheme.verifying_key_size(),
+            AkeProtocol::Kem(kem) => kem.encapsulation_key_size(),
+        }
+    }
+
+    /// Size of an encoded client keyshare on `KE1` (`Npk` of `ake_group`, or an encapsulation key with KEM).
+    pub fn keyshare_size(&self) -> usize {
+        match self.ake_protocol {
+            AkeProtocol::Kem(kem) => kem.encapsulation_key_size(),
+            _ => self.ake_group.public_key_size(),
+        }
+    }
+
+    /// Size of an encoded server keyshare on `KE2` (`Npk` of `ake_group`, or a ciphertext with KEM).
+    pub fn server_keyshare_size(&self) -> usize {
+        match self.ake_protocol {
+            AkeProtocol::Kem(kem) => kem.ciphertext_size(),
+            _ => self.ake_group.public_key_size(),
+        }
+    }
+
+    /// Size of the seed a long-term keypair is derived from (`Nseed`), see
+    /// [`OpaqueConfig::derive_long_term_keypair`].
+    pub fn seed_size(&self) -> usize {
+        match self.ake_protocol {
+            AkeProtocol::Kem(kem) => kem.seed_size(),
+            _ => 32,
+        }
+    }
+
+    /// Size of a serialized [`crate::envelope::Envelope`] (`Ne`): `Nn + Nm`, plus `Nsk` with the external mode.
+    pub fn envelope_size(&self) -> usize {
+        let inner_envelope_size = match self.mode {
+            EnvelopeMode::Internal => 0,
+            EnvelopeMode::External => self.private_key_size(),
+        };
+        NONCE_SIZE + self.suite.hash_size() + inner_envelope_size
+    }
+
+    /// Resolves the server identity, falling back to the encoded server's public key.
+    pub fn server_identity_or(&self, server_pub_key: &[u8]) -> Vec<u8> {
+        self.server_identity.clone().unwrap_or_else(|| server_pub_key.to_vec())
+    }
+
+    /// Resolves the client identity: `client_identity` when given, then the configured one, falling back to the
+    /// encoded client's public key.
+    pub fn client_identity_or(&self, client_identity: Option<&[u8]>, client_pub_key: &[u8]) -> Vec<u8> {
+        client_identity
+            .or(self.client_identity.as_deref())
+            .unwrap_or(client_pub_key)
+            .to_vec()
+    }
+
+    /// Serializes the configuration as:
+    /// `version (1) || accepted_versions || suite_id (2) || ake_protocol (2) || ake_group (1) || hybrid_kem (1) ||
+    /// mode (1) || context || server_identity || client_identity`, where `hybrid_kem` is zero when not set,
+    /// `accepted_versions` is prefixed by its count (1 byte), `context` is prefixed by its length (2 bytes)
+    /// and every identity by a presence flag (1 byte) followed by its length (2 bytes).
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When there are more than 255 accepted versions, or the context or an identity is longer
+    ///   than 65535 bytes.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        if self.accepted_versions.len() > u8::MAX as usize {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let mut output = vec![self.version.id(), self.accepted_versions.len() as u8];
+        output.extend(self.accepted_versions.iter().map(ProtocolVersion::id));
+        output.extend_from_slice(&self.suite.id().to_be_bytes());
+        output.extend_from_slice(&self.ake_protocol.id());
+        output.push(self.ake_group.id());
+        output.push(self.hybrid_kem.map_or(0, |kem| kem.id()));
+        output.push(self.mode.id());
+        write_field(&mut output, &self.context)?;
+        write_optional_field(&mut output, &self.server_identity)?;
+        write_optional_field(&mut output, &self.client_identity)?;
+        Ok(output)
+    }
+
+    /// Deserializes a configuration created by [`OpaqueConfig::serialize`].
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is truncated, has trailing bytes or unknown version/suite/protocol/group/KEM/
+    ///   mode values.
+    pub fn deserialize(input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+
+        let version = ProtocolVersion::from_id(read_u8(&mut reader)?).ok_or_else(invalid_data)?;
+        let accepted_count = read_u8(&mut reader)?;
+        let accepted_versions = (0..accepted_count)
+            .map(|_| ProtocolVersion::from_id(read_u8(&mut reader)?).ok_or_else(invalid_data))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let suite = CipherSuite::from_id(u16::from_be_bytes([read_u8(&mut reader)?, read_u8(&mut reader)?]))
+            .ok_or_else(invalid_data)?;
+        let ake_protocol = AkeProtocol::from_id([read_u8(&mut reader)?, read_u8(&mut reader)?]).ok_or_else(invalid_data)?;
+        let ake_group = AkeGroup::from_id(read_u8(&mut reader)?).ok_or_else(invalid_data)?;
+        let hybrid_kem = match read_u8(&mut reader)? {
+            0 => None,
+            id => Some(Kem::from_id(id).ok_or_else(invalid_data)?),
+        };
+        let mode = EnvelopeMode::from_id(read_u8(&mut reader)?).ok_or_else(invalid_data)?;
+        let context = read_field(&mut reader)?;
+        let server_identity = read_optional_field(&mut reader)?;
+        let client_identity = read_optional_field(&mut reader)?;
+
+        if !reader.is_empty() || !accepted_versions.contains(&version) {
+            return Err(invalid_data());
+        }
+
+        Ok(OpaqueConfig {
+            version,
+            accepted_versions,
+            suite,
+            ake_protocol,
+            ake_group,
+            hybrid_kem,
+            mode,
+            context,
+            server_identity,
+            client_identity,
+        })
+    }
+}
+
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+pub(crate) fn invalid_data() -> io::Error {
+    io::Error::from(io::ErrorKind::InvalidData)
+}
+
+/// Writes `field` prefixed by its length (2 bytes), failing with `InvalidInput` when it doesn't fit.
+pub(crate) fn write_field(output: &mut Vec<u8>, field: &[u8]) -> io::Result<()> {
+    if field.len() > u16::MAX as usize {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    output.extend_from_slice(&(field.len() as u16).to_be_bytes());
+    output.extend_from_slice(field);
+    Ok(())
+}
+
+pub(crate) fn write_optional_field(output: &mut Vec<u8>, field: &Option<Vec<u8>>) -> io::Result<()> {
+    match field {
+        Some(value) => {
+            output.push(1);
+            write_field(output, value)
+        }
+        None => {
+            output.push(0);
+            Ok(())
+        }
+    }
+}
+
+pub(crate) fn read_u8(reader: &mut &[u8]) -> io::Result<u8> {
+    let (&byte, rest) = reader.split_first().ok_or_else(invalid_data)?;
+    *reader = rest;
+    Ok(byte)
+}
+
+pub(crate) fn read_u16(reader: &mut &[u8]) -> io::Result<u16> {
+    Ok(u16::from_be_bytes([read_u8(reader)?, read_u8(reader)?]))
+}
+
+pub(crate) fn read_field(reader: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = read_u16(reader)? as usize;
+    read_bytes(reader, len)
+}
+
+/// Reads a field of a fixed size `len` (without length prefix).
+pub(crate) fn read_bytes(reader: &mut &[u8], len: usize) -> io::Result<Vec<u8>> {
+    if reader.len() < len {
+        return Err(invalid_data());
+    }
+
+    let (field, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(field.to_vec())
+}
+
+pub(crate) fn read_optional_field(reader: &mut &[u8]) -> io::Result<Option<Vec<u8>>> {
+    match read_u8(reader)? {
+        0 => Ok(None),
+        1 => Ok(Some(read_field(reader)?)),
+        _ => Err(invalid_data()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> OpaqueConfig {
+        OpaqueConfig::new(
+            CipherSuite::Ristretto255Sha512,
+            EnvelopeMode::Internal,
+            b"application".to_vec(),
+            Some(b"server".to_vec()),
+            None,
+        )
+        .with_versions(ProtocolVersion::Rfc9807, &[ProtocolVersion::Draft18])
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let config = config();
+        let serialized = config.serialize().unwrap();
+        assert_eq!(OpaqueConfig::deserialize(&serialized).unwrap(), config);
+    }
+
+    #[test]
+    fn oversize_fields_are_rejected() {
+        let mut config = config();
+        config.context = vec![0; 70000];
+        assert_eq!(config.serialize().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let mut config = self::config();
+        config.client_identity = Some(vec![0; u16::MAX as usize + 1]);
+        assert_eq!(config.serialize().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let mut config = self::config();
+        config.accepted_versions = vec![ProtocolVersion::LATEST; 256];
+        assert_eq!(config.serialize().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let mut output = Vec::new();
+        assert!(write_field(&mut output, &[0; u16::MAX as usize]).is_ok());
+        assert_eq!(write_field(&mut output, &[0; u16::MAX as usize + 1]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn malformed_input_is_rejected() {
+        let serialized = config().serialize().unwrap();
+
+        let mut trailing = serialized.clone();
+        trailing.push(0);
+        assert_eq!(OpaqueConfig::deserialize(&trailing).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        for len in 0..serialized.len() {
+            assert_eq!(OpaqueConfig::deserialize(&serialized[..len]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut unknown_version = serialized;
+        unknown_version[0] = 0xff;
+        assert_eq!(OpaqueConfig::deserialize(&unknown_version).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn check_hash_compares_the_hash_function() {
+        assert!(CipherSuite::Ristretto255Sha512.check_hash::<Sha512>().is_ok());
+        assert!(CipherSuite::P384Sha384.check_hash::<Sha384>().is_ok());
+        assert!(CipherSuite::Ristretto255Sha3_512.check_hash::<Sha3_512>().is_ok());
+
+        // Same output sizes, different hash functions.
+        assert_eq!(CipherSuite::Ristretto255Sha512.check_hash::<Sha3_512>().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(CipherSuite::Ristretto255Sha3_512.check_hash::<Sha512>().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(CipherSuite::P256Sha256.check_hash::<sha3::Sha3_256>().unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let suites = [
+            CipherSuite::Ristretto255Sha512,
+            CipherSuite::P256Sha256,
+            CipherSuite::P384Sha384,
+            CipherSuite::Ristretto255Sha3_512,
+        ];
+        for suite in suites.iter() {
+            assert_eq!(CipherSuite::from_id(suite.id()), Some(*suite));
+        }
+    }
+
+    #[test]
+    fn client_identity_resolution() {
+        let mut config = config();
+        assert_eq!(config.client_identity_or(None, b"public key"), b"public key");
+        assert_eq!(config.client_identity_or(Some(b"alice"), b"public key"), b"alice");
+
+        config.client_identity = Some(b"default".to_vec());
+        assert_eq!(config.client_identity_or(None, b"public key"), b"default");
+        assert_eq!(config.client_identity_or(Some(b"alice"), b"public key"), b"alice");
+        assert_eq!(config.server_identity_or(b"server key"), b"server");
+    }
+}
@@ -1,3 +1,15 @@
+use std::io;
+use crate::kdf::SuiteHash;
+use rand::{CryptoRng, RngCore};
+use crate::config::{invalid_data, OpaqueConfig, NONCE_SIZE};
+use crate::kdf;
+
+static STR_AUTH_KEY: &[u8] = b"AuthKey";
+static STR_EXPORT_KEY: &[u8] = b"ExportKey";
+static STR_MASKING_KEY: &[u8] = b"MaskingKey";
+static STR_PRIVATE_KEY: &[u8] = b"PrivateKey";
+static STR_PAD: &[u8] = b"Pad";
+
 /// Credentials info that will be encoded inside the Envelope.
 pub struct CleartextCredentials {
     /// Encoded server public key for the AKE protocol.
@@ -18,20 +30,30 @@ impl CleartextCredentials {
     /// * `server_identity` - The optional encoded server's identity.
     /// * `client_identity` - The optional encoded client's identity.
     pub fn new(
-        server_pub_key: Vec<u8>,
-        client_pub_key: Vec<u8>,
-        server_identity: Option<Vec<u8>>,
-        client_identity: Option<Vec<u8>>,
+        server_pub_key: &[u8],
+        client_pub_key: &[u8],
+        server_identity: Option<&[u8]>,
+        client_identity: Option<&[u8]>,
     ) -> Self {
-        let srv_identity = if let Some(identity) = server_identity { identity } else { server_pub_key.clone() };
-        let cli_identity = if let Some(identity) = client_identity { identity } else { client_pub_key };
-
         CleartextCredentials {
-            server_public_key: server_pub_key,
-            server_identity: srv_identity,
-            client_identity: cli_identity,
+            server_public_key: server_pub_key.to_vec(),
+            server_identity: server_identity.unwrap_or(server_pub_key).to_vec(),
+            client_identity: client_identity.unwrap_or(client_pub_key).to_vec(),
         }
     }
+
+    /// Encodes the credentials as `server_public_key || I2OSP(len(server_identity), 2) || server_identity ||
+    /// I2OSP(len(client_identity), 2) || client_identity`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When an identity is longer than 65535 bytes.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut output = self.server_public_key.clone();
+        crate::config::write_field(&mut output, &self.server_identity)?;
+        crate::config::write_field(&mut output, &self.client_identity)?;
+        Ok(output)
+    }
 }
 
 /// A mode dependent envelope structure. In fact, it's only used on [`EnvelopeMode::External`].
@@ -41,42 +63,94 @@ pub struct InnerEnvelope {
     credentials: Vec<u8>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum EnvelopeMode {
     /// Internal mode: In this mode, the client's private and public keys are deterministically derived
     /// from the OPRF output. In this case, there are no [`InnerEnvelope`].
     Internal,
     /// External mode: This mode allows applications to import or generate keys for the
     /// client.  This specification only imports the client's private key and
-    /// internally recovers the corresponding public key.  Implementations
-    /// are free to import both, in which case the functions
-    /// "FinalizeRequest()", "CreateEnvelope()", and "BuildInnerEnvelope()"
-    /// must be adapted accordingly.
+    /// internally recovers the corresponding public key.
+    ///
+    /// The imported key must be an encoded private key of the configured [`crate::group::AkeGroup`], e.g. an
+    /// existing X25519 private key (32 bytes, as issued by any RFC 7748 implementation) can be imported directly.
+    /// With SIGMA-I ([`crate::ake::AkeProtocol::SigmaI`]) the imported key is the client's signing key instead,
+    /// and with KEM ([`crate::ake::AkeProtocol::Kem`]) the client's decapsulation key.
     External,
 }
 
 impl EnvelopeMode {
+    /// One byte identifier of the mode, used when serializing a [`OpaqueConfig`].
+    pub fn id(&self) -> u8 {
+        match self {
+            EnvelopeMode::Internal => 0x01,
+            EnvelopeMode::External => 0x02,
+        }
+    }
+
+    /// Returns the mode matching `id`, if any.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(EnvelopeMode::Internal),
+            0x02 => Some(EnvelopeMode::External),
+            _ => None,
+        }
+    }
+
     /// Create [`InnerEnvelope`].
     ///
     /// The input and output will actually depend on the mode. Please, refer to
     /// [`EnvelopeMode::internal_build_inner_envelope()`] and [`EnvelopeMode::external_build_inner_envelope()`] for more details.
-    pub fn build_inner_envelope(
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When the external mode isn't given a client private key.
+    pub fn build_inner_envelope<D>(
         &self,
-        _pwd: String,
-        _nonce: Vec<u8>,
-        _client_pri_key: Vec<u8>,
-    ) { // -> (InnerEnv, Vec<u8>) {
+        config: &OpaqueConfig,
+        pwd: &[u8],
+        nonce: &[u8],
+        client_pri_key: Option<&[u8]>,
+    ) -> io::Result<(Option<InnerEnvelope>, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        match self {
+            EnvelopeMode::Internal => Ok((None, EnvelopeMode::internal_build_inner_envelope::<D>(config, pwd, nonce)?)),
+            EnvelopeMode::External => {
+                let client_pri_key = client_pri_key.ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+                let (inner_env, client_pub_key) =
+                    EnvelopeMode::external_build_inner_envelope::<D>(config, pwd, nonce, client_pri_key)?;
+                Ok((Some(inner_env), client_pub_key))
+            }
+        }
     }
 
     /// Recover and return the client's private and public keys.
     ///
     /// The input and output will actually depend on the mode. Please, refer to
     /// [`EnvelopeMode::internal_recover_keys()`] and [`EnvelopeMode::external_recover_keys()`] for more details.
-    pub fn recover_keys(
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the [`InnerEnvelope`] doesn't match the mode.
+    pub fn recover_keys<D>(
         &self,
-        _pwd: String,
-        _nonce: Vec<u8>,
-        _inner_env: InnerEnvelope,
-    ) { // -> (Vec<u8>, Vec<u8>) {
+        config: &OpaqueConfig,
+        pwd: &[u8],
+        nonce: &[u8],
+        inner_env: Option<&InnerEnvelope>,
+    ) -> io::Result<(Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        match (self, inner_env) {
+            (EnvelopeMode::Internal, None) => EnvelopeMode::internal_recover_keys::<D>(config, pwd, nonce),
+            (EnvelopeMode::External, Some(inner_env)) => {
+                EnvelopeMode::external_recover_keys::<D>(config, pwd, nonce, inner_env)
+            }
+            _ => Err(invalid_data()),
+        }
     }
 
     // ||=================================================
@@ -87,6 +161,7 @@ impl EnvelopeMode {
     ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`], which selects the kind of keypair (see [`OpaqueConfig::derive_long_term_keypair`]).
     /// * `pwd`: Randomized password.
     /// * `nonce`: A unique nonce.
     ///
@@ -94,12 +169,12 @@ impl EnvelopeMode {
     ///
     /// * `client_pub_key`: The new generated client's public key.
     ///
-    fn internal_build_inner_envelope(_pwd: String, _nonce: Vec<u8>) { // -> Vec<u8> {
-        /*
-        1. seed = Expand(randomized_pwd, concat(nonce, "PrivateKey"), Nsk)
-        2. _, client_public_key = DeriveAuthKeyPair(seed)
-        3. Output (nil, client_public_key)
-        */
+    fn internal_build_inner_envelope<D>(config: &OpaqueConfig, pwd: &[u8], nonce: &[u8]) -> io::Result<Vec<u8>>
+    where
+        D: SuiteHash,
+    {
+        let (_, client_pub_key) = EnvelopeMode::internal_recover_keys::<D>(config, pwd, nonce)?;
+        Ok(client_pub_key)
     }
 
     /// Recovers the client's keypair.
@@ -109,6 +184,7 @@ impl EnvelopeMode {
     ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`], which selects the kind of keypair (see [`OpaqueConfig::derive_long_term_keypair`]).
     /// * `pwd`: Randomized password.
     /// * `nonce`: A unique nonce.
     ///
@@ -116,33 +192,12 @@ impl EnvelopeMode {
     ///
     /// * `client_pri_key`: The encoded client private key.
     /// * `client_pub_key`: The encoded client public key.
-    fn internal_recover_keys(_pwd: String, _nonce: Vec<u8>) { // -> (Vec<u8>, Vec<u8>)
-        /*
-        1. seed = Expand(randomized_pwd, concat(nonce, "PrivateKey"), Nsk)
-        2. client_private_key, client_public_key = DeriveAuthKeyPair(seed)
-        4. Output (client_private_key, client_public_key)
-        */
-    }
-
-    /// This methods generates the internal keypair.
-    ///
-    /// # Arguments
-    ///
-    /// * `seed`: pseudo-random byte sequence used as a seed.
-    ///
-    /// # Returns
-    ///
-    /// * `private_key`: a private key.
-    /// * `public_key`: the associated public key.
-    fn derive_auth_keypair(_seed: Vec<u8>) { // -> Vec<u8>
-        /*
-        1. private_key = HashToScalar(seed, dst="OPAQUE-HashToScalar")
-        2. public_key = ScalarBaseMult(private_key)
-        3. Output (private_key, public_key)
-
-        Note: HashToScalar(msg, dst) is as specified in [I-D.irtf-cfrg-voprf], except that the "dst"
-        parameter is "OPAQUE-HashToScalar".
-        */
+    fn internal_recover_keys<D>(config: &OpaqueConfig, pwd: &[u8], nonce: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        let seed = kdf::expand::<D>(pwd, &[nonce, STR_PRIVATE_KEY].concat(), config.seed_size())?;
+        config.derive_long_term_keypair::<D>(&seed)
     }
 
     // ||=================================================
@@ -152,11 +207,9 @@ impl EnvelopeMode {
     /// client's private key, which is then stored encrypted in the [`InnerEnvelope`].  On key
     /// recovery, the client's public key is recovered using the private key.
     ///
-    /// Note: The public key can be provided, in which case the "recover_public_key" step can be
-    /// avoided. This is just something to think about when it's time to implement.
-    ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`], which selects the kind of key (see [`OpaqueConfig::recover_long_term_public_key`]).
     /// * `pwd`: Randomized password.
     /// * `nonce`: A unique nonce.
     /// * `client_pri_key`: The encoded client private key.
@@ -165,20 +218,36 @@ impl EnvelopeMode {
     ///
     /// * `inner_env`: An [`InnerEnvelope`] structure.
     /// * `client_pub_key`: The encoded client public key.
-    fn external_build_inner_envelope(_pwd: String, _nonce: Vec<u8>, _client_pri_key: Vec<u8>) { // -> (InnerEnv, Vec<u8>) {
-        /*
-        1. pseudorandom_pad = Expand(randomized_pwd, concat(nonce, "Pad"), len(client_private_key))
-        2. encrypted_creds = xor(client_private_key, pseudorandom_pad)
-        3. Create InnerEnvelope inner_env with encrypted_creds
-        4. client_public_key = RecoverPublicKey(client_private_key)
-        5. Output (inner_env, client_public_key)
-        */
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When the private key isn't a valid key of the configured protocol.
+    fn external_build_inner_envelope<D>(
+        config: &OpaqueConfig,
+        pwd: &[u8],
+        nonce: &[u8],
+        client_pri_key: &[u8],
+    ) -> io::Result<(InnerEnvelope, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        if client_pri_key.len() != config.private_key_size() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let client_pub_key = config
+            .recover_long_term_public_key(client_pri_key)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        let pseudorandom_pad = kdf::expand::<D>(pwd, &[nonce, STR_PAD].concat(), client_pri_key.len())?;
+        let credentials = xor(client_pri_key, &pseudorandom_pad);
+        Ok((InnerEnvelope { credentials }, client_pub_key))
     }
 
     /// Recovers the client's keypair.
     ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`], which selects the kind of key (see [`OpaqueConfig::recover_long_term_public_key`]).
     /// * `pwd`: Randomized password.
     /// * `nonce`: A unique nonce.
     /// * `inner_env`: An [`InnerEnvelope`].
@@ -187,14 +256,22 @@ impl EnvelopeMode {
     ///
     /// * `client_pri_key`: The encoded client private key.
     /// * `client_pub_key`: The encoded client public key.
-    fn external_recover_keys(_pwd: String, _nonce: Vec<u8>) { // -> (Vec<u8>, Vec<u8>)
-        /*
-        1. encrypted_creds = inner_env.encrypted_creds
-        2. pseudorandom_pad = Expand(randomized_pwd, concat(nonce, "Pad"), len(encrypted_creds))
-        3. client_private_key = xor(encrypted_creds, pseudorandom_pad)
-        4. client_public_key = RecoverPublicKey(client_private_key)
-        5. Output (client_private_key, client_public_key)
-        */
+    fn external_recover_keys<D>(
+        config: &OpaqueConfig,
+        pwd: &[u8],
+        nonce: &[u8],
+        inner_env: &InnerEnvelope,
+    ) -> io::Result<(Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        let encrypted_creds = &inner_env.credentials;
+        let pseudorandom_pad = kdf::expand::<D>(pwd, &[nonce, STR_PAD].concat(), encrypted_creds.len())?;
+        let client_pri_key = xor(encrypted_creds, &pseudorandom_pad);
+        // A wrong password gives a random private key, which may not even be valid: it's reported the same way
+        // as a wrong authentication tag.
+        let client_pub_key = config.recover_long_term_public_key(&client_pri_key).map_err(|_| invalid_data())?;
+        Ok((client_pri_key, client_pub_key))
     }
 }
 
@@ -205,7 +282,7 @@ impl EnvelopeMode {
 ///
 /// The envelope struct will be created at the registration stage.
 ///
-/// Note: the serialized envelope size varies based on the mode.
+/// Note: the serialized envelope size varies based on the mode (see [`OpaqueConfig::envelope_size`]).
 pub struct Envelope {
     /// Unique nonce used to protect the Envelope.
     nonce: Vec<u8>,
@@ -221,82 +298,249 @@ impl Envelope {
     ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
     /// * `pwd`: Randomized password.
     /// * `server_pub_key`: The encoded server's public key.
-    /// * `client_private`: The encoded client's private key.
-    /// * `server_identity` - The optional encoded server's identity.
-    /// * `client_identity` - The optional encoded client's identity.
-    ///
-    /// For the "internal" mode, implementations can choose to leave out the client_private_key parameter, as it
-    /// is not used.
-    ///
-    /// For the "external" mode, implementations are free to additionally provide "client_pub_key" to this function.
-    /// With this, public key not need to be recovered by ```BuildInnerEnvelope()``` and that function should be adapted
-    /// accordingly.
+    /// * `client_pri_key`: The encoded client's private key, only used in external mode, otherwise it will be
+    ///   ```None```.
+    /// * `client_identity` - The optional encoded client's identity (defaults to `config.client_identity`).
     ///
     /// # Returns
     ///
     /// * ```envelope```: The envelope itself.
-    /// * ```client_pub_key```: The client's public key (when not in "external" mode).
+    /// * ```client_pub_key```: The client's public key.
     /// * ```masking_key```: A key used by the server to encrypt the envelope during login.
     /// * ```export_key```: An additional client key.
-    pub fn create(
-        _pwd: String,
-        _server_pub_key: Vec<u8>,
-        _client_private_key: Vec<u8>,
-        _server_identity: Option<Vec<String>>,
-        _client_identity: Option<Vec<String>>,
-    ) {  // -> (Self, Vec<u8>, Vec<u8>, Vec<u8>) {
-        /*
-        1. envelope_nonce = random(Nn)
-        2. auth_key = Expand(randomized_pwd, concat(envelope_nonce, "AuthKey"), Nh)
-        3. export_key = Expand(randomized_pwd, concat(envelope_nonce, "ExportKey"), Nh)
-        4. masking_key = Expand(randomized_pwd, "MaskingKey", Nh)
-        5. inner_env, client_public_key = BuildInnerEnvelope(randomized_pwd, envelope_nonce, client_private_key)
-        6. cleartext_creds = CreateCleartextCredentials(server_public_key, client_public_key, server_identity, client_identity)
-        7. auth_tag = MAC(auth_key, concat(envelope_nonce, inner_env, cleartext_creds))
-        8. Create Envelope envelope with (envelope_nonce, inner_env, auth_tag)
-        9. Output (envelope, client_public_key, masking_key, export_key)
-        */
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When the external mode isn't given a valid client private key, or an identity is longer
+    ///   than 65535 bytes.
+    #[allow(clippy::type_complexity)]
+    pub fn create<D, R>(
+        config: &OpaqueConfig,
+        rng: &mut R,
+        pwd: &[u8],
+        server_pub_key: &[u8],
+        client_pri_key: Option<&[u8]>,
+        client_identity: Option<&[u8]>,
+    ) -> io::Result<(Self, Vec<u8>, Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let mut nonce = vec![0u8; NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+
+        let (auth_key, export_key) = Envelope::keys::<D>(pwd, &nonce)?;
+        let masking_key = kdf::expand::<D>(pwd, STR_MASKING_KEY, kdf::output_size::<D>())?;
+        let (inner_env, client_pub_key) = config.mode.build_inner_envelope::<D>(config, pwd, &nonce, client_pri_key)?;
+
+        let mut envelope = Envelope { nonce, inner_env, auth_tag: Vec::new() };
+        envelope.auth_tag = kdf::mac::<D>(&auth_key, &envelope.authenticated_data(
+            config,
+            server_pub_key,
+            &client_pub_key,
+            client_identity,
+        )?);
+
+        Ok((envelope, client_pub_key, masking_key, export_key))
     }
 
     /// Clients recovers their Envelope during authentication.
     ///
     /// # Arguments
     ///
-    /// * `mode`: What mode (internal/external) is being used.
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server (holds the mode being used).
     /// * `pwd`: Randomized password.
     /// * `server_pub_key`: The encoded server's public key.
-    /// * `server_identity` - The optional encoded server's identity.
-    /// * `client_identity` - The optional encoded client's identity.
+    /// * `client_identity` - The optional encoded client's identity (defaults to `config.client_identity`).
     ///
     /// # Returns
     ///
     /// * ```client_pri_key```: The encoded client private key.
+    /// * ```client_pub_key```: The encoded client public key.
     /// * ```export_key```: An additional client key.
     ///
     /// # Exceptions
     ///
-    /// * ```EnvelopeRecoveryError```: When the envelope fails to be recovered.
-    pub fn recover(
+    /// * `InvalidData` (```EnvelopeRecoveryError```): When the envelope fails to be recovered (i.e. a wrong
+    ///   password).
+    pub fn recover<D>(
         &self,
-        _mode: EnvelopeMode,
-        _pwd: String,
-        _server_pub_key: Vec<u8>,
-        _server_identity: Option<Vec<String>>,
-        _client_identity: Option<Vec<String>>,
-    ) { // -> (Vec<u8>, Vec<u8>)
-        /*
-        1. auth_key = Expand(randomized_pwd, concat(envelope.nonce, "AuthKey"), Nh)
-        2. export_key = Expand(randomized_pwd, concat(envelope.nonce, "ExportKey", Nh)
-        3. (client_private_key, client_public_key) =
-            RecoverKeys(randomized_pwd, envelope.nonce, envelope.inner_env)
-        4. cleartext_creds = CreateCleartextCredentials(server_public_key,
-                              client_public_key, server_identity, client_identity)
-        5. expected_tag = MAC(auth_key, concat(envelope.nonce, inner_env, cleartext_creds))
-        6. If !ct_equal(envelope.auth_tag, expected_tag),
-             raise EnvelopeRecoveryError
-        7. Output (client_private_key, export_key)
-        */
+        config: &OpaqueConfig,
+        pwd: &[u8],
+        server_pub_key: &[u8],
+        client_identity: Option<&[u8]>,
+    ) -> io::Result<(Vec<u8>, Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        let (auth_key, export_key) = Envelope::keys::<D>(pwd, &self.nonce)?;
+        let (client_pri_key, client_pub_key) =
+            config.mode.recover_keys::<D>(config, pwd, &self.nonce, self.inner_env.as_ref())?;
+
+        let authenticated_data = self.authenticated_data(config, server_pub_key, &client_pub_key, client_identity)?;
+        kdf::verify::<D>(&auth_key, &authenticated_data, &self.auth_tag)?;
+        Ok((client_pri_key, client_pub_key, export_key))
+    }
+
+    /// An envelope of [`OpaqueConfig::envelope_size`] zeros, for the fake records of unknown clients. It can't be
+    /// recovered, but it's masked on the credential response like any other envelope.
+    pub(crate) fn zeros<D: SuiteHash>(config: &OpaqueConfig) -> Self {
+        Envelope {
+            nonce: vec![0u8; NONCE_SIZE],
+            inner_env: match config.mode {
+                EnvelopeMode::Internal => None,
+                EnvelopeMode::External => {
+                    Some(InnerEnvelope { credentials: vec![0u8; config.private_key_size()] })
+                }
+            },
+            auth_tag: vec![0u8; kdf::output_size::<D>()],
+        }
+    }
+
+    /// Encodes the envelope as `nonce || inner_env || auth_tag`, [`OpaqueConfig::envelope_size`] bytes.
+    pub fn serialize(&self) -> Vec<u8> {
+        let inner_env = self.inner_env.as_ref().map_or(&[][..], |inner_env| &inner_env.credentials);
+        [&self.nonce, inner_env, &self.auth_tag].concat()
+    }
+
+    /// Decodes an envelope encoded by [`Envelope::serialize`], whose authentication tag is `Nm` bytes long (the
+    /// output size of `D`). The inner envelope is whatever lies between the nonce and the tag.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is too short.
+    pub fn deserialize<D: SuiteHash>(input: &[u8]) -> io::Result<Self> {
+        let tag_size = kdf::output_size::<D>();
+        if input.len() < NONCE_SIZE + tag_size {
+            return Err(invalid_data());
+        }
+
+        let (nonce, rest) = input.split_at(NONCE_SIZE);
+        let (inner_env, auth_tag) = rest.split_at(rest.len() - tag_size);
+        Ok(Envelope {
+            nonce: nonce.to_vec(),
+            inner_env: if inner_env.is_empty() { None } else { Some(InnerEnvelope { credentials: inner_env.to_vec() }) },
+            auth_tag: auth_tag.to_vec(),
+        })
+    }
+
+    /// Derives `auth_key` and `export_key` from the randomized password and the envelope nonce.
+    fn keys<D>(pwd: &[u8], nonce: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        Ok((
+            kdf::expand::<D>(pwd, &[nonce, STR_AUTH_KEY].concat(), kdf::output_size::<D>())?,
+            kdf::expand::<D>(pwd, &[nonce, STR_EXPORT_KEY].concat(), kdf::output_size::<D>())?,
+        ))
+    }
+
+    /// The data covered by `auth_tag`: `concat(nonce, inner_env, cleartext_creds)`.
+    fn authenticated_data(
+        &self,
+        config: &OpaqueConfig,
+        server_pub_key: &[u8],
+        client_pub_key: &[u8],
+        client_identity: Option<&[u8]>,
+    ) -> io::Result<Vec<u8>> {
+        let cleartext_creds = CleartextCredentials::new(
+            server_pub_key,
+            client_pub_key,
+            config.server_identity.as_deref(),
+            client_identity.or(config.client_identity.as_deref()),
+        );
+        let inner_env = self.inner_env.as_ref().map_or(&[][..], |inner_env| &inner_env.credentials);
+        Ok([&self.nonce, inner_env, &cleartext_creds.serialize()?].concat())
+    }
+}
+
+/// Derives the randomized password from the OPRF output `y`: `Extract("", concat(y, Harden(y)))`, where `Harden`
+/// is the identity (no key stretching function is used).
+pub(crate) fn randomized_password<D>(oprf_output: &[u8]) -> Vec<u8>
+where
+    D: SuiteHash,
+{
+    kdf::extract::<D>(&[], &[oprf_output, oprf_output].concat())
+}
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+fn xor(x: &[u8], y: &[u8]) -> Vec<u8> {
+    x.iter().zip(y).map(|(&x1, &x2)| x1 ^ x2).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use sha2::Sha512;
+    use crate::config::CipherSuite;
+    use crate::group::AkeGroup;
+
+    /// A randomized password, which has the size of the hash output.
+    const RPWD: [u8; 64] = [7; 64];
+
+    fn config(mode: EnvelopeMode) -> OpaqueConfig {
+        OpaqueConfig::new(CipherSuite::Ristretto255Sha512, mode, Vec::new(), Some(b"server".to_vec()), None)
+    }
+
+    #[test]
+    fn internal_envelope_round_trip() {
+        let config = config(EnvelopeMode::Internal);
+        let (_, server_pub_key) = config.generate_long_term_keypair(&mut OsRng).unwrap();
+        let (envelope, client_pub_key, masking_key, export_key) =
+            Envelope::create::<Sha512, _>(&config, &mut OsRng, &RPWD, &server_pub_key, None, Some(b"alice")).unwrap();
+        assert_eq!(envelope.serialize().len(), config.envelope_size());
+        assert_eq!(masking_key.len(), 64);
+
+        let envelope = Envelope::deserialize::<Sha512>(&envelope.serialize()).unwrap();
+        let (client_pri_key, recovered_pub_key, recovered_export_key) =
+            envelope.recover::<Sha512>(&config, &RPWD, &server_pub_key, Some(b"alice")).unwrap();
+        assert_eq!(recovered_pub_key, client_pub_key);
+        assert_eq!(AkeGroup::Ristretto255.recover_public_key(&client_pri_key).unwrap(), client_pub_key);
+        assert_eq!(recovered_export_key, export_key);
+    }
+
+    #[test]
+    fn external_envelope_round_trip() {
+        let config = config(EnvelopeMode::External);
+        let (_, server_pub_key) = config.generate_long_term_keypair(&mut OsRng).unwrap();
+        let (client_pri_key, client_pub_key) = config.generate_long_term_keypair(&mut OsRng).unwrap();
+        let (envelope, recovered_pub_key, _, _) =
+            Envelope::create::<Sha512, _>(&config, &mut OsRng, &RPWD, &server_pub_key, Some(&client_pri_key), None)
+                .unwrap();
+        assert_eq!(recovered_pub_key, client_pub_key);
+        assert_eq!(envelope.serialize().len(), config.envelope_size());
+
+        let (recovered_pri_key, _, _) = envelope.recover::<Sha512>(&config, &RPWD, &server_pub_key, None).unwrap();
+        assert_eq!(recovered_pri_key, client_pri_key);
+
+        let missing_key = Envelope::create::<Sha512, _>(&config, &mut OsRng, &RPWD, &server_pub_key, None, None);
+        assert_eq!(missing_key.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn wrong_inputs_fail_recovery() {
+        let config = config(EnvelopeMode::Internal);
+        let (_, server_pub_key) = config.generate_long_term_keypair(&mut OsRng).unwrap();
+        let (_, other_pub_key) = config.generate_long_term_keypair(&mut OsRng).unwrap();
+        let (envelope, _, _, _) =
+            Envelope::create::<Sha512, _>(&config, &mut OsRng, &RPWD, &server_pub_key, None, None).unwrap();
+
+        let kind = |result: io::Result<(Vec<u8>, Vec<u8>, Vec<u8>)>| result.err().unwrap().kind();
+        assert_eq!(kind(envelope.recover::<Sha512>(&config, &[1; 64], &server_pub_key, None)), io::ErrorKind::InvalidData);
+        assert_eq!(kind(envelope.recover::<Sha512>(&config, &RPWD, &other_pub_key, None)), io::ErrorKind::InvalidData);
+        assert_eq!(
+            kind(envelope.recover::<Sha512>(&config, &RPWD, &server_pub_key, Some(b"mallory"))),
+            io::ErrorKind::InvalidData,
+        );
+        let external = self::config(EnvelopeMode::External);
+        assert_eq!(kind(envelope.recover::<Sha512>(&external, &RPWD, &server_pub_key, None)), io::ErrorKind::InvalidData);
+        assert_eq!(Envelope::deserialize::<Sha512>(&[0; 95]).err().unwrap().kind(), io::ErrorKind::InvalidData);
     }
 }
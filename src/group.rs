@@ -0,0 +1,714 @@
+//! # AKE groups
+//!
+//! Groups that can be used for the Diffie-Hellman operations of the AKE (keyshares and long-term keys).
+//! The group is independent of the OPRF group and is selected through [`crate::config::OpaqueConfig::ake_group`].
+//!
+//! Keys are always handled in their encoded form:
+//! - ristretto255: 32 bytes canonical scalars and 32 bytes compressed points;
+//! - X25519: 32 bytes private keys (clamped on use, as in RFC 7748) and 32 bytes u-coordinates, so keys issued
+//!   by other Curve25519 key management systems can be imported as they are;
+//! - P-256 and P-384: big-endian scalars (32 and 48 bytes) and compressed SEC1 points (33 and 49 bytes), so keys
+//!   held by an HSM or issued by an existing PKI can be used as they are. Points are checked to be on the curve
+//!   (and not the identity) whenever they are deserialized.
+use std::io;
+use rand::{RngCore, CryptoRng};
+use crate::kdf::SuiteHash;
+use curve25519_dalek::constants::{RISTRETTO_BASEPOINT_POINT, X25519_BASEPOINT};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use p256::elliptic_curve::ff::{Field, PrimeField};
+use p256::elliptic_curve::hash2curve::{FromOkm, MapToCurve};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use crate::config::CipherSuite;
+use crate::oprf::{self, expand_message_xmd};
+
+static STR_DERIVE_DIFFIE_HELLMAN_KEY_PAIR: &[u8] = b"OPAQUE-DeriveDiffieHellmanKeyPair";
+static STR_HMQV_EXPONENT: &[u8] = b"OPAQUE-HMQV-Exponent";
+
+/// Groups supported by the AKE.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AkeGroup {
+    /// ristretto255 (same group as the OPRF).
+    Ristretto255,
+    /// Curve25519 in Montgomery form, as specified on RFC 7748.
+    X25519,
+    /// NIST P-256 with compressed SEC1 encoding.
+    P256,
+    /// NIST P-384 with compressed SEC1 encoding.
+    P384,
+}
+
+impl AkeGroup {
+    /// One byte identifier of the group, used when serializing a [`crate::config::OpaqueConfig`].
+    pub fn id(&self) -> u8 {
+        match self {
+            AkeGroup::Ristretto255 => 0x01,
+            AkeGroup::X25519 => 0x02,
+            AkeGroup::P256 => 0x03,
+            AkeGroup::P384 => 0x04,
+        }
+    }
+
+    /// Returns the group matching `id`, if any.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(AkeGroup::Ristretto255),
+            0x02 => Some(AkeGroup::X25519),
+            0x03 => Some(AkeGroup::P256),
+            0x04 => Some(AkeGroup::P384),
+            _ => None,
+        }
+    }
+
+    /// Size of an encoded private key (`Nsk` on the draft).
+    pub fn private_key_size(&self) -> usize {
+        match self {
+            AkeGroup::Ristretto255 | AkeGroup::X25519 | AkeGroup::P256 => 32,
+            AkeGroup::P384 => 48,
+        }
+    }
+
+    /// Size of an encoded public key (`Npk` on the draft).
+    pub fn public_key_size(&self) -> usize {
+        match self {
+            AkeGroup::Ristretto255 | AkeGroup::X25519 => 32,
+            AkeGroup::P256 => 33,
+            AkeGroup::P384 => 49,
+        }
+    }
+
+    /// Generates a random keypair (`GenerateAuthKeyPair` on the draft).
+    ///
+    /// # Returns
+    ///
+    /// * `private_key`: The encoded private key.
+    /// * `public_key`: The encoded public key.
+    pub fn generate_keypair<R: RngCore + CryptoRng>(&self, rng: &mut R) -> (Vec<u8>, Vec<u8>) {
+        let private_key = match self {
+            AkeGroup::Ristretto255 => random_nonzero_scalar(rng).to_bytes().to_vec(),
+            AkeGroup::X25519 => {
+                let mut bytes = [0u8; 32];
+                rng.fill_bytes(&mut bytes);
+                bytes.to_vec()
+            }
+            AkeGroup::P256 => p256::NonZeroScalar::random(rng).to_repr().to_vec(),
+            AkeGroup::P384 => p384::NonZeroScalar::random(rng).to_repr().to_vec(),
+        };
+
+        // The private key was just generated, so it's always valid.
+        let public_key = self.recover_public_key(&private_key).expect("generated private keys are valid");
+        (private_key, public_key)
+    }
+
+    /// Deterministically derives a keypair from `seed` (`DeriveDiffieHellmanKeyPair` on RFC 9807).
+    ///
+    /// On ristretto255, P-256 and P-384 this is `DeriveKeyPair(seed, "OPAQUE-DeriveDiffieHellmanKeyPair")` of
+    /// RFC 9497 with the context string of `suite` (see [`crate::oprf`]). On X25519 the first 32 bytes of the seed
+    /// are used as the private key.
+    ///
+    /// # Arguments
+    ///
+    /// * `suite`: The cipher suite, whose hash function (`D`) and context string are used.
+    /// * `seed`: pseudo-random byte sequence used as a seed (at least 32 bytes).
+    ///
+    /// # Returns
+    ///
+    /// * `private_key`: The encoded private key.
+    /// * `public_key`: The encoded public key.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When the seed is shorter than 32 bytes, or `D` isn't the suite's hash function.
+    pub fn derive_keypair<D: SuiteHash>(&self, suite: CipherSuite, seed: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        if seed.len() < 32 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let private_key = match self {
+            AkeGroup::X25519 => seed[..32].to_vec(),
+            _ => oprf::derive_private_key::<D>(*self, suite, seed, STR_DERIVE_DIFFIE_HELLMAN_KEY_PAIR)?,
+        };
+
+        let public_key = self.recover_public_key(&private_key)?;
+        Ok((private_key, public_key))
+    }
+
+    /// Whether the group supports HMQV ([`crate::ake::AkeProtocol::Hmqv`]), which needs a prime order group
+    /// with point addition (X25519 only exposes the Montgomery ladder).
+    pub fn supports_hmqv(&self) -> bool {
+        match self {
+            AkeGroup::Ristretto255 | AkeGroup::P256 | AkeGroup::P384 => true,
+            AkeGroup::X25519 => false,
+        }
+    }
+
+    /// Derives the HMQV exponent bound to a keyshare and an identity, `d = H(X, server_identity)` for the client
+    /// keyshare and `e = H(Y, client_identity)` for the server keyshare.
+    ///
+    /// # Exceptions
+    ///
+    /// * `Unsupported`: When the group doesn't support HMQV.
+    pub fn hmqv_exponent<D: SuiteHash>(&self, keyshare: &[u8], identity: &[u8]) -> io::Result<Vec<u8>> {
+        if !self.supports_hmqv() {
+            return Err(io::Error::from(io::ErrorKind::Unsupported));
+        }
+
+        let msg = [
+            &(keyshare.len() as u16).to_be_bytes(),
+            keyshare,
+            &(identity.len() as u16).to_be_bytes(),
+            identity,
+        ].concat();
+        self.hash_to_scalar::<D>(&msg, STR_HMQV_EXPONENT)
+    }
+
+    /// Computes the HMQV shared secret `(peer_keyshare + peer_exponent * peer_public_key) * (secret + exponent * private_key)`.
+    ///
+    /// The client calls it with `exponent = d` and `peer_exponent = e`, the server with `exponent = e` and
+    /// `peer_exponent = d` (see [`AkeGroup::hmqv_exponent`]). Each side computes two scalar multiplications,
+    /// instead of the three of 3DH.
+    ///
+    /// # Arguments
+    ///
+    /// * `secret`: Own ephemeral private key.
+    /// * `private_key`: Own long-term private key.
+    /// * `exponent`: Own HMQV exponent.
+    /// * `peer_keyshare`: Peer's ephemeral public key.
+    /// * `peer_public_key`: Peer's long-term public key.
+    /// * `peer_exponent`: Peer's HMQV exponent.
+    ///
+    /// # Exceptions
+    ///
+    /// * `Unsupported`: When the group doesn't support HMQV.
+    /// * `InvalidData`: When any key is invalid, or when the shared secret is the identity.
+    pub fn hmqv(
+        &self,
+        secret: &[u8],
+        private_key: &[u8],
+        exponent: &[u8],
+        peer_keyshare: &[u8],
+        peer_public_key: &[u8],
+        peer_exponent: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        match self {
+            AkeGroup::Ristretto255 => {
+                let scalar = deserialize_ristretto_scalar(secret)?
+                    + deserialize_ristretto_scalar(exponent)? * deserialize_ristretto_scalar(private_key)?;
+                let point = deserialize_ristretto_point(peer_keyshare)?
+                    + deserialize_ristretto_point(peer_public_key)? * deserialize_ristretto_scalar(peer_exponent)?;
+                let shared = point * scalar;
+                if shared.is_identity() {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                Ok(shared.compress().to_bytes().to_vec())
+            }
+            AkeGroup::P256 => {
+                let scalar = deserialize_p256_scalar(secret)?
+                    + deserialize_p256_scalar(exponent)? * deserialize_p256_scalar(private_key)?;
+                let point = deserialize_p256_point(peer_keyshare)?
+                    + deserialize_p256_point(peer_public_key)? * deserialize_p256_scalar(peer_exponent)?;
+                let shared = point * scalar;
+                if shared == p256::ProjectivePoint::IDENTITY {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                Ok(serialize_p256_point(shared))
+            }
+            AkeGroup::P384 => {
+                let scalar = deserialize_p384_scalar(secret)?
+                    + deserialize_p384_scalar(exponent)? * deserialize_p384_scalar(private_key)?;
+                let point = deserialize_p384_point(peer_keyshare)?
+                    + deserialize_p384_point(peer_public_key)? * deserialize_p384_scalar(peer_exponent)?;
+                let shared = point * scalar;
+                if shared == p384::ProjectivePoint::IDENTITY {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                Ok(serialize_p384_point(shared))
+            }
+            AkeGroup::X25519 => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+
+    /// Hashes `msg` into a non-zero encoded scalar of a prime order group (`HashToScalar` on RFC 9497):
+    /// `expand_message_xmd(msg, dst, L)` reduced modulo the group order, with `L = 64` on ristretto255, `L = 48`
+    /// on P-256 and `L = 72` on P-384, so the bias of the reduction stays negligible.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the scalar is zero.
+    /// * `Unsupported`: When the group isn't a prime order group (X25519).
+    pub(crate) fn hash_to_scalar<D: SuiteHash>(&self, msg: &[u8], dst: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            AkeGroup::Ristretto255 => {
+                let uniform_bytes = expand_message_xmd::<D>(msg, dst, 64)?;
+                let mut bytes = [0u8; 64];
+                bytes.copy_from_slice(&uniform_bytes);
+                let scalar = Scalar::from_bytes_mod_order_wide(&bytes);
+                if scalar == Scalar::zero() {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                Ok(scalar.to_bytes().to_vec())
+            }
+            AkeGroup::P256 => {
+                let uniform_bytes = expand_message_xmd::<D>(msg, dst, 48)?;
+                let mut bytes = [0u8; 48];
+                bytes.copy_from_slice(&uniform_bytes);
+                let scalar = p256::Scalar::from_okm(&bytes.into());
+                if bool::from(scalar.is_zero()) {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                Ok(scalar.to_repr().to_vec())
+            }
+            AkeGroup::P384 => {
+                let uniform_bytes = expand_message_xmd::<D>(msg, dst, 72)?;
+                let scalar = p384::Scalar::from_okm(uniform_bytes.as_slice().into());
+                if bool::from(scalar.is_zero()) {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                Ok(scalar.to_repr().to_vec())
+            }
+            AkeGroup::X25519 => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+
+    /// Hashes `msg` into a non-identity encoded element of a prime order group (`HashToGroup` on RFC 9497), with
+    /// the hash-to-curve suites of RFC 9380: `ristretto255_XMD:SHA-512_R255MAP_RO_` (64 bytes mapped by
+    /// `from_uniform_bytes`), `P256_XMD:SHA-256_SSWU_RO_` and `P384_XMD:SHA-384_SSWU_RO_` (two 48 or 72 bytes
+    /// field elements mapped and added).
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the element is the identity.
+    /// * `Unsupported`: When the group isn't a prime order group (X25519).
+    pub(crate) fn hash_to_group<D: SuiteHash>(&self, msg: &[u8], dst: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            AkeGroup::Ristretto255 => {
+                let uniform_bytes = expand_message_xmd::<D>(msg, dst, 64)?;
+                let mut bytes = [0u8; 64];
+                bytes.copy_from_slice(&uniform_bytes);
+                let point = RistrettoPoint::from_uniform_bytes(&bytes);
+                if point.is_identity() {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                Ok(point.compress().to_bytes().to_vec())
+            }
+            AkeGroup::P256 => {
+                let uniform_bytes = expand_message_xmd::<D>(msg, dst, 96)?;
+                let (first, second) = uniform_bytes.split_at(48);
+                let mut u = [[0u8; 48]; 2];
+                u[0].copy_from_slice(first);
+                u[1].copy_from_slice(second);
+                let point = p256::FieldElement::from_okm(&u[0].into()).map_to_curve()
+                    + p256::FieldElement::from_okm(&u[1].into()).map_to_curve();
+                if point == p256::ProjectivePoint::IDENTITY {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                Ok(serialize_p256_point(point))
+            }
+            AkeGroup::P384 => {
+                let uniform_bytes = expand_message_xmd::<D>(msg, dst, 144)?;
+                let (first, second) = uniform_bytes.split_at(72);
+                let point = p384::FieldElement::from_okm(first.into()).map_to_curve()
+                    + p384::FieldElement::from_okm(second.into()).map_to_curve();
+                if point == p384::ProjectivePoint::IDENTITY {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                Ok(serialize_p384_point(point))
+            }
+            AkeGroup::X25519 => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+
+    /// Inverts an encoded non-zero scalar of a prime order group, modulo the group order.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the scalar isn't a valid encoded non-zero scalar.
+    /// * `Unsupported`: When the group isn't a prime order group (X25519).
+    pub(crate) fn invert_scalar(&self, scalar: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            AkeGroup::Ristretto255 => Ok(deserialize_ristretto_scalar(scalar)?.invert().to_bytes().to_vec()),
+            AkeGroup::P256 => {
+                let inverted: Option<p256::Scalar> = deserialize_p256_scalar(scalar)?.invert().into();
+                inverted
+                    .map(|inverted| inverted.to_repr().to_vec())
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+            }
+            AkeGroup::P384 => {
+                let inverted: Option<p384::Scalar> = deserialize_p384_scalar(scalar)?.invert().into();
+                inverted
+                    .map(|inverted| inverted.to_repr().to_vec())
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))
+            }
+            AkeGroup::X25519 => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+
+    /// Recovers the encoded public key matching an encoded private key (`RecoverPublicKey` on the draft).
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the private key isn't a valid encoded private key for the group.
+    pub fn recover_public_key(&self, private_key: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            AkeGroup::Ristretto255 => {
+                let scalar = deserialize_ristretto_scalar(private_key)?;
+                Ok((RISTRETTO_BASEPOINT_POINT * scalar).compress().to_bytes().to_vec())
+            }
+            AkeGroup::X25519 => {
+                let scalar = clamp_x25519_scalar(private_key)?;
+                Ok((X25519_BASEPOINT * scalar).to_bytes().to_vec())
+            }
+            AkeGroup::P256 => {
+                let scalar = deserialize_p256_scalar(private_key)?;
+                Ok(serialize_p256_point(p256::ProjectivePoint::GENERATOR * scalar))
+            }
+            AkeGroup::P384 => {
+                let scalar = deserialize_p384_scalar(private_key)?;
+                Ok(serialize_p384_point(p384::ProjectivePoint::GENERATOR * scalar))
+            }
+        }
+    }
+
+    /// Checks an encoded public key received from the peer.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the public key isn't a valid (non-identity) encoded element of the group.
+    pub fn validate_public_key(&self, public_key: &[u8]) -> io::Result<()> {
+        match self {
+            AkeGroup::Ristretto255 => deserialize_ristretto_point(public_key).map(|_| ()),
+            AkeGroup::X25519 => deserialize_montgomery_point(public_key).map(|_| ()),
+            AkeGroup::P256 => deserialize_p256_point(public_key).map(|_| ()),
+            AkeGroup::P384 => deserialize_p384_point(public_key).map(|_| ()),
+        }
+    }
+
+    /// Computes the Diffie-Hellman shared secret between a private key and a peer's public key.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When any key is invalid, or when the shared secret is the identity (i.e. the peer sent a
+    ///   low order point).
+    pub fn diffie_hellman(&self, private_key: &[u8], public_key: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            AkeGroup::Ristretto255 => {
+                let scalar = deserialize_ristretto_scalar(private_key)?;
+                let point = deserialize_ristretto_point(public_key)?;
+                Ok((point * scalar).compress().to_bytes().to_vec())
+            }
+            AkeGroup::X25519 => {
+                let scalar = clamp_x25519_scalar(private_key)?;
+                let point = deserialize_montgomery_point(public_key)?;
+                let shared = (point * scalar).to_bytes();
+                if shared == [0u8; 32] {
+                    return Err(io::Error::from(io::ErrorKind::InvalidData));
+                }
+                Ok(shared.to_vec())
+            }
+            AkeGroup::P256 => {
+                let scalar = deserialize_p256_scalar(private_key)?;
+                let point = deserialize_p256_point(public_key)?;
+                Ok(serialize_p256_point(point * scalar))
+            }
+            AkeGroup::P384 => {
+                let scalar = deserialize_p384_scalar(private_key)?;
+                let point = deserialize_p384_point(public_key)?;
+                Ok(serialize_p384_point(point * scalar))
+            }
+        }
+    }
+}
+
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+fn random_nonzero_scalar<R: RngCore + CryptoRng>(rng: &mut R) -> Scalar {
+    loop {
+        let mut scalar_bytes = [0u8; 64];
+        rng.fill_bytes(&mut scalar_bytes);
+        let scalar = Scalar::from_bytes_mod_order_wide(&scalar_bytes);
+
+        if scalar != Scalar::zero() {
+            break scalar;
+        }
+    }
+}
+
+fn to_array<const N: usize>(input: &[u8]) -> io::Result<[u8; N]> {
+    if input.len() != N {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    let mut bytes = [0u8; N];
+    bytes.copy_from_slice(input);
+    Ok(bytes)
+}
+
+fn deserialize_ristretto_scalar(input: &[u8]) -> io::Result<Scalar> {
+    match Scalar::from_canonical_bytes(to_array(input)?) {
+        Some(scalar) if scalar != Scalar::zero() => Ok(scalar),
+        _ => Err(io::Error::from(io::ErrorKind::InvalidData)),
+    }
+}
+
+fn deserialize_ristretto_point(input: &[u8]) -> io::Result<RistrettoPoint> {
+    match CompressedRistretto(to_array(input)?).decompress() {
+        Some(point) if !point.is_identity() => Ok(point),
+        _ => Err(io::Error::from(io::ErrorKind::InvalidData)),
+    }
+}
+
+/// Clamps an X25519 private key as specified on RFC 7748 (section 5).
+fn clamp_x25519_scalar(input: &[u8]) -> io::Result<Scalar> {
+    let mut bytes = to_array(input)?;
+    bytes[0] &= 248;
+    bytes[31] &= 127;
+    bytes[31] |= 64;
+    Ok(Scalar::from_bits(bytes))
+}
+
+fn deserialize_montgomery_point(input: &[u8]) -> io::Result<MontgomeryPoint> {
+    let point = MontgomeryPoint(to_array(input)?);
+    // Low order points only produce all-zero shared secrets, the DH step rejects them. The all-zero
+    // u-coordinate is refused up front, since it can't be a valid public key.
+    if point.0 == [0u8; 32] {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    Ok(point)
+}
+
+fn deserialize_p256_scalar(input: &[u8]) -> io::Result<p256::Scalar> {
+    let bytes = to_array(input)?;
+    let scalar: Option<p256::Scalar> = p256::Scalar::from_repr(bytes.into()).into();
+    match scalar {
+        Some(scalar) if !bool::from(scalar.is_zero()) => Ok(scalar),
+        _ => Err(io::Error::from(io::ErrorKind::InvalidData)),
+    }
+}
+
+/// Deserializes a compressed SEC1 point, `PublicKey` rejects points off the curve and the identity. The tag is
+/// checked first, since `PublicKey` also accepts compact points (tag 0x05) of the same size.
+fn deserialize_p256_point(input: &[u8]) -> io::Result<p256::ProjectivePoint> {
+    if input.len() != 33 || !matches!(input[0], 0x02 | 0x03) {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    p256::PublicKey::from_sec1_bytes(input)
+        .map(|public_key| public_key.to_projective())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+fn serialize_p256_point(point: p256::ProjectivePoint) -> Vec<u8> {
+    point.to_affine().to_encoded_point(true).as_bytes().to_vec()
+}
+
+fn deserialize_p384_scalar(input: &[u8]) -> io::Result<p384::Scalar> {
+    let bytes: [u8; 48] = to_array(input)?;
+    let scalar: Option<p384::Scalar> = p384::Scalar::from_repr(bytes.into()).into();
+    match scalar {
+        Some(scalar) if !bool::from(scalar.is_zero()) => Ok(scalar),
+        _ => Err(io::Error::from(io::ErrorKind::InvalidData)),
+    }
+}
+
+/// Same as [`deserialize_p256_point`], with 49 bytes compressed points.
+fn deserialize_p384_point(input: &[u8]) -> io::Result<p384::ProjectivePoint> {
+    if input.len() != 49 || !matches!(input[0], 0x02 | 0x03) {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    p384::PublicKey::from_sec1_bytes(input)
+        .map(|public_key| public_key.to_projective())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+fn serialize_p384_point(point: p384::ProjectivePoint) -> Vec<u8> {
+    point.to_affine().to_encoded_point(true).as_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+    use rand::rngs::OsRng;
+    use sha2::{Sha256, Sha384, Sha512};
+
+    // RFC 7748, section 6.1.
+    const ALICE_PRIVATE_KEY: [u8; 32] = hex!("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+    const ALICE_PUBLIC_KEY: [u8; 32] = hex!("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a");
+    const BOB_PRIVATE_KEY: [u8; 32] = hex!("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+    const BOB_PUBLIC_KEY: [u8; 32] = hex!("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f");
+    const SHARED_SECRET: [u8; 32] = hex!("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+
+    /// `DeriveKeyPair(seed, info)` of RFC 9497, spelled out.
+    fn rfc9497_derive_private_key<D: SuiteHash>(group: AkeGroup, context_string: &[u8]) -> Vec<u8> {
+        let info = b"test key";
+        let derive_input = [&[0xa3u8; 32][..], &(info.len() as u16).to_be_bytes(), info, &[0]].concat();
+        group.hash_to_scalar::<D>(&derive_input, &[b"DeriveKeyPair", context_string].concat()).unwrap()
+    }
+
+    #[test]
+    fn hash_to_scalar_matches_rfc9497() {
+        // RFC 9497, appendix A.1.1 (ristretto255-SHA512) and A.3.1 (P256-SHA256), OPRF mode.
+        assert_eq!(
+            rfc9497_derive_private_key::<Sha512>(AkeGroup::Ristretto255, b"OPRFV1-\x00-ristretto255-SHA512"),
+            hex!("5ebcea5ee37023ccb9fc2d2019f9d7737be85591ae8652ffa9ef0f4d37063b0e"),
+        );
+        assert_eq!(
+            rfc9497_derive_private_key::<Sha256>(AkeGroup::P256, b"OPRFV1-\x00-P256-SHA256"),
+            hex!("159749d750713afe245d2d39ccfaae8381c53ce92d098a9375ee70739c7ac0bf"),
+        );
+    }
+
+    #[test]
+    fn p256_derives_keypairs() {
+        let group = AkeGroup::P256;
+        let (private_key, public_key) = group.derive_keypair::<Sha256>(CipherSuite::P256Sha256, &[7u8; 32]).unwrap();
+        assert_eq!(group.derive_keypair::<Sha256>(CipherSuite::P256Sha256, &[7u8; 32]).unwrap(), (private_key.clone(), public_key.clone()));
+        assert_eq!(public_key.len(), group.public_key_size());
+        assert_eq!(group.recover_public_key(&private_key).unwrap(), public_key);
+        group.validate_public_key(&public_key).unwrap();
+    }
+
+    #[test]
+    fn p256_rejects_invalid_keys() {
+        let group = AkeGroup::P256;
+        let (private_key, public_key) = group.generate_keypair(&mut OsRng);
+
+        // The identity (SEC1 encodes it as a single zero byte), a point off the curve, a compact point and an
+        // uncompressed point.
+        let off_curve = (0..=255u8)
+            .map(|x| [&[0x02][..], &[0; 31], &[x]].concat())
+            .find(|point| p256::PublicKey::from_sec1_bytes(point).is_err())
+            .unwrap();
+        let mut compact = public_key.clone();
+        compact[0] = 0x05;
+        let uncompressed = p256::PublicKey::from_sec1_bytes(&public_key).unwrap().to_encoded_point(false);
+        for invalid in [vec![0u8], vec![0u8; 33], off_curve, compact, uncompressed.as_bytes().to_vec()].iter() {
+            assert_eq!(group.validate_public_key(invalid).unwrap_err().kind(), io::ErrorKind::InvalidData);
+            assert_eq!(group.diffie_hellman(&private_key, invalid).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        // The zero scalar and scalars not reduced modulo the group order.
+        let order = hex!("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551");
+        for invalid in [[0u8; 32], order, [0xff; 32]].iter() {
+            assert_eq!(group.recover_public_key(invalid).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn p384_hash_to_group_matches_rfc9380() {
+        // RFC 9380, appendix J.3.1 (P384_XMD:SHA-384_SSWU_RO_), both points have an even y-coordinate.
+        let dst = b"QUUX-V01-CS02-with-P384_XMD:SHA-384_SSWU_RO_";
+        let vectors: [(&[u8], [u8; 48]); 2] = [
+            (b"", hex!("eb9fe1b4f4e14e7140803c1d99d0a93cd823d2b024040f9c067a8eca1f5a2eeac9ad604973527a356f3fa3aeff0e4d83")),
+            (b"abc", hex!("e02fc1a5f44a7519419dd314e29863f30df55a514da2d655775a81d413003c4d4e7fd59af0826dfaad4200ac6f60abe1")),
+        ];
+        for (msg, x) in vectors.iter() {
+            let point = AkeGroup::P384.hash_to_group::<Sha384>(msg, dst).unwrap();
+            assert_eq!(point, [&[0x02][..], x].concat());
+        }
+    }
+
+    #[test]
+    fn p384_derives_keypairs() {
+        let group = AkeGroup::P384;
+        let (private_key, public_key) = group.derive_keypair::<Sha384>(CipherSuite::P384Sha384, &[7u8; 32]).unwrap();
+        assert_eq!(private_key.len(), group.private_key_size());
+        assert_eq!(public_key.len(), group.public_key_size());
+        assert_eq!(group.recover_public_key(&private_key).unwrap(), public_key);
+
+        let (peer_private_key, peer_public_key) = group.generate_keypair(&mut OsRng);
+        assert_eq!(
+            group.diffie_hellman(&private_key, &peer_public_key).unwrap(),
+            group.diffie_hellman(&peer_private_key, &public_key).unwrap(),
+        );
+
+        let mut compact = public_key.clone();
+        compact[0] = 0x05;
+        for invalid in [vec![0u8], vec![0u8; 49], compact, public_key[..33].to_vec()].iter() {
+            assert_eq!(group.validate_public_key(invalid).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        for invalid in [vec![0u8; 48], private_key[..32].to_vec()].iter() {
+            assert_eq!(group.recover_public_key(invalid).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn x25519_imports_rfc7748_keys() {
+        let group = AkeGroup::X25519;
+        assert_eq!(group.recover_public_key(&ALICE_PRIVATE_KEY).unwrap(), ALICE_PUBLIC_KEY);
+        assert_eq!(group.recover_public_key(&BOB_PRIVATE_KEY).unwrap(), BOB_PUBLIC_KEY);
+        assert_eq!(group.diffie_hellman(&ALICE_PRIVATE_KEY, &BOB_PUBLIC_KEY).unwrap(), SHARED_SECRET);
+        assert_eq!(group.diffie_hellman(&BOB_PRIVATE_KEY, &ALICE_PUBLIC_KEY).unwrap(), SHARED_SECRET);
+    }
+
+    #[test]
+    fn x25519_derives_and_generates_keypairs() {
+        let group = AkeGroup::X25519;
+        let seed = [ALICE_PRIVATE_KEY, BOB_PRIVATE_KEY].concat();
+        let (private_key, public_key) = group.derive_keypair::<Sha512>(CipherSuite::Ristretto255Sha512, &seed).unwrap();
+        assert_eq!(private_key, ALICE_PRIVATE_KEY);
+        assert_eq!(public_key, ALICE_PUBLIC_KEY);
+        assert_eq!(group.derive_keypair::<Sha512>(CipherSuite::Ristretto255Sha512, &seed[..31]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let (private_key, public_key) = group.generate_keypair(&mut OsRng);
+        let (peer_private_key, peer_public_key) = group.generate_keypair(&mut OsRng);
+        assert_eq!(
+            group.diffie_hellman(&private_key, &peer_public_key).unwrap(),
+            group.diffie_hellman(&peer_private_key, &public_key).unwrap(),
+        );
+    }
+
+    #[test]
+    fn x25519_rejects_malformed_keys() {
+        let group = AkeGroup::X25519;
+        assert_eq!(group.recover_public_key(&ALICE_PRIVATE_KEY[..31]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(group.validate_public_key(&[0u8; 32]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(group.validate_public_key(&BOB_PUBLIC_KEY[..31]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(group.hmqv_exponent::<Sha512>(&BOB_PUBLIC_KEY, b"id").unwrap_err().kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn x25519_rejects_all_zero_shared_secrets() {
+        // Points of small order (RFC 7748 section 6.1 asks to check for the all-zero output they produce).
+        let low_order_points = [
+            hex!("0100000000000000000000000000000000000000000000000000000000000000"),
+            hex!("e0eb7a7c3b41b8ae1656e3faf19fc46ada098deb9c32b1fd866205165f49b800"),
+            hex!("5f9c95bca3508c24b1d0b1559c83ef5b04445cc4581c8e86d8224eddd09f1157"),
+            hex!("ecffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff7f"),
+        ];
+        for point in &low_order_points {
+            assert!(AkeGroup::X25519.validate_public_key(point).is_ok());
+            assert_eq!(
+                AkeGroup::X25519.diffie_hellman(&ALICE_PRIVATE_KEY, point).unwrap_err().kind(),
+                io::ErrorKind::InvalidData,
+            );
+        }
+    }
+
+    #[test]
+    fn hmqv_secrets_match() {
+        for group in [AkeGroup::Ristretto255, AkeGroup::P256, AkeGroup::P384].iter() {
+            let (client_private_key, client_public_key) = group.generate_keypair(&mut OsRng);
+            let (server_private_key, server_public_key) = group.generate_keypair(&mut OsRng);
+            let (client_secret, client_keyshare) = group.generate_keypair(&mut OsRng);
+            let (server_secret, server_keyshare) = group.generate_keypair(&mut OsRng);
+            let d = group.hmqv_exponent::<Sha512>(&client_keyshare, b"server").unwrap();
+            let e = group.hmqv_exponent::<Sha512>(&server_keyshare, b"client").unwrap();
+
+            let client = group.hmqv(&client_secret, &client_private_key, &d, &server_keyshare, &server_public_key, &e);
+            let server = group.hmqv(&server_secret, &server_private_key, &e, &client_keyshare, &client_public_key, &d);
+            assert_eq!(client.unwrap(), server.unwrap());
+
+            // Exponents bound to other identities give another secret.
+            let other = group.hmqv_exponent::<Sha512>(&client_keyshare, b"mallory").unwrap();
+            let client = group.hmqv(&client_secret, &client_private_key, &other, &server_keyshare, &server_public_key, &e);
+            let server = group.hmqv(&server_secret, &server_private_key, &e, &client_keyshare, &client_public_key, &d);
+            assert_ne!(client.unwrap(), server.unwrap());
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Key derivation, MAC and hash helpers used across the protocol.
+//!
+//! Every helper is generic over the suite's hash function `D` (any [`SuiteHash`], see
+//! [`crate::config::CipherSuite`]), as long as the client and the server agree on it.
+//!
+//! The names match the ones used by the draft:
+//! - `Extract(salt, ikm)`: HKDF-Extract;
+//! - `Expand(prk, info, L)`: HKDF-Expand;
+//! - `Expand-Label(secret, label, context, L)`: HKDF-Expand with a labeled `info`;
+//! - `MAC(key, msg)`: HMAC (and `ct_equal(tag, MAC(key, msg))` as [`verify`]);
+//! - `Hash(msg)`: the plain hash function.
+use std::io;
+use digest::Digest;
+use digest::core_api::BlockSizeUser;
+use hkdf::SimpleHkdf;
+use hmac::{Mac, SimpleHmac};
+
+/// Hash functions the suites are built on (`Sha256`, `Sha384`, `Sha512` and `Sha3_512`, see
+/// [`crate::config::CipherSuite`]): any fixed output hash function with a block size, as needed by HMAC and
+/// `expand_message_xmd`.
+///
+/// It's implemented for every such type, the suite's functions check that they were given the suite's own hash
+/// function (see [`crate::config::CipherSuite::check_hash`]).
+pub trait SuiteHash: Digest + BlockSizeUser + Clone + 'static {}
+
+impl<D: Digest + BlockSizeUser + Clone + 'static> SuiteHash for D {}
+
+/// Extracts a pseudorandom key from `ikm` using `salt`.
+///
+/// # Arguments
+///
+/// * `salt`: An optional salt (an empty slice is the same as no salt).
+/// * `ikm`: The input keying material.
+///
+/// # Returns
+///
+/// * `prk`: A pseudorandom key of `Nh` bytes.
+pub fn extract<D>(salt: &[u8], ikm: &[u8]) -> Vec<u8>
+where
+    D: SuiteHash,
+{
+    let (prk, _) = SimpleHkdf::<D>::extract(Some(salt), ikm);
+    prk.to_vec()
+}
+
+/// Expands `prk` into `len` bytes bound to `info`.
+///
+/// # Arguments
+///
+/// * `prk`: A pseudorandom key of at least `Nh` bytes.
+/// * `info`: Context and application specific information.
+/// * `len`: Number of output bytes.
+///
+/// # Returns
+///
+/// * `okm`: The output keying material.
+pub fn expand<D>(prk: &[u8], info: &[u8], len: usize) -> io::Result<Vec<u8>>
+where
+    D: SuiteHash,
+{
+    let hkdf = SimpleHkdf::<D>::from_prk(prk).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut okm = vec![0u8; len];
+    hkdf.expand(info, &mut okm).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    Ok(okm)
+}
+
+/// Expands `secret` into `len` bytes bound to `label` and `context`, as `Expand-Label` on the draft:
+/// `Expand(secret, concat(I2OSP(len, 2), I2OSP(len("OPAQUE-" + label), 1), "OPAQUE-" + label,
+/// I2OSP(len(context), 1), context), len)`.
+///
+/// # Exceptions
+///
+/// * `InvalidInput`: When `len` doesn't fit on 2 bytes, `label`/`context` don't fit on 255 bytes, or `len` is
+///   bigger than what HKDF can output (`255 * Nh`).
+pub fn expand_label<D>(secret: &[u8], label: &[u8], context: &[u8], len: usize) -> io::Result<Vec<u8>>
+where
+    D: SuiteHash,
+{
+    let full_label = [b"OPAQUE-".as_ref(), label].concat();
+    if len > u16::MAX as usize || full_label.len() > 255 || context.len() > 255 {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let mut info = Vec::with_capacity(4 + full_label.len() + context.len());
+    info.extend_from_slice(&(len as u16).to_be_bytes());
+    info.push(full_label.len() as u8);
+    info.extend_from_slice(&full_label);
+    info.push(context.len() as u8);
+    info.extend_from_slice(context);
+    expand::<D>(secret, &info, len)
+}
+
+/// Computes an authentication tag over `msg`.
+///
+/// # Arguments
+///
+/// * `key`: The MAC key.
+/// * `msg`: The message to be authenticated.
+///
+/// # Returns
+///
+/// * `tag`: An authentication tag of `Nm` bytes.
+pub fn mac<D>(key: &[u8], msg: &[u8]) -> Vec<u8>
+where
+    D: SuiteHash,
+{
+    // HMAC accepts keys of any size, so this never fails.
+    let mut mac = <SimpleHmac<D> as Mac>::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(msg);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Verifies an authentication tag over `msg`, in constant time.
+///
+/// # Exceptions
+///
+/// * `InvalidData`: When the tag doesn't match.
+pub fn verify<D>(key: &[u8], msg: &[u8], tag: &[u8]) -> io::Result<()>
+where
+    D: SuiteHash,
+{
+    let mut mac = <SimpleHmac<D> as Mac>::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(msg);
+    mac.verify_slice(tag).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+/// Compares two byte strings in constant time (`ct_equal` on the draft), i.e. an authentication tag computed
+/// earlier and a received one.
+pub fn ct_equal(x: &[u8], y: &[u8]) -> bool {
+    x.len() == y.len() && x.iter().zip(y).fold(0u8, |acc, (&a, &b)| acc | (a ^ b)) == 0
+}
+
+/// Hashes `msg` with the suite's hash function.
+pub fn hash<D>(msg: &[u8]) -> Vec<u8>
+where
+    D: SuiteHash,
+{
+    D::digest(msg).to_vec()
+}
+
+/// The output size of the suite's hash function (`Nh` on the draft).
+pub fn output_size<D: SuiteHash>() -> usize {
+    <D as Digest>::output_size()
+}
+
+/// Encrypts and authenticates `plaintext` with a one-time `key`, outputting `ciphertext || tag` where
+/// `ciphertext = xor(Expand(key, concat(info, "Pad"), len(plaintext)), plaintext)` and
+/// `tag = MAC(Expand(key, concat(info, "MacKey"), Nh), ciphertext)`.
+///
+/// Used for the handshake extensions, whose keys come from the handshake secrets and are used only once.
+///
+/// # Arguments
+///
+/// * `key`: A one-time key of at least `Nh` bytes.
+/// * `info`: Label binding the output to its usage.
+/// * `plaintext`: The data to be protected.
+pub fn seal<D>(key: &[u8], info: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>>
+where
+    D: SuiteHash,
+{
+    let pad = expand::<D>(key, &[info, b"Pad"].concat(), plaintext.len())?;
+    let mac_key = expand::<D>(key, &[info, b"MacKey"].concat(), output_size::<D>())?;
+
+    let mut output: Vec<u8> = pad.iter().zip(plaintext).map(|(&p, &m)| p ^ m).collect();
+    let tag = mac::<D>(&mac_key, &output);
+    output.extend_from_slice(&tag);
+    Ok(output)
+}
+
+/// Verifies and decrypts the output of [`seal`].
+///
+/// # Exceptions
+///
+/// * `InvalidData`: When the input is too short or the tag doesn't match (compared in constant time).
+pub fn open<D>(key: &[u8], info: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>>
+where
+    D: SuiteHash,
+{
+    if sealed.len() < output_size::<D>() {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    let (ciphertext, tag) = sealed.split_at(sealed.len() - output_size::<D>());
+    let mac_key = expand::<D>(key, &[info, b"MacKey"].concat(), output_size::<D>())?;
+    verify::<D>(&mac_key, ciphertext, tag)?;
+
+    let pad = expand::<D>(key, &[info, b"Pad"].concat(), ciphertext.len())?;
+    Ok(pad.iter().zip(ciphertext).map(|(&p, &c)| p ^ c).collect())
+}
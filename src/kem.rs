@@ -0,0 +1,420 @@
+//! # KEMs
+//!
+//! Key encapsulation mechanisms used by the post-quantum AKE ([`crate::ake::AkeProtocol::Kem`]), in which the
+//! Diffie-Hellman operations are replaced by encapsulations to the peer's keys.
+//!
+//! Keys and ciphertexts are handled in their encoded form, as specified on FIPS 203 for ML-KEM, and the
+//! primitives are the ones of the `ml-kem` crate. The input checks of FIPS 203 (section 7) are run on every
+//! received key, since the crate doesn't run them.
+use std::io;
+use std::convert::TryFrom;
+use ml_kem::kem::Decapsulate;
+use ml_kem::{Ciphertext, EncapsulateDeterministic, Encoded, EncodedSizeUser, KemCore, MlKem768, B32};
+use rand::{CryptoRng, RngCore};
+use sha3::{Digest, Sha3_256};
+use crate::config::invalid_data;
+
+/// The ML-KEM modulus `q`.
+const Q: u16 = 3329;
+
+type MlKem768DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type MlKem768EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// Supported KEMs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kem {
+    /// ML-KEM-768 (FIPS 203), security category 3.
+    MlKem768,
+}
+
+impl Kem {
+    /// One byte identifier of the KEM, used when serializing a [`crate::config::OpaqueConfig`].
+    pub fn id(&self) -> u8 {
+        match self {
+            Kem::MlKem768 => 0x01,
+        }
+    }
+
+    /// Returns the KEM matching `id`, if any.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(Kem::MlKem768),
+            _ => None,
+        }
+    }
+
+    /// Size of an encoded encapsulation (public) key.
+    pub fn encapsulation_key_size(&self) -> usize {
+        match self {
+            Kem::MlKem768 => 1184,
+        }
+    }
+
+    /// Size of an encoded decapsulation (private) key.
+    pub fn decapsulation_key_size(&self) -> usize {
+        match self {
+            Kem::MlKem768 => 2400,
+        }
+    }
+
+    /// Size of an encoded ciphertext.
+    pub fn ciphertext_size(&self) -> usize {
+        match self {
+            Kem::MlKem768 => 1088,
+        }
+    }
+
+    /// Size of a shared secret.
+    pub fn shared_secret_size(&self) -> usize {
+        32
+    }
+
+    /// Size of the seed used by [`Kem::derive_keypair`] (`d || z` on FIPS 203).
+    pub fn seed_size(&self) -> usize {
+        64
+    }
+
+    /// Recovers the encapsulation key embedded in an encoded decapsulation key
+    /// (`dk = dk_pke || ek || H(ek) || z` on FIPS 203).
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the decapsulation key doesn't have the expected size.
+    pub fn recover_encapsulation_key(&self, decapsulation_key: &[u8]) -> io::Result<Vec<u8>> {
+        if decapsulation_key.len() != self.decapsulation_key_size() {
+            return Err(invalid_data());
+        }
+
+        let start = self.decapsulation_key_size() - self.encapsulation_key_size() - 64;
+        Ok(decapsulation_key[start..start + self.encapsulation_key_size()].to_vec())
+    }
+
+    /// Generates a random keypair.
+    ///
+    /// # Returns
+    ///
+    /// * `decapsulation_key`: The encoded decapsulation key.
+    /// * `encapsulation_key`: The encoded encapsulation key.
+    pub fn generate_keypair<R: RngCore + CryptoRng>(&self, rng: &mut R) -> (Vec<u8>, Vec<u8>) {
+        match self {
+            Kem::MlKem768 => {
+                let (dk, ek) = MlKem768::generate(rng);
+                (dk.as_bytes().to_vec(), ek.as_bytes().to_vec())
+            }
+        }
+    }
+
+    /// Deterministically derives a keypair from `seed`, used by the internal envelope mode
+    /// (`ML-KEM.KeyGen_internal(d, z)` on FIPS 203, with `d, z = seed[..32], seed[32..]`).
+    ///
+    /// # Arguments
+    ///
+    /// * `seed`: 64 bytes pseudo-random seed.
+    ///
+    /// # Returns
+    ///
+    /// * `decapsulation_key`: The encoded decapsulation key.
+    /// * `encapsulation_key`: The encoded encapsulation key.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When the seed isn't [`Kem::seed_size`] bytes long.
+    pub fn derive_keypair(&self, seed: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        if seed.len() != self.seed_size() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let (d, z) = seed.split_at(32);
+        let (d, z) = (B32::try_from(d).map_err(|_| invalid_data())?, B32::try_from(z).map_err(|_| invalid_data())?);
+        match self {
+            Kem::MlKem768 => {
+                let (dk, ek) = MlKem768::generate_deterministic(&d, &z);
+                Ok((dk.as_bytes().to_vec(), ek.as_bytes().to_vec()))
+            }
+        }
+    }
+
+    /// Encapsulates a fresh shared secret to `encapsulation_key`.
+    ///
+    /// # Returns
+    ///
+    /// * `ciphertext`: The encoded ciphertext, sent to the owner of the decapsulation key.
+    /// * `shared_secret`: The shared secret.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the encapsulation key fails the FIPS 203 input checks.
+    pub fn encapsulate<R: RngCore + CryptoRng>(
+        &self,
+        rng: &mut R,
+        encapsulation_key: &[u8],
+    ) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let mut m = [0u8; 32];
+        rng.fill_bytes(&mut m);
+        self.encapsulate_with(encapsulation_key, &m)
+    }
+
+    /// Decapsulates `ciphertext`. Invalid ciphertexts implicitly reject (a pseudo-random shared secret is
+    /// returned), so a failure is only noticed by the MAC checks of the handshake.
+    ///
+    /// # Returns
+    ///
+    /// * `shared_secret`: The shared secret.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the key or the ciphertext don't have the expected size, or the key fails the FIPS 203
+    ///   hash check.
+    pub fn decapsulate(&self, decapsulation_key: &[u8], ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        if decapsulation_key.len() != self.decapsulation_key_size() || ciphertext.len() != self.ciphertext_size() {
+            return Err(invalid_data());
+        }
+
+        // Hash check: the decapsulation key embeds H(ek) right after ek.
+        let start = self.decapsulation_key_size() - self.encapsulation_key_size() - 64;
+        let (encapsulation_key, rest) = decapsulation_key[start..].split_at(self.encapsulation_key_size());
+        if Sha3_256::digest(encapsulation_key)[..] != rest[..32] {
+            return Err(invalid_data());
+        }
+
+        match self {
+            Kem::MlKem768 => {
+                let dk = Encoded::<MlKem768DecapsulationKey>::try_from(decapsulation_key).map_err(|_| invalid_data())?;
+                let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext).map_err(|_| invalid_data())?;
+                let shared_secret = MlKem768DecapsulationKey::from_bytes(&dk)
+                    .decapsulate(&ciphertext)
+                    .map_err(|_| invalid_data())?;
+                Ok(shared_secret.to_vec())
+            }
+        }
+    }
+
+    /// Encapsulates the shared secret derived from `m` (`ML-KEM.Encaps_internal(ek, m)` on FIPS 203), after the
+    /// input checks of the encapsulation key.
+    fn encapsulate_with(&self, encapsulation_key: &[u8], m: &[u8; 32]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        if encapsulation_key.len() != self.encapsulation_key_size() {
+            return Err(invalid_data());
+        }
+
+        // Modulus check: ByteEncode12(ByteDecode12(ek)) == ek, i.e. every coefficient of t is reduced mod q.
+        let t = &encapsulation_key[..encapsulation_key.len() - 32];
+        if t.chunks(3).any(|c| c[0] as u16 | (c[1] as u16 & 0x0f) << 8 >= Q || (c[1] as u16 >> 4 | (c[2] as u16) << 4) >= Q) {
+            return Err(invalid_data());
+        }
+
+        match self {
+            Kem::MlKem768 => {
+                let ek = Encoded::<MlKem768EncapsulationKey>::try_from(encapsulation_key).map_err(|_| invalid_data())?;
+                let (ciphertext, shared_secret) = MlKem768EncapsulationKey::from_bytes(&ek)
+                    .encapsulate_deterministic(&B32::from(*m))
+                    .map_err(|_| invalid_data())?;
+                Ok((ciphertext.to_vec(), shared_secret.to_vec()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+    use rand::rngs::OsRng;
+
+    /// ML-KEM-768 `keyGen` vectors of the NIST ACVP server (tcId 26 and 27), as `[d, z, SHA3-256(ek), SHA3-256(dk)]`.
+    const KEY_GEN_VECTORS: [[[u8; 32]; 4]; 2] = [
+        [
+            hex!("e34a701c4c87582f42264ee422d3c684d97611f2523efe0c998af05056d693dc"),
+            hex!("a85768f3486bd32a01bf9a8f21ea938e648eae4e5448c34c3eb88820b159eedd"),
+            hex!("e29020839d052fa372585627f8b59ee312ae414c979d825f06a6929a79625718"),
+            hex!("a4e8ba80bb7a745e936d47784c07ffa6a314caf5a8deb4648c5c2d6ae930ebcd"),
+        ],
+        [
+            hex!("444f032dd19ae7518c4b35b0732a41dc567845aba8bd7b04a9c413a0cf2de0b5"),
+            hex!("df0f282411f4a071489a8f618e2ae5aef40131cac5233d6d731522720c2feb1c"),
+            hex!("bba283f4c993a010081e2cc571d97234472cc9858d199cf0d6e6b9bd720c2665"),
+            hex!("f5fe5588a70ed8bb7e744a3f46aa0200fa333dbe216106d62787141dd5fc42d8"),
+        ],
+    ];
+
+    /// ML-KEM-768 `encapsulation` vector of the NIST ACVP server (tcId 26).
+    const ENCAPSULATION_KEY: [u8; 1184] = hex!(
+        "89d2cb65f94dcbfc890efc7d0e5a7a38344d1641a3d0b024d50797a5f23c3a18b3101a1269069f43a842bacc098a8821271c673db1beb330"
+        "34e4d7774d16635c7c2c3c2763453538bc1632e1851591a51642974e5928abb8e55fe55612f9b141aff015545394b2092e590970ec29a7b7"
+        "e7aa1fb4493bf7cb731906c2a5cb49e6614859064e19b8fa26af51c44b5e7535bfdac072b646d3ea490d277f0d97ced47395fed91e8f2bce"
+        "0e3ca122c2025f74067ab928a822b35653a74f06757629afb1a1caf237100ea935e793c8f58a71b3d6ae2c8658b10150d4a38f572a0d49d2"
+        "8ae89451d338326fdb3b4350036c1081117740edb86b12081c5c1223dbb5660d5b3cb3787d481849304c68be875466f14ee5495c2bd795ae"
+        "412d09002d65b8719b90cba3603ac4958ea03cc138c86f7851593125334701b677f82f4952a4c93b5b4c134bb42a857fd15c650864a6aa94"
+        "eb691c0b691be4684c1f5b7490467fc01b1d1fda4dda35c4ecc231bc73a6fef42c99d34eb82a4d014987b3e386910c62679a118f3c5bd9f4"
+        "67e4162042424357db92ef484a4a1798c1257e870a30cb20aaa0335d83314fe0aa7e63a862648041a72a6321523220b1ace9bb701b21ac12"
+        "53cb812c15575a9085eabeade73a4ae76e6a7b158a20586d78a5ac620a5c9abcc9c043350a73656b0abe822da5e0ba76045fad75401d7a3b"
+        "703791b7e99261710f86b72421d240a347638377205a152c794130a4e047742b888303bddc309116764de7424cebea6db65348ac537e01a9"
+        "cc56ea667d5aa87ac9aaa4317d262c10143050b8d07a728ca633c13e468abcead372c77b8ecf3b986b98c1e55860b2b4216766ad874c35ed"
+        "7205068739230220b5a2317d102c598356f168acbe80608de4c9a710b8dd07078cd7c671058af1b0b8304a314f7b29be78a933c7b9294424"
+        "954a1bf8bc745de86198659e0e1225a910726074969c39a97c19240601a46e013dcdcb677a8cbd2c95a40629c256f24a328951df57502ab3"
+        "0772cc7e5b850027c8551781ce4985bdacf6b865c104e8a4bc65c41694d456b7169e45ab3d7acabeafe23ad6a7b94d1979a2f4c1cae7cd77"
+        "d681d290b5d8e451bfdcccf5310b9d12a88ec29b10255d5e17a192670aa9731c5ca67ec784c502781be8527d6fc003c6701b3632284b4030"
+        "7a527c7620377feb0b73f722c9e3cd4dec64876b93ab5b7cfc4a657f852b659282864384f442b22e8a21109387b8b47585fc680d0ba45c7a"
+        "8b1d7274bda57845d100d0f42a3b74628773351fd7ac305b2497639be90b3f4f71a6aa3561eecc6a691bb5cb3914d8634ca1e1af543c049a"
+        "8c6e868c51f0423bd2d5ae09b79e57c27f3fe3ae2b26a441babfc6718ce8c05b4fe793b910b8fbcbbe7f1013242b40e0514d0bdc5c88bac5"
+        "94c794ce5122fbf34896819147b928381587963b0b90034aa07a10be176e01c80ad6a4b71b10af4241400a2a4cbbc05961a15ec1474ed51a"
+        "3cc6d35800679a462809caa3ab4f7094cd6610b4a700cba939e7eac93e38c99755908727619ed76a34e53c4fa25bfc97008206697dd145e5"
+        "b9188e5b014e941681e15fe3e132b8a3903474148ba28b987111c9bcb3989bbbc671c581b44a492845f288e62196e471fed3c39c1bbddb08"
+        "37d0d4706b0922c4"
+    );
+    const ENCAPSULATION_M: [u8; 32] = hex!("2ce74ad291133518fe60c7df5d251b9d82add48462ff505c6e547e949e6b6bf7");
+    const ENCAPSULATION_SHARED_SECRET: [u8; 32] = hex!("2696d28e9c61c2a01ce9b1608dcb9d292785a0cd58efb7fe13b1de95f0db55b3");
+    /// SHA3-256 of the expected ciphertext.
+    const ENCAPSULATION_CIPHERTEXT_HASH: [u8; 32] = hex!("6a0940cb38cbbdf2dfa53d1b510cd876b2854e12a354ed3e9e0b07999c7cf2fe");
+
+    /// ML-KEM-768 `decapsulation` vectors of the NIST ACVP server (tcId 88 and 86, a modified ciphertext).
+    const DECAPSULATION_KEY: [u8; 2400] = hex!(
+        "1e4ac87b1a692a529fdbbab93374c57d110b10f2b1ddebac0d196b7ba631b8e9293028a8f379888c422dc8d32bbf226010c2c1ec73189080"
+        "456b0564b258b0f23131bc79c8e8c11cef3938b243c5ce9c0edd37c8f9d29877dbbb615b9b5ac3c948487e467196a9143efbc7cedb64b45d"
+        "4acda2666cbc2804f2c8662e128f6a9969ec15bc0b9351f6f96346aa7abc743a14fa030e37a2e7597bddfc5a22f9cedaf8614832527210b2"
+        "6f024c7f6c0dcf551e97a4858764c321d1834ad51d75bb246d277237b7bd41dc4362d063f4298292272d01011780b79856b296c4e946658b"
+        "79603197c9b2a99ec66acb06ce2f69b5a5a61e9bd06ad443ceb0c74ed65345a903b614e81368aac2b3d2a79ca8ccaa1c3b88fb82a3663286"
+        "0b3f7950833fd0212ec96ede4ab6f5a0bda3ec6060a658f9457f6cc87c6b620c1a1451987486e496612a101d0e9c20577c571edb5282608b"
+        "f4e1ac926c0db1c82a504a799d89885ca6252bd5b1c183af701392a407c05b848c2a3016c40613f02a449b3c7926da067a53311650684009"
+        "7510460bbfd36073dcb0bfa009b36a9123eaa68f835f74a01b00d2097835964df521ce9210789c30b7f06e5844b444c53322396e4799baf6"
+        "a88af7315860d0192d48c2c0da6b5ba64325543acdf5900e8bc477ab05820072d463affed097e062bd78c99d12b385131a241b708865b419"
+        "0af69ea0a64db71448a60829369c7555198e438c9abc310bc70101913bb12faa5beef975841617c847cd6b336f877987753822020b92c4cc"
+        "97055c9b1e0b128bf11f505005b6ab0e627795a20609efa991e598b80f37b1c6a1c3a1e9aee7028f77570ab2139128a00108c50eb305cdb8"
+        "f9a603a6b078413f6f9b14c6d82b5199ce59d887902a281a027b717495fe12672a127bbf9b256c43720d7c160b281c12757da135b1933352"
+        "be4ab67e40248afc318e2370c3b8208e695bdf337459b9acbfe5b487f76e9b4b4001d6cf90ca8c699a174d42972dc733f33389fdf59a1dab"
+        "a81d834955027334185ad02c76cf294846ca9294ba0ed66741ddec791cab34196ac5657c5a78321b56c33306b5102397a5c09c3508f76b48"
+        "282459f81d0c72a43f737bc2f12f45422628b67db51ac1424276a6c08c3f7615665bbb8e928148a270f991bcf365a90f87c30687b68809c9"
+        "1f231813b866bea82e30374d80aa0c02973437498a53b14bf6b6ca1ed76ab8a20d54a083f4a26b7c038d81967640c20bf4431e71dacce857"
+        "7b21240e494c31f2d877daf4924fd39d82d6167fbcc1f9c5a259f843e30987ccc4bce7493a2404b5e44387f707425781b743fb555685584e"
+        "2557cc038b1a9b3f4043121f5472eb2b96e5941fec011ceea50791636c6abc26c1377ee3b5146fc7c85cb335b1e795eec2033ee44b9aa906"
+        "85245ef7b4436c000e66bc8bcbf1cdb803ac1421b1fdb266d5291c8310373a8a3ce9562ab197953871ab99f382cc5aa9c0f273d1dca55d27"
+        "12853871e1a83cb3b85450f76d3f3c42bab5505f7212fdb6b8b7f6029972a8f3751e4c94c1108b02d6ac79f8d938f05a1b2c229b14b42b31"
+        "b01a364017e59578c6b033833774cb9b570f9086b722903b375446b495d8a29bf80751877a80fb724a0210c3e1692f397c2f1ddc2e6ba17a"
+        "f81b92acfabef5f7573cb493d184027b718238c89a3549b8905b28a83362867c082d3019d3ca70700731ceb73e8472c1a3a093361c5fea6a"
+        "7d40955d07a41b64e50081a361b604cc518447c8e25765ab7d68b243275207af8ca6564a4cb1e94199dba1878c59bec809ab48b2f211badc"
+        "6a1998d9c7227c1303f469d46a9c7e5303f98aba67569ae8227c16ba1fb3244466a25e7f823671810cc26206feb29c7e2a1a91959eeb03a9"
+        "8252a4f7412674eb9a4b277e1f2595fca64033b41b40330812e9735b7c607501cd8183a22afc3392553744f33c4d202526945c6d78a60e20"
+        "1a16987a6fa59d94464b56506556784824a07058f57320e76c825b9347f2936f4a0e5cdaa18cf8833945ae312a36b5f5a3810aac82381fda"
+        "e4cb9c6831d8eb8abab850416443d739086b1c326fc2a3975704e396a59680c3b5f360f5480d2b62169cd94ca71b37bc5878ba2985e068ba"
+        "050b2ce50726d4b4451b77aaa8676eae094982210192197b1e92a27f59868b78867887b9a70c32af84630aa908814379e6519150ba16439b"
+        "5e2b0603d06aa6674557f5b0983e5cb6a97596069b01bb3128c416680657204fd07640392e16b19f337a99a304844e1aa474e9c799062971"
+        "f672268960f5a82f950070bbe9c2a71950a3785bdf0b8440255ed63928d257845168b1eccc4191325aa76645719b28ebd89302dc6723c786"
+        "df5217b243099ca78238e57e64692f206b177abc259660395cd7860fb35a16f6b2fe6548c85ab66330c517fa74cdf3cb49d26b1181901af7"
+        "75a1e180813b6a24c456829b5c38104ece43c76a437a6a33b6fc6c5e65c8a89466c1425485b29b9e1854368afca353e143d0a90a6c6c9e7f"
+        "db62a606856b5614f12b64b796020c3534c3605cfdc73b86714f411850228a28b8f4b49e663416c84f7e381f6af1071343bf9d39b4543924"
+        "0cc03897295fea080b14bb2d8119a880e164495c61bebc7139c11857c85e1750338d6343913706a507c9566464cd2837cf914d1a3c35e89b"
+        "235c6ab7ed078bed234757c02ef6993d4a273cb8150528da4d76708177e9425546c83e147039766603b30da6268f4598a53194240a2832a3"
+        "d67533b5056f9aaac61b4b17b9a2693aa0d58891e6cc56cdd772410900c405af20b903797c64876915c37b8487a1449ce924cd345c29a36e"
+        "08238f7a157cc7e516ab5ba73c8063f726bb5a0a0319e57127438c7fc601c99ccaae4c1a83726fdcb5045ed1a82a985ea995396d77272c66"
+        "ce493289f6110910f37c2741ce47026a6f8261999c6482572b1693912ef12eebea7acf9234fb409f2a6090e6b0bfd895469d0b2a921bb723"
+        "f87a33ea5465ab90f514b67698c0768b6ca498b022c512fa0875f054aa2265867e31c0e522651e024a07d60dd9f633166921f4126bc2b6aa"
+        "01cc15a09b85bff8218c5aae95bc1ffb26ae5a137670f04910ca9d7241b6660c394c5455917746a26682fb71a432ea9530e839bdeb074330"
+        "04f45a0ddaa0b24e3a566a540815f281e3fc259ac6cbc0acb8d62268b603bc676ab415c474bb94873e4487ae31a4e3845c79901550890ee8"
+        "784eef904fee62ba8c5f952c68413052e0a7e3388bb8ff0ad602ae3ea14d9df6dd5e4cc6a381a41da5c137ecc49df587e178eaf47702ec62"
+        "3780691a3233f69f12bd9c9b9637c51378ad71a831055277254cc63c5ad4cb76b4ab82e5fca135e8d26a6b3a89fa5b6f"
+    );
+    const DECAPSULATION_CIPHERTEXT: [u8; 1088] = hex!(
+        "a5c81c76c24305e1ce5d8135d41523682e9ee6d7b40ad41df1f37c9b17dce78076019a6b0b7c95c9be7af29507b2d5a6987c8ee325919085"
+        "5243e6e56f5620608c52d96fab103a8700fba1a87dca6078118a0871762c9534c0c0c3978c91c3a01f0f608dcf757815438fe8957c8a8591"
+        "83b1b6721a0865bebc799d4e5c0e7bd3eae4858e6ab6a2e7658ed80d4ed158b036b93fa03afa6ae3136cf3d693c911bcc75905e5b0cb2865"
+        "b9e9884522a77777613e53111d5a1c7d3dab734ceb03657ae0c89763e99471054776bae7d51b0e73a5bb35aec30ff6bc93684916fef11625"
+        "86452f426653e2ca844d5744307ff9aeb287a6447783b21a0e939c81421d631f5dcb452e51ed34e3dad1cf504e0a3b0f4711a8dc6499d169"
+        "1d109569336ce1558a4c0a464e2087ea8f9e3b18f747ef61f4576aeb42b17cadb7f0fd84da8e3a6f471d95edfa65be9e6c9f6ae756a22a4f"
+        "1a5c543c26ba7bad88e16d5f5b7e12e2d4ca34b3a64d17f87ccfc4ff8c5e4f53752a077c68721e8cc817f9ff24876170ff2af89fa95855a5"
+        "b1de347c07fddbcfe7264aa5ed6401491561d831538f852b0ed7b9e8ebaffc060284f22d2baee56fa9f6d01432a115a2d6a64c38ae0a50ba"
+        "362fb57b53e3e855b83ce8c42274045599f65fa6a8921d85f94ed230b516712db6fd2ff28b3a3371d9be058ae75c2fa591b7ec3c3daa1f76"
+        "42bc26c324c08090607e6662154db37cf747967a1f9fc29089f570ebe60eeef89fd24481028c85aef1dc3b09f22cd3691bbbb821c7a8a0f3"
+        "5ad12be1dd199b977048f3d48c16bb2ca94cecb8928770d5bb329a0327e0b286faa1c65281031a31c84f2edc9c04d475ed4e128e51efa97d"
+        "0148cba6c95f674c589f301c265bed708e9ad8da3c5cecbdeeed35ef1e253132ba89920d786b88230b013bcf2dc92d6b157afa8da8592cd0"
+        "743d4982be60d7c2d5c472ab9fa7f4cc3d12b0ebaf0abe555c75805426844dd9428643f84406a1b8d6faedfd8ae6e73a72772a2159acabd9"
+        "72aeb6f7de091ac5fdd7f49a3dc6641cdf62446b4b04a31f73b80a62f80a404a8cb18ce3e65480ef7b52bf0091117e5d08eae1b0aabb72e6"
+        "dffff76f6e44bbd7ea570d6604bc2e74318bafa315a38861aa1b21afb2a53f2614f1d640075984ae62e2fca1d1b4db369f15705ce7d4df8a"
+        "e98264501051c0def21d645d49625af02ca428d9f0c2cd9fbaeeab97e8e9151662b6992b4c99ab1b925d08920363373f76d3fdf0828caa69"
+        "c8b1bdc6f521df641cf1c8a4e7ef0c23289a4e2cf18acebbe4c1e68369bd5235120142ecdd1a73811e2e533a647d7aee16daa03b683639dc"
+        "f1e1f1e71cfaed48f69aec3e831733da19cebec1ddbf71cbae0800f2f6d64a096ec495d62f4344f7aa5621b322353a795aa099ea3a070272"
+        "d053d4653a20cf210eaaf12cae6023d8e5118df04b384a44d1edb91c44989ef7ee57f2bf81a24bdc76807da967ee6525410c5c485067efc3"
+        "d39a9ad42cc753baa59a1fd28af35c00d18a406a28fc79ba"
+    );
+    const DECAPSULATION_SHARED_SECRET: [u8; 32] = hex!("dc5b8888bc1eba5c1969c21164ea43e22e7ac0cd012a2f26cb8c487e69ef7ce4");
+    const MODIFIED_CIPHERTEXT: [u8; 1088] = hex!(
+        "74a26c7d27146a22c7eab420134e973799cec1da2df61ae0fa7905a3a47485a063076bfa22d6e4fe5059de0a32e38f11abd63f990e91bd0e"
+        "3a5bc6e710dfe5dc0f6d4a18147ebc2e2d9b179374d83692c53efbd45f28a2a928c2494f903576c410eb1773895ebeadb119960eebda9c3c"
+        "710795a6d9b781fc58b30d08107f4e20944a382afb079f31d21724f2c26e6a53412f0a908be7586f2b3d6d7c1dea0270e98aa209244bd88e"
+        "d68aae01432342ba5f49e015cb476b5b78d15ea77a354cc9e9fd07137d8760be42fd4746c62c02028e7b405ddc95df3d021921cfeddb3d96"
+        "1b957eca302a263dab2dc117beb3e79efacfcf936dfc09fc0d19c358d724fa381ea06ca067c384e944302c3907ab15a1da4b41352692add5"
+        "9b061541f07eff25ec42f46e1a0e370cad06ff3fd997d4d2c5648af762231b382d0593401936cba21551a2ae30d8e8effcf43916b83138bb"
+        "5e610364429879fa9cdd5b7d3cf2feabaa1dc8d50ce69402e21103e795df7074d1fcf65f8a4e18986d5417780602c63be5a044863384bd3d"
+        "8ffb685eac567ed8349dcf2ceb702b7375b145729998049d13e2cd466cf2231b9d3a20018ee908f8514a6c6a89df7232f91fcd84b81ebc8b"
+        "c539e9a37a4324755564be1bf4fa1fb4571e0abbc9b52f9d090c33be599de6c8532c7cb7ec8b4e2d3c07505280e99923865903ffd18bc13b"
+        "9c8164aa1eae84e38d3f57fdb8801785f105a6a8574bd2fe9bf305848e525330bc2d24f0257e47a4950f433a9233e8cdeba81dbae7d8c1a0"
+        "6d01f70de6ef663207d84952827bab3d451cbea0990007fbdb4240fe899a706f7c1563e05c70be9d575189ef83e0cf76195f6652491cce04"
+        "f1ce2092170a92e0dd7301246a4c44fc0b4ee6aaa63fc7027840abd2ec25f654589738cd38b9e10b975cfb6c1d2eb4da97736998f84fdddd"
+        "810d72da3c5ab13507420ddbfaa4f7750c1fae9c7dfb30f40a12aea689fc78da900020e3abb32a364d5c6b3c7544a1b5734a41e95c8314b4"
+        "48cd0b738d829af772a8f81c51adba2d85f326c8f5d6961cf12d44a9bedea00d1df5b48f429b1ce0c15ea5f5bc10b017247ba2c6be922b05"
+        "63b8e9698677cb6c45ccf2081bf84219d2904c11ff92199f8aefad62d8608e200802c5a07202cc820e9e520e31bf36a83002eca4018b0b3a"
+        "398801562aa86c77ab0d50a8fbc3768b0a643b97e7f9072168de29b8175999c9aa48d301a3f0303172e9c7d4f16329d5ca9d42397c3982e1"
+        "0c9da42de88bd6c2ab91c1e71e778e58bb8f801f207a88a9b47f9c687afbba34eda6d2899e4fa0008aa2b539711753dc7c07f614e814f683"
+        "d6c037562ae1fbbe6d7d5fa54b7a6d9451e11b01aaccc3bf2ed64742dd100e0eab2df6cccf937b6d5981eca0e01f3245cf26a72ad1adf066"
+        "c8f5430d72f509963a657d85e554c14e26e8bec5d5f3ab998c9b29f16b04747d80749b30e51fd2a7f690c22f9986aaf6358d6fab8ded5497"
+        "1b32641de2b258590eeaa6bf1f32324a7c4c983f49466d86"
+    );
+    const MODIFIED_SHARED_SECRET: [u8; 32] = hex!("3d23b10df232a180786f61261e85278251746580bebca6acbad60aef6952be69");
+
+    #[test]
+    fn key_generation_matches_fips203() {
+        let kem = Kem::MlKem768;
+        for [d, z, ek_hash, dk_hash] in KEY_GEN_VECTORS.iter() {
+            let (dk, ek) = kem.derive_keypair(&[&d[..], &z[..]].concat()).unwrap();
+            assert_eq!(Sha3_256::digest(&ek)[..], ek_hash[..]);
+            assert_eq!(Sha3_256::digest(&dk)[..], dk_hash[..]);
+            assert_eq!(kem.recover_encapsulation_key(&dk).unwrap(), ek);
+        }
+        assert_eq!(kem.derive_keypair(&[0; 32]).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn encapsulation_matches_fips203() {
+        let (ciphertext, shared_secret) = Kem::MlKem768.encapsulate_with(&ENCAPSULATION_KEY, &ENCAPSULATION_M).unwrap();
+        assert_eq!(Sha3_256::digest(&ciphertext)[..], ENCAPSULATION_CIPHERTEXT_HASH[..]);
+        assert_eq!(shared_secret, ENCAPSULATION_SHARED_SECRET);
+    }
+
+    #[test]
+    fn decapsulation_matches_fips203() {
+        let kem = Kem::MlKem768;
+        assert_eq!(kem.decapsulate(&DECAPSULATION_KEY, &DECAPSULATION_CIPHERTEXT).unwrap(), DECAPSULATION_SHARED_SECRET);
+        // Implicit rejection: a modified ciphertext gives the pseudo-random secret of FIPS 203.
+        assert_eq!(kem.decapsulate(&DECAPSULATION_KEY, &MODIFIED_CIPHERTEXT).unwrap(), MODIFIED_SHARED_SECRET);
+    }
+
+    #[test]
+    fn encapsulation_round_trip() {
+        let kem = Kem::MlKem768;
+        let (dk, ek) = kem.generate_keypair(&mut OsRng);
+        assert_eq!((dk.len(), ek.len()), (kem.decapsulation_key_size(), kem.encapsulation_key_size()));
+
+        let (ciphertext, shared_secret) = kem.encapsulate(&mut OsRng, &ek).unwrap();
+        assert_eq!(ciphertext.len(), kem.ciphertext_size());
+        assert_eq!(shared_secret.len(), kem.shared_secret_size());
+        assert_eq!(kem.decapsulate(&dk, &ciphertext).unwrap(), shared_secret);
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        let kem = Kem::MlKem768;
+        let (dk, ek) = kem.generate_keypair(&mut OsRng);
+
+        // Modulus check: a coefficient equal to q.
+        let mut unreduced = ek.clone();
+        unreduced[0] = 0x01;
+        unreduced[1] = (unreduced[1] & 0xf0) | 0x0d;
+        assert_eq!(kem.encapsulate(&mut OsRng, &unreduced).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(kem.encapsulate(&mut OsRng, &ek[1..]).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // Hash check: H(ek) doesn't match the embedded encapsulation key.
+        let (ciphertext, _) = kem.encapsulate(&mut OsRng, &ek).unwrap();
+        let mut corrupted = dk.clone();
+        corrupted[kem.decapsulation_key_size() - 64] ^= 1;
+        assert_eq!(kem.decapsulate(&corrupted, &ciphertext).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(kem.decapsulate(&dk, &ciphertext[1..]).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -8,6 +8,9 @@
 //! 
 //! Note: since we're trying to keep everything in sync, some breaking changes may occur along the way, but we'll ***try*** to keep the interfaces as
 //! consistent as possible in order to start using it out of the box without major problems.
+//! Handshake messages have the layouts of RFC 9807, and stored records are tagged with a [`config::ProtocolVersion`],
+//! so the server can keep answering older versions side by side with the latest one, and clients don't need to be
+//! upgraded in lockstep.
 //! 
 //! # Opaque Protocol
 //! Note: This section is just summarizing the [protocol overview](https://datatracker.ietf.org/doc/html/draft-irtf-cfrg-opaque#section-3)
@@ -39,15 +42,35 @@
 //! The client outputs a ```export_key``` matching that one from registration, and a ```session_key``` (which is the primary AKE output).
 //! 
 //! The server outputs a single value ```session_key``` that matches that of the client.
+//!
+//! Both sides can derive further independent keys from the ```session_key``` with [`session::SessionKey::export`].
 //! 
 //! After that, client and server can use these values as needed.
 //! 
 
 pub mod oprf;
+pub mod kdf;
 pub mod envelope;
 pub mod messages;
 pub mod opaque;
 pub mod ake;
+pub mod config;
+pub mod group;
+pub mod signature;
+pub mod kem;
+pub mod session;
+pub mod record;
+pub mod noise;
+pub mod resumption;
+pub mod server_setup;
+pub mod store;
+pub mod stateless;
+pub mod sessions;
+pub mod replay;
+pub mod throttle;
+
+#[cfg(test)]
+pub(crate) mod test_support;
 
 pub fn hello_world() -> String {
     String::from("Hello, world!")
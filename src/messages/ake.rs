@@ -1,34 +1,290 @@
+use std::io;
+use crate::config::{
+    invalid_data, read_bytes, read_optional_field, write_field, write_optional_field, OpaqueConfig, NONCE_SIZE,
+};
 use crate::messages::credential::{CredentialRequest, CredentialResponse};
 
 
 /// Structure sent by the client to the server at the beginning of the AKE protocol.
+///
+/// The message has the fixed layout of RFC 9807, every field having the size given by the [`OpaqueConfig`]. The
+/// optional fields of this crate follow as a trailer (see [`write_trailer`]), which is left out when none of them
+/// is used.
 pub struct KE1 {
     /// A [`CredentialRequest`] created using [`CredentialRequest::create_credential_request`].
-    request: CredentialRequest,
+    pub(crate) request: CredentialRequest,
     /// A fresh randomly generated nonce.
-    client_nonce: Vec<u8>,
-    /// Client ephemeral key shared.
-    client_keyshare: Vec<u8>,
+    pub(crate) client_nonce: Vec<u8>,
+    /// Client ephemeral key shared, encoded as a public key of `config.ake_group` (`Npk` bytes, i.e. a
+    /// compressed SEC1 point on P-256). With the KEM protocol it's an ephemeral encapsulation key.
+    pub(crate) client_keyshare: Vec<u8>,
+    /// Client ephemeral encapsulation key of `config.hybrid_kem`. Only used in hybrid mode, otherwise it will be
+    /// ```None```.
+    pub(crate) client_kem_keyshare: Option<Vec<u8>>,
+    /// Optional application information (i.e. a device identifier), sent in plaintext and authenticated by the
+    /// handshake MACs.
+    pub(crate) client_info: Option<Vec<u8>>,
+}
+
+impl KE1 {
+    /// The client's fresh nonce.
+    pub fn client_nonce(&self) -> &[u8] {
+        &self.client_nonce
+    }
+
+    /// The client's ephemeral keyshare.
+    pub fn client_keyshare(&self) -> &[u8] {
+        &self.client_keyshare
+    }
+
+    /// Encodes the message as `request (Noe) || client_nonce (Nn) || client_keyshare (Npk)`, followed by the
+    /// trailer `client_kem_keyshare || client_info`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When a field of the trailer is longer than 65535 bytes.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.request.serialize(&mut output)?;
+        output.extend_from_slice(&self.client_nonce);
+        output.extend_from_slice(&self.client_keyshare);
+        write_trailer(&mut output, &[&self.client_kem_keyshare, &self.client_info])?;
+        Ok(output)
+    }
+
+    /// Decodes a message encoded by [`KE1::serialize`] with the sizes of `config`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is truncated or has trailing bytes.
+    pub fn deserialize(config: &OpaqueConfig, input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+        let request = CredentialRequest::deserialize(config, &mut reader)?;
+        let client_nonce = read_bytes(&mut reader, NONCE_SIZE)?;
+        let client_keyshare = read_bytes(&mut reader, config.keyshare_size())?;
+        let [client_kem_keyshare, client_info] = read_trailer(reader)?;
+        Ok(KE1 { request, client_nonce, client_keyshare, client_kem_keyshare, client_info })
+    }
 }
 
 /// Used by KE2
-struct InnerKE2 {
+pub(crate) struct InnerKE2 {
     /// A [`CredentialResponse`] created using [`CredentialResponse::create_credential_response`].
-    response: CredentialResponse,
+    pub(crate) response: CredentialResponse,
     /// A fresh randomly generated nonce.
-    server_nonce: Vec<u8>,
-    /// Server ephemeral key share of fixed size.
-    server_keyshare: Vec<u8>,
+    pub(crate) server_nonce: Vec<u8>,
+    /// Server ephemeral key share, encoded as a public key of `config.ake_group` (`Npk` bytes, i.e. a compressed
+    /// SEC1 point on P-256). With the KEM protocol it's the ciphertext encapsulated to the client's ephemeral
+    /// encapsulation key.
+    pub(crate) server_keyshare: Vec<u8>,
+    /// Ciphertext encapsulated to the client's `client_kem_keyshare`. Only used in hybrid mode, otherwise it will
+    /// be ```None```.
+    pub(crate) server_kem_keyshare: Option<Vec<u8>>,
+}
+
+impl InnerKE2 {
+    /// Encodes the structure as `response || server_nonce || server_keyshare`, as used by the preamble of RFC 9807.
+    /// In hybrid mode, `server_kem_keyshare` follows, prefixed by its length (2 bytes), so the preamble covers it.
+    pub(crate) fn serialize(&self, output: &mut Vec<u8>) -> io::Result<()> {
+        self.response.serialize(output)?;
+        output.extend_from_slice(&self.server_nonce);
+        output.extend_from_slice(&self.server_keyshare);
+        match &self.server_kem_keyshare {
+            Some(server_kem_keyshare) => write_field(output, server_kem_keyshare),
+            None => Ok(()),
+        }
+    }
 }
 
+/// Structure sent by the server to the client in answer to [`KE1`].
+///
+/// As [`KE1`], it has the fixed layout of RFC 9807 followed by an optional trailer.
 pub struct KE2 {
     /// A [`InnerKE2`] stucture.
-    inner_ke2: InnerKE2,
-    /// An authentication tag computed over the handshake transcript.
-    server_mac: Vec<u8>,
+    pub(crate) inner_ke2: InnerKE2,
+    /// Optional application extensions from the server (i.e. an MFA challenge), sealed with a key derived from
+    /// the handshake secrets (see [`crate::kdf::seal`]) and covered by `server_mac`.
+    pub(crate) server_extensions: Option<Vec<u8>>,
+    /// An authentication tag computed over the handshake transcript (over the server identity with SIGMA-I).
+    pub(crate) server_mac: Vec<u8>,
+    /// Server's signature over the handshake transcript. Only used with SIGMA-I, otherwise it will be ```None```.
+    pub(crate) server_signature: Option<Vec<u8>>,
+    /// Ciphertext encapsulated by the server to the client's long-term key. Only used with the KEM protocol,
+    /// otherwise it will be ```None```.
+    pub(crate) server_kem_ciphertext: Option<Vec<u8>>,
+    /// The ids of the server key and OPRF seed the record is re-bound to, and the server's current public key, as
+    /// `concat(I2OSP(key_id, 2), I2OSP(seed_id, 2), server_public_key)`, covered by `server_mac`. Only used when
+    /// the record was registered with an older server key or OPRF seed (see the "Server key rotation" section of
+    /// [`crate::ake`]), otherwise it will be ```None```.
+    pub(crate) server_key_update: Option<Vec<u8>>,
+    /// The OPRF evaluation of the client's blinded element with the key derived from the seed of
+    /// `server_key_update`, sealed with `Ke2` and covered by `server_mac`. Only used when the record was
+    /// registered with an older OPRF seed (see the "OPRF seed rotation" section of [`crate::ake`]), otherwise it
+    /// will be ```None```.
+    pub(crate) oprf_seed_update: Option<Vec<u8>>,
+    /// A MAC over the channel binding alone, checked by the client before `server_mac` so a binding mismatch is
+    /// told apart from other failures (see the "Channel binding" section of [`crate::ake`]). Only used when the
+    /// handshake is bound to an outer channel, otherwise it will be ```None```.
+    pub(crate) server_binding_mac: Option<Vec<u8>>,
+}
+
+impl KE2 {
+    /// Encodes the message as `response (Noe + Nn + Npk + Ne) || server_nonce (Nn) || server_keyshare (Npk) ||
+    /// server_mac (Nm)`, followed by the trailer `server_kem_keyshare || server_extensions || server_signature ||
+    /// server_kem_ciphertext || server_key_update || oprf_seed_update || server_binding_mac`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When a field of the trailer is longer than 65535 bytes.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        self.inner_ke2.response.serialize(&mut output)?;
+        output.extend_from_slice(&self.inner_ke2.server_nonce);
+        output.extend_from_slice(&self.inner_ke2.server_keyshare);
+        output.extend_from_slice(&self.server_mac);
+        write_trailer(
+            &mut output,
+            &[
+                &self.inner_ke2.server_kem_keyshare,
+                &self.server_extensions,
+                &self.server_signature,
+                &self.server_kem_ciphertext,
+                &self.server_key_update,
+                &self.oprf_seed_update,
+                &self.server_binding_mac,
+            ],
+        )?;
+        Ok(output)
+    }
+
+    /// Decodes a message encoded by [`KE2::serialize`] with the sizes of `config`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is truncated or has trailing bytes.
+    pub fn deserialize(config: &OpaqueConfig, input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+        let response = CredentialResponse::deserialize(config, &mut reader)?;
+        let server_nonce = read_bytes(&mut reader, NONCE_SIZE)?;
+        let server_keyshare = read_bytes(&mut reader, config.server_keyshare_size())?;
+        let server_mac = read_bytes(&mut reader, config.suite.hash_size())?;
+        let [
+            server_kem_keyshare,
+            server_extensions,
+            server_signature,
+            server_kem_ciphertext,
+            server_key_update,
+            oprf_seed_update,
+            server_binding_mac,
+        ] = read_trailer(reader)?;
+
+        Ok(KE2 {
+            inner_ke2: InnerKE2 { response, server_nonce, server_keyshare, server_kem_keyshare },
+            server_extensions,
+            server_mac,
+            server_signature,
+            server_kem_ciphertext,
+            server_key_update,
+            oprf_seed_update,
+            server_binding_mac,
+        })
+    }
 }
 
+/// Structure sent by the client to the server to complete the handshake.
+///
+/// As [`KE1`], it has the fixed layout of RFC 9807 followed by an optional trailer.
 pub struct KE3 {
-    /// An authentication tag computed over the handshake transcript.
-    client_mac: Vec<u8>,
-}
\ No newline at end of file
+    /// Optional application extensions from the client (i.e. an MFA answer), sealed with a key derived from the
+    /// handshake secrets (see [`crate::kdf::seal`]).
+    pub(crate) client_extensions: Option<Vec<u8>>,
+    /// An authentication tag computed over the handshake transcript (over the client identity with SIGMA-I).
+    pub(crate) client_mac: Vec<u8>,
+    /// Client's signature over the handshake transcript. Only used with SIGMA-I, otherwise it will be ```None```.
+    pub(crate) client_signature: Option<Vec<u8>>,
+    /// Ciphertext encapsulated by the client to the server's long-term key. Only used with the KEM protocol,
+    /// otherwise it will be ```None```.
+    pub(crate) client_kem_ciphertext: Option<Vec<u8>>,
+    /// A new [`crate::messages::registration::RegistrationUpload`] bound to the ids of `server_key_update`, sealed
+    /// with `Ke3`. Only used when `KE2` carried a `server_key_update`, otherwise it will be ```None```.
+    pub(crate) record_update: Option<Vec<u8>>,
+    /// A MAC over the channel binding alone, checked by the server before `client_mac` (see `server_binding_mac`
+    /// on [`KE2`]). Only used when the handshake is bound to an outer channel, otherwise it will be ```None```.
+    pub(crate) client_binding_mac: Option<Vec<u8>>,
+}
+
+impl KE3 {
+    /// Encodes the message as `client_mac (Nm)`, followed by the trailer `client_extensions || client_signature ||
+    /// client_kem_ciphertext || record_update || client_binding_mac`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When a field of the trailer is longer than 65535 bytes.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut output = self.client_mac.clone();
+        write_trailer(
+            &mut output,
+            &[
+                &self.client_extensions,
+                &self.client_signature,
+                &self.client_kem_ciphertext,
+                &self.record_update,
+                &self.client_binding_mac,
+            ],
+        )?;
+        Ok(output)
+    }
+
+    /// Decodes a message encoded by [`KE3::serialize`] with the sizes of `config`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is truncated or has trailing bytes.
+    pub fn deserialize(config: &OpaqueConfig, input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+        let client_mac = read_bytes(&mut reader, config.suite.hash_size())?;
+        let [client_extensions, client_signature, client_kem_ciphertext, record_update, client_binding_mac] =
+            read_trailer(reader)?;
+        Ok(KE3 { client_extensions, client_mac, client_signature, client_kem_ciphertext, record_update, client_binding_mac })
+    }
+}
+
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+/// Appends the optional fields of a message after its RFC 9807 layout: nothing when none of them is used (so the
+/// message is the one of the RFC), otherwise every field with a presence flag (1 byte) and a length prefix (2
+/// bytes).
+fn write_trailer(output: &mut Vec<u8>, fields: &[&Option<Vec<u8>>]) -> io::Result<()> {
+    if fields.iter().all(|field| field.is_none()) {
+        return Ok(());
+    }
+
+    for field in fields {
+        write_optional_field(output, field)?;
+    }
+    Ok(())
+}
+
+/// Reads the trailer written by [`write_trailer`] from the rest of a message.
+///
+/// # Exceptions
+///
+/// * `InvalidData`: When the trailer is truncated, has trailing bytes or encodes no field (an empty trailer is
+///   written as nothing, so each message has a single encoding).
+fn read_trailer<const N: usize>(mut reader: &[u8]) -> io::Result<[Option<Vec<u8>>; N]> {
+    if reader.is_empty() {
+        return Ok(std::array::from_fn(|_| None));
+    }
+
+    let mut fields: [Option<Vec<u8>>; N] = std::array::from_fn(|_| None);
+    for field in fields.iter_mut() {
+        *field = read_optional_field(&mut reader)?;
+    }
+
+    if !reader.is_empty() || fields.iter().all(|field| field.is_none()) {
+        return Err(invalid_data());
+    }
+    Ok(fields)
+}
@@ -1,4 +1,15 @@
+use std::io;
+use crate::kdf::SuiteHash;
+use rand::{CryptoRng, RngCore};
+use crate::config::{invalid_data, read_bytes, OpaqueConfig, NONCE_SIZE};
+use crate::envelope::{randomized_password, Envelope};
+use crate::kdf;
 use crate::messages::registration::RegistrationUpload;
+use crate::oprf;
+use crate::server_setup::ServerSetup;
+
+static STR_MASKING_KEY: &[u8] = b"MaskingKey";
+static STR_CREDENTIAL_RESPONSE_PAD: &[u8] = b"CredentialResponsePad";
 
 pub struct CredentialRequest {
     /// Serialized OPRF group element.
@@ -12,18 +23,64 @@ impl CredentialRequest {
     ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
     /// * `pwd`: Client's password.
+    /// * `rng`: A cryptographically secure random number generator.
     ///
     /// # Return
     ///
     /// * `request`: CredentialRequest struct.
     /// * `blind`: an OPRF scalar.
-    pub fn create_credential_request(_pwd: String) { // -> (Self, )
-        /*
-        1. (blind, M) = Blind(password)
-        2. Create CredentialRequest request with M
-        3. Output (request, blind)
-        */
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When `D` isn't the hash function of `config.suite`.
+    pub fn create_credential_request<D, R>(config: &OpaqueConfig, pwd: &[u8], rng: &mut R) -> io::Result<(Self, Vec<u8>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let (blind, data) = oprf::blind::<D, R>(config.suite, pwd, rng)?;
+        Ok((CredentialRequest { data }, blind))
+    }
+
+    /// [USED BY THE SERVER]
+    ///
+    /// Evaluates the request with the OPRF key of `identifier` derived from the seed identified by `seed_id`.
+    /// Besides the credential response, it's used for the upgrade of a record registered with an older seed (see
+    /// the "OPRF seed rotation" section of [`crate::ake`]).
+    ///
+    /// # Exceptions
+    ///
+    /// * `NotFound` (```UnknownOprfSeed```): When there's no seed with this id (i.e. it was retired).
+    /// * `InvalidData`: When `data` isn't a valid element of the OPRF group.
+    pub(crate) fn evaluate<D>(
+        &self,
+        config: &OpaqueConfig,
+        server_setup: &ServerSetup,
+        seed_id: u16,
+        identifier: &str,
+    ) -> io::Result<Vec<u8>>
+    where
+        D: SuiteHash,
+    {
+        let oprf_key = server_setup.oprf_key::<D>(config.suite, seed_id, identifier)?;
+        oprf::evaluate(config.suite, &oprf_key, &self.data)
+    }
+
+    /// Encodes the request as `blinded_message` (`Noe` bytes), as on RFC 9807.
+    pub(crate) fn serialize(&self, output: &mut Vec<u8>) -> io::Result<()> {
+        output.extend_from_slice(&self.data);
+        Ok(())
+    }
+
+    /// Decodes a request encoded by [`CredentialRequest::serialize`] from the head of `reader`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When `reader` is too short.
+    pub(crate) fn deserialize(config: &OpaqueConfig, reader: &mut &[u8]) -> io::Result<Self> {
+        Ok(CredentialRequest { data: read_bytes(reader, config.suite.oprf_group().public_key_size())? })
     }
 }
 
@@ -39,72 +96,263 @@ pub struct CredentialResponse {
 impl CredentialResponse {
     /// [USED BY THE SERVER]
     ///
-    /// If a client's record exists with the corresponding identifier, call this function normally.
-    ///
-    /// If a client's record does not exist, call this function passing a record configured as:
-    /// - record.masking_key: random byte array;
-    /// - record.envelope: random byte array consisting only of zeros.
+    /// If a client's record exists with the corresponding identifier, call this function normally. If it
+    /// doesn't, call [`CredentialResponse::fake_credential_response`] instead.
     ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
     /// * `request`: [`CredentialRequest`] structure.
-    /// * `server_pub_key`: Server's public key.
+    /// * `server_setup`: The server's [`ServerSetup`] (public key of `record.server_key_id` and seed of
+    ///   `record.oprf_seed_id`).
     /// * `record`: [`RegistrationUpload`] structure (output of registration).
     /// * `identifier`: user's identifier.
-    /// * `oprf_seed`: the server side seed.
+    /// * `rng`: A cryptographically secure random number generator.
     ///
     /// # Return
     ///
     /// * `response`: [`CredentialResponse`] structure.
-    pub fn create_credential_response(
-        _request: CredentialRequest,
-        _server_pub_key: Vec<u8>,
-        _record: RegistrationUpload,
-        _identifier: String,
-        _oprf_seed: Vec<u8>
-    ) { // -> Self
-        /*
-        1. ikm = Expand(oprf_seed, concat(credential_identifier, "OprfKey"), Nok)
-        2. (oprf_key, _) = DeriveKeyPair(ikm)
-        3. Z = Evaluate(oprf_key, request.data)
-        4. masking_nonce = random(Nn)
-        5. credential_response_pad = Expand(record.masking_key, concat(masking_nonce, "CredentialResponsePad"), Npk + Ne)
-        6. masked_response = xor(credential_response_pad, concat(server_public_key, record.envelope))
-        7. Create CredentialResponse response with (Z, masking_nonce, masked_response)
-        8. Output response
-        */
+    ///
+    /// # Exceptions
+    ///
+    /// * `NotFound` (```UnknownServerKey```/```UnknownOprfSeed```): When the key or the seed of the record was
+    ///   retired.
+    /// * `InvalidData`: When `request.data` isn't a valid element of the OPRF group, or the envelope of the record
+    ///   doesn't have the size of `config`.
+    pub fn create_credential_response<D, R>(
+        config: &OpaqueConfig,
+        request: &CredentialRequest,
+        server_setup: &ServerSetup,
+        record: &RegistrationUpload,
+        identifier: &str,
+        rng: &mut R,
+    ) -> io::Result<Self>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let data = request.evaluate::<D>(config, server_setup, record.oprf_seed_id(), identifier)?;
+
+        let (_, server_pub_key) = server_setup
+            .server_keypair(record.server_key_id())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let envelope = record.envelope().serialize();
+        if envelope.len() != config.envelope_size() {
+            return Err(invalid_data());
+        }
+
+        let mut masking_nonce = vec![0u8; NONCE_SIZE];
+        rng.fill_bytes(&mut masking_nonce);
+        let credential_response_pad = credential_response_pad::<D>(
+            record.masking_key(),
+            &masking_nonce,
+            server_pub_key.len() + envelope.len(),
+        )?;
+        let masked_response = xor(&credential_response_pad, &[server_pub_key, &envelope].concat());
+
+        Ok(CredentialResponse { data, masking_nonce, masked_response })
+    }
+
+    /// [USED BY THE SERVER]
+    ///
+    /// Answers a credential request for an identifier without a record, with the fake record of
+    /// [`RegistrationUpload::fake_record`]. The fake record is derived from the server's setup, so the same
+    /// identifier always gets the same answer (a random masking key would change on every request, letting an
+    /// attacker enumerate accounts by querying twice and comparing), and the response is created by
+    /// [`CredentialResponse::create_credential_response`] on a record of the same shape as a registered one, so
+    /// it has the same length and runs the same computations.
+    ///
+    /// Logins answered with a fake record MUST go through the same `ServerInit`/`ServerFinish` calls (and so the
+    /// same [`crate::throttle::Throttle`]) as the ones of registered clients.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
+    /// * `request`: [`CredentialRequest`] structure.
+    /// * `server_setup`: The server's [`ServerSetup`].
+    /// * `identifier`: The unknown identifier, as given by the client.
+    /// * `rng`: A cryptographically secure random number generator.
+    ///
+    /// # Return
+    ///
+    /// * `response`: [`CredentialResponse`] structure.
+    pub fn fake_credential_response<D, R>(
+        config: &OpaqueConfig,
+        request: &CredentialRequest,
+        server_setup: &ServerSetup,
+        identifier: &str,
+        rng: &mut R,
+    ) -> io::Result<Self>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let record = RegistrationUpload::fake_record::<D>(config, server_setup, identifier)?;
+        CredentialResponse::create_credential_response::<D, R>(config, request, server_setup, &record, identifier, rng)
     }
 
     /// [USED BY THE CLIENT]
     ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
     /// * `pwd`: Client's password.
     /// * `blind`: OPRF scalar value.
-    /// * `server_identity`: Optional server identity.
-    /// * `client_identity`: Optional client identity.
+    /// * `client_identity`: Optional client identity (defaults to `config.client_identity`).
     ///
     /// # Return
     ///
     /// * `client_pri_key`: Client's private key.
+    /// * `client_pub_key`: Client's public key, the default client identity.
     /// * `server_pub_key`: Server's public key.
     /// * `export_key`: An additional client key.
+    /// * `randomized_pwd`: The randomized password, only used to re-bind the record to a new server key.
+    ///
+    /// # Exceptions
     ///
-    pub fn recover_credentials(
+    /// * `InvalidData` (```EnvelopeRecoveryError```): When the envelope can't be recovered (i.e. a wrong password,
+    ///   or an identifier without a record), or the response doesn't have the size of `config`.
+    #[allow(clippy::type_complexity)]
+    pub fn recover_credentials<D>(
         &self,
-        _pwd: String,
-        _blind: Vec<u8>,
-        _server_identity: Option<String>,
-        _client_identity: Option<String>,
-    ) { // -> (Vec<u8>, Vec<u8>, Vec<u8>)
-        /*
-        1. y = Finalize(password, blind, response.data)
-        2. randomized_pwd = Extract("", Harden(y, params))
-        3. masking_key = Expand(randomized_pwd, "MaskingKey", Nh)
-        4. credential_response_pad = Expand(masking_key, concat(response.masking_nonce, "CredentialResponsePad"), Npk + Ne)
-        5. concat(server_public_key, envelope) = xor(credential_response_pad, response.masked_response)
-        6. (client_private_key, export_key) = RecoverEnvelope(randomized_pwd, server_public_key, envelope, server_identity, client_identity)
-        7. Output (client_private_key, response.server_public_key, export_key)
-        */
+        config: &OpaqueConfig,
+        pwd: &[u8],
+        blind: &[u8],
+        client_identity: Option<&[u8]>,
+    ) -> io::Result<(Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        let oprf_output = oprf::finalize::<D>(config.suite, pwd, blind, &self.data)?;
+        let randomized_pwd = randomized_password::<D>(&oprf_output);
+        let masking_key = kdf::expand::<D>(&randomized_pwd, STR_MASKING_KEY, kdf::output_size::<D>())?;
+
+        if self.masked_response.len() != config.public_key_size() + config.envelope_size() {
+            return Err(invalid_data());
+        }
+        let credential_response_pad =
+            credential_response_pad::<D>(&masking_key, &self.masking_nonce, self.masked_response.len())?;
+        let unmasked = xor(&credential_response_pad, &self.masked_response);
+        let (server_pub_key, envelope) = unmasked.split_at(config.public_key_size());
+
+        let envelope = Envelope::deserialize::<D>(envelope)?;
+        let (client_pri_key, client_pub_key, export_key) =
+            envelope.recover::<D>(config, &randomized_pwd, server_pub_key, client_identity)?;
+        Ok((client_pri_key, client_pub_key, server_pub_key.to_vec(), export_key, randomized_pwd))
+    }
+
+    /// Encodes the response as `evaluated_message (Noe) || masking_nonce (Nn) || masked_response (Npk + Ne)`, as
+    /// on RFC 9807.
+    pub(crate) fn serialize(&self, output: &mut Vec<u8>) -> io::Result<()> {
+        output.extend_from_slice(&self.data);
+        output.extend_from_slice(&self.masking_nonce);
+        output.extend_from_slice(&self.masked_response);
+        Ok(())
+    }
+
+    /// Decodes a response encoded by [`CredentialResponse::serialize`] from the head of `reader`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When `reader` is too short.
+    pub(crate) fn deserialize(config: &OpaqueConfig, reader: &mut &[u8]) -> io::Result<Self> {
+        Ok(CredentialResponse {
+            data: read_bytes(reader, config.suite.oprf_group().public_key_size())?,
+            masking_nonce: read_bytes(reader, NONCE_SIZE)?,
+            masked_response: read_bytes(reader, config.public_key_size() + config.envelope_size())?,
+        })
+    }
+}
+
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+fn credential_response_pad<D>(masking_key: &[u8], masking_nonce: &[u8], len: usize) -> io::Result<Vec<u8>>
+where
+    D: SuiteHash,
+{
+    kdf::expand::<D>(masking_key, &[masking_nonce, STR_CREDENTIAL_RESPONSE_PAD].concat(), len)
+}
+
+fn xor(x: &[u8], y: &[u8]) -> Vec<u8> {
+    x.iter().zip(y).map(|(&x1, &x2)| x1 ^ x2).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use sha2::Sha512;
+    use crate::test_support::{config, register};
+
+    fn respond(config: &OpaqueConfig, setup: &ServerSetup, record: &RegistrationUpload, pwd: &[u8]) -> (CredentialResponse, Vec<u8>) {
+        let (request, blind) = CredentialRequest::create_credential_request::<Sha512, _>(config, pwd, &mut OsRng).unwrap();
+        let response = CredentialResponse::create_credential_response::<Sha512, _>(
+            config, &request, setup, record, "alice", &mut OsRng,
+        ).unwrap();
+
+        let mut serialized = Vec::new();
+        response.serialize(&mut serialized).unwrap();
+        let mut reader = &serialized[..];
+        let response = CredentialResponse::deserialize(config, &mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert!(CredentialResponse::deserialize(config, &mut &serialized[1..]).is_err());
+        (response, blind)
+    }
+
+    #[test]
+    fn credentials_are_recovered() {
+        let config = config();
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+
+        let (response, blind) = respond(&config, &setup, &record, b"password");
+        let (_, client_pub_key, server_pub_key, recovered_export_key, _) =
+            response.recover_credentials::<Sha512>(&config, b"password", &blind, None).unwrap();
+        assert_eq!(client_pub_key, record.client_pub_key());
+        assert_eq!(server_pub_key, setup.server_public_key());
+        assert_eq!(recovered_export_key, export_key);
+    }
+
+    #[test]
+    fn wrong_password_fails() {
+        let config = config();
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+
+        let (response, blind) = respond(&config, &setup, &record, b"wrong password");
+        let result = response.recover_credentials::<Sha512>(&config, b"wrong password", &blind, None);
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn fake_response_has_the_size_of_a_response() {
+        let config = config();
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+        let (response, _) = respond(&config, &setup, &record, b"password");
+
+        let (request, blind) = CredentialRequest::create_credential_request::<Sha512, _>(&config, b"password", &mut OsRng).unwrap();
+        let fake = CredentialResponse::fake_credential_response::<Sha512, _>(&config, &request, &setup, "mallory", &mut OsRng)
+            .unwrap();
+        assert_eq!(fake.masked_response.len(), response.masked_response.len());
+        let result = fake.recover_credentials::<Sha512>(&config, b"password", &blind, None);
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn retired_material_is_not_found() {
+        let config = config();
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+        setup.rotate_server_key(&config, &mut OsRng).unwrap();
+        setup.retire_server_key(0).unwrap();
+
+        let (request, _) = CredentialRequest::create_credential_request::<Sha512, _>(&config, b"password", &mut OsRng).unwrap();
+        let result = CredentialResponse::create_credential_response::<Sha512, _>(
+            &config, &request, &setup, &record, "alice", &mut OsRng,
+        );
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::NotFound);
     }
 }
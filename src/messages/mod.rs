@@ -1,3 +1,4 @@
 pub mod registration;
 pub mod credential;
-pub mod ake;
\ No newline at end of file
+pub mod ake;
+pub mod resumption;
\ No newline at end of file
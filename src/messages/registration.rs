@@ -1,4 +1,11 @@
-use crate::envelope::Envelope;
+use std::io;
+use crate::kdf::SuiteHash;
+use rand::{CryptoRng, RngCore};
+use crate::config::{invalid_data, read_bytes, read_u16, read_u8, OpaqueConfig, ProtocolVersion};
+use crate::envelope::{randomized_password, Envelope};
+use crate::kdf;
+use crate::oprf;
+use crate::server_setup::ServerSetup;
 
 pub struct RegistrationRequest {
     /// Serialized OPRF group element.
@@ -10,19 +17,49 @@ impl RegistrationRequest {
     ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
     /// * `pwd`: Client's password.
+    /// * `rng`: A cryptographically secure random number generator.
     ///
     /// # Returns
     ///
     /// * `request`: RegistrationRequest structure.
     /// * `blind`: An OPRF scalar.
     ///
-    pub fn create_registration_request(_pwd: String) { // -> (Self, ) {
-        /*
-        1. (blind, M) = Blind(password)
-        2. Create RegistrationRequest request with M
-        3. Output (request, blind)
-        */
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When `D` isn't the hash function of `config.suite`.
+    pub fn create_registration_request<D, R>(
+        config: &OpaqueConfig,
+        pwd: &[u8],
+        rng: &mut R,
+    ) -> io::Result<(Self, Vec<u8>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let (blind, data) = oprf::blind::<D, R>(config.suite, pwd, rng)?;
+        Ok((RegistrationRequest { data }, blind))
+    }
+
+    /// Encodes the request as `blinded_message` (`Noe` bytes), as on RFC 9807.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        Ok(self.data.clone())
+    }
+
+    /// Decodes a request encoded by [`RegistrationRequest::serialize`].
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input doesn't have the size of an element of the OPRF group of `config`.
+    pub fn deserialize(config: &OpaqueConfig, input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+        let request = RegistrationRequest { data: read_bytes(&mut reader, config.suite.oprf_group().public_key_size())? };
+
+        if !reader.is_empty() {
+            return Err(invalid_data());
+        }
+        Ok(request)
     }
 }
 
@@ -31,83 +68,442 @@ pub struct RegistrationResponse {
     data: Vec<u8>,
     /// Server's encoded public key that will be used for the online authenticated key exchange stage.
     server_pub_key: Vec<u8>,
+    /// Id of the server's keypair (see [`ServerSetup::current_key_id`]).
+    server_key_id: u16,
+    /// Id of the OPRF seed the response was evaluated with (see [`ServerSetup::current_seed_id`]).
+    oprf_seed_id: u16,
 }
 
 impl RegistrationResponse {
     /// Creates a new [`RegistrationResponse`];
     ///
+    /// The per-client OPRF key is derived from the current `oprf_seed` on every call, so it never needs to be
+    /// stored.
+    ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server;
     /// * `request`: A RegistrationRequest structure;
-    /// * `server_pub_key`: Server's public key;
+    /// * `server_setup`: The server's [`ServerSetup`] (public key and current `oprf_seed`);
     /// * `identifier`: User's credential identifier;
-    /// * `oprf_seed`: Server-side seed;
     ///
     /// # Returns
     ///
     /// * `response`: RegistrationResponse structure.
-    /// * `oprf_key`: the per-client OPRF key known only to the server.
-    ///
-    pub fn create_registration_response(
-        _request: RegistrationRequest,
-        _server_pub_key: Vec<u8>,
-        _identifier: String,
-        _oprf_seed: Vec<u8>,
-    ) { // -> (Self, )
-        /*
-        1. ikm = Expand(oprf_seed, concat(credential_identifier, "OprfKey"), Nok)
-        2. (oprf_key, _) = DeriveKeyPair(ikm)
-        3. Z = Evaluate(oprf_key, request.data)
-        4. Create RegistrationResponse response with (Z, server_public_key)
-        5. Output (response, oprf_key)
-        */
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When `D` isn't the hash function of `config.suite`.
+    /// * `InvalidData`: When `request.data` isn't a valid element of the OPRF group.
+    pub fn create_registration_response<D>(
+        config: &OpaqueConfig,
+        request: &RegistrationRequest,
+        server_setup: &ServerSetup,
+        identifier: &str,
+    ) -> io::Result<Self>
+    where
+        D: SuiteHash,
+    {
+        let seed_id = server_setup.current_seed_id();
+        let oprf_key = server_setup.oprf_key::<D>(config.suite, seed_id, identifier)?;
+
+        Ok(RegistrationResponse {
+            data: oprf::evaluate(config.suite, &oprf_key, &request.data)?,
+            server_pub_key: server_setup.server_public_key().to_vec(),
+            server_key_id: server_setup.current_key_id(),
+            oprf_seed_id: seed_id,
+        })
+    }
+
+    /// Encodes the response as `evaluated_message (Noe) || server_public_key (Npk)`, as on RFC 9807, followed by
+    /// `server_key_id (2) || oprf_seed_id (2)` once the server key or the OPRF seed was rotated (see
+    /// [`write_ids`]).
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut output = [&self.data[..], &self.server_pub_key].concat();
+        write_ids(&mut output, self.server_key_id, self.oprf_seed_id);
+        Ok(output)
+    }
+
+    /// Decodes a response encoded by [`RegistrationResponse::serialize`].
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is truncated or has trailing bytes.
+    pub fn deserialize(config: &OpaqueConfig, input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+        let data = read_bytes(&mut reader, config.suite.oprf_group().public_key_size())?;
+        let server_pub_key = read_bytes(&mut reader, config.public_key_size())?;
+        let (server_key_id, oprf_seed_id) = read_ids(reader)?;
+        Ok(RegistrationResponse { data, server_pub_key, server_key_id, oprf_seed_id })
     }
 }
 
 pub struct RegistrationUpload {
+    /// The [`ProtocolVersion`] the record was created with. Logins for this record run with this version.
+    version: ProtocolVersion,
     /// Client's encoded public key.
     client_pub_key: Vec<u8>,
     /// A key used by the server to preserve confidentiality of the envelope during login.
     masking_key: Vec<u8>,
     /// Client's [`crate::envelope::Envelope`] structure.
     envelope: Envelope,
+    /// Id of the server's keypair the envelope is bound to. Logins for this record use that keypair.
+    server_key_id: u16,
+    /// Id of the OPRF seed the record was registered with. Logins for this record derive the OPRF key from it.
+    oprf_seed_id: u16,
 }
 
 impl RegistrationUpload {
     /// To create the user record used for further authentication, the client
     /// executes the following function. Depending on the mode, implementations are free to leave out the
-    /// "client_private_key" parameter ("internal" mode), or to additionally
-    /// include "client_public_key" ("external" mode).
+    /// "client_private_key" parameter ("internal" mode). In "external" mode, the client's public key is
+    /// recovered from "client_private_key".
     ///
     /// # Arguments
     ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
     /// * `pwd`: Client's password.
     /// * `blind`: The OPRF scalar value used for blinding.
     /// * `response`: A [`RegistrationResponse`] structure.
-    /// * `client_pri_key`: Client's private key (only in non-internal modes).
-    /// * `client_pub_key`: Client's public key (only in external mode).
-    /// * `server_identity`: The optional encoded server identity.
-    /// * `client_identity`: The optional encoded client identity.
+    /// * `client_pri_key`: Client's private key, only used in external mode, otherwise it will be ```None```.
+    /// * `client_identity`: The optional encoded client identity (defaults to `config.client_identity`).
+    /// * `rng`: A cryptographically secure random number generator.
     ///
     /// # Returns
     ///
     /// * `record`: A [`RegistrationUpload`] structure.
     /// * `export_key`: An additional client key.
-    pub fn finalize_request(
-        _pwd: String,
-        _blind: Vec<u8>, // I guess it's bytes??
-        _response: RegistrationResponse,
-        _client_pri_key: Option<Vec<u8>>,
-        _client_pub_key: Option<Vec<u8>>,
-        _server_identity: Option<String>,
-        _client_identity: Option<String>,
-    ) { // -> (Self, Vec<u8)
-        /*
-        1. y = Finalize(password, blind, response.data)
-        2. randomized_pwd = Extract("", Harden(y, params))
-        3. (envelope, client_public_key, masking_key, export_key) = CreateEnvelope(randomized_pwd, response.server_public_key, client_private_key, server_identity, client_identity)
-        4. Create RegistrationUpload record with (client_public_key, masking_key, envelope)
-        5. Output (record, export_key)
-         */
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When `response.data` isn't a valid element of the OPRF group.
+    /// * `InvalidInput`: When `D` isn't the hash function of `config.suite`, or the external mode isn't given a
+    ///   valid client private key.
+    #[allow(clippy::too_many_arguments)]
+    pub fn finalize_request<D, R>(
+        config: &OpaqueConfig,
+        pwd: &[u8],
+        blind: &[u8],
+        response: &RegistrationResponse,
+        client_pri_key: Option<&[u8]>,
+        client_identity: Option<&[u8]>,
+        rng: &mut R,
+    ) -> io::Result<(Self, Vec<u8>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let oprf_output = oprf::finalize::<D>(config.suite, pwd, blind, &response.data)?;
+        let randomized_pwd = randomized_password::<D>(&oprf_output);
+        RegistrationUpload::create::<D, R>(
+            config,
+            rng,
+            &randomized_pwd,
+            &response.server_pub_key,
+            client_pri_key,
+            client_identity,
+            response.server_key_id,
+            response.oprf_seed_id,
+        )
+    }
+
+    /// [USED BY THE SERVER]
+    ///
+    /// Builds the fake record of an identifier without a record, to be used in its place on the login
+    /// (`CreateCredentialResponse` and `ServerInit`), so unknown identifiers can't be told apart from registered
+    /// ones. It's derived from the server's setup (see [`ServerSetup::fake_record`]), so the same identifier
+    /// always gets the same record, and has the same shape as a registered record. It's bound to one of the
+    /// server keys and OPRF seeds still in use (see [`ServerSetup::fake_record_ids`]), so while a rotation is in
+    /// progress its `KE2` carries a `server_key_update` and an `oprf_seed_update` as often as the one of a record
+    /// that wasn't upgraded yet. Its version is the one `ServerInit` negotiates for a new
+    /// registration (`config.version`), and since `KE2` doesn't depend on the version of the record, it can't be
+    /// told apart from the records of the other versions of `config.accepted_versions` either.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
+    /// * `server_setup`: The server's [`ServerSetup`].
+    /// * `identifier`: The unknown identifier, as given by the client.
+    ///
+    /// # Returns
+    ///
+    /// * `record`: A fake [`RegistrationUpload`] structure.
+    pub fn fake_record<D>(config: &OpaqueConfig, server_setup: &ServerSetup, identifier: &str) -> io::Result<Self>
+    where
+        D: SuiteHash,
+    {
+        let (masking_key, client_pub_key) = server_setup.fake_record::<D>(identifier.as_bytes())?;
+        let (server_key_id, oprf_seed_id) = server_setup.fake_record_ids::<D>(identifier.as_bytes())?;
+        Ok(RegistrationUpload {
+            version: config.negotiate(config.version)?,
+            client_pub_key,
+            masking_key,
+            // Masked on the response like any envelope.
+            envelope: Envelope::zeros::<D>(config),
+            server_key_id,
+            oprf_seed_id,
+        })
+    }
+
+    /// The [`ProtocolVersion`] the record was created with.
+    pub fn version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    /// Client's encoded public key.
+    pub fn client_pub_key(&self) -> &[u8] {
+        &self.client_pub_key
+    }
+
+    /// Id of the server's keypair the record is bound to.
+    pub fn server_key_id(&self) -> u16 {
+        self.server_key_id
+    }
+
+    /// Id of the OPRF seed the record was registered with.
+    pub fn oprf_seed_id(&self) -> u16 {
+        self.oprf_seed_id
+    }
+
+    pub(crate) fn masking_key(&self) -> &[u8] {
+        &self.masking_key
+    }
+
+    pub(crate) fn envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    /// Encodes the upload sent by the client as `client_public_key (Npk) || masking_key (Nh) || envelope (Ne)`,
+    /// as on RFC 9807, followed by the ids of the server key and the OPRF seed once either was rotated (see
+    /// [`RegistrationResponse::serialize`]). The version isn't sent, see [`RegistrationUpload::serialize_record`]
+    /// to store the record.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut output = self.serialize_fields();
+        write_ids(&mut output, self.server_key_id, self.oprf_seed_id);
+        Ok(output)
+    }
+
+    /// Decodes an upload encoded by [`RegistrationUpload::serialize`], `D` being the hash function of
+    /// `config.suite`. The record gets `config.version`, the version the client registered with.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is truncated or has trailing bytes.
+    pub fn deserialize<D: SuiteHash>(config: &OpaqueConfig, input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+        let (client_pub_key, masking_key, envelope) = read_fields::<D>(config, &mut reader)?;
+        let (server_key_id, oprf_seed_id) = read_ids(reader)?;
+        Ok(RegistrationUpload {
+            version: config.version,
+            client_pub_key,
+            masking_key,
+            envelope,
+            server_key_id,
+            oprf_seed_id,
+        })
+    }
+
+    /// Encodes the record to be stored by the server as `version (1) || server_key_id (2) || oprf_seed_id (2) ||
+    /// client_public_key (Npk) || masking_key (Nh) || envelope (Ne)`. The version is kept with the record (and
+    /// not on the messages): logins for this record run with it.
+    pub fn serialize_record(&self) -> io::Result<Vec<u8>> {
+        let mut output = vec![self.version.id()];
+        output.extend_from_slice(&self.server_key_id.to_be_bytes());
+        output.extend_from_slice(&self.oprf_seed_id.to_be_bytes());
+        output.extend_from_slice(&self.serialize_fields());
+        Ok(output)
+    }
+
+    /// Decodes a record encoded by [`RegistrationUpload::serialize_record`], `D` being the hash function of
+    /// `config.suite`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is truncated, has trailing bytes or an unknown version.
+    pub fn deserialize_record<D: SuiteHash>(config: &OpaqueConfig, input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+        let version = ProtocolVersion::from_id(read_u8(&mut reader)?).ok_or_else(invalid_data)?;
+        let server_key_id = read_u16(&mut reader)?;
+        let oprf_seed_id = read_u16(&mut reader)?;
+        let (client_pub_key, masking_key, envelope) = read_fields::<D>(config, &mut reader)?;
+
+        if !reader.is_empty() {
+            return Err(invalid_data());
+        }
+        Ok(RegistrationUpload { version, client_pub_key, masking_key, envelope, server_key_id, oprf_seed_id })
+    }
+
+    /// `client_public_key || masking_key || envelope`, shared by the upload and the stored record.
+    fn serialize_fields(&self) -> Vec<u8> {
+        [&self.client_pub_key[..], &self.masking_key, &self.envelope.serialize()].concat()
+    }
+
+    /// Builds the record of `randomized_pwd`, bound to the server key `server_key_id` and the OPRF seed
+    /// `oprf_seed_id`. Also used by `ClientFinish` to re-bind a record on login.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn create<D, R>(
+        config: &OpaqueConfig,
+        rng: &mut R,
+        randomized_pwd: &[u8],
+        server_pub_key: &[u8],
+        client_pri_key: Option<&[u8]>,
+        client_identity: Option<&[u8]>,
+        server_key_id: u16,
+        oprf_seed_id: u16,
+    ) -> io::Result<(Self, Vec<u8>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let (envelope, client_pub_key, masking_key, export_key) = Envelope::create::<D, R>(
+            config,
+            rng,
+            randomized_pwd,
+            server_pub_key,
+            client_pri_key,
+            client_identity,
+        )?;
+
+        let record = RegistrationUpload {
+            version: config.version,
+            client_pub_key,
+            masking_key,
+            envelope,
+            server_key_id,
+            oprf_seed_id,
+        };
+        Ok((record, export_key))
+    }
+}
+
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+/// Appends `server_key_id (2) || oprf_seed_id (2)` when either id isn't zero, so messages of a server that never
+/// rotated its key nor its seed are the ones of RFC 9807.
+fn write_ids(output: &mut Vec<u8>, server_key_id: u16, oprf_seed_id: u16) {
+    if server_key_id != 0 || oprf_seed_id != 0 {
+        output.extend_from_slice(&server_key_id.to_be_bytes());
+        output.extend_from_slice(&oprf_seed_id.to_be_bytes());
+    }
+}
+
+/// Reads the ids written by [`write_ids`] from the rest of a message: nothing stands for two zero ids.
+fn read_ids(mut reader: &[u8]) -> io::Result<(u16, u16)> {
+    if reader.is_empty() {
+        return Ok((0, 0));
+    }
+
+    let ids = (read_u16(&mut reader)?, read_u16(&mut reader)?);
+    // Two zero ids are never written, so each message has a single encoding.
+    if !reader.is_empty() || ids == (0, 0) {
+        return Err(invalid_data());
+    }
+    Ok(ids)
+}
+
+/// Reads `client_public_key (Npk) || masking_key (Nh) || envelope (Ne)` from the head of `reader`.
+fn read_fields<D: SuiteHash>(config: &OpaqueConfig, reader: &mut &[u8]) -> io::Result<(Vec<u8>, Vec<u8>, Envelope)> {
+    let client_pub_key = read_bytes(reader, config.public_key_size())?;
+    let masking_key = read_bytes(reader, kdf::output_size::<D>())?;
+    let envelope = Envelope::deserialize::<D>(&read_bytes(reader, config.envelope_size())?)?;
+    Ok((client_pub_key, masking_key, envelope))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use sha2::Sha512;
+    use crate::test_support::{config, register};
+
+    #[test]
+    fn registration_binds_the_current_key_and_seed() {
+        let config = config();
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        setup.rotate_server_key(&config, &mut OsRng).unwrap();
+        setup.rotate_oprf_seed(&mut OsRng).unwrap();
+
+        let (record, export_key) = register::<Sha512>(&config, &setup, b"password", None);
+        assert_eq!(record.version(), config.version);
+        assert_eq!(record.server_key_id(), 1);
+        assert_eq!(record.oprf_seed_id(), 1);
+        assert_eq!(record.envelope().serialize().len(), config.envelope_size());
+        assert_eq!(export_key.len(), 64);
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let config = config();
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+
+        let serialized = record.serialize_record().unwrap();
+        let restored = RegistrationUpload::deserialize_record::<Sha512>(&config, &serialized).unwrap();
+        assert_eq!(restored.serialize_record().unwrap(), serialized);
+        assert_eq!(restored.client_pub_key(), record.client_pub_key());
+        assert_eq!(restored.version(), config.version);
+
+        let kind = |input: &[u8]| RegistrationUpload::deserialize_record::<Sha512>(&config, input).err().unwrap().kind();
+        assert_eq!(kind(&serialized[..serialized.len() - 1]), io::ErrorKind::InvalidData);
+        assert_eq!(kind(&[&serialized[..], &[0]].concat()), io::ErrorKind::InvalidData);
+        assert_eq!(kind(&[&[0xff], &serialized[1..]].concat()), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn messages_have_the_rfc_layout_until_a_rotation() {
+        let config = config();
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (request, blind) = RegistrationRequest::create_registration_request::<Sha512, _>(&config, b"pwd", &mut OsRng).unwrap();
+        assert_eq!(request.serialize().unwrap().len(), 32);
+
+        // Noe + Npk, then Npk + Nh + Ne (Nn + Nm).
+        let response = RegistrationResponse::create_registration_response::<Sha512>(&config, &request, &setup, "alice").unwrap();
+        assert_eq!(response.serialize().unwrap().len(), 32 + 32);
+        let (record, _) = RegistrationUpload::finalize_request::<Sha512, _>(&config, b"pwd", &blind, &response, None, None, &mut OsRng)
+            .unwrap();
+        assert_eq!(record.serialize().unwrap().len(), 32 + 64 + 32 + 64);
+
+        // The ids follow once the seed was rotated, and zero ids are only encoded as nothing.
+        setup.rotate_oprf_seed(&mut OsRng).unwrap();
+        let response = RegistrationResponse::create_registration_response::<Sha512>(&config, &request, &setup, "alice").unwrap();
+        let serialized = response.serialize().unwrap();
+        assert_eq!(serialized.len(), 32 + 32 + 4);
+        let restored = RegistrationResponse::deserialize(&config, &serialized).unwrap();
+        assert_eq!((restored.server_key_id, restored.oprf_seed_id), (0, 1));
+
+        let kind = |input: &[u8]| RegistrationResponse::deserialize(&config, input).err().unwrap().kind();
+        assert_eq!(kind(&[&serialized[..64], &[0; 4]].concat()), io::ErrorKind::InvalidData);
+        assert_eq!(kind(&serialized[..66]), io::ErrorKind::InvalidData);
+        assert_eq!(kind(&serialized[..63]), io::ErrorKind::InvalidData);
+        assert_eq!(RegistrationRequest::deserialize(&config, &serialized[..33]).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn same_password_gives_the_same_oprf_output() {
+        let config = config();
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (first, first_export_key) = register::<Sha512>(&config, &setup, b"password", None);
+        let (second, second_export_key) = register::<Sha512>(&config, &setup, b"password", None);
+        // The OPRF output is the same, but every envelope has its own nonce.
+        assert_eq!(first.masking_key(), second.masking_key());
+        assert_ne!(first_export_key, second_export_key);
+
+        let (other, _) = register::<Sha512>(&config, &setup, b"other password", None);
+        assert_ne!(first.masking_key(), other.masking_key());
+    }
+
+    #[test]
+    fn fake_record_has_the_shape_of_a_record() {
+        let config = config();
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = register::<Sha512>(&config, &setup, b"password", None);
+        let fake = RegistrationUpload::fake_record::<Sha512>(&config, &setup, "mallory").unwrap();
+        assert_eq!(fake.serialize_record().unwrap().len(), record.serialize_record().unwrap().len());
+        assert_eq!(
+            fake.serialize_record().unwrap(),
+            RegistrationUpload::fake_record::<Sha512>(&config, &setup, "mallory").unwrap().serialize_record().unwrap()
+        );
     }
 }
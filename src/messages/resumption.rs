@@ -0,0 +1,92 @@
+use std::io;
+use crate::config::{invalid_data, read_field, write_field};
+
+
+/// Structure sent by the client to resume a session with a ticket, instead of [`crate::messages::ake::KE1`].
+#[derive(Clone)]
+pub struct ResumptionRequest {
+    /// The client identity the ticket was issued to.
+    pub client_identity: Vec<u8>,
+    /// The ticket issued by the server after the previous login, as received.
+    pub ticket: Vec<u8>,
+    /// A fresh randomly generated nonce.
+    pub client_nonce: Vec<u8>,
+    /// An authentication tag over the request, proving the knowledge of the resumption secret.
+    pub binder: Vec<u8>,
+}
+
+impl ResumptionRequest {
+    /// Encodes the request, every field with a two bytes length prefix.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When a field is longer than 65535 bytes.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        write_field(&mut output, &self.client_identity)?;
+        write_field(&mut output, &self.ticket)?;
+        write_field(&mut output, &self.client_nonce)?;
+        write_field(&mut output, &self.binder)?;
+        Ok(output)
+    }
+
+    /// Decodes a request encoded by [`ResumptionRequest::serialize`].
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is malformed.
+    pub fn deserialize(input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+        let request = ResumptionRequest {
+            client_identity: read_field(&mut reader)?,
+            ticket: read_field(&mut reader)?,
+            client_nonce: read_field(&mut reader)?,
+            binder: read_field(&mut reader)?,
+        };
+
+        if !reader.is_empty() {
+            return Err(invalid_data());
+        }
+        Ok(request)
+    }
+}
+
+/// Structure sent by the server to accept a [`ResumptionRequest`].
+pub struct ResumptionResponse {
+    /// A fresh randomly generated nonce.
+    pub server_nonce: Vec<u8>,
+    /// An authentication tag over the resumption transcript.
+    pub server_mac: Vec<u8>,
+}
+
+impl ResumptionResponse {
+    /// Encodes the response, every field with a two bytes length prefix.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When a field is longer than 65535 bytes.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        let mut output = Vec::new();
+        write_field(&mut output, &self.server_nonce)?;
+        write_field(&mut output, &self.server_mac)?;
+        Ok(output)
+    }
+
+    /// Decodes a response encoded by [`ResumptionResponse::serialize`].
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is malformed.
+    pub fn deserialize(input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+        let response = ResumptionResponse {
+            server_nonce: read_field(&mut reader)?,
+            server_mac: read_field(&mut reader)?,
+        };
+
+        if !reader.is_empty() {
+            return Err(invalid_data());
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,189 @@
+//! Noise protocol integration - a Noise PSK and prologue from a completed OPAQUE handshake.
+//!
+//! Existing [Noise](https://noiseprotocol.org/noise.html) channels can add password-based mutual
+//! authentication by running OPAQUE first, and then using its outputs on a PSK handshake pattern:
+//!
+//! ```txt
+//!     psk = Expand-Label(session_key, "NoisePSK", transcript_hash, 32)
+//!     prologue = concat("OPAQUE-Noise-", transcript_hash)
+//! ```
+//!
+//! The PSK is only known to peers that completed the OPAQUE handshake (so only to a client knowing the
+//! password and to the server holding its record), and the prologue binds the Noise handshake to that OPAQUE
+//! handshake. The Noise handshake fails if either side uses outputs from another session.
+//!
+//! Both values fit any `psk` pattern, i.e. `Noise_NNpsk0_25519_ChaChaPoly_SHA256` (PSK on slot 0) when
+//! the peers have no static keys, or `Noise_XXpsk3_25519_ChaChaPoly_SHA256` (PSK on slot 3) to also
+//! authenticate existing static keys. See `examples/noise.rs` for a round trip between two peers.
+//!
+//! The PSK is derived with its own label, so it's independent from the keys of
+//! [`SessionKey::export`] and [`crate::record`].
+use std::io;
+use crate::kdf::SuiteHash;
+use crate::kdf;
+use crate::session::SessionKey;
+
+/// Size of a Noise PSK.
+pub static PSK_SIZE: usize = 32;
+
+static STR_PROLOGUE: &[u8] = b"OPAQUE-Noise-";
+
+/// Derives the Noise PSK of an OPAQUE session.
+///
+/// # Arguments
+///
+/// * `session_key`: The [`SessionKey`] output by `ClientFinish` or `ServerFinish`.
+///
+/// # Returns
+///
+/// * `psk`: A PSK of [`PSK_SIZE`] bytes.
+pub fn psk<D>(session_key: &SessionKey) -> io::Result<Vec<u8>>
+where
+    D: SuiteHash,
+{
+    kdf::expand_label::<D>(session_key.as_bytes(), b"NoisePSK", session_key.transcript_hash(), PSK_SIZE)
+}
+
+/// Builds the Noise prologue of an OPAQUE session, binding the Noise handshake to its transcript.
+///
+/// # Arguments
+///
+/// * `session_key`: The [`SessionKey`] output by `ClientFinish` or `ServerFinish`.
+pub fn prologue(session_key: &SessionKey) -> Vec<u8> {
+    [STR_PROLOGUE, session_key.transcript_hash()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha512;
+    use snow::{Builder, HandshakeState};
+    use rand::rngs::OsRng;
+    use crate::ake::AkeProtocol;
+    use crate::kem::Kem;
+    use crate::server_setup::ServerSetup;
+    use crate::test_support;
+
+    static NN_PATTERN: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+    static XX_PATTERN: &str = "Noise_XXpsk3_25519_ChaChaPoly_SHA256";
+
+    /// Builds a Noise peer keyed by `session_key`, with the PSK on slot 0 of NNpsk0 or on slot 3 of XXpsk3
+    /// when the peer has a `static_key`.
+    fn peer(session_key: &SessionKey, initiator: bool, static_key: Option<&[u8]>) -> HandshakeState {
+        let psk = psk::<Sha512>(session_key).unwrap();
+        let prologue = prologue(session_key);
+        let builder = match static_key {
+            Some(static_key) => Builder::new(XX_PATTERN.parse().unwrap()).local_private_key(static_key).psk(3, &psk),
+            None => Builder::new(NN_PATTERN.parse().unwrap()).psk(0, &psk),
+        };
+        let builder = builder.prologue(&prologue);
+        if initiator {
+            builder.build_initiator().unwrap()
+        } else {
+            builder.build_responder().unwrap()
+        }
+    }
+
+    /// Runs the handshake messages from `initiator` and `responder` alternately, then a message each way
+    /// in transport mode.
+    fn round_trip(mut initiator: HandshakeState, mut responder: HandshakeState) {
+        let mut message = [0u8; 1024];
+        let mut payload = [0u8; 1024];
+
+        let mut turn = 0;
+        while !initiator.is_handshake_finished() || !responder.is_handshake_finished() {
+            let (writer, reader) = if turn % 2 == 0 { (&mut initiator, &mut responder) } else { (&mut responder, &mut initiator) };
+            let len = writer.write_message(&[], &mut message).unwrap();
+            reader.read_message(&message[..len], &mut payload).unwrap();
+            turn += 1;
+        }
+
+        let mut initiator = initiator.into_transport_mode().unwrap();
+        let mut responder = responder.into_transport_mode().unwrap();
+        let len = initiator.write_message(b"hello over noise", &mut message).unwrap();
+        let len = responder.read_message(&message[..len], &mut payload).unwrap();
+        assert_eq!(&payload[..len], b"hello over noise");
+        let len = responder.write_message(b"hello back", &mut message).unwrap();
+        let len = initiator.read_message(&message[..len], &mut payload).unwrap();
+        assert_eq!(&payload[..len], b"hello back");
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_psk() {
+        let protocols = [AkeProtocol::TripleDh, AkeProtocol::Kem(Kem::MlKem768)];
+        for protocol in protocols.iter() {
+            let config = test_support::config().with_ake_protocol(*protocol);
+            let (client, server) = test_support::session_keys::<Sha512>(&config);
+            assert_eq!(client.transcript_hash(), server.transcript_hash());
+            assert_eq!(psk::<Sha512>(&client).unwrap(), psk::<Sha512>(&server).unwrap());
+            assert_eq!(psk::<Sha512>(&client).unwrap().len(), PSK_SIZE);
+            assert_eq!(prologue(&client), prologue(&server));
+        }
+    }
+
+    #[test]
+    fn nnpsk0_handshake_round_trip() {
+        let (client, server) = test_support::session_keys::<Sha512>(&test_support::config());
+        round_trip(peer(&client, true, None), peer(&server, false, None));
+    }
+
+    #[test]
+    fn xxpsk3_handshake_authenticates_static_keys() {
+        let (client, server) = test_support::session_keys::<Sha512>(&test_support::config());
+        let builder = || Builder::new(XX_PATTERN.parse().unwrap());
+        let client_static = builder().generate_keypair().unwrap();
+        let server_static = builder().generate_keypair().unwrap();
+
+        let mut initiator = peer(&client, true, Some(&client_static.private));
+        let mut responder = peer(&server, false, Some(&server_static.private));
+        let mut message = [0u8; 1024];
+        let mut payload = [0u8; 1024];
+        // -> e
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message[..len], &mut payload).unwrap();
+        // <- e, ee, s, es
+        let len = responder.write_message(&[], &mut message).unwrap();
+        initiator.read_message(&message[..len], &mut payload).unwrap();
+        // -> s, se, psk
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        responder.read_message(&message[..len], &mut payload).unwrap();
+        assert_eq!(initiator.get_remote_static(), Some(&server_static.public[..]));
+        assert_eq!(responder.get_remote_static(), Some(&client_static.public[..]));
+
+        round_trip(
+            peer(&client, true, Some(&client_static.private)),
+            peer(&server, false, Some(&server_static.private)),
+        );
+
+        // Static keys don't replace the OPAQUE session: a peer of another session fails from the first message,
+        // whose payload is encrypted under its prologue.
+        let (other, _) = test_support::session_keys::<Sha512>(&test_support::config());
+        let mut initiator = peer(&other, true, Some(&client_static.private));
+        let mut responder = peer(&server, false, Some(&server_static.private));
+        let len = initiator.write_message(&[], &mut message).unwrap();
+        assert!(responder.read_message(&message[..len], &mut payload).is_err());
+    }
+
+    #[test]
+    fn differing_sessions_fail_the_handshake() {
+        let config = test_support::config();
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let (record, _) = test_support::register::<Sha512>(&config, &setup, b"password", None);
+        let server = test_support::login::<Sha512>(&config, &setup, &record, b"password").unwrap().server_session_key;
+        let other = test_support::login::<Sha512>(&config, &setup, &record, b"password").unwrap().client_session_key;
+        assert_ne!(other.transcript_hash(), server.transcript_hash());
+        // Another login of the same record, then the same key with another transcript.
+        let others = [other, SessionKey::new(server.as_bytes().to_vec(), vec![0x25; 64])];
+        let mut message = [0u8; 1024];
+        let mut payload = [0u8; 1024];
+
+        for other in &others {
+            assert_ne!(psk::<Sha512>(other).unwrap(), psk::<Sha512>(&server).unwrap());
+
+            let mut initiator = peer(other, true, None);
+            let mut responder = peer(&server, false, None);
+            let len = initiator.write_message(&[], &mut message).unwrap();
+            assert!(responder.read_message(&message[..len], &mut payload).is_err());
+        }
+    }
+}
@@ -5,7 +5,9 @@
 //! for more information, please read the draft.
 //!
 //! Both registration and login stages may vary according to the mode as described on the
-//! [`crate::envelope::EnvelopeMode`] enum.
+//! [`crate::envelope::EnvelopeMode`] enum. The mode, the cipher suite, the application context and the
+//! default identities are all held by a [`crate::config::OpaqueConfig`], which is passed to every function of
+//! both stages and MUST be the same on client and server.
 //!
 //! ### Offline stage
 //! This step can occur before client and server connect to each other (hence the stage's name).
@@ -13,9 +15,11 @@
 //! The client should get one identifier (i.e., email, username, etc) and one password.
 //!
 //! The server should have one keypair (`server_pri_key`/`server_pub_key`) for use with the AKE protocol,
-//! and a `oprf_seed` (byte array). The server can use multiple keypairs and multiple seeds for multiple
-//! clients, so long as they are consistent for each client. The server should keep each keypair and seed
-//! related to a client stored somewhere, it can't be changed later, so don't lose it!
+//! and a `oprf_seed` (byte array), generated once as a [`crate::server_setup::ServerSetup`]
+//! (`ServerSetup::new`) and passed to every server-side function. The server can use multiple setups for
+//! multiple clients, so long as they are consistent for each client. The server should keep each setup
+//! related to a client stored somewhere (see `ServerSetup::serialize`), it can't be changed later, so don't
+//! lose it!
 //!
 //! Note: If using "external" mode, the client should provide a keypair (`client_pri_key`/`client_pub_key`) for use
 //! with the AKE protocol as well. The keypair may be randomly generated for the account or provided by the
@@ -31,9 +35,8 @@
 //!
 //!    (response, oprf_key) = CreateRegistrationResponse(
 //!                                request,
-//!                                server_public_key,
-//!                                credential_identifier,
-//!                                oprf_seed
+//!                                server_setup,
+//!                                credential_identifier
 //!                            )
 //!                  <---------response--------
 //!
@@ -49,9 +52,9 @@
 //!```
 //!
 //! After `FinalizeRequest`, the server stores the `record` object along with the associated
-//! `client_identity` and `credential_identifier`.
+//! `client_identity` and `credential_identifier`, i.e. on a [`crate::store::CredentialStore`].
 //!
-//! Note: Once again, the server keypair and the oprf_seed should both be persisted!
+//! Note: Once again, the server setup (keypair and oprf_seed) should be persisted!
 //!
 //!
 //!
@@ -60,7 +63,8 @@
 //! This stage is composed of a concurrent OPRF and key exchange flow. In the end, the client proves its
 //! knowledge of the password, and both client and server agree on:
 //! - Mutually authenticated shared secret key;
-//! - Any optional application information exchange during the handshake.
+//! - Any optional application information exchange during the handshake (see the "Application information"
+//!   section of the [`crate::ake`] module).
 //!
 //! The key exchange (AKE) flow can be seen on the [`crate::ake`] module description.
 //!
\ No newline at end of file
@@ -1,114 +1,203 @@
+//! The OPRF of the [`CipherSuite`] in use (RFC 9497 in base mode).
+//!
+//! Elements and scalars are handled in their encoded form, as described on [`crate::group`]. Every domain
+//! separation tag is built from the context string of the suite, `"OPRFV1-" || I2OSP(mode, 1) || "-" ||
+//! identifier`, so each suite gets its own tags.
 use std::mem::size_of;
 use std::io;
-use std::ops::Deref;
 use rand::{RngCore, CryptoRng};
-use rand::rngs::OsRng;
-use sha2::Sha512;
-use digest::{Digest, BlockInput};
-use digest::generic_array::typenum::Unsigned;
-use curve25519_dalek::scalar::Scalar;
-use curve25519_dalek::ristretto::RistrettoPoint;
-use curve25519_dalek::digest::Output;
-
-static STR_VOPRF: &[u8] = b"VOPRF06-HashToGroup-";
-static STR_VOPRF_FINALIZE: &[u8] = b"VOPRF06-Finalize-";
+use digest::Digest;
+use digest::core_api::BlockSizeUser;
+use crate::kdf::SuiteHash;
+use crate::config::CipherSuite;
+use crate::group::AkeGroup;
+
+static STR_OPRF_V1: &[u8] = b"OPRFV1-";
+static STR_HASH_TO_GROUP: &[u8] = b"HashToGroup-";
+static STR_DERIVE_KEY_PAIR: &[u8] = b"DeriveKeyPair";
+static STR_FINALIZE: &[u8] = b"Finalize";
 static MODE_BASE: u8 = 0x00;
-static SUITE_ID: usize = 0x0001;
 
-/// Convert "input" into an element of the OPRF group, randomize it by an scalar and return both.
+/// Convert "input" into an element of the OPRF group, randomize it by a random scalar and return both.
+///
+/// Generic over the suite's hash function `D` (i.e. `Sha512` or `Sha256`), which is used by
+/// `expand_message_xmd` during the HashToGroup step.
 ///
 /// # Arguments
 ///
+/// * `suite`: The cipher suite, which selects the group and the context string.
 /// * `input`: A user input to be blinded (in our case, the user password).
+/// * `rng`: A cryptographically secure random number generator.
 ///
 /// # Returns
 ///
-/// * `blind`: Scalar used to randomize the OPRF element.
-/// * `blinded_element`: OPRF element after blind.
-pub(crate) fn blind(input: &[u8]) -> io::Result<(Vec<u8>, Scalar, RistrettoPoint)> {
+/// * `blind`: Encoded scalar used to randomize the OPRF element.
+/// * `blinded_element`: Encoded OPRF element after blind.
+///
+/// # Exceptions
+///
+/// * `InvalidInput`: When `D` isn't the suite's hash function, or `input` maps to the identity.
+pub(crate) fn blind<D, R>(
+    suite: CipherSuite,
+    input: &[u8],
+    rng: &mut R,
+) -> io::Result<(Vec<u8>, Vec<u8>)>
+where
+    D: SuiteHash,
+    R: RngCore + CryptoRng,
+{
     // Random Scalar (blind = GG.RandomScalar()).
-    let mut rng = OsRng::default();
-    let blind = nonzero_random_scalar(&mut rng);
-
-    let dst = [STR_VOPRF, &get_context_string(MODE_BASE)].concat();
-
-    // Map To Curve (P = GG.HashToGroup(input)).
-    let uniform_bytes = expand_message_xmd(input, &dst, <Sha512 as Digest>::OutputSize::to_usize())?;
-    let bits: [u8; 64] = {
-        let mut bytes = [0u8; 64];
-        bytes.copy_from_slice(&uniform_bytes);
-        bytes
-    };
-    let p = RistrettoPoint::from_uniform_bytes(&bits);
-
-    // Serialize Element (blindedElement = GG.SerializeElement(blind * P)).
-    let blinded_element = p * &blind;
-
-    Ok((input.to_vec(), blind, blinded_element))
+    let (blind, _) = suite.oprf_group().generate_keypair(rng);
+    let blinded_element = blind_with::<D>(suite, input, &blind)?;
+    Ok((blind, blinded_element))
 }
 
 /// Computes the (V)OPRF evaluation over the client's blinded token, according to the specs on the rfc
-/// (https://datatracker.ietf.org/doc/html/draft-irtf-cfrg-voprf-06#section-3.4.1.1).
+/// (https://www.rfc-editor.org/rfc/rfc9497#section-3.3.1).
 ///
 /// # Arguments
 ///
-/// * `point`: the serialized element (a ristretto point in our case).
-/// * `oprf_key`: a private key (a Scalar in our case).
+/// * `suite`: The cipher suite, which selects the group.
+/// * `oprf_key`: The encoded private key.
+/// * `blinded_element`: The encoded element received from the client.
 ///
 /// # Returns
 ///
-/// * `evaluated_element`: the evaluated value (another ristretto point in our case).
-pub(crate) fn evaluate(point: RistrettoPoint, oprf_key: &Scalar) -> RistrettoPoint {
-    point * oprf_key
+/// * `evaluated_element`: The encoded evaluated element.
+///
+/// # Exceptions
+///
+/// * `InvalidData`: When the blinded element isn't a valid non-identity element of the group.
+pub(crate) fn evaluate(suite: CipherSuite, oprf_key: &[u8], blinded_element: &[u8]) -> io::Result<Vec<u8>> {
+    suite.oprf_group().diffie_hellman(oprf_key, blinded_element)
 }
 
-/// The client unblinds the server response, verifies the server's proof if verifiability is required,
-/// and produces a byte array corresponding to the output of the OPRF protocol.
+/// The client unblinds the server response and produces a byte array corresponding to the output of the OPRF
+/// protocol.
+///
+/// The output length matches the output size of the suite's hash function `D`.
 ///
 /// # Arguments
 ///
+/// * `suite`: The cipher suite, which selects the group.
 /// * `input`: A user input to be blinded (in our case, the user password).
-/// * `blind`: A random scalar used during the blind method as the blind factor.
-/// * `element`: The element that was evaluated.
+/// * `blind`: The encoded scalar used during the blind method as the blind factor.
+/// * `evaluated_element`: The encoded element that was evaluated by the server.
 ///
 /// # Returns
 ///
 /// * `output`: A byte array used as the OPRF output.
-pub(crate) fn finalize(input: &[u8], blind: &Scalar, element: RistrettoPoint) -> Vec<u8> {
-    let unblinded = element * blind.invert();
-    let dst = [STR_VOPRF_FINALIZE, &get_context_string(MODE_BASE)].concat();
-
-    let compressed = unblinded.compress();
-    let unblinded_arr = compressed.as_bytes();
+///
+/// # Exceptions
+///
+/// * `InvalidInput`: When `D` isn't the suite's hash function.
+/// * `InvalidData`: When the evaluated element isn't a valid non-identity element of the group.
+pub(crate) fn finalize<D: SuiteHash>(
+    suite: CipherSuite,
+    input: &[u8],
+    blind: &[u8],
+    evaluated_element: &[u8],
+) -> io::Result<Vec<u8>> {
+    suite.check_hash::<D>()?;
+    let group = suite.oprf_group();
+    let unblinded = group.diffie_hellman(&group.invert_scalar(blind)?, evaluated_element)?;
 
     let hash_input = [
         serialize(input, 2),
-        serialize(unblinded_arr, 2),
-        serialize(&dst, 2),
+        serialize(&unblinded, 2),
+        STR_FINALIZE.to_vec(),
     ].concat();
-    <Sha512 as Digest>::digest(&hash_input).to_vec()
+    Ok(D::digest(&hash_input).to_vec())
+}
+
+/// Deterministically derives an OPRF keypair from `seed` and `info` (`DeriveKeyPair` on RFC 9497), see
+/// [`derive_private_key`].
+///
+/// # Arguments
+///
+/// * `suite`: The cipher suite, which selects the group.
+/// * `seed`: A pseudo-random seed of at least 32 bytes.
+/// * `info`: Public information bound to the keypair.
+///
+/// # Returns
+///
+/// * `private_key`: The encoded private key.
+/// * `public_key`: The encoded public key.
+///
+/// # Exceptions
+///
+/// * `InvalidInput`: When `D` isn't the suite's hash function, or `info` is longer than 65535 bytes.
+/// * `InvalidData`: When no valid private key could be derived (which is negligibly likely).
+pub(crate) fn derive_key_pair<D: SuiteHash>(
+    suite: CipherSuite,
+    seed: &[u8],
+    info: &[u8],
+) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let group = suite.oprf_group();
+    let private_key = derive_private_key::<D>(group, suite, seed, info)?;
+    let public_key = group.recover_public_key(&private_key)?;
+    Ok((private_key, public_key))
+}
+
+/// The private key of `DeriveKeyPair(seed, info)` on RFC 9497: the first non-zero
+/// `HashToScalar(deriveInput || I2OSP(counter, 1), DST = "DeriveKeyPair" || contextString)`, with
+/// `deriveInput = seed || I2OSP(len(info), 2) || info`.
+///
+/// The scalar is taken on `group`, which is the suite's OPRF group for OPRF keys, and the AKE group for the
+/// AKE keys of RFC 9807 (`DeriveDiffieHellmanKeyPair`, see [`AkeGroup::derive_keypair`]).
+///
+/// # Exceptions
+///
+/// * `InvalidInput`: When `D` isn't the suite's hash function, or `info` is longer than 65535 bytes.
+/// * `Unsupported`: When `group` isn't a prime order group (X25519).
+/// * `InvalidData`: When no valid private key could be derived (which is negligibly likely).
+pub(crate) fn derive_private_key<D: SuiteHash>(
+    group: AkeGroup,
+    suite: CipherSuite,
+    seed: &[u8],
+    info: &[u8],
+) -> io::Result<Vec<u8>> {
+    suite.check_hash::<D>()?;
+    if info.len() > u16::MAX as usize {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    let derive_input = [seed, &serialize(info, 2)].concat();
+    let dst = [STR_DERIVE_KEY_PAIR, &get_context_string(suite, MODE_BASE)].concat();
+    let mut last_error = io::Error::from(io::ErrorKind::InvalidData);
+    for counter in 0..=255u8 {
+        match group.hash_to_scalar::<D>(&[&derive_input, &[counter][..]].concat(), &dst) {
+            Ok(private_key) => return Ok(private_key),
+            Err(error) if error.kind() == io::ErrorKind::Unsupported => return Err(error),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
 }
 
 
 // ||===============================================================================================
 // || Helper Methods ||
 // ||===============================================================================================
-fn nonzero_random_scalar<R: RngCore + CryptoRng>(rng: &mut R) -> Scalar {
-    loop {
-        let mut scalar_bytes = [0u8; 64];
-        rng.fill_bytes(&mut scalar_bytes);
-        let scalar = Scalar::from_bytes_mod_order_wide(&scalar_bytes);
-
-        if scalar != Scalar::zero() {
-            break scalar;
-        }
-    }
+/// The blinding step of [`blind`], with a given `blind` scalar.
+fn blind_with<D: SuiteHash>(suite: CipherSuite, input: &[u8], blind: &[u8]) -> io::Result<Vec<u8>> {
+    suite.check_hash::<D>()?;
+    let dst = [STR_HASH_TO_GROUP, &get_context_string(suite, MODE_BASE)].concat();
+
+    // Map To Curve (P = GG.HashToGroup(input)), the identity is an invalid input.
+    let group = suite.oprf_group();
+    let element = group
+        .hash_to_group::<D>(input, &dst)
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+    // Serialize Element (blindedElement = GG.SerializeElement(blind * P)).
+    group.diffie_hellman(blind, &element)
 }
 
 /// Integer to Octet String primitive
 fn i2osp(input: usize, length: usize) -> Vec<u8> {
     if length <= size_of::<usize>() {
-        return (&input.to_be_bytes()[size_of::<usize>() - length..]).to_vec();
+        return input.to_be_bytes()[size_of::<usize>() - length..].to_vec();
     }
 
     let mut output = vec![0u8; length];
@@ -121,19 +210,21 @@ fn i2osp(input: usize, length: usize) -> Vec<u8> {
 
 fn xor(x: &[u8], y: &[u8]) -> io::Result<Vec<u8>> {
     if x.len() != y.len() {
-        return Err(io::Error::from_raw_os_error(2));
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
     }
 
     Ok(x.iter().zip(y).map(|(&x1, &x2)| x1 ^ x2).collect())
 }
 
-fn expand_message_xmd(msg: &[u8], dst: &[u8], len_bytes: usize) -> io::Result<Vec<u8>> {
-    let b_bytes = <Sha512 as Digest>::OutputSize::to_usize();
-    let r_bytes = <Sha512 as BlockInput>::BlockSize::to_usize();
+/// expand_message_xmd as described on RFC 9380 (section 5.3.1), generic over any Merkle-Damgard hash function
+/// exposing its block size (the SHA-2 family).
+pub(crate) fn expand_message_xmd<D: SuiteHash>(msg: &[u8], dst: &[u8], len_bytes: usize) -> io::Result<Vec<u8>> {
+    let b_bytes = <D as Digest>::output_size();
+    let r_bytes = <D as BlockSizeUser>::block_size();
 
-    let ell = (len_bytes / b_bytes + ((len_bytes % b_bytes != 0) as usize));
-    if ell > 255 {
-        return Err(io::Error::from_raw_os_error(1));
+    let ell = len_bytes.div_ceil(b_bytes);
+    if ell > 255 || len_bytes > u16::MAX as usize || dst.len() > 255 {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
     }
 
     let dst_prime = [dst, &i2osp(dst.len(), 1)].concat();
@@ -141,32 +232,165 @@ fn expand_message_xmd(msg: &[u8], dst: &[u8], len_bytes: usize) -> io::Result<Ve
     let l_i_b_str = i2osp(len_bytes, 2);
     let msg_prime = [&z_pad, msg, &l_i_b_str, &i2osp(0, 1), &dst_prime].concat();
 
-    let mut b: Vec<Vec<u8>> = vec![Sha512::digest(&msg_prime).to_vec()];
+    let mut b: Vec<Vec<u8>> = vec![D::digest(&msg_prime).to_vec()];
 
-    let mut h = Sha512::new();
-    h.update(&b[0]);
-    h.update(&i2osp(1, 1));
-    h.update(&dst_prime);
-    b.push(h.finalize_reset().to_vec()); // b[1]
+    let b_1 = D::new().chain_update(&b[0]).chain_update(i2osp(1, 1)).chain_update(&dst_prime).finalize();
+    b.push(b_1.to_vec()); // b[1]
 
     let mut uniform_bytes: Vec<u8> = Vec::new();
     uniform_bytes.extend_from_slice(&b[1]);
 
     for i in 2..(ell + 1) {
-        h.update(xor(&b[0], &b[i - 1])?);
-        h.update(&i2osp(i, 1));
-        h.update(&dst_prime);
-        b.push(h.finalize_reset().to_vec()); // b[i]
+        let b_i = D::new().chain_update(xor(&b[0], &b[i - 1])?).chain_update(i2osp(i, 1)).chain_update(&dst_prime);
+        b.push(b_i.finalize().to_vec()); // b[i]
         uniform_bytes.extend_from_slice(&b[i]);
     }
 
     Ok(uniform_bytes[..len_bytes].to_vec())
 }
 
-fn get_context_string(mode: u8) -> Vec<u8> {
-    [i2osp(mode as usize, 1), i2osp(SUITE_ID, 2)].concat()
+/// Context string of the OPRF, `"OPRFV1-" || I2OSP(mode, 1) || "-" || identifier` on RFC 9497.
+fn get_context_string(suite: CipherSuite, mode: u8) -> Vec<u8> {
+    [STR_OPRF_V1, &i2osp(mode as usize, 1), b"-", suite.identifier().as_bytes()].concat()
 }
 
 fn serialize(input: &[u8], max_bytes: usize) -> Vec<u8> {
     [&i2osp(input.len(), max_bytes), input].concat()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+    use rand::rngs::OsRng;
+    use sha2::{Sha256, Sha512};
+
+    /// A test vector of RFC 9497 (appendix A), OPRF mode, with `Seed = a3...a3` and `KeyInfo = "test key"`.
+    struct Vector<'a> {
+        input: &'a [u8],
+        blind: &'a [u8],
+        blinded_element: &'a [u8],
+        evaluation_element: &'a [u8],
+        output: &'a [u8],
+    }
+
+    fn check_vectors<D: SuiteHash>(suite: CipherSuite, private_key: &[u8], vectors: &[Vector]) {
+        let (derived_private_key, _) = derive_key_pair::<D>(suite, &[0xa3; 32], b"test key").unwrap();
+        assert_eq!(derived_private_key, private_key);
+
+        for vector in vectors {
+            let blinded_element = blind_with::<D>(suite, vector.input, vector.blind).unwrap();
+            assert_eq!(blinded_element, vector.blinded_element);
+            let evaluation_element = evaluate(suite, private_key, &blinded_element).unwrap();
+            assert_eq!(evaluation_element, vector.evaluation_element);
+            let output = finalize::<D>(suite, vector.input, vector.blind, &evaluation_element).unwrap();
+            assert_eq!(output, vector.output);
+        }
+    }
+
+    #[test]
+    fn ristretto255_sha512_matches_rfc9497() {
+        // RFC 9497, appendix A.1.1.
+        let blind = hex!("64d37aed22a27f5191de1c1d69fadb899d8862b58eb4220029e036ec4c1f6706");
+        check_vectors::<Sha512>(
+            CipherSuite::Ristretto255Sha512,
+            &hex!("5ebcea5ee37023ccb9fc2d2019f9d7737be85591ae8652ffa9ef0f4d37063b0e"),
+            &[
+                Vector {
+                    input: &hex!("00"),
+                    blind: &blind,
+                    blinded_element: &hex!("609a0ae68c15a3cf6903766461307e5c8bb2f95e7e6550e1ffa2dc99e412803c"),
+                    evaluation_element: &hex!("7ec6578ae5120958eb2db1745758ff379e77cb64fe77b0b2d8cc917ea0869c7e"),
+                    output: &hex!(
+                        "527759c3d9366f277d8c6020418d96bb393ba2afb20ff90df23fb7708264e2f3"
+                        "ab9135e3bd69955851de4b1f9fe8a0973396719b7912ba9ee8aa7d0b5e24bcf6"
+                    ),
+                },
+                Vector {
+                    input: &[0x5a; 17],
+                    blind: &blind,
+                    blinded_element: &hex!("da27ef466870f5f15296299850aa088629945a17d1f5b7f5ff043f76b3c06418"),
+                    evaluation_element: &hex!("b4cbf5a4f1eeda5a63ce7b77c7d23f461db3fcab0dd28e4e17cecb5c90d02c25"),
+                    output: &hex!(
+                        "f4a74c9c592497375e796aa837e907b1a045d34306a749db9f34221f7e750cb4"
+                        "f2a6413a6bf6fa5e19ba6348eb673934a722a7ede2e7621306d18951e7cf2c73"
+                    ),
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn p256_sha256_matches_rfc9497() {
+        // RFC 9497, appendix A.3.1.
+        let blind = hex!("3338fa65ec36e0290022b48eb562889d89dbfa691d1cde91517fa222ed7ad364");
+        check_vectors::<Sha256>(
+            CipherSuite::P256Sha256,
+            &hex!("159749d750713afe245d2d39ccfaae8381c53ce92d098a9375ee70739c7ac0bf"),
+            &[
+                Vector {
+                    input: &hex!("00"),
+                    blind: &blind,
+                    blinded_element: &hex!("03723a1e5c09b8b9c18d1dcbca29e8007e95f14f4732d9346d490ffc195110368d"),
+                    evaluation_element: &hex!("030de02ffec47a1fd53efcdd1c6faf5bdc270912b8749e783c7ca75bb412958832"),
+                    output: &hex!("a0b34de5fa4c5b6da07e72af73cc507cceeb48981b97b7285fc375345fe495dd"),
+                },
+                Vector {
+                    input: &[0x5a; 17],
+                    blind: &blind,
+                    blinded_element: &hex!("03cc1df781f1c2240a64d1c297b3f3d16262ef5d4cf102734882675c26231b0838"),
+                    evaluation_element: &hex!("03a0395fe3828f2476ffcd1f4fe540e5a8489322d398be3c4e5a869db7fcb7c52c"),
+                    output: &hex!("c748ca6dd327f0ce85f4ae3a8cd6d4d5390bbb804c9e12dcf94f853fece3dcce"),
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn random_blinds_give_the_same_output() {
+        let suite = CipherSuite::P256Sha256;
+        let (private_key, _) = derive_key_pair::<Sha256>(suite, &[7; 32], b"info").unwrap();
+        let outputs: Vec<Vec<u8>> = (0..2)
+            .map(|_| {
+                let (blind, blinded_element) = blind::<Sha256, _>(suite, b"password", &mut OsRng).unwrap();
+                let evaluation_element = evaluate(suite, &private_key, &blinded_element).unwrap();
+                finalize::<Sha256>(suite, b"password", &blind, &evaluation_element).unwrap()
+            })
+            .collect();
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn the_domain_separation_depends_on_the_suite_and_info() {
+        let suite = CipherSuite::Ristretto255Sha512;
+        let (key, _) = derive_key_pair::<Sha512>(suite, &[7; 32], b"info").unwrap();
+        let (other_key, _) = derive_key_pair::<Sha512>(suite, &[7; 32], b"other info").unwrap();
+        assert_ne!(key, other_key);
+        assert_ne!(
+            get_context_string(CipherSuite::Ristretto255Sha512, MODE_BASE),
+            get_context_string(CipherSuite::P256Sha256, MODE_BASE),
+        );
+        assert_eq!(
+            derive_private_key::<Sha512>(AkeGroup::X25519, suite, &[7; 32], b"info").unwrap_err().kind(),
+            io::ErrorKind::Unsupported,
+        );
+    }
+
+    #[test]
+    fn mismatched_hashes_and_elements_are_rejected() {
+        let kind = |result: io::Result<(Vec<u8>, Vec<u8>)>| result.unwrap_err().kind();
+        assert_eq!(kind(blind::<Sha512, _>(CipherSuite::P256Sha256, b"x", &mut OsRng)), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(blind::<Sha256, _>(CipherSuite::Ristretto255Sha512, b"x", &mut OsRng)), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            kind(derive_key_pair::<Sha512>(CipherSuite::P256Sha256, &[7; 32], b"")),
+            io::ErrorKind::InvalidInput,
+        );
+
+        let (private_key, _) = derive_key_pair::<Sha256>(CipherSuite::P256Sha256, &[7; 32], b"").unwrap();
+        assert_eq!(evaluate(CipherSuite::P256Sha256, &private_key, &[0]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            evaluate(CipherSuite::Ristretto255Sha512, &[1; 32], &[0; 32]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData,
+        );
+    }
+}
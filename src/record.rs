@@ -0,0 +1,382 @@
+//! Record layer - an authenticated encrypted channel built on the session key.
+//!
+//! Once the AKE completes, both sides hold the same [`SessionKey`], which can be used to secure a raw stream
+//! (i.e. a TCP socket or a websocket) without TLS. Every message is sealed into a record with
+//! ChaCha20-Poly1305 (RFC 8439):
+//!
+//! ```txt
+//!     record = concat(I2OSP(epoch, 2), I2OSP(sequence_number, 8), ciphertext, tag)
+//! ```
+//!
+//! - Keys are per direction: `client_secret = Expand-Label(session_key, "ClientRecordSecret", "", Nh)` and
+//!   `server_secret = Expand-Label(session_key, "ServerRecordSecret", "", Nh)`, and each secret is expanded
+//!   into a key (`"RecordKey"`) and an IV (`"RecordIV"`). The labels aren't used anywhere else, so these keys
+//!   are independent from the handshake keys and from the keys of [`SessionKey::export`].
+//! - The nonce is the IV XORed with the sequence number, as in TLS 1.3, so a nonce is never reused under the
+//!   same key. The header is the AAD of each record.
+//! - The receiver rejects replayed records. On a stream transport ([`RecordLayer::new`]) records must arrive in
+//!   order, so a reordered or dropped record is also rejected. On a datagram transport
+//!   ([`RecordLayer::with_reordering`]) records may arrive out of order within a window of 64 records.
+//! - Each direction is rekeyed with [`RecordLayer::rekey`], and automatically every [`RECORDS_PER_KEY`] records:
+//!   `secret = Expand-Label(secret, "RecordKeyUpdate", "", Nh)`. The epoch on the header tells the receiver to
+//!   follow, and the old keys are dropped, so records sealed before a rekey must be received before the ones
+//!   sealed after it.
+//!
+//! Both sides MUST derive the record layer from the same session key and hash function, with opposite roles.
+//! Since the AKE outputs can't be used before `ClientFinish`/`ServerFinish` succeed, neither can the record layer.
+use std::io;
+use std::marker::PhantomData;
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use crate::kdf::SuiteHash;
+use crate::kdf;
+use crate::session::SessionKey;
+
+static KEY_SIZE: usize = 32;
+static NONCE_SIZE: usize = 12;
+static REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// Size of the record header (epoch and sequence number).
+pub static HEADER_SIZE: usize = 10;
+/// Size of the authentication tag of each record.
+pub static TAG_SIZE: usize = 16;
+/// Number of records sealed with a key before the sending direction is rekeyed.
+pub static RECORDS_PER_KEY: u64 = 1 << 32;
+
+/// The side of the handshake a [`RecordLayer`] belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Keys of one direction, for one epoch.
+struct TrafficKeys {
+    secret: Vec<u8>,
+    cipher: ChaCha20Poly1305,
+    iv: Vec<u8>,
+    epoch: u16,
+}
+
+impl TrafficKeys {
+    fn new<D>(secret: Vec<u8>, epoch: u16) -> io::Result<Self>
+    where
+        D: SuiteHash,
+    {
+        let key = kdf::expand_label::<D>(&secret, b"RecordKey", &[], KEY_SIZE)?;
+        let iv = kdf::expand_label::<D>(&secret, b"RecordIV", &[], NONCE_SIZE)?;
+
+        Ok(TrafficKeys {
+            secret,
+            cipher: ChaCha20Poly1305::new_from_slice(&key).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+            iv,
+            epoch,
+        })
+    }
+
+    /// Derives the keys of the next epoch.
+    fn next<D>(&self) -> io::Result<Self>
+    where
+        D: SuiteHash,
+    {
+        let epoch = self.epoch.checked_add(1).ok_or_else(exhausted)?;
+        let secret = kdf::expand_label::<D>(&self.secret, b"RecordKeyUpdate", &[], kdf::output_size::<D>())?;
+        TrafficKeys::new::<D>(secret, epoch)
+    }
+
+    fn nonce(&self, sequence_number: u64) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&self.iv);
+        for (n, s) in nonce[NONCE_SIZE - 8..].iter_mut().zip(&sequence_number.to_be_bytes()) {
+            *n ^= s;
+        }
+        Nonce::from(nonce)
+    }
+}
+
+/// One side of an encrypted channel.
+///
+/// Generic over the suite's hash function `D`, used to derive the record keys.
+pub struct RecordLayer<D> {
+    send: TrafficKeys,
+    send_sequence: u64,
+    receive: TrafficKeys,
+    /// Highest sequence number received on the current epoch plus one.
+    receive_next: u64,
+    /// Bit `i` is set when `receive_next - 1 - i` was received.
+    receive_window: u64,
+    allow_reordering: bool,
+    _hash: PhantomData<D>,
+}
+
+impl<D> RecordLayer<D>
+where
+    D: SuiteHash,
+{
+    /// Derives the record layer of `role` from the session key. Records must be received in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_key`: The [`SessionKey`] output by the AKE.
+    /// * `role`: The side this record layer belongs to.
+    pub fn new(session_key: &SessionKey, role: Role) -> io::Result<Self> {
+        let client_secret = kdf::expand_label::<D>(session_key.as_bytes(), b"ClientRecordSecret", &[], kdf::output_size::<D>())?;
+        let server_secret = kdf::expand_label::<D>(session_key.as_bytes(), b"ServerRecordSecret", &[], kdf::output_size::<D>())?;
+        let (send_secret, receive_secret) = match role {
+            Role::Client => (client_secret, server_secret),
+            Role::Server => (server_secret, client_secret),
+        };
+
+        Ok(RecordLayer {
+            send: TrafficKeys::new::<D>(send_secret, 0)?,
+            send_sequence: 0,
+            receive: TrafficKeys::new::<D>(receive_secret, 0)?,
+            receive_next: 0,
+            receive_window: 0,
+            allow_reordering: false,
+            _hash: PhantomData,
+        })
+    }
+
+    /// Accepts records received out of order (within a window of 64 records), for datagram transports.
+    /// Replayed records are still rejected.
+    pub fn with_reordering(mut self) -> Self {
+        self.allow_reordering = true;
+        self
+    }
+
+    /// Seals `plaintext` into a record.
+    ///
+    /// # Exceptions
+    ///
+    /// * `Other`: When every epoch was used, and the session must be established again.
+    pub fn seal(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        if self.send_sequence == RECORDS_PER_KEY {
+            self.rekey()?;
+        }
+
+        let mut record = Vec::with_capacity(HEADER_SIZE + plaintext.len() + TAG_SIZE);
+        record.extend_from_slice(&self.send.epoch.to_be_bytes());
+        record.extend_from_slice(&self.send_sequence.to_be_bytes());
+
+        let nonce = self.send.nonce(self.send_sequence);
+        let ciphertext = self.send.cipher
+            .encrypt(&nonce, Payload { msg: plaintext, aad: &record })
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        record.extend_from_slice(&ciphertext);
+
+        self.send_sequence += 1;
+        Ok(record)
+    }
+
+    /// Opens a record sealed by the peer.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the record is malformed, fails to authenticate, belongs to an unexpected epoch,
+    ///   arrives out of order (or too late, with reordering) or the peer ran out of epochs.
+    /// * `AlreadyExists`: When the record was already received (replay).
+    pub fn open(&mut self, record: &[u8]) -> io::Result<Vec<u8>> {
+        if record.len() < HEADER_SIZE + TAG_SIZE {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+
+        let (header, ciphertext) = record.split_at(HEADER_SIZE);
+        let epoch = u16::from_be_bytes([header[0], header[1]]);
+        let mut sequence_bytes = [0u8; 8];
+        sequence_bytes.copy_from_slice(&header[2..]);
+        let sequence_number = u64::from_be_bytes(sequence_bytes);
+
+        // The peer rekeyed: its records are checked against a fresh window, and the keys are only replaced
+        // once one of them authenticates.
+        let next_keys = if epoch == self.receive.epoch {
+            None
+        } else if Some(epoch) == self.receive.epoch.checked_add(1) {
+            Some(self.receive.next::<D>().map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?)
+        } else {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        };
+        let (receive_next, receive_window) = match next_keys {
+            Some(_) => (0, 0),
+            None => (self.receive_next, self.receive_window),
+        };
+
+        check_sequence_number(self.allow_reordering, receive_next, receive_window, sequence_number)?;
+
+        let keys = next_keys.as_ref().unwrap_or(&self.receive);
+        let nonce = keys.nonce(sequence_number);
+        let plaintext = keys.cipher
+            .decrypt(&nonce, Payload { msg: ciphertext, aad: header })
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+
+        if let Some(keys) = next_keys {
+            self.receive = keys;
+        }
+        if sequence_number >= receive_next {
+            let shift = sequence_number - receive_next + 1;
+            self.receive_window = if shift >= REPLAY_WINDOW_SIZE { 1 } else { (receive_window << shift) | 1 };
+            self.receive_next = sequence_number + 1;
+        } else {
+            self.receive_window = receive_window | 1 << (receive_next - 1 - sequence_number);
+            self.receive_next = receive_next;
+        }
+
+        Ok(plaintext)
+    }
+
+    /// Rekeys the sending direction. The peer follows when it receives the next record.
+    ///
+    /// # Exceptions
+    ///
+    /// * `Other`: When every epoch was used, and the session must be established again.
+    pub fn rekey(&mut self) -> io::Result<()> {
+        self.send = self.send.next::<D>()?;
+        self.send_sequence = 0;
+        Ok(())
+    }
+}
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+
+/// Checks a received sequence number against the replay window, before the record is decrypted.
+fn check_sequence_number(allow_reordering: bool, receive_next: u64, receive_window: u64, sequence_number: u64) -> io::Result<()> {
+    if sequence_number >= receive_next {
+        if !allow_reordering && sequence_number != receive_next {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        return Ok(());
+    }
+
+    // In order, every previous record was received, so this one is a replay.
+    if !allow_reordering {
+        return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+    }
+
+    let offset = receive_next - 1 - sequence_number;
+    if offset >= REPLAY_WINDOW_SIZE {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    if receive_window & (1 << offset) != 0 {
+        return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+    }
+    Ok(())
+}
+
+fn exhausted() -> io::Error {
+    io::Error::from(io::ErrorKind::Other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha512;
+
+    fn pair() -> (RecordLayer<Sha512>, RecordLayer<Sha512>) {
+        let session_key = SessionKey::new(vec![7u8; 64], vec![9u8; 64]);
+        (
+            RecordLayer::new(&session_key, Role::Client).unwrap(),
+            RecordLayer::new(&session_key, Role::Server).unwrap(),
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let (mut client, mut server) = pair();
+        let record = client.seal(b"hello").unwrap();
+        assert_eq!(record.len(), HEADER_SIZE + 5 + TAG_SIZE);
+        assert_eq!(server.open(&record).unwrap(), b"hello");
+
+        let record = server.seal(b"world").unwrap();
+        assert_eq!(client.open(&record).unwrap(), b"world");
+
+        // Each direction has its own keys.
+        let record = client.seal(b"loop").unwrap();
+        assert_eq!(client.open(&record).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tampered_records_are_rejected() {
+        let (mut client, mut server) = pair();
+        let mut record = client.seal(b"hello").unwrap();
+        let last = record.len() - 1;
+        record[last] ^= 1;
+        assert_eq!(server.open(&record).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(server.open(&record[..HEADER_SIZE]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn replays_are_rejected() {
+        let (mut client, mut server) = pair();
+        let record = client.seal(b"once").unwrap();
+        server.open(&record).unwrap();
+        assert_eq!(server.open(&record).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+
+        let (mut client, server) = pair();
+        let mut server = server.with_reordering();
+        let record = client.seal(b"once").unwrap();
+        server.open(&record).unwrap();
+        server.open(&client.seal(b"twice").unwrap()).unwrap();
+        assert_eq!(server.open(&record).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn reordering_needs_opt_in() {
+        let (mut client, mut server) = pair();
+        let first = client.seal(b"first").unwrap();
+        let second = client.seal(b"second").unwrap();
+        assert_eq!(server.open(&second).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        server.open(&first).unwrap();
+        server.open(&second).unwrap();
+    }
+
+    #[test]
+    fn reordering_within_the_window() {
+        let (mut client, server) = pair();
+        let mut server = server.with_reordering();
+        let records = (0..REPLAY_WINDOW_SIZE + 1)
+            .map(|index| client.seal(&index.to_be_bytes()).unwrap())
+            .collect::<Vec<_>>();
+
+        let last = records.len() - 1;
+        assert_eq!(server.open(&records[last]).unwrap(), (last as u64).to_be_bytes());
+        // The oldest record in the window is accepted once, the one just before it is too old.
+        assert_eq!(server.open(&records[1]).unwrap(), 1u64.to_be_bytes());
+        assert_eq!(server.open(&records[1]).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(server.open(&records[0]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        for index in (2..last).rev() {
+            server.open(&records[index]).unwrap();
+        }
+    }
+
+    #[test]
+    fn receiver_follows_a_rekey() {
+        let (mut client, mut server) = pair();
+        let before = client.seal(b"before").unwrap();
+        client.rekey().unwrap();
+        let after = client.seal(b"after").unwrap();
+        assert_eq!(&after[..HEADER_SIZE], &[0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        server.open(&before).unwrap();
+        assert_eq!(server.open(&after).unwrap(), b"after");
+        // The old keys were dropped, and the next epoch only moves forward.
+        assert_eq!(server.open(&before).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(server.open(&client.seal(b"again").unwrap()).unwrap(), b"again");
+
+        // Skipping an epoch isn't allowed.
+        client.rekey().unwrap();
+        client.rekey().unwrap();
+        assert_eq!(server.open(&client.seal(b"skipped").unwrap()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn forged_epoch_change_keeps_the_keys() {
+        let (mut client, mut server) = pair();
+        let record = client.seal(b"genuine").unwrap();
+        let mut forged = record.clone();
+        forged[1] = 1;
+        assert_eq!(server.open(&forged).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(server.open(&record).unwrap(), b"genuine");
+    }
+}
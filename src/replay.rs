@@ -0,0 +1,127 @@
+//! KE1 replay detection.
+//!
+//! A replayed `KE1` makes the server run `ServerInit` again: one OPRF evaluation and the AKE computations, for
+//! nothing, since the attacker can't finish the handshake. It also lets an attacker observe the timing of those
+//! computations for the client's record as often as they like. A [`ReplayCache`], given to `ServerInit`,
+//! remembers the `KE1`s seen recently and rejects a second one before any of that work:
+//!
+//! - `KE1`s are identified by their `client_nonce` and `client_keyshare`, both fresh on every login, so honest
+//!   clients never collide. Only a hash of them is kept, so every entry has the same (small) size.
+//! - Entries are evicted once older than the window given to [`ReplayCache::new`], so the window SHOULD be at
+//!   least as long as the time a `KE1` can take to arrive (i.e. a few minutes).
+//! - At most `capacity` entries are kept. When the cache is full, the oldest entry is evicted (so under a flood of
+//!   fresh `KE1`s, replays older than the flood may go undetected, but logins keep working). The capacity SHOULD
+//!   cover the logins expected within the window.
+//!
+//! A replay is reported as an `AlreadyExists` error holding a [`ReplayedKe1`], see [`is_replay`].
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use digest::Digest;
+use sha2::Sha256;
+use crate::config::write_field;
+use crate::messages::ake::KE1;
+
+/// The error held by the `AlreadyExists` error of a replayed `KE1`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplayedKe1;
+
+impl fmt::Display for ReplayedKe1 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("replayed KE1")
+    }
+}
+
+impl Error for ReplayedKe1 {}
+
+/// Whether `error` reports a replayed `KE1` (i.e. to log it apart from other failed logins).
+pub fn is_replay(error: &io::Error) -> bool {
+    matches!(error.get_ref(), Some(inner) if inner.is::<ReplayedKe1>())
+}
+
+/// Recently seen `KE1`s.
+pub struct ReplayCache {
+    window: Duration,
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    seen: HashSet<[u8; 32]>,
+    /// The same entries as `seen`, from the oldest to the newest, with the time they were seen.
+    by_age: VecDeque<([u8; 32], Instant)>,
+}
+
+impl ReplayCache {
+    /// Creates an empty cache.
+    ///
+    /// # Arguments
+    ///
+    /// * `window`: How long a `KE1` is remembered.
+    /// * `capacity`: The maximum number of `KE1`s remembered at once.
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        ReplayCache {
+            window,
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Records `ke1`, failing if it was already seen within the window.
+    ///
+    /// # Exceptions
+    ///
+    /// * `AlreadyExists`: When `ke1` is a replay. The error holds a [`ReplayedKe1`].
+    pub fn check(&self, ke1: &KE1) -> io::Result<()> {
+        self.check_fields(ke1.client_nonce(), ke1.client_keyshare())
+    }
+
+    /// Same as [`ReplayCache::check`], given the `client_nonce` and `client_keyshare` of a `KE1`.
+    pub fn check_fields(&self, client_nonce: &[u8], client_keyshare: &[u8]) -> io::Result<()> {
+        let mut input = Vec::new();
+        write_field(&mut input, client_nonce)?;
+        write_field(&mut input, client_keyshare)?;
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&Sha256::digest(&input));
+
+        let now = Instant::now();
+        let mut entries = self.lock()?;
+        while let Some(&(oldest, seen_at)) = entries.by_age.front() {
+            if now.duration_since(seen_at) < self.window {
+                break;
+            }
+            entries.by_age.pop_front();
+            entries.seen.remove(&oldest);
+        }
+
+        if entries.seen.contains(&key) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, ReplayedKe1));
+        }
+        while entries.by_age.len() >= self.capacity.max(1) {
+            if let Some((oldest, _)) = entries.by_age.pop_front() {
+                entries.seen.remove(&oldest);
+            }
+        }
+        entries.seen.insert(key);
+        entries.by_age.push_back((key, now));
+        Ok(())
+    }
+
+    /// Number of `KE1`s remembered, expired ones included until evicted.
+    pub fn len(&self) -> io::Result<usize> {
+        Ok(self.lock()?.by_age.len())
+    }
+
+    /// Whether no `KE1` is remembered.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, Entries>> {
+        self.entries.lock().map_err(|_| io::Error::from(io::ErrorKind::Other))
+    }
+}
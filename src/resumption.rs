@@ -0,0 +1,589 @@
+//! Session resumption - reconnecting with a ticket instead of a full login.
+//!
+//! A full login costs a round trip of `KE1`/`KE2`/`KE3` plus the client-side key stretching. After
+//! `ServerFinish`, the server can issue a resumption ticket to the client, and both sides derive a resumption
+//! secret from the session key:
+//!
+//! ```txt
+//!     resumption_secret = Expand-Label(session_key, "ResumptionSecret", transcript_hash, Nh)
+//! ```
+//!
+//! The ticket is the resumption secret, the client identity, a random ticket id and an expiry time, encrypted
+//! by the server with XChaCha20-Poly1305 under a ticket key only the server knows, so the server doesn't need
+//! to store anything per ticket. Its nonces are random and 24 bytes long, so they don't repeat however many
+//! tickets a ticket key issues. The client keeps the resumption secret with the ticket (it doesn't need the
+//! session key afterwards), and resumes with a PSK handshake:
+//!
+//! ```txt
+//!     Client                                                     Server
+//!     ------------------------------------------------------------------
+//!      binder = MAC(Expand-Label(resumption_secret, "ResumptionBinder", "", Nh),
+//!                   Hash(concat(client_identity, ticket, client_nonce)))
+//!                  ---(client_identity, ticket, client_nonce, binder)--->
+//!
+//!                  resumption_secret = open ticket, check expiry, identity, binder and single use
+//!                  <-----------------(server_nonce, server_mac)-----------
+//!
+//!     transcript_hash = Hash(concat("OPAQUE-Resumption", client_identity, ticket, client_nonce, server_nonce))
+//!     session_key = Expand-Label(resumption_secret, "ResumedSessionKey", transcript_hash, Nh)
+//!     server_mac = MAC(Expand-Label(resumption_secret, "ResumptionServerMAC", transcript_hash, Nh), transcript_hash)
+//! ```
+//!
+//! - Tickets expire after the lifetime given to [`TicketIssuer::new`].
+//! - Tickets are single-use: the issuer records the ids of resumed tickets in a [`UsedTickets`] until they
+//!   expire, and a second resumption with the same ticket is rejected. A new ticket can be issued from the resumed
+//!   session. By default the ids are kept in memory ([`MemoryUsedTickets`]), so they're only shared by the
+//!   threads of one process, and forgotten on restart: servers sharing a ticket key (or restarting within the
+//!   ticket lifetime) MUST plug a shared [`UsedTickets`] through [`TicketIssuer::with_used_tickets`], otherwise
+//!   a ticket can be resumed once per process.
+//! - Tickets are bound to the client identity they were issued to, and rejected for any other identity.
+//!
+//! The resumed session key is a [`SessionKey`] like the one of a full login, so it can be exported, used on a
+//! record layer or used to issue the next ticket. Unlike a full login, a resumed session isn't forward secret
+//! with respect to the ticket key and the resumption secret, so tickets SHOULD have a short lifetime.
+use std::collections::HashMap;
+use std::io;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use crate::kdf::SuiteHash;
+use rand::{CryptoRng, RngCore};
+use crate::config::{invalid_data, read_field, write_field};
+use crate::kdf;
+use crate::messages::resumption::{ResumptionRequest, ResumptionResponse};
+use crate::session::SessionKey;
+
+/// Size of a ticket key.
+pub static TICKET_KEY_SIZE: usize = 32;
+
+/// Number of resumed tickets remembered by the [`MemoryUsedTickets`] of [`TicketIssuer::new`].
+pub static DEFAULT_USED_TICKETS_CAPACITY: usize = 65536;
+
+static TICKET_ID_SIZE: usize = 16;
+static TICKET_NONCE_SIZE: usize = 24;
+static NONCE_SIZE: usize = 32;
+static STR_TICKET: &[u8] = b"OPAQUE-Ticket";
+static STR_RESUMPTION: &[u8] = b"OPAQUE-Resumption";
+
+/// Derives the resumption secret of a session, on both client and server.
+///
+/// # Arguments
+///
+/// * `session_key`: The [`SessionKey`] output by `ClientFinish`, `ServerFinish` or a previous resumption.
+pub fn resumption_secret<D>(session_key: &SessionKey) -> io::Result<Vec<u8>>
+where
+    D: SuiteHash,
+{
+    kdf::expand_label::<D>(
+        session_key.as_bytes(),
+        b"ResumptionSecret",
+        session_key.transcript_hash(),
+        kdf::output_size::<D>(),
+    )
+}
+
+/// Record of the resumed tickets, enforcing their single use.
+///
+/// Every method takes `&self`, so it can be shared by the threads resuming sessions. Implementations backed by a
+/// shared storage (i.e. a database) let several servers share a ticket key.
+pub trait UsedTickets {
+    /// Records that the ticket `ticket_id` was used, atomically with the check that it wasn't before.
+    ///
+    /// # Arguments
+    ///
+    /// * `ticket_id`: The random id of the ticket.
+    /// * `expires_at`: When the ticket expires, in seconds since the UNIX epoch. The id can be forgotten after it.
+    /// * `now`: The current time, in seconds since the UNIX epoch.
+    ///
+    /// # Returns
+    ///
+    /// * `first_use`: Whether the ticket wasn't used before.
+    fn mark_used(&self, ticket_id: &[u8], expires_at: u64, now: u64) -> io::Result<bool>;
+}
+
+/// A [`UsedTickets`] kept in memory, holding at most `capacity` unexpired ticket ids.
+///
+/// When it's full, resumptions are refused until some ticket expires (clients then fall back to a full login),
+/// since forgetting an unexpired id would let its ticket be resumed again.
+pub struct MemoryUsedTickets {
+    capacity: usize,
+    /// Ids of the resumed tickets, with their expiry time (seconds since the UNIX epoch).
+    entries: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl MemoryUsedTickets {
+    /// Creates an empty record, holding at most `capacity` ticket ids.
+    pub fn new(capacity: usize) -> Self {
+        MemoryUsedTickets {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl UsedTickets for MemoryUsedTickets {
+    /// # Exceptions
+    ///
+    /// * `OutOfMemory`: When `capacity` unexpired tickets are already recorded.
+    fn mark_used(&self, ticket_id: &[u8], expires_at: u64, now: u64) -> io::Result<bool> {
+        let mut entries = self.entries.lock().map_err(|_| io::Error::from(io::ErrorKind::Other))?;
+        if entries.contains_key(ticket_id) {
+            return Ok(false);
+        }
+
+        if entries.len() >= self.capacity {
+            entries.retain(|_, &mut expiry| expiry > now);
+            if entries.len() >= self.capacity {
+                return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+            }
+        }
+        entries.insert(ticket_id.to_vec(), expires_at);
+        Ok(true)
+    }
+}
+
+/// Server side of the resumption: issues tickets and resumes sessions from them.
+///
+/// Generic over the suite's hash function `D`.
+pub struct TicketIssuer<D> {
+    cipher: XChaCha20Poly1305,
+    lifetime: Duration,
+    used_tickets: Box<dyn UsedTickets + Send + Sync>,
+    _hash: PhantomData<D>,
+}
+
+impl<D> TicketIssuer<D>
+where
+    D: SuiteHash,
+{
+    /// Creates an issuer, recording the resumed tickets in a [`MemoryUsedTickets`] of
+    /// [`DEFAULT_USED_TICKETS_CAPACITY`] tickets (see [`TicketIssuer::with_used_tickets`]).
+    ///
+    /// # Arguments
+    ///
+    /// * `ticket_key`: A random key of [`TICKET_KEY_SIZE`] bytes, known only to the server(s) resuming sessions.
+    /// * `lifetime`: How long a ticket can be used after being issued.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When the ticket key doesn't have [`TICKET_KEY_SIZE`] bytes.
+    pub fn new(ticket_key: &[u8], lifetime: Duration) -> io::Result<Self> {
+        if ticket_key.len() != TICKET_KEY_SIZE {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        Ok(TicketIssuer {
+            cipher: XChaCha20Poly1305::new_from_slice(ticket_key).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+            lifetime,
+            used_tickets: Box::new(MemoryUsedTickets::new(DEFAULT_USED_TICKETS_CAPACITY)),
+            _hash: PhantomData,
+        })
+    }
+
+    /// Records the resumed tickets in `used_tickets` instead, i.e. one shared by every server using the ticket key.
+    pub fn with_used_tickets<U: UsedTickets + Send + Sync + 'static>(mut self, used_tickets: U) -> Self {
+        self.used_tickets = Box::new(used_tickets);
+        self
+    }
+
+    /// Issues a ticket after a successful `ServerFinish` (or resumption).
+    ///
+    /// # Arguments
+    ///
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `client_identity`: The identity of the authenticated client.
+    /// * `session_key`: The [`SessionKey`] of the session.
+    ///
+    /// # Returns
+    ///
+    /// * `ticket`: An opaque ticket to be sent to the client.
+    pub fn issue<R: RngCore + CryptoRng>(&self, rng: &mut R, client_identity: &[u8], session_key: &SessionKey) -> io::Result<Vec<u8>> {
+        let mut ticket_id = vec![0u8; TICKET_ID_SIZE];
+        rng.fill_bytes(&mut ticket_id);
+        let expires_at = now()?.saturating_add(self.lifetime.as_secs());
+
+        let mut plaintext = Vec::new();
+        write_field(&mut plaintext, &ticket_id)?;
+        plaintext.extend_from_slice(&expires_at.to_be_bytes());
+        write_field(&mut plaintext, client_identity)?;
+        write_field(&mut plaintext, &resumption_secret::<D>(session_key)?)?;
+
+        let mut nonce = [0u8; 24];
+        rng.fill_bytes(&mut nonce);
+        let ciphertext = self.cipher
+            .encrypt(&XNonce::from(nonce), Payload { msg: &plaintext, aad: STR_TICKET })
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        Ok([&nonce[..], &ciphertext[..]].concat())
+    }
+
+    /// Resumes a session from a [`ResumptionRequest`].
+    ///
+    /// # Arguments
+    ///
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `request`: The [`ResumptionRequest`] sent by the client.
+    ///
+    /// # Returns
+    ///
+    /// * `response`: The [`ResumptionResponse`] to be sent to the client.
+    /// * `session_key`: The [`SessionKey`] of the resumed session, for `request.client_identity`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the ticket wasn't issued by this issuer, or the binder doesn't match.
+    /// * `TimedOut`: When the ticket expired.
+    /// * `PermissionDenied`: When the ticket was issued to another client identity.
+    /// * `AlreadyExists`: When the ticket was already used.
+    /// * Any error of the [`UsedTickets`] (i.e. `OutOfMemory` when a [`MemoryUsedTickets`] is full).
+    pub fn resume<R: RngCore + CryptoRng>(&self, rng: &mut R, request: &ResumptionRequest) -> io::Result<(ResumptionResponse, SessionKey)> {
+        if request.ticket.len() < TICKET_NONCE_SIZE {
+            return Err(invalid_data());
+        }
+
+        let (nonce_bytes, ciphertext) = request.ticket.split_at(TICKET_NONCE_SIZE);
+        let mut nonce = [0u8; 24];
+        nonce.copy_from_slice(nonce_bytes);
+        let plaintext = self.cipher
+            .decrypt(&XNonce::from(nonce), Payload { msg: ciphertext, aad: STR_TICKET })
+            .map_err(|_| invalid_data())?;
+
+        let mut reader = plaintext.as_slice();
+        let ticket_id = read_field(&mut reader)?;
+        if reader.len() < 8 {
+            return Err(invalid_data());
+        }
+        let (expiry_bytes, rest) = reader.split_at(8);
+        reader = rest;
+        let mut expires_at = [0u8; 8];
+        expires_at.copy_from_slice(expiry_bytes);
+        let expires_at = u64::from_be_bytes(expires_at);
+        let client_identity = read_field(&mut reader)?;
+        let resumption_secret = read_field(&mut reader)?;
+        if !reader.is_empty() {
+            return Err(invalid_data());
+        }
+
+        let now = now()?;
+        if now >= expires_at {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        if client_identity != request.client_identity {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        verify_binder::<D>(&resumption_secret, request)?;
+
+        if !self.used_tickets.mark_used(&ticket_id, expires_at, now)? {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+
+        let mut server_nonce = vec![0u8; NONCE_SIZE];
+        rng.fill_bytes(&mut server_nonce);
+        let (session_key, server_mac_key) = derive_session::<D>(&resumption_secret, request, &server_nonce)?;
+        let server_mac = kdf::mac::<D>(&server_mac_key, session_key.transcript_hash());
+
+        Ok((ResumptionResponse { server_nonce, server_mac }, session_key))
+    }
+}
+
+/// Client side of the resumption.
+pub struct ResumptionClient<D> {
+    resumption_secret: Vec<u8>,
+    request: ResumptionRequest,
+    _hash: PhantomData<D>,
+}
+
+impl<D> ResumptionClient<D>
+where
+    D: SuiteHash,
+{
+    /// Starts a resumption with a ticket received after a previous session.
+    ///
+    /// # Arguments
+    ///
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `client_identity`: The client identity of the previous session.
+    /// * `ticket`: The ticket issued by the server.
+    /// * `resumption_secret`: The [`resumption_secret`] of the session the ticket was issued on.
+    ///
+    /// # Returns
+    ///
+    /// * `client`: The client state, waiting for the [`ResumptionResponse`].
+    /// * `request`: The [`ResumptionRequest`] to be sent to the server.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When the resumption secret doesn't have `Nh` bytes.
+    pub fn start<R: RngCore + CryptoRng>(
+        rng: &mut R,
+        client_identity: &[u8],
+        ticket: &[u8],
+        resumption_secret: &[u8],
+    ) -> io::Result<(Self, ResumptionRequest)> {
+        if resumption_secret.len() != kdf::output_size::<D>() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let resumption_secret = resumption_secret.to_vec();
+        let mut client_nonce = vec![0u8; NONCE_SIZE];
+        rng.fill_bytes(&mut client_nonce);
+
+        let binder_key = kdf::expand_label::<D>(&resumption_secret, b"ResumptionBinder", &[], kdf::output_size::<D>())?;
+        let binder = kdf::mac::<D>(&binder_key, &binder_input::<D>(client_identity, ticket, &client_nonce)?);
+        let request = ResumptionRequest {
+            client_identity: client_identity.to_vec(),
+            ticket: ticket.to_vec(),
+            client_nonce,
+            binder,
+        };
+
+        let client = ResumptionClient {
+            resumption_secret,
+            request: request.clone(),
+            _hash: PhantomData,
+        };
+        Ok((client, request))
+    }
+
+    /// Finishes the resumption.
+    ///
+    /// # Arguments
+    ///
+    /// * `response`: The [`ResumptionResponse`] sent by the server.
+    ///
+    /// # Returns
+    ///
+    /// * `session_key`: The [`SessionKey`] of the resumed session.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the server MAC doesn't match.
+    pub fn finish(self, response: &ResumptionResponse) -> io::Result<SessionKey> {
+        let (session_key, server_mac_key) = derive_session::<D>(&self.resumption_secret, &self.request, &response.server_nonce)?;
+        kdf::verify::<D>(&server_mac_key, session_key.transcript_hash(), &response.server_mac)?;
+
+        Ok(session_key)
+    }
+}
+
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+fn now() -> io::Result<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .map_err(|_| io::Error::from(io::ErrorKind::Other))
+}
+
+fn binder_input<D>(client_identity: &[u8], ticket: &[u8], client_nonce: &[u8]) -> io::Result<Vec<u8>>
+where
+    D: SuiteHash,
+{
+    let mut input = Vec::new();
+    write_field(&mut input, client_identity)?;
+    write_field(&mut input, ticket)?;
+    write_field(&mut input, client_nonce)?;
+    Ok(kdf::hash::<D>(&input))
+}
+
+fn verify_binder<D>(resumption_secret: &[u8], request: &ResumptionRequest) -> io::Result<()>
+where
+    D: SuiteHash,
+{
+    let binder_key = kdf::expand_label::<D>(resumption_secret, b"ResumptionBinder", &[], kdf::output_size::<D>())?;
+    let input = binder_input::<D>(&request.client_identity, &request.ticket, &request.client_nonce)?;
+    kdf::verify::<D>(&binder_key, &input, &request.binder)
+}
+
+/// Derives the resumed session key and the server MAC key.
+fn derive_session<D>(resumption_secret: &[u8], request: &ResumptionRequest, server_nonce: &[u8]) -> io::Result<(SessionKey, Vec<u8>)>
+where
+    D: SuiteHash,
+{
+    let mut transcript = STR_RESUMPTION.to_vec();
+    write_field(&mut transcript, &request.client_identity)?;
+    write_field(&mut transcript, &request.ticket)?;
+    write_field(&mut transcript, &request.client_nonce)?;
+    write_field(&mut transcript, server_nonce)?;
+    let transcript_hash = kdf::hash::<D>(&transcript);
+
+    let session_key = kdf::expand_label::<D>(resumption_secret, b"ResumedSessionKey", &transcript_hash, kdf::output_size::<D>())?;
+    let server_mac_key = kdf::expand_label::<D>(resumption_secret, b"ResumptionServerMAC", &transcript_hash, kdf::output_size::<D>())?;
+
+    Ok((SessionKey::new(session_key, transcript_hash), server_mac_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use sha2::Sha512;
+
+    fn issuer(lifetime: Duration) -> TicketIssuer<Sha512> {
+        TicketIssuer::new(&[3; 32], lifetime).unwrap()
+    }
+
+    fn session_key() -> SessionKey {
+        SessionKey::new(vec![1; 64], vec![2; 64])
+    }
+
+    fn secret() -> Vec<u8> {
+        resumption_secret::<Sha512>(&session_key()).unwrap()
+    }
+
+    fn request(issuer: &TicketIssuer<Sha512>) -> (ResumptionClient<Sha512>, ResumptionRequest) {
+        let ticket = issuer.issue(&mut OsRng, b"alice", &session_key()).unwrap();
+        ResumptionClient::<Sha512>::start(&mut OsRng, b"alice", &ticket, &secret()).unwrap()
+    }
+
+    #[test]
+    fn issue_and_resume() {
+        let issuer = issuer(Duration::from_secs(60));
+        let (client, request) = request(&issuer);
+        let request = ResumptionRequest::deserialize(&request.serialize().unwrap()).unwrap();
+
+        let (response, server_key) = issuer.resume(&mut OsRng, &request).unwrap();
+        let response = ResumptionResponse::deserialize(&response.serialize().unwrap()).unwrap();
+        let client_key = client.finish(&response).unwrap();
+        assert_eq!(client_key.as_bytes(), server_key.as_bytes());
+        assert_eq!(client_key.transcript_hash(), server_key.transcript_hash());
+        assert_ne!(client_key.as_bytes(), session_key().as_bytes());
+    }
+
+    #[test]
+    fn tampered_server_mac_is_rejected() {
+        let issuer = issuer(Duration::from_secs(60));
+        let (client, request) = request(&issuer);
+        let (mut response, _) = issuer.resume(&mut OsRng, &request).unwrap();
+        response.server_mac[0] ^= 1;
+        assert_eq!(client.finish(&response).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn binder_mismatch_is_rejected() {
+        let issuer = issuer(Duration::from_secs(60));
+        let (_, mut request) = request(&issuer);
+        request.binder[0] ^= 1;
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // A client that doesn't know the resumption secret can't compute the binder.
+        let ticket = request.ticket.clone();
+        let other_secret = resumption_secret::<Sha512>(&SessionKey::new(vec![4; 64], vec![2; 64])).unwrap();
+        let (_, request) = ResumptionClient::<Sha512>::start(&mut OsRng, b"alice", &ticket, &other_secret).unwrap();
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // The binder covers the nonce.
+        let (_, mut request) = self::request(&issuer);
+        request.client_nonce[0] ^= 1;
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn expired_tickets_are_rejected() {
+        let issuer = issuer(Duration::from_secs(0));
+        let (_, request) = request(&issuer);
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn tickets_are_single_use() {
+        let issuer = issuer(Duration::from_secs(60));
+        let (_, request) = request(&issuer);
+        issuer.resume(&mut OsRng, &request).unwrap();
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+
+        // Even with a fresh nonce and binder.
+        let (_, request) =
+            ResumptionClient::<Sha512>::start(&mut OsRng, b"alice", &request.ticket, &secret()).unwrap();
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn tickets_are_bound_to_the_client_identity() {
+        let issuer = issuer(Duration::from_secs(60));
+        let ticket = issuer.issue(&mut OsRng, b"alice", &session_key()).unwrap();
+        let (_, request) = ResumptionClient::<Sha512>::start(&mut OsRng, b"bob", &ticket, &secret()).unwrap();
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn foreign_and_malformed_tickets_are_rejected() {
+        let issuer = issuer(Duration::from_secs(60));
+        let other_issuer = TicketIssuer::<Sha512>::new(&[4; 32], Duration::from_secs(60)).unwrap();
+        let (_, request) = self::request(&other_issuer);
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        // A ticket encrypted under the right key, with trailing bytes after its fields.
+        let mut plaintext = Vec::new();
+        write_field(&mut plaintext, &[5; TICKET_ID_SIZE]).unwrap();
+        plaintext.extend_from_slice(&u64::MAX.to_be_bytes());
+        write_field(&mut plaintext, b"alice").unwrap();
+        write_field(&mut plaintext, &secret()).unwrap();
+        plaintext.push(0);
+        let nonce = [6u8; 24];
+        let ciphertext = issuer.cipher
+            .encrypt(&XNonce::from(nonce), Payload { msg: &plaintext, aad: STR_TICKET })
+            .unwrap();
+        let ticket = [&nonce[..], &ciphertext[..]].concat();
+        let (_, request) = ResumptionClient::<Sha512>::start(&mut OsRng, b"alice", &ticket, &secret()).unwrap();
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let (_, mut request) = self::request(&issuer);
+        request.ticket.truncate(TICKET_NONCE_SIZE - 1);
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tickets_have_extended_nonces() {
+        let issuer = issuer(Duration::from_secs(60));
+        let first = issuer.issue(&mut OsRng, b"alice", &session_key()).unwrap();
+        let second = issuer.issue(&mut OsRng, b"alice", &session_key()).unwrap();
+        assert_ne!(first[..TICKET_NONCE_SIZE], second[..TICKET_NONCE_SIZE]);
+
+        // A ticket cut after a 12-byte nonce isn't opened.
+        let (_, request) = ResumptionClient::<Sha512>::start(&mut OsRng, b"alice", &first[..12], &secret()).unwrap();
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn clients_start_from_the_resumption_secret() {
+        let issuer = issuer(Duration::from_secs(60));
+        let ticket = issuer.issue(&mut OsRng, b"alice", &session_key()).unwrap();
+        let kind = ResumptionClient::<Sha512>::start(&mut OsRng, b"alice", &ticket, &secret()[..32]).err().unwrap().kind();
+        assert_eq!(kind, io::ErrorKind::InvalidInput);
+
+        // The session key isn't needed once the secret is derived.
+        let secret = secret();
+        let (client, request) = ResumptionClient::<Sha512>::start(&mut OsRng, b"alice", &ticket, &secret).unwrap();
+        let (response, server_key) = issuer.resume(&mut OsRng, &request).unwrap();
+        assert_eq!(client.finish(&response).unwrap().as_bytes(), server_key.as_bytes());
+    }
+
+    #[test]
+    fn memory_used_tickets_are_bounded() {
+        let used_tickets = MemoryUsedTickets::new(2);
+        assert!(used_tickets.mark_used(b"first", 100, 10).unwrap());
+        assert!(!used_tickets.mark_used(b"first", 100, 10).unwrap());
+        assert!(used_tickets.mark_used(b"second", 50, 10).unwrap());
+
+        // Full of unexpired tickets: refused, and the known ones are still reported.
+        let error = used_tickets.mark_used(b"third", 100, 10).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::OutOfMemory);
+        assert!(!used_tickets.mark_used(b"second", 50, 10).unwrap());
+
+        // Once a ticket expired, its entry makes room for a new one.
+        assert!(used_tickets.mark_used(b"third", 100, 50).unwrap());
+        assert!(!used_tickets.mark_used(b"first", 100, 50).unwrap());
+    }
+
+    #[test]
+    fn used_tickets_are_pluggable() {
+        let issuer = issuer(Duration::from_secs(60)).with_used_tickets(MemoryUsedTickets::new(0));
+        let (_, request) = request(&issuer);
+        assert_eq!(issuer.resume(&mut OsRng, &request).err().unwrap().kind(), io::ErrorKind::OutOfMemory);
+    }
+}
@@ -0,0 +1,611 @@
+//! # Server setup
+//!
+//! The server's long-term material, which MUST be generated once and persisted (losing it locks every
+//! registered client out, since records can only be used with the same material):
+//! - the AKE keypairs (`server_pri_key`/`server_pub_key`), of the kind required by `config.ake_protocol`;
+//! - the `oprf_seed`, from which the per-client OPRF keys are derived;
+//! - the fake-record keypair, whose private key is the secret the masking keys of the fake records used to
+//!   answer logins of unknown clients are derived from, and whose public key is the client public key of every
+//!   fake record (see [`ServerSetup::fake_record`]), so they're indistinguishable from registered ones.
+//!
+//! A [`ServerSetup`] is created once with [`ServerSetup::new`], stored with [`ServerSetup::serialize`] and
+//! loaded on every start with [`ServerSetup::deserialize`], which validates it against the configuration. It's
+//! passed to every server-side function of both stages.
+//!
+//! The serialized setup holds private keys, so it MUST be stored as securely as any other server secret.
+//!
+//! ## Key rotation
+//!
+//! AKE keypairs are identified by a key id, and every record stores the id of the keypair it was registered
+//! with (`RegistrationUpload::server_key_id`). [`ServerSetup::rotate_server_key`] adds a new keypair, used for
+//! every new registration, while the older ones keep serving the logins of records registered with them. On
+//! the next successful login of such a record, the record is re-bound to the newest keypair (see the "Server
+//! key rotation" section of [`crate::ake`]), so once every record was re-bound (or a compromised key must be
+//! dropped right away) the old keypair is removed with [`ServerSetup::retire_server_key`].
+//!
+//! ## OPRF seed rotation
+//!
+//! Rotating the `oprf_seed` changes every OPRF key, so it's done lazily in the same way: seeds are identified by
+//! a seed id, every record stores the id of the seed it was registered with (`RegistrationUpload::oprf_seed_id`),
+//! and [`ServerSetup::rotate_oprf_seed`] adds a new seed used for new registrations while older seeds keep
+//! serving the logins of their records. A successful login under an older seed also runs an upgrade
+//! registration round, completed by the client without user interaction, which replaces the record with one
+//! registered under the current seed (see the "OPRF seed rotation" section of [`crate::ake`]).
+//! [`ServerSetup::oprf_seed_usage`] reports how many records still use each seed, so an old seed is retired
+//! with [`ServerSetup::retire_oprf_seed`] once no record uses it (the remaining clients must register again).
+use std::io;
+use crate::kdf::SuiteHash;
+use rand::{CryptoRng, RngCore};
+use crate::ake::AkeProtocol;
+use crate::config::{invalid_data, read_field, read_u16, read_u8, write_field, CipherSuite, OpaqueConfig};
+use crate::group::AkeGroup;
+use crate::kdf;
+use crate::oprf;
+
+/// Version of the serialization format of [`ServerSetup`]. Only this version is accepted, a format change MUST
+/// bump it.
+pub static SERVER_SETUP_VERSION: u8 = 0x01;
+
+static STR_FAKE_RECORD: &[u8] = b"OPAQUE-FakeRecord";
+static STR_OPRF_KEY: &[u8] = b"OprfKey";
+static STR_DERIVE_KEY_PAIR: &[u8] = b"OPAQUE-DeriveKeyPair";
+
+/// An AKE keypair of the server, with its key id.
+struct ServerKey {
+    key_id: u16,
+    private_key: Vec<u8>,
+    public_key: Vec<u8>,
+}
+
+/// An OPRF seed of the server, with its seed id.
+struct OprfSeed {
+    seed_id: u16,
+    seed: Vec<u8>,
+}
+
+/// The server's long-term material.
+pub struct ServerSetup {
+    ake_protocol: AkeProtocol,
+    ake_group: AkeGroup,
+    /// Every AKE keypair still in use, the last one being the current one.
+    server_keys: Vec<ServerKey>,
+    /// Every OPRF seed still in use, the last one being the current one.
+    oprf_seeds: Vec<OprfSeed>,
+    fake_private_key: Vec<u8>,
+    fake_public_key: Vec<u8>,
+}
+
+impl ServerSetup {
+    /// Generates a new setup for `config`, its AKE keypair having the key id 0 and its OPRF seed the seed id 0.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The [`OpaqueConfig`] the setup is used with (the keypairs depend on its AKE protocol/group).
+    /// * `rng`: A cryptographically secure random number generator.
+    pub fn new<R: RngCore + CryptoRng>(config: &OpaqueConfig, rng: &mut R) -> io::Result<Self> {
+        let (private_key, public_key) = config.generate_long_term_keypair(rng)?;
+        let (fake_private_key, fake_public_key) = config.generate_long_term_keypair(rng)?;
+        let mut oprf_seed = vec![0u8; config.suite.hash_size()];
+        rng.fill_bytes(&mut oprf_seed);
+
+        Ok(ServerSetup {
+            ake_protocol: config.ake_protocol,
+            ake_group: config.ake_group,
+            server_keys: vec![ServerKey { key_id: 0, private_key, public_key }],
+            oprf_seeds: vec![OprfSeed { seed_id: 0, seed: oprf_seed }],
+            fake_private_key,
+            fake_public_key,
+        })
+    }
+
+    /// Id of the current AKE keypair, used for new registrations.
+    pub fn current_key_id(&self) -> u16 {
+        self.current_key().key_id
+    }
+
+    /// Ids of every AKE keypair still in use, from the oldest to the current one.
+    pub fn key_ids(&self) -> Vec<u16> {
+        self.server_keys.iter().map(|key| key.key_id).collect()
+    }
+
+    /// Server's current encoded public key, shared with clients during registration.
+    pub fn server_public_key(&self) -> &[u8] {
+        &self.current_key().public_key
+    }
+
+    /// Server's current encoded private key.
+    pub fn server_private_key(&self) -> &[u8] {
+        &self.current_key().private_key
+    }
+
+    /// Server's encoded keypair (private key, public key) identified by `key_id`, used by the logins of records
+    /// registered with it.
+    pub fn server_keypair(&self, key_id: u16) -> Option<(&[u8], &[u8])> {
+        self.server_keys
+            .iter()
+            .find(|key| key.key_id == key_id)
+            .map(|key| (key.private_key.as_slice(), key.public_key.as_slice()))
+    }
+
+    /// Current seed used to derive per-client OPRF keys, used for new registrations.
+    pub fn oprf_seed(&self) -> &[u8] {
+        &self.current_seed().seed
+    }
+
+    /// Id of the current OPRF seed.
+    pub fn current_seed_id(&self) -> u16 {
+        self.current_seed().seed_id
+    }
+
+    /// Ids of every OPRF seed still in use, from the oldest to the current one.
+    pub fn seed_ids(&self) -> Vec<u16> {
+        self.oprf_seeds.iter().map(|seed| seed.seed_id).collect()
+    }
+
+    /// OPRF seed identified by `seed_id`, used by the logins of records registered with it.
+    pub fn oprf_seed_for(&self, seed_id: u16) -> Option<&[u8]> {
+        self.oprf_seeds
+            .iter()
+            .find(|seed| seed.seed_id == seed_id)
+            .map(|seed| seed.seed.as_slice())
+    }
+
+    /// Derives the OPRF key of `identifier` from the seed identified by `seed_id`:
+    ///
+    /// ```txt
+    ///     seed = Expand(oprf_seed, concat(credential_identifier, "OprfKey"), Nok)
+    ///     (oprf_key, _) = DeriveKeyPair(seed, "OPAQUE-DeriveKeyPair")
+    /// ```
+    ///
+    /// # Arguments
+    ///
+    /// * `suite`: The cipher suite, which selects the OPRF group.
+    /// * `seed_id`: The id of the OPRF seed.
+    /// * `identifier`: User's credential identifier.
+    ///
+    /// # Exceptions
+    ///
+    /// * `NotFound` (```UnknownOprfSeed```): When there's no seed with this id (i.e. it was retired).
+    /// * `InvalidInput`: When `D` isn't the suite's hash function.
+    pub(crate) fn oprf_key<D>(
+        &self,
+        suite: CipherSuite,
+        seed_id: u16,
+        identifier: &str,
+    ) -> io::Result<Vec<u8>>
+    where
+        D: SuiteHash,
+    {
+        let oprf_seed = self.oprf_seed_for(seed_id).ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let info = [identifier.as_bytes(), STR_OPRF_KEY].concat();
+        let seed = kdf::expand::<D>(oprf_seed, &info, suite.oprf_group().private_key_size())?;
+        let (oprf_key, _) = oprf::derive_key_pair::<D>(suite, &seed, STR_DERIVE_KEY_PAIR)?;
+        Ok(oprf_key)
+    }
+
+    /// Public key of the fake-record keypair.
+    pub fn fake_public_key(&self) -> &[u8] {
+        &self.fake_public_key
+    }
+
+    /// Derives the material of the fake record of an unknown `identifier`, from the fake-record keypair:
+    ///
+    /// ```txt
+    ///     secret = Extract("OPAQUE-FakeRecord", fake_private_key)
+    ///     masking_key = Expand-Label(secret, "FakeMaskingKey", Hash(identifier), Nh)
+    ///     client_public_key = fake_public_key
+    /// ```
+    ///
+    /// The same identifier always gets the same fake record, so querying an unknown identifier twice can't tell
+    /// it apart from a registered one. The client public key is only seen masked, so it's generated once with the
+    /// setup rather than derived on every login, which would make the logins of unknown identifiers slower.
+    ///
+    /// # Arguments
+    ///
+    /// * `identifier`: The unknown credential identifier.
+    ///
+    /// # Returns
+    ///
+    /// * `masking_key`: The fake record's masking key, of `Nh` bytes.
+    /// * `client_public_key`: The fake record's client public key.
+    pub fn fake_record<D>(&self, identifier: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)>
+    where
+        D: SuiteHash,
+    {
+        let secret = kdf::extract::<D>(STR_FAKE_RECORD, &self.fake_private_key);
+        let context = kdf::hash::<D>(identifier);
+        let masking_key = kdf::expand_label::<D>(&secret, b"FakeMaskingKey", &context, kdf::output_size::<D>())?;
+
+        Ok((masking_key, self.fake_public_key.clone()))
+    }
+
+    /// Picks the server key and OPRF seed the fake record of an unknown `identifier` is bound to: either the
+    /// current ones, as a record registered or upgraded since the last rotation, or the oldest ones still in use,
+    /// as a record that wasn't upgraded yet:
+    ///
+    /// ```txt
+    ///     secret = Extract("OPAQUE-FakeRecord", fake_private_key)
+    ///     selector = Expand-Label(secret, "FakeRecordIds", Hash(identifier), 1)
+    ///     (key_id, seed_id) = (key_ids[0], seed_ids[0])        if selector[0] is odd
+    ///                         (current_key_id, current_seed_id) otherwise
+    /// ```
+    ///
+    /// While a rotation is in progress, half of the fake records get a `server_key_update` (and the extra OPRF
+    /// evaluation of an `oprf_seed_update`) like the records that weren't upgraded, and the other half none like
+    /// the up-to-date ones. The same identifier is always bound to the same ids, until a key or a seed is rotated
+    /// or retired.
+    ///
+    /// # Arguments
+    ///
+    /// * `identifier`: The unknown credential identifier.
+    ///
+    /// # Returns
+    ///
+    /// * `key_id`: The id of the fake record's server key.
+    /// * `seed_id`: The id of the fake record's OPRF seed.
+    pub fn fake_record_ids<D>(&self, identifier: &[u8]) -> io::Result<(u16, u16)>
+    where
+        D: SuiteHash,
+    {
+        let secret = kdf::extract::<D>(STR_FAKE_RECORD, &self.fake_private_key);
+        let context = kdf::hash::<D>(identifier);
+        let selector = kdf::expand_label::<D>(&secret, b"FakeRecordIds", &context, 1)?;
+
+        if selector[0] & 1 == 1 {
+            Ok((self.server_keys[0].key_id, self.oprf_seeds[0].seed_id))
+        } else {
+            Ok((self.current_key_id(), self.current_seed_id()))
+        }
+    }
+
+    /// Generates a new AKE keypair, which becomes the current one. Older keypairs keep serving the logins of
+    /// the records registered with them, until they're re-bound.
+    ///
+    /// # Returns
+    ///
+    /// * `key_id`: The id of the new keypair.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When `config` isn't the one of this setup.
+    /// * `Other`: When every key id was used.
+    pub fn rotate_server_key<R: RngCore + CryptoRng>(&mut self, config: &OpaqueConfig, rng: &mut R) -> io::Result<u16> {
+        if config.ake_protocol != self.ake_protocol || config.ake_group != self.ake_group {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let key_id = self.current_key_id().checked_add(1).ok_or_else(|| io::Error::from(io::ErrorKind::Other))?;
+        let (private_key, public_key) = config.generate_long_term_keypair(rng)?;
+        self.server_keys.push(ServerKey { key_id, private_key, public_key });
+        Ok(key_id)
+    }
+
+    /// Removes the AKE keypair identified by `key_id`. Records still registered with it can't log in anymore,
+    /// and must register again.
+    ///
+    /// # Exceptions
+    ///
+    /// * `NotFound`: When there's no keypair with this id.
+    /// * `InvalidInput`: When `key_id` is the current keypair.
+    pub fn retire_server_key(&mut self, key_id: u16) -> io::Result<()> {
+        if key_id == self.current_key_id() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let index = self.server_keys
+            .iter()
+            .position(|key| key.key_id == key_id)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        self.server_keys.remove(index);
+        Ok(())
+    }
+
+    /// Generates a new OPRF seed, which becomes the current one. Older seeds keep serving the logins of the
+    /// records registered with them, until they're upgraded.
+    ///
+    /// # Returns
+    ///
+    /// * `seed_id`: The id of the new seed.
+    ///
+    /// # Exceptions
+    ///
+    /// * `Other`: When every seed id was used.
+    pub fn rotate_oprf_seed<R: RngCore + CryptoRng>(&mut self, rng: &mut R) -> io::Result<u16> {
+        let seed_id = self.current_seed_id().checked_add(1).ok_or_else(|| io::Error::from(io::ErrorKind::Other))?;
+        let mut seed = vec![0u8; self.current_seed().seed.len()];
+        rng.fill_bytes(&mut seed);
+        self.oprf_seeds.push(OprfSeed { seed_id, seed });
+        Ok(seed_id)
+    }
+
+    /// Removes the OPRF seed identified by `seed_id`. Records still registered with it can't log in anymore,
+    /// and must register again.
+    ///
+    /// # Exceptions
+    ///
+    /// * `NotFound`: When there's no seed with this id.
+    /// * `InvalidInput`: When `seed_id` is the current seed.
+    pub fn retire_oprf_seed(&mut self, seed_id: u16) -> io::Result<()> {
+        if seed_id == self.current_seed_id() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let index = self.oprf_seeds
+            .iter()
+            .position(|seed| seed.seed_id == seed_id)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        self.oprf_seeds.remove(index);
+        Ok(())
+    }
+
+    /// Counts how many records use each OPRF seed.
+    ///
+    /// # Arguments
+    ///
+    /// * `record_seed_ids`: The `oprf_seed_id` of every stored record.
+    ///
+    /// # Returns
+    ///
+    /// * `usage`: `(seed_id, count)` for every seed of the setup, from the oldest to the current one, followed by
+    ///   the unknown seed ids found on records (i.e. of retired seeds).
+    pub fn oprf_seed_usage<I: IntoIterator<Item = u16>>(&self, record_seed_ids: I) -> Vec<(u16, usize)> {
+        let mut usage: Vec<(u16, usize)> = self.oprf_seeds.iter().map(|seed| (seed.seed_id, 0)).collect();
+        for seed_id in record_seed_ids {
+            match usage.iter_mut().find(|(id, _)| *id == seed_id) {
+                Some((_, count)) => *count += 1,
+                None => usage.push((seed_id, 1)),
+            }
+        }
+        usage
+    }
+
+    /// Serializes the setup as:
+    /// `SERVER_SETUP_VERSION (1) || ake_protocol (2) || ake_group (1) || key_count (2) || server_keys ||
+    /// seed_count (2) || oprf_seeds || fake_private_key || fake_public_key`, where `server_keys` are encoded from
+    /// the oldest to the current one as `key_id (2) || private_key || public_key`, `oprf_seeds` in the same order
+    /// as `seed_id (2) || seed`, and every key and seed is prefixed by its length (2 bytes).
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When there are more than 65535 keys or seeds, or a key doesn't fit its length prefix.
+    pub fn serialize(&self) -> io::Result<Vec<u8>> {
+        if self.server_keys.len() > u16::MAX as usize || self.oprf_seeds.len() > u16::MAX as usize {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        let mut output = vec![SERVER_SETUP_VERSION];
+        output.extend_from_slice(&self.ake_protocol.id());
+        output.push(self.ake_group.id());
+        output.extend_from_slice(&(self.server_keys.len() as u16).to_be_bytes());
+        for key in &self.server_keys {
+            output.extend_from_slice(&key.key_id.to_be_bytes());
+            write_field(&mut output, &key.private_key)?;
+            write_field(&mut output, &key.public_key)?;
+        }
+        output.extend_from_slice(&(self.oprf_seeds.len() as u16).to_be_bytes());
+        for seed in &self.oprf_seeds {
+            output.extend_from_slice(&seed.seed_id.to_be_bytes());
+            write_field(&mut output, &seed.seed)?;
+        }
+        write_field(&mut output, &self.fake_private_key)?;
+        write_field(&mut output, &self.fake_public_key)?;
+        Ok(output)
+    }
+
+    /// Deserializes a setup created by [`ServerSetup::serialize`], and validates it against `config`.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The [`OpaqueConfig`] the setup is used with.
+    /// * `input`: The serialized setup.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the input is truncated, has trailing bytes or an unknown format version, was
+    ///   created for another AKE protocol/group, has no AKE keypair or seed, unordered key/seed ids, a seed of
+    ///   the wrong size, or a public key doesn't match its private key.
+    pub fn deserialize(config: &OpaqueConfig, input: &[u8]) -> io::Result<Self> {
+        let mut reader = input;
+
+        if read_u8(&mut reader)? != SERVER_SETUP_VERSION {
+            return Err(invalid_data());
+        }
+        let ake_protocol = AkeProtocol::from_id([read_u8(&mut reader)?, read_u8(&mut reader)?]).ok_or_else(invalid_data)?;
+        let ake_group = AkeGroup::from_id(read_u8(&mut reader)?).ok_or_else(invalid_data)?;
+
+        let key_count = read_u16(&mut reader)?;
+        let server_keys = (0..key_count)
+            .map(|_| Ok(ServerKey {
+                key_id: read_u16(&mut reader)?,
+                private_key: read_field(&mut reader)?,
+                public_key: read_field(&mut reader)?,
+            }))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let seed_count = read_u16(&mut reader)?;
+        let oprf_seeds = (0..seed_count)
+            .map(|_| Ok(OprfSeed { seed_id: read_u16(&mut reader)?, seed: read_field(&mut reader)? }))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let setup = ServerSetup {
+            ake_protocol,
+            ake_group,
+            server_keys,
+            oprf_seeds,
+            fake_private_key: read_field(&mut reader)?,
+            fake_public_key: read_field(&mut reader)?,
+        };
+
+        if !reader.is_empty() {
+            return Err(invalid_data());
+        }
+        setup.validate(config)?;
+        Ok(setup)
+    }
+
+    fn current_key(&self) -> &ServerKey {
+        // `new` and `deserialize` never build a setup without keypairs, and the current one can't be retired.
+        self.server_keys.last().expect("a server setup always has a keypair")
+    }
+
+    fn current_seed(&self) -> &OprfSeed {
+        // `new` and `deserialize` never build a setup without seeds, and the current one can't be retired.
+        self.oprf_seeds.last().expect("a server setup always has an OPRF seed")
+    }
+
+    /// Checks the setup was created for `config`, key and seed ids are increasing, seeds have the right size,
+    /// and every public key matches its private key.
+    fn validate(&self, config: &OpaqueConfig) -> io::Result<()> {
+        if self.ake_protocol != config.ake_protocol
+            || self.ake_group != config.ake_group
+            || self.server_keys.is_empty()
+            || self.server_keys.windows(2).any(|keys| keys[0].key_id >= keys[1].key_id)
+            || self.oprf_seeds.is_empty()
+            || self.oprf_seeds.windows(2).any(|seeds| seeds[0].seed_id >= seeds[1].seed_id)
+            || self.oprf_seeds.iter().any(|seed| seed.seed.len() != config.suite.hash_size())
+        {
+            return Err(invalid_data());
+        }
+
+        let keypairs = self.server_keys
+            .iter()
+            .map(|key| (&key.private_key, &key.public_key))
+            .chain(std::iter::once((&self.fake_private_key, &self.fake_public_key)));
+        for (private_key, public_key) in keypairs {
+            let recovered = config.recover_long_term_public_key(private_key).map_err(|_| invalid_data())?;
+            if &recovered != public_key {
+                return Err(invalid_data());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use sha2::Sha512;
+    use crate::config::CipherSuite;
+    use crate::envelope::EnvelopeMode;
+
+    fn config() -> OpaqueConfig {
+        OpaqueConfig::new(CipherSuite::Ristretto255Sha512, EnvelopeMode::Internal, Vec::new(), None, None)
+    }
+
+    fn assert_same(first: &ServerSetup, second: &ServerSetup) {
+        assert_eq!(first.serialize().unwrap(), second.serialize().unwrap());
+        assert_eq!(first.key_ids(), second.key_ids());
+        assert_eq!(first.seed_ids(), second.seed_ids());
+        assert_eq!(first.server_private_key(), second.server_private_key());
+        assert_eq!(first.oprf_seed(), second.oprf_seed());
+        assert_eq!(first.fake_public_key(), second.fake_public_key());
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let config = config();
+        let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        let serialized = setup.serialize().unwrap();
+        assert_eq!(serialized[0], SERVER_SETUP_VERSION);
+
+        let restored = ServerSetup::deserialize(&config, &serialized).unwrap();
+        assert_same(&setup, &restored);
+        assert_eq!(restored.key_ids(), vec![0]);
+        assert_eq!(restored.seed_ids(), vec![0]);
+        assert_eq!(
+            restored.fake_record::<Sha512>(b"unknown").unwrap(),
+            setup.fake_record::<Sha512>(b"unknown").unwrap()
+        );
+    }
+
+    #[test]
+    fn serialize_round_trip_after_rotations() {
+        let config = config();
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        assert_eq!(setup.rotate_server_key(&config, &mut OsRng).unwrap(), 1);
+        assert_eq!(setup.rotate_server_key(&config, &mut OsRng).unwrap(), 2);
+        assert_eq!(setup.rotate_oprf_seed(&mut OsRng).unwrap(), 1);
+        setup.retire_server_key(0).unwrap();
+
+        let restored = ServerSetup::deserialize(&config, &setup.serialize().unwrap()).unwrap();
+        assert_same(&setup, &restored);
+        assert_eq!(restored.key_ids(), vec![1, 2]);
+        assert_eq!(restored.current_key_id(), 2);
+        assert_eq!(restored.seed_ids(), vec![0, 1]);
+        assert_eq!(restored.current_seed_id(), 1);
+        assert_eq!(restored.server_keypair(1), setup.server_keypair(1));
+        assert_eq!(restored.server_keypair(0), None);
+        assert_eq!(restored.oprf_seed_for(0), setup.oprf_seed_for(0));
+
+        // The restored setup keeps rotating from where it was.
+        let mut restored = restored;
+        assert_eq!(restored.rotate_server_key(&config, &mut OsRng).unwrap(), 3);
+        restored.retire_oprf_seed(0).unwrap();
+        let restored = ServerSetup::deserialize(&config, &restored.serialize().unwrap()).unwrap();
+        assert_eq!(restored.key_ids(), vec![1, 2, 3]);
+        assert_eq!(restored.seed_ids(), vec![1]);
+    }
+
+    #[test]
+    fn fake_records_are_spread_over_the_material_in_use() {
+        let config = config();
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        assert_eq!(setup.fake_record_ids::<Sha512>(b"unknown").unwrap(), (0, 0));
+
+        setup.rotate_server_key(&config, &mut OsRng).unwrap();
+        setup.rotate_oprf_seed(&mut OsRng).unwrap();
+        let ids: Vec<(u16, u16)> = (0..64u8)
+            .map(|index| setup.fake_record_ids::<Sha512>(&[index]).unwrap())
+            .collect();
+        assert!(ids.iter().all(|ids| *ids == (0, 0) || *ids == (1, 1)));
+        assert!(ids.contains(&(0, 0)) && ids.contains(&(1, 1)));
+        // The same identifier keeps the same ids, also once restored.
+        let restored = ServerSetup::deserialize(&config, &setup.serialize().unwrap()).unwrap();
+        assert_eq!(restored.fake_record_ids::<Sha512>(&[0]).unwrap(), ids[0]);
+
+        setup.retire_server_key(0).unwrap();
+        setup.retire_oprf_seed(0).unwrap();
+        assert_eq!(setup.fake_record_ids::<Sha512>(b"unknown").unwrap(), (1, 1));
+    }
+
+    #[test]
+    fn current_material_can_not_be_retired() {
+        let config = config();
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        assert_eq!(setup.retire_server_key(0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(setup.retire_server_key(7).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(setup.retire_oprf_seed(0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(setup.retire_oprf_seed(7).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn malformed_setups_are_rejected() {
+        let config = config();
+        let serialized = ServerSetup::new(&config, &mut OsRng).unwrap().serialize().unwrap();
+        let kind = |input: &[u8]| ServerSetup::deserialize(&config, input).err().unwrap().kind();
+
+        for version in &[0x00, 0x02, 0x03, 0xff] {
+            let mut other_version = serialized.clone();
+            other_version[0] = *version;
+            assert_eq!(kind(&other_version), io::ErrorKind::InvalidData);
+        }
+        for len in 0..serialized.len() {
+            assert_eq!(kind(&serialized[..len]), io::ErrorKind::InvalidData);
+        }
+        let mut trailing = serialized.clone();
+        trailing.push(0);
+        assert_eq!(kind(&trailing), io::ErrorKind::InvalidData);
+
+        // The public key of the first keypair is right after the private key: flip its last byte.
+        let private_key_len = u16::from_be_bytes([serialized[8], serialized[9]]) as usize;
+        let public_key_len = u16::from_be_bytes([serialized[10 + private_key_len], serialized[11 + private_key_len]]);
+        let public_key_end = 12 + private_key_len + public_key_len as usize;
+        let mut mismatched = serialized.clone();
+        mismatched[public_key_end - 1] ^= 1;
+        assert_eq!(kind(&mismatched), io::ErrorKind::InvalidData);
+
+        let other_config = config.clone().with_ake_protocol(AkeProtocol::Hmqv);
+        assert_eq!(ServerSetup::deserialize(&other_config, &serialized).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
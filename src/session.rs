@@ -0,0 +1,131 @@
+//! Session outputs of the AKE.
+//!
+//! Both `ClientFinish` and `ServerFinish` output a [`SessionKey`]. Applications usually need more than one
+//! key from a login (i.e. a MAC key for API tokens, an encryption key for a websocket and a signing key for
+//! audit logs), so instead of using the session key directly, independent keys are derived from it with
+//! [`SessionKey::export`], in the same way as TLS exporters (RFC 8446 section 7.5):
+//!
+//! ```txt
+//!     exporter_secret = Expand-Label(session_key, concat("Exporter-", label), "", Nh)
+//!     exported_key = Expand-Label(exporter_secret, "Exporter", Hash(context), len)
+//! ```
+//!
+//! The `"Exporter"` labels are never used by the handshake key schedule, so exported keys are independent
+//! from the handshake keys and from each other (as long as labels or contexts differ). Both sides MUST use
+//! the same label, context and length to get the same key.
+use std::io;
+use crate::kdf::SuiteHash;
+use crate::kdf;
+
+/// The shared secret of a successful handshake.
+pub struct SessionKey {
+    key: Vec<u8>,
+    transcript_hash: Vec<u8>,
+}
+
+impl SessionKey {
+    /// Wraps the `session_key` output by the key schedule.
+    ///
+    /// # Arguments
+    ///
+    /// * `key`: The session key.
+    /// * `transcript_hash`: The hash of the handshake transcript, the same on both sides (see [`crate::ake`]).
+    pub fn new(key: Vec<u8>, transcript_hash: Vec<u8>) -> Self {
+        SessionKey { key, transcript_hash }
+    }
+
+    /// The raw session key, `Nh` bytes long.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.key
+    }
+
+    /// The hash of the handshake transcript, which identifies the session without revealing its key.
+    pub fn transcript_hash(&self) -> &[u8] {
+        &self.transcript_hash
+    }
+
+    /// Exports a key bound to `label` and `context`.
+    ///
+    /// # Arguments
+    ///
+    /// * `label`: Identifies the usage of the exported key (i.e. `b"api-token-mac"`), at most 239 bytes.
+    /// * `context`: Optional application context (an empty slice for none), hashed before being used.
+    /// * `len`: Size of the exported key, at most `255 * Nh` bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `exported_key`: A key of `len` bytes.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When `label` or `len` are too big.
+    pub fn export<D>(&self, label: &[u8], context: &[u8], len: usize) -> io::Result<Vec<u8>>
+    where
+        D: SuiteHash,
+    {
+        let exporter_secret = kdf::expand_label::<D>(
+            &self.key,
+            &[b"Exporter-".as_ref(), label].concat(),
+            &[],
+            kdf::output_size::<D>(),
+        )?;
+
+        kdf::expand_label::<D>(&exporter_secret, b"Exporter", &kdf::hash::<D>(context), len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha512;
+    use crate::test_support;
+
+    #[test]
+    fn both_sides_export_the_same_keys() {
+        let (client, server) = test_support::session_keys::<Sha512>(&test_support::config());
+        for len in [16, 32, 64, 255 * 64].iter() {
+            let exported = client.export::<Sha512>(b"api-token-mac", b"user 42", *len).unwrap();
+            assert_eq!(exported.len(), *len);
+            assert_eq!(server.export::<Sha512>(b"api-token-mac", b"user 42", *len).unwrap(), exported);
+        }
+    }
+
+    #[test]
+    fn labels_and_contexts_separate_exported_keys() {
+        let (session_key, _) = test_support::session_keys::<Sha512>(&test_support::config());
+        let export = |label: &[u8], context: &[u8]| session_key.export::<Sha512>(label, context, 32).unwrap();
+
+        let exported = export(b"api-token-mac", b"");
+        assert_ne!(export(b"websocket-key", b""), exported);
+        assert_ne!(export(b"api-token-mac", b"user 42"), exported);
+        assert_ne!(export(b"api-token-mac", b"user 43"), export(b"api-token-mac", b"user 42"));
+        // Exporting to a different length isn't a prefix of the same export.
+        assert_ne!(&session_key.export::<Sha512>(b"api-token-mac", b"", 64).unwrap()[..32], &exported[..]);
+    }
+
+    #[test]
+    fn exported_keys_are_independent_from_other_keys() {
+        let (session_key, _) = test_support::session_keys::<Sha512>(&test_support::config());
+        let nh = kdf::output_size::<Sha512>();
+
+        assert_ne!(session_key.export::<Sha512>(b"", b"", nh).unwrap(), session_key.as_bytes());
+        // Exports reusing the labels of the record layer and of Noise still get other keys.
+        for label in [&b"ClientRecordSecret"[..], b"ServerRecordSecret"].iter() {
+            let record_secret = kdf::expand_label::<Sha512>(session_key.as_bytes(), label, &[], nh).unwrap();
+            assert_ne!(session_key.export::<Sha512>(label, b"", nh).unwrap(), record_secret);
+        }
+        let psk = crate::noise::psk::<Sha512>(&session_key).unwrap();
+        let exported = session_key.export::<Sha512>(b"NoisePSK", session_key.transcript_hash(), psk.len()).unwrap();
+        assert_ne!(exported, psk);
+    }
+
+    #[test]
+    fn oversized_labels_and_lengths_are_rejected() {
+        let (session_key, _) = test_support::session_keys::<Sha512>(&test_support::config());
+        let kind = |label: &[u8], len: usize| session_key.export::<Sha512>(label, b"", len).err().unwrap().kind();
+
+        assert!(session_key.export::<Sha512>(&[b'l'; 239], b"", 32).is_ok());
+        assert_eq!(kind(&[b'l'; 240], 32), io::ErrorKind::InvalidInput);
+        assert_eq!(kind(b"label", 255 * 64 + 1), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,382 @@
+//! Login sessions - the [`ServerState`]s of pending logins, kept in memory between `KE2` and `KE3`.
+//!
+//! [`LoginSessions::start`] runs `ServerInit` and stores its state, returning a random session id to be sent to
+//! the client alongside `KE2`. When `KE3` arrives with that id, [`LoginSessions::finish`] removes the state and
+//! runs `ServerFinish` with it:
+//!
+//! ```txt
+//!     Client                                                     Server
+//!     ------------------------------------------------------------------
+//!                  -----------------------ke1-------------------------->
+//!                                     (session_id, ke2) = sessions.start(ke1, ...)
+//!                  <-----------------(ke2, session_id)------------------
+//!                  -----------------(ke3, session_id)------------------>
+//!                                     session_key = sessions.finish(session_id, ke3)
+//! ```
+//!
+//! Servers that run `ServerInit`/`ServerFinish` themselves store and remove the state with
+//! [`LoginSessions::insert`] and [`LoginSessions::take`] instead. Either way, the [`ServerState`] is consumed by
+//! `ServerFinish`, so it can't be used twice.
+//!
+//! - Sessions expire after the lifetime given to [`LoginSessions::new`], so abandoned logins don't pile up.
+//! - At most `max_in_flight` sessions are pending at once, so a flood of `KE1` can't exhaust the server's memory.
+//! - A state can only be taken once: a second `KE3` for the same session (i.e. a replayed one) is rejected, even
+//!   after the first one failed, since a failed `ServerFinish` MUST NOT be retried with the same state. The ids
+//!   of taken sessions are remembered until they expire, at most `max_in_flight` of them: past that, the oldest
+//!   ones are forgotten, and a second `KE3` for them is rejected as an unknown session.
+//!
+//! [`LoginSessions`] is `Send + Sync`, so a single instance can be shared (i.e. within an `Arc`) by every task
+//! or thread serving logins. For servers that can't keep state, see [`crate::stateless`].
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use crate::kdf::SuiteHash;
+use rand::{CryptoRng, RngCore};
+use crate::ake::ServerState;
+use crate::config::OpaqueConfig;
+use crate::messages::ake::{KE1, KE2, KE3};
+use crate::messages::registration::RegistrationUpload;
+use crate::replay::ReplayCache;
+use crate::server_setup::ServerSetup;
+use crate::session::SessionKey;
+use crate::throttle::Throttle;
+
+/// Size of a session id.
+pub static SESSION_ID_SIZE: usize = 16;
+
+/// Pending login sessions, by session id.
+pub struct LoginSessions {
+    lifetime: Duration,
+    max_in_flight: usize,
+    sessions: Mutex<Sessions>,
+}
+
+#[derive(Default)]
+struct Sessions {
+    /// States waiting for `KE3`, with their expiry time.
+    pending: HashMap<Vec<u8>, (ServerState, Instant)>,
+    /// Ids of the sessions already taken, with their expiry time, to tell a second `KE3` from an unknown session.
+    taken: HashMap<Vec<u8>, Instant>,
+}
+
+impl Sessions {
+    fn evict_expired(&mut self, now: Instant) -> usize {
+        let pending = self.pending.len();
+        self.pending.retain(|_, (_, expires_at)| *expires_at > now);
+        self.taken.retain(|_, expires_at| *expires_at > now);
+        pending - self.pending.len()
+    }
+}
+
+impl LoginSessions {
+    /// Creates an empty manager.
+    ///
+    /// # Arguments
+    ///
+    /// * `lifetime`: How long a session waits for `KE3` after `KE2` was sent.
+    /// * `max_in_flight`: The maximum number of sessions waiting for `KE3` at once.
+    pub fn new(lifetime: Duration, max_in_flight: usize) -> Self {
+        LoginSessions {
+            lifetime,
+            max_in_flight,
+            sessions: Mutex::new(Sessions::default()),
+        }
+    }
+
+    /// Stores the state output by `ServerInit`.
+    ///
+    /// # Arguments
+    ///
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `state`: The [`ServerState`] of the login.
+    ///
+    /// # Returns
+    ///
+    /// * `session_id`: A random id of [`SESSION_ID_SIZE`] bytes, to be sent to the client alongside `KE2`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `WouldBlock`: When `max_in_flight` sessions are already pending. The login SHOULD be rejected (or
+    ///   retried later by the client).
+    pub fn insert<R: RngCore + CryptoRng>(&self, rng: &mut R, state: ServerState) -> io::Result<Vec<u8>> {
+        let now = Instant::now();
+        let mut sessions = self.lock()?;
+        if sessions.pending.len() >= self.max_in_flight {
+            sessions.evict_expired(now);
+            if sessions.pending.len() >= self.max_in_flight {
+                return Err(io::Error::from(io::ErrorKind::WouldBlock));
+            }
+        }
+
+        let mut session_id = vec![0u8; SESSION_ID_SIZE];
+        loop {
+            rng.fill_bytes(&mut session_id);
+            if !sessions.pending.contains_key(&session_id) && !sessions.taken.contains_key(&session_id) {
+                break;
+            }
+        }
+
+        sessions.pending.insert(session_id.clone(), (state, now + self.lifetime));
+        Ok(session_id)
+    }
+
+    /// Removes the state of a session to run `ServerFinish` with, when `KE3` arrives.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id`: The session id sent by the client with `KE3`.
+    ///
+    /// # Returns
+    ///
+    /// * `state`: The [`ServerState`] stored by [`LoginSessions::insert`].
+    ///
+    /// # Exceptions
+    ///
+    /// * `NotFound`: When the session is unknown (never inserted, removed, or expired long ago).
+    /// * `TimedOut`: When the session expired.
+    /// * `AlreadyExists`: When the session was already taken, i.e. on a second `KE3`.
+    pub fn take(&self, session_id: &[u8]) -> io::Result<ServerState> {
+        let now = Instant::now();
+        let mut sessions = self.lock()?;
+        if matches!(sessions.taken.get(session_id), Some(expires_at) if *expires_at > now) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+
+        let (state, expires_at) = sessions
+            .pending
+            .remove(session_id)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        if expires_at <= now {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+
+        if sessions.taken.len() >= self.max_in_flight {
+            sessions.evict_expired(now);
+        }
+        // Still full: the oldest taken session is forgotten, a second `KE3` for it is then an unknown session.
+        if sessions.taken.len() >= self.max_in_flight.max(1) {
+            let oldest = sessions.taken.iter().min_by_key(|(_, expires_at)| **expires_at).map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                sessions.taken.remove(&oldest);
+            }
+        }
+        sessions.taken.insert(session_id.to_vec(), expires_at);
+        Ok(state)
+    }
+
+    /// Runs `ServerInit` (see [`ServerState::server_init`]) and stores its state.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The [`OpaqueConfig`] agreed by client and server.
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `server_setup`: The server's [`ServerSetup`].
+    /// * `record`: The client's [`RegistrationUpload`], or the one of [`RegistrationUpload::fake_record`].
+    /// * `identifier`: The user's identifier.
+    /// * `client_identity`: Optional encoded client identity (defaults to `config.client_identity`).
+    /// * `ke1`: A [`KE1`] structure.
+    /// * `server_extensions`: Optional application extensions, sent encrypted.
+    /// * `channel_binding`: Optional binding to the outer channel (i.e. a `tls-exporter` value).
+    /// * `replay_cache`: Optional [`ReplayCache`], to reject replayed `KE1`s before any computation.
+    /// * `throttle`: Optional [`Throttle`], checked with `identifier` and given the attempt as a failure until
+    ///   the login succeeds. It MUST be the same one given to [`LoginSessions::finish`].
+    ///
+    /// # Returns
+    ///
+    /// * `session_id`: A random id of [`SESSION_ID_SIZE`] bytes, to be sent to the client alongside `KE2`.
+    /// * `ke2`: A [`KE2`] structure.
+    /// * `client_info`: The client's application information, if any. It MUST NOT be trusted before
+    ///   `ServerFinish` succeeds.
+    ///
+    /// # Exceptions
+    ///
+    /// * `WouldBlock`: When `max_in_flight` sessions are already pending.
+    /// * Every exception of [`ServerState::server_init`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn start<D, R>(
+        &self,
+        config: &OpaqueConfig,
+        rng: &mut R,
+        server_setup: &ServerSetup,
+        record: &RegistrationUpload,
+        identifier: &str,
+        client_identity: Option<&[u8]>,
+        ke1: &KE1,
+        server_extensions: Option<&[u8]>,
+        channel_binding: Option<&[u8]>,
+        replay_cache: Option<&ReplayCache>,
+        throttle: Option<&dyn Throttle>,
+    ) -> io::Result<(Vec<u8>, KE2, Option<Vec<u8>>)>
+    where
+        D: SuiteHash,
+        R: RngCore + CryptoRng,
+    {
+        let (state, ke2, client_info) = ServerState::server_init::<D, R>(
+            config,
+            rng,
+            server_setup,
+            record,
+            identifier,
+            client_identity,
+            ke1,
+            server_extensions,
+            channel_binding,
+            replay_cache,
+            throttle,
+        )?;
+        let session_id = self.insert(rng, state)?;
+        Ok((session_id, ke2, client_info))
+    }
+
+    /// Takes the state of a session and runs `ServerFinish` (see [`ServerState::server_finish`]) with it.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: The [`OpaqueConfig`] given to [`LoginSessions::start`].
+    /// * `server_setup`: The server's [`ServerSetup`].
+    /// * `session_id`: The session id sent by the client with `KE3`.
+    /// * `ke3`: A [`KE3`] structure.
+    /// * `throttle`: Optional [`Throttle`], given the success of the login. It MUST be the same one given to
+    ///   [`LoginSessions::start`].
+    ///
+    /// # Returns
+    ///
+    /// * `session_key`: Shared session secret, see [`SessionKey`].
+    /// * `client_extensions`: The decrypted client extensions, if any.
+    /// * `record_update`: The record re-bound to the current server key and OPRF seed, if any. It replaces the
+    ///   stored record.
+    ///
+    /// # Exceptions
+    ///
+    /// * `NotFound`/`TimedOut`/`AlreadyExists`: See [`LoginSessions::take`].
+    /// * Every exception of [`ServerState::server_finish`].
+    #[allow(clippy::type_complexity)]
+    pub fn finish<D>(
+        &self,
+        config: &OpaqueConfig,
+        server_setup: &ServerSetup,
+        session_id: &[u8],
+        ke3: &KE3,
+        throttle: Option<&dyn Throttle>,
+    ) -> io::Result<(SessionKey, Option<Vec<u8>>, Option<RegistrationUpload>)>
+    where
+        D: SuiteHash,
+    {
+        self.take(session_id)?.server_finish::<D>(config, server_setup, ke3, throttle)
+    }
+
+    /// Drops a pending session (i.e. when the connection is closed before `KE3`).
+    ///
+    /// # Returns
+    ///
+    /// * `removed`: Whether the session was pending.
+    pub fn remove(&self, session_id: &[u8]) -> io::Result<bool> {
+        Ok(self.lock()?.pending.remove(session_id).is_some())
+    }
+
+    /// Number of sessions waiting for `KE3`, expired ones included until evicted.
+    pub fn in_flight(&self) -> io::Result<usize> {
+        Ok(self.lock()?.pending.len())
+    }
+
+    /// Drops the expired sessions. Expired sessions are also dropped when the maximum is reached, so calling it
+    /// periodically is only needed to release their memory sooner.
+    ///
+    /// # Returns
+    ///
+    /// * `evicted`: The number of pending sessions dropped.
+    pub fn evict_expired(&self) -> io::Result<usize> {
+        Ok(self.lock()?.evict_expired(Instant::now()))
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, Sessions>> {
+        self.sessions.lock().map_err(|_| io::Error::from(io::ErrorKind::Other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use sha2::Sha512;
+    use crate::ake::ClientState;
+    use crate::test_support::{self, Server};
+
+    // The manager is shared by every task serving logins.
+    const _: fn() = || {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<LoginSessions>();
+    };
+
+    fn server() -> Server {
+        test_support::server::<Sha512>(test_support::config())
+    }
+
+    /// Starts a login on `sessions`, returning the session id and the client's `KE3`.
+    fn start(server: &Server, sessions: &LoginSessions) -> io::Result<(Vec<u8>, KE3)> {
+        let (client_state, ke1) =
+            ClientState::client_init::<Sha512, _>(&server.config, &mut OsRng, b"password", None, None)?;
+        let (session_id, ke2, _) = sessions.start::<Sha512, _>(
+            &server.config, &mut OsRng, &server.setup, &server.record, test_support::IDENTIFIER, None, &ke1, None, None, None, None,
+        )?;
+        let (ke3, _, _, _, _) = client_state.client_finish::<Sha512, _>(&server.config, &mut OsRng, b"password", &ke2, None, None)?;
+        Ok((session_id, ke3))
+    }
+
+    #[test]
+    fn sessions_finish_once() {
+        let server = server();
+        let sessions = LoginSessions::new(Duration::from_secs(60), 8);
+        let (session_id, ke3) = start(&server, &sessions).unwrap();
+        assert_eq!(session_id.len(), SESSION_ID_SIZE);
+        assert_eq!(sessions.in_flight().unwrap(), 1);
+
+        assert!(sessions.finish::<Sha512>(&server.config, &server.setup, &session_id, &ke3, None).is_ok());
+        assert_eq!(sessions.in_flight().unwrap(), 0);
+        let kind = sessions.finish::<Sha512>(&server.config, &server.setup, &session_id, &ke3, None).err().unwrap().kind();
+        assert_eq!(kind, io::ErrorKind::AlreadyExists);
+        assert_eq!(sessions.take(&[0; 16]).err().unwrap().kind(), io::ErrorKind::NotFound);
+
+        // A failed ServerFinish consumes the session as well.
+        let (session_id, mut ke3) = start(&server, &sessions).unwrap();
+        ke3.client_mac[0] ^= 1;
+        assert_eq!(sessions.finish::<Sha512>(&server.config, &server.setup, &session_id, &ke3, None).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(sessions.take(&session_id).err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let server = server();
+        let sessions = LoginSessions::new(Duration::from_secs(0), 8);
+        let (session_id, ke3) = start(&server, &sessions).unwrap();
+        assert_eq!(sessions.finish::<Sha512>(&server.config, &server.setup, &session_id, &ke3, None).err().unwrap().kind(), io::ErrorKind::TimedOut);
+
+        start(&server, &sessions).unwrap();
+        assert_eq!(sessions.evict_expired().unwrap(), 1);
+        assert_eq!(sessions.in_flight().unwrap(), 0);
+    }
+
+    #[test]
+    fn sessions_are_bounded() {
+        let server = server();
+        let sessions = LoginSessions::new(Duration::from_secs(60), 2);
+        let (first, ke3) = start(&server, &sessions).unwrap();
+        let (second, _) = start(&server, &sessions).unwrap();
+        assert_eq!(start(&server, &sessions).err().unwrap().kind(), io::ErrorKind::WouldBlock);
+
+        // Taking or dropping a session frees its slot.
+        sessions.finish::<Sha512>(&server.config, &server.setup, &first, &ke3, None).unwrap();
+        assert!(sessions.remove(&second).unwrap());
+        start(&server, &sessions).unwrap();
+        start(&server, &sessions).unwrap();
+
+        // Taken ids are bounded as well, the oldest ones being forgotten.
+        let sessions = LoginSessions::new(Duration::from_secs(60), 2);
+        for _ in 0..4 {
+            let (session_id, _) = start(&server, &sessions).unwrap();
+            sessions.take(&session_id).unwrap();
+        }
+        assert_eq!(sessions.lock().unwrap().taken.len(), 2);
+    }
+}
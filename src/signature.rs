@@ -0,0 +1,183 @@
+//! # Signature schemes
+//!
+//! Signature schemes used by the SIGMA-I AKE (see [`crate::ake::AkeProtocol::SigmaI`]), in which both client and
+//! server authenticate with long-term signing keys instead of Diffie-Hellman keys. This allows devices holding
+//! signing-only keys to take part in the protocol.
+//!
+//! Keys are always handled in their encoded form:
+//! - Ed25519: 32 bytes seeds (RFC 8032) as signing keys and 32 bytes verifying keys;
+//! - ECDSA P-256 (with SHA-256): 32 bytes big-endian scalars as signing keys and 33 bytes compressed SEC1 points as
+//!   verifying keys. Signatures are the 64 bytes fixed size `r || s` encoding.
+use std::convert::TryFrom;
+use std::io;
+use rand::{RngCore, CryptoRng};
+use crate::kdf::SuiteHash;
+use ed25519_dalek::{Signer as _, Verifier as _};
+use crate::config::CipherSuite;
+use crate::group::AkeGroup;
+
+/// Supported signature schemes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// Ed25519, as specified on RFC 8032.
+    Ed25519,
+    /// ECDSA over P-256 with SHA-256 and deterministic nonces (RFC 6979).
+    EcdsaP256,
+}
+
+impl SignatureScheme {
+    /// One byte identifier of the scheme, used when serializing a [`crate::config::OpaqueConfig`].
+    pub fn id(&self) -> u8 {
+        match self {
+            SignatureScheme::Ed25519 => 0x01,
+            SignatureScheme::EcdsaP256 => 0x02,
+        }
+    }
+
+    /// Returns the scheme matching `id`, if any.
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(SignatureScheme::Ed25519),
+            0x02 => Some(SignatureScheme::EcdsaP256),
+            _ => None,
+        }
+    }
+
+    /// Size of an encoded signing key.
+    pub fn signing_key_size(&self) -> usize {
+        32
+    }
+
+    /// Size of an encoded verifying key.
+    pub fn verifying_key_size(&self) -> usize {
+        match self {
+            SignatureScheme::Ed25519 => 32,
+            SignatureScheme::EcdsaP256 => 33,
+        }
+    }
+
+    /// Size of an encoded signature.
+    pub fn signature_size(&self) -> usize {
+        64
+    }
+
+    /// Generates a random signing keypair.
+    ///
+    /// # Returns
+    ///
+    /// * `signing_key`: The encoded signing key.
+    /// * `verifying_key`: The encoded verifying key.
+    pub fn generate_keypair<R: RngCore + CryptoRng>(&self, rng: &mut R) -> (Vec<u8>, Vec<u8>) {
+        match self {
+            SignatureScheme::Ed25519 => {
+                let mut seed = [0u8; 32];
+                rng.fill_bytes(&mut seed);
+                let signing_key = ed25519_dalek::SigningKey::from_bytes(&seed);
+                (seed.to_vec(), signing_key.verifying_key().to_bytes().to_vec())
+            }
+            // ECDSA P-256 keys are the same as the P-256 AKE keys.
+            SignatureScheme::EcdsaP256 => AkeGroup::P256.generate_keypair(rng),
+        }
+    }
+
+    /// Deterministically derives a signing keypair from `seed`, used by the internal envelope mode.
+    ///
+    /// On Ed25519 the first 32 bytes of the seed are used as the signing key, on ECDSA P-256 the signing key
+    /// is derived as in [`AkeGroup::derive_keypair`].
+    pub fn derive_keypair<D: SuiteHash>(&self, suite: CipherSuite, seed: &[u8]) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        match self {
+            SignatureScheme::Ed25519 => {
+                if seed.len() < 32 {
+                    return Err(io::Error::from(io::ErrorKind::InvalidInput));
+                }
+
+                let signing_key = seed[..32].to_vec();
+                let verifying_key = self.recover_verifying_key(&signing_key)?;
+                Ok((signing_key, verifying_key))
+            }
+            SignatureScheme::EcdsaP256 => AkeGroup::P256.derive_keypair::<D>(suite, seed),
+        }
+    }
+
+    /// Recovers the encoded verifying key matching an encoded signing key.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the signing key isn't a valid encoded signing key for the scheme.
+    pub fn recover_verifying_key(&self, signing_key: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            SignatureScheme::Ed25519 => {
+                let signing_key = deserialize_ed25519_signing_key(signing_key)?;
+                Ok(signing_key.verifying_key().to_bytes().to_vec())
+            }
+            SignatureScheme::EcdsaP256 => {
+                let signing_key = deserialize_ecdsa_signing_key(signing_key)?;
+                Ok(signing_key.verifying_key().to_encoded_point(true).as_bytes().to_vec())
+            }
+        }
+    }
+
+    /// Signs `msg`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the signing key isn't a valid encoded signing key for the scheme.
+    pub fn sign(&self, signing_key: &[u8], msg: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            SignatureScheme::Ed25519 => {
+                let signing_key = deserialize_ed25519_signing_key(signing_key)?;
+                Ok(signing_key.sign(msg).to_bytes().to_vec())
+            }
+            SignatureScheme::EcdsaP256 => {
+                let signing_key = deserialize_ecdsa_signing_key(signing_key)?;
+                let signature: p256::ecdsa::Signature = signing_key.sign(msg);
+                Ok(signature.to_bytes().to_vec())
+            }
+        }
+    }
+
+    /// Verifies a signature over `msg`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the verifying key or the signature are malformed, or when the signature is invalid.
+    pub fn verify(&self, verifying_key: &[u8], msg: &[u8], signature: &[u8]) -> io::Result<()> {
+        let valid = match self {
+            SignatureScheme::Ed25519 => {
+                let verifying_key = deserialize_ed25519_verifying_key(verifying_key)?;
+                let signature = ed25519_dalek::Signature::from_slice(signature)
+                    .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+                verifying_key.verify_strict(msg, &signature).is_ok()
+            }
+            SignatureScheme::EcdsaP256 => {
+                let verifying_key = p256::ecdsa::VerifyingKey::from_sec1_bytes(verifying_key)
+                    .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+                let signature = p256::ecdsa::Signature::from_slice(signature)
+                    .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+                verifying_key.verify(msg, &signature).is_ok()
+            }
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(io::Error::from(io::ErrorKind::InvalidData))
+        }
+    }
+}
+
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+fn deserialize_ed25519_signing_key(input: &[u8]) -> io::Result<ed25519_dalek::SigningKey> {
+    ed25519_dalek::SigningKey::try_from(input).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+fn deserialize_ed25519_verifying_key(input: &[u8]) -> io::Result<ed25519_dalek::VerifyingKey> {
+    ed25519_dalek::VerifyingKey::try_from(input).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
+
+fn deserialize_ecdsa_signing_key(input: &[u8]) -> io::Result<p256::ecdsa::SigningKey> {
+    p256::ecdsa::SigningKey::from_slice(input).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))
+}
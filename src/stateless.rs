@@ -0,0 +1,230 @@
+//! Stateless servers - the [`ServerState`] sealed into a token between `KE2` and `KE3`.
+//!
+//! After `ServerInit`, the server seals its state with a key shared by every server of the deployment, and sends
+//! the token to the client alongside `KE2`. The client presents it back with `KE3`, and whichever server receives
+//! it opens the token and runs `ServerFinish` on the recovered state:
+//!
+//! ```txt
+//!     Client                                                     Server
+//!     ------------------------------------------------------------------
+//!                  -----------------------ke1-------------------------->
+//!                                     (ke2, server_state) = ServerInit(...)
+//!                                     token = StateSealer::seal(server_state)
+//!                  <--------------------(ke2, token)--------------------
+//!                  --------------------(ke3, token)-------------------->
+//!                                     server_state = StateSealer::open(token)
+//!                                     ServerFinish(server_state, ke3)
+//! ```
+//!
+//! The token is the state (its AKE protocol included) and an expiry time, encrypted with XChaCha20-Poly1305
+//! under the sealing key, so it keeps the state's secrets (`session_key`, `expected_client_mac`, ...) from the
+//! client and can't be forged or altered without the sealing key. The state holds no long-term key: with KEM,
+//! it keeps the id of the server's keypair, looked up in the [`crate::server_setup::ServerSetup`] given to
+//! `ServerFinish`. Every token has a random 24-byte nonce, long
+//! enough for a sealing key to seal any number of tokens without a nonce collision. Its size doesn't depend on
+//! the client's record, only on the AKE protocol and the optional fields set on `KE2`.
+//!
+//! Unlike a state kept in memory, a token isn't consumed by `ServerFinish`: the same `KE3` and token can be
+//! presented again until the token expires. A replayed `KE3` only yields a session key already known to the
+//! client, but tokens SHOULD have a lifetime about as short as a login (i.e. a minute), and servers that can
+//! share a small amount of state SHOULD also reject tokens already presented.
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use rand::{CryptoRng, RngCore};
+use crate::ake::ServerState;
+use crate::config::invalid_data;
+
+/// Size of a sealing key.
+pub static STATE_KEY_SIZE: usize = 32;
+
+static STATE_TOKEN_VERSION: u8 = 0x02;
+const TOKEN_NONCE_SIZE: usize = 24;
+static STR_SERVER_STATE: &[u8] = b"OPAQUE-ServerState";
+
+/// Seals [`ServerState`]s into tokens and opens them back.
+pub struct StateSealer {
+    cipher: XChaCha20Poly1305,
+    lifetime: Duration,
+}
+
+impl StateSealer {
+    /// Creates a sealer.
+    ///
+    /// # Arguments
+    ///
+    /// * `sealing_key`: A random key of [`STATE_KEY_SIZE`] bytes, shared by every server that may receive `KE3`.
+    /// * `lifetime`: How long a token can be presented after being sealed.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidInput`: When the sealing key doesn't have [`STATE_KEY_SIZE`] bytes.
+    pub fn new(sealing_key: &[u8], lifetime: Duration) -> io::Result<Self> {
+        if sealing_key.len() != STATE_KEY_SIZE {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        Ok(StateSealer {
+            cipher: XChaCha20Poly1305::new_from_slice(sealing_key).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+            lifetime,
+        })
+    }
+
+    /// Seals the state output by `ServerInit`.
+    ///
+    /// # Arguments
+    ///
+    /// * `rng`: A cryptographically secure random number generator.
+    /// * `state`: The [`ServerState`] of the login.
+    ///
+    /// # Returns
+    ///
+    /// * `token`: An opaque token to be sent to the client alongside `KE2`.
+    pub fn seal<R: RngCore + CryptoRng>(&self, rng: &mut R, state: &ServerState) -> io::Result<Vec<u8>> {
+        let expires_at = now()?.saturating_add(self.lifetime.as_secs());
+        let mut plaintext = expires_at.to_be_bytes().to_vec();
+        plaintext.extend_from_slice(&state.serialize()?);
+
+        let mut nonce = [0u8; TOKEN_NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+        let ciphertext = self.cipher
+            .encrypt(&XNonce::from(nonce), Payload { msg: &plaintext, aad: &aad() })
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        Ok([&[STATE_TOKEN_VERSION][..], &nonce[..], &ciphertext[..]].concat())
+    }
+
+    /// Opens a token presented with `KE3`.
+    ///
+    /// Opening a token doesn't consume it: until it expires, the same token (and `KE3`) can be presented again
+    /// and is opened again. Servers that must run `ServerFinish` at most once per login have to remember the
+    /// tokens already presented (i.e. in a store shared by every server) for the token lifetime.
+    ///
+    /// # Arguments
+    ///
+    /// * `token`: The token sent by the client.
+    ///
+    /// # Returns
+    ///
+    /// * `state`: The [`ServerState`] to run `ServerFinish` with.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the token wasn't sealed with this key (or was altered), or has an unknown version.
+    /// * `TimedOut`: When the token expired.
+    pub fn open(&self, token: &[u8]) -> io::Result<ServerState> {
+        if token.len() < 1 + TOKEN_NONCE_SIZE || token[0] != STATE_TOKEN_VERSION {
+            return Err(invalid_data());
+        }
+
+        let (nonce_bytes, ciphertext) = token[1..].split_at(TOKEN_NONCE_SIZE);
+        let mut nonce = [0u8; TOKEN_NONCE_SIZE];
+        nonce.copy_from_slice(nonce_bytes);
+        let plaintext = self.cipher
+            .decrypt(&XNonce::from(nonce), Payload { msg: ciphertext, aad: &aad() })
+            .map_err(|_| invalid_data())?;
+
+        if plaintext.len() < 8 {
+            return Err(invalid_data());
+        }
+        let (expiry_bytes, state) = plaintext.split_at(8);
+        let mut expires_at = [0u8; 8];
+        expires_at.copy_from_slice(expiry_bytes);
+        if now()? >= u64::from_be_bytes(expires_at) {
+            return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+
+        ServerState::deserialize(state)
+    }
+}
+
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+fn now() -> io::Result<u64> {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .map_err(|_| io::Error::from(io::ErrorKind::Other))
+}
+
+/// The token's associated data: the label and the token version, so tokens of other formats are rejected.
+fn aad() -> Vec<u8> {
+    [STR_SERVER_STATE, &[STATE_TOKEN_VERSION]].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use sha2::Sha512;
+    use crate::ake::AkeProtocol;
+    use crate::config::OpaqueConfig;
+    use crate::kem::Kem;
+    use crate::messages::ake::KE3;
+    use crate::server_setup::ServerSetup;
+    use crate::signature::SignatureScheme;
+    use crate::test_support;
+
+    fn sealer(lifetime: Duration) -> StateSealer {
+        StateSealer::new(&[7; 32], lifetime).unwrap()
+    }
+
+    /// Runs a login of `protocol` up to `KE3`, returning the config, the server's setup and state, and `KE3`.
+    fn start_login(protocol: AkeProtocol) -> (OpaqueConfig, ServerSetup, ServerState, KE3) {
+        let server = test_support::server::<Sha512>(test_support::config().with_ake_protocol(protocol));
+        let pending = test_support::start_login::<Sha512>(&server.config, &server.setup, &server.record, b"password").unwrap();
+        (server.config, server.setup, pending.server_state, pending.ke3)
+    }
+
+    #[test]
+    fn sealed_states_finish_the_login() {
+        let protocols = [
+            AkeProtocol::TripleDh,
+            AkeProtocol::Hmqv,
+            AkeProtocol::SigmaI(SignatureScheme::Ed25519),
+            AkeProtocol::Kem(Kem::MlKem768),
+        ];
+        let sealer = sealer(Duration::from_secs(60));
+        for protocol in protocols.iter() {
+            let (config, setup, state, ke3) = start_login(*protocol);
+            let token = sealer.seal(&mut OsRng, &state).unwrap();
+            assert_eq!(token[0], STATE_TOKEN_VERSION);
+
+            let opened = sealer.open(&token).unwrap();
+            assert_eq!(opened.serialize().unwrap(), state.serialize().unwrap());
+            assert!(opened.server_finish::<Sha512>(&config, &setup, &ke3, None).is_ok());
+            // The token isn't consumed.
+            assert!(sealer.open(&token).is_ok());
+        }
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let (_, _, state, _) = start_login(AkeProtocol::TripleDh);
+        let token = sealer(Duration::from_secs(0)).seal(&mut OsRng, &state).unwrap();
+        assert_eq!(sealer(Duration::from_secs(60)).open(&token).err().unwrap().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn altered_tokens_are_rejected() {
+        let (_, _, state, _) = start_login(AkeProtocol::TripleDh);
+        let sealer = sealer(Duration::from_secs(60));
+        let token = sealer.seal(&mut OsRng, &state).unwrap();
+        let kind = |token: &[u8]| sealer.open(token).err().unwrap().kind();
+
+        for index in [0, 1, TOKEN_NONCE_SIZE + 1, token.len() - 1].iter() {
+            let mut altered = token.clone();
+            altered[*index] ^= 1;
+            assert_eq!(kind(&altered), io::ErrorKind::InvalidData);
+        }
+        assert_eq!(kind(&token[..TOKEN_NONCE_SIZE]), io::ErrorKind::InvalidData);
+        assert_eq!(kind(&token[..token.len() - 1]), io::ErrorKind::InvalidData);
+
+        let other = StateSealer::new(&[8; 32], Duration::from_secs(60)).unwrap();
+        assert_eq!(other.open(&token).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(StateSealer::new(&[7; 16], Duration::from_secs(60)).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+}
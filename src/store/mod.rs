@@ -0,0 +1,578 @@
+//! # Credential store
+//!
+//! Before calling `CreateCredentialResponse`, the server has to fetch the client's record (the
+//! `RegistrationUpload` of the registration stage) by its credential identifier, and after a login carrying a
+//! `record_update` (see the "Server key rotation" and "OPRF seed rotation" sections of [`crate::ake`]) it has to
+//! replace it. The [`CredentialStore`] trait covers that storage, so a server can be written once for any backend:
+//! - [`CredentialStore::get`], [`CredentialStore::put`] and [`CredentialStore::delete`] for plain access;
+//! - [`CredentialStore::compare_and_swap`] for updates racing with other logins or registrations (i.e. two
+//!   concurrent logins both upgrading the same record, or a registration that must not overwrite an account).
+//!
+//! Records are kept serialized, along with a [`RecordMetadata`] holding the ids of the server key and OPRF seed
+//! they're bound to (so rotations can be tracked without parsing them, see [`CredentialStore::oprf_seed_ids`]).
+//!
+//! The following implementations are provided:
+//! - [`MemoryStore`], which keeps everything in memory (i.e. for tests or ephemeral servers);
+//! - [`FileStore`], which keeps a copy in memory and persists every change to a single file. The file is never
+//!   modified in place: changes are written to a uniquely named temporary file, synced and then renamed over it,
+//!   so a crash leaves either the old or the new contents, never a mix of both;
+//! - `sqlite::SqliteStore` (requires the `sqlite` feature), on a SQLite database.
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use crate::config::{invalid_data, read_field, read_u8, write_field};
+use crate::messages::registration::RegistrationUpload;
+
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// Format version written by [`FileStore`].
+const FILE_STORE_VERSION: u8 = 0x01;
+
+/// Counter making the names of [`FileStore`]'s temporary files unique within the process.
+static TEMP_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+type Records = BTreeMap<String, StoredRecord>;
+
+/// Information kept along with a record.
+///
+/// The ids are the ones of the record: build stored records with [`StoredRecord::new`], the stores reject a
+/// record whose metadata doesn't match it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordMetadata {
+    /// Id of the server's keypair the record is bound to (`RegistrationUpload::server_key_id`).
+    pub server_key_id: u16,
+    /// Id of the OPRF seed the record was registered with (`RegistrationUpload::oprf_seed_id`).
+    pub oprf_seed_id: u16,
+    /// When the client registered, in seconds since the UNIX epoch.
+    pub registered_at: u64,
+    /// When the record was last replaced (i.e. by a `record_update`), in seconds since the UNIX epoch.
+    pub updated_at: u64,
+}
+
+/// A record, as stored by a [`CredentialStore`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredRecord {
+    /// The record, serialized by `RegistrationUpload::serialize_record`.
+    pub record: Vec<u8>,
+    /// See [`RecordMetadata`].
+    pub metadata: RecordMetadata,
+}
+
+impl StoredRecord {
+    /// Serializes `record` for a [`CredentialStore`], with the ids of its metadata taken from the record.
+    ///
+    /// # Arguments
+    ///
+    /// * `record`: The client's record (i.e. from the `RegistrationUpload` of the registration, or a
+    ///   `record_update`).
+    /// * `registered_at`: When the client registered, in seconds since the UNIX epoch.
+    /// * `updated_at`: When the record was last replaced, in seconds since the UNIX epoch.
+    pub fn new(record: &RegistrationUpload, registered_at: u64, updated_at: u64) -> io::Result<Self> {
+        Ok(StoredRecord {
+            record: record.serialize_record()?,
+            metadata: RecordMetadata {
+                server_key_id: record.server_key_id(),
+                oprf_seed_id: record.oprf_seed_id(),
+                registered_at,
+                updated_at,
+            },
+        })
+    }
+}
+
+/// Storage of the clients' records, by credential identifier.
+///
+/// Every method takes `&self`, so a store can be shared by the threads serving logins, and every change is
+/// atomic: after an error, the stored record is either the old or the new one.
+///
+/// Identifiers and records are limited to 65535 bytes (the length prefix of [`FileStore`]'s format), and every
+/// implementation enforces it so records can be moved between backends: [`CredentialStore::put`] and
+/// [`CredentialStore::compare_and_swap`] fail with `InvalidInput` on longer ones, changing nothing. They also
+/// fail with `InvalidInput` on a record whose [`RecordMetadata`] ids aren't the ones of the serialized record
+/// (see [`StoredRecord::new`]), so the metadata can be trusted for rotations.
+pub trait CredentialStore {
+    /// Fetches the record of `identifier`.
+    ///
+    /// # Returns
+    ///
+    /// * `record`: The stored record, or ```None``` when `identifier` isn't registered.
+    fn get(&self, identifier: &str) -> io::Result<Option<StoredRecord>>;
+
+    /// Stores `record` as the record of `identifier`, replacing the current one if any.
+    fn put(&self, identifier: &str, record: StoredRecord) -> io::Result<()>;
+
+    /// Deletes the record of `identifier`.
+    ///
+    /// # Returns
+    ///
+    /// * `deleted`: Whether `identifier` had a record.
+    fn delete(&self, identifier: &str) -> io::Result<bool>;
+
+    /// Replaces the record of `identifier` with `new`, only if the stored record is still `current`.
+    ///
+    /// # Arguments
+    ///
+    /// * `identifier`: User's credential identifier.
+    /// * `current`: The record expected to be stored, ```None``` to expect no record (i.e. on registration).
+    /// * `new`: The record to store, ```None``` to delete it.
+    ///
+    /// # Returns
+    ///
+    /// * `swapped`: Whether the record was replaced. When ```false```, nothing was changed.
+    fn compare_and_swap(
+        &self,
+        identifier: &str,
+        current: Option<&StoredRecord>,
+        new: Option<StoredRecord>,
+    ) -> io::Result<bool>;
+
+    /// Identifiers of every stored record, in ascending order.
+    fn identifiers(&self) -> io::Result<Vec<String>>;
+
+    /// The `oprf_seed_id` of every stored record, to be passed to
+    /// [`crate::server_setup::ServerSetup::oprf_seed_usage`].
+    fn oprf_seed_ids(&self) -> io::Result<Vec<u16>> {
+        let mut seed_ids = Vec::new();
+        for identifier in self.identifiers()? {
+            if let Some(record) = self.get(&identifier)? {
+                seed_ids.push(record.metadata.oprf_seed_id);
+            }
+        }
+        Ok(seed_ids)
+    }
+}
+
+/// A [`CredentialStore`] kept in memory only.
+#[derive(Default)]
+pub struct MemoryStore {
+    records: Mutex<Records>,
+}
+
+impl MemoryStore {
+    /// Creates an empty [`MemoryStore`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CredentialStore for MemoryStore {
+    fn get(&self, identifier: &str) -> io::Result<Option<StoredRecord>> {
+        Ok(lock(&self.records)?.get(identifier).cloned())
+    }
+
+    fn put(&self, identifier: &str, record: StoredRecord) -> io::Result<()> {
+        validate(identifier, Some(&record))?;
+        lock(&self.records)?.insert(identifier.to_string(), record);
+        Ok(())
+    }
+
+    fn delete(&self, identifier: &str) -> io::Result<bool> {
+        Ok(lock(&self.records)?.remove(identifier).is_some())
+    }
+
+    fn compare_and_swap(
+        &self,
+        identifier: &str,
+        current: Option<&StoredRecord>,
+        new: Option<StoredRecord>,
+    ) -> io::Result<bool> {
+        validate(identifier, new.as_ref())?;
+        let mut records = lock(&self.records)?;
+        if records.get(identifier) != current {
+            return Ok(false);
+        }
+
+        apply(&mut records, identifier, new);
+        Ok(true)
+    }
+
+    fn identifiers(&self) -> io::Result<Vec<String>> {
+        Ok(lock(&self.records)?.keys().cloned().collect())
+    }
+}
+
+/// A [`CredentialStore`] persisted to a single file, which is replaced atomically on every change.
+///
+/// The whole file is rewritten on every change, so it fits deployments with a moderate number of clients. Only
+/// one [`FileStore`] (in one process) may use a file at a time: two stores on the same file never corrupt it
+/// (each one writes its own temporary file), but the last change persisted overwrites the other store's ones.
+pub struct FileStore {
+    path: PathBuf,
+    records: Mutex<Records>,
+}
+
+impl FileStore {
+    /// Opens the store persisted at `path`, or creates an empty one if the file doesn't exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: The store's file. A temporary file is created next to it (same name, followed by the process id,
+    ///   a counter and the `.tmp` extension) while changes are written, and removed if writing it fails.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the file is truncated, has trailing bytes or an unknown format version.
+    /// * Any error of the file system while reading the file.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let records = match fs::read(&path) {
+            Ok(contents) => decode(&contents)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => return Err(error),
+        };
+
+        Ok(Self { path, records: Mutex::new(records) })
+    }
+
+    /// Writes `records` to a temporary file, syncs it and renames it over the store's file.
+    fn persist(&self, records: &Records) -> io::Result<()> {
+        let contents = encode(records)?;
+        let temp_path = self.temp_path();
+        let written = File::create(&temp_path).and_then(|mut file| {
+            file.write_all(&contents)?;
+            file.sync_all()?;
+            fs::rename(&temp_path, &self.path)
+        });
+        if let Err(error) = written {
+            let _ = fs::remove_file(&temp_path);
+            return Err(error);
+        }
+
+        // The rename itself is only durable once the directory is synced.
+        #[cfg(unix)]
+        {
+            let directory = match self.path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            File::open(directory)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// A temporary file name no other store (in this or another process) is using.
+    fn temp_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+        name.push(format!(".{}.{}.tmp", process::id(), TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        self.path.with_file_name(name)
+    }
+
+    /// Applies a change to a copy of the records, and only keeps it once persisted.
+    fn update(&self, identifier: &str, new: Option<StoredRecord>) -> io::Result<()> {
+        let mut records = lock(&self.records)?;
+        self.update_locked(&mut records, identifier, new)
+    }
+
+    fn update_locked(
+        &self,
+        records: &mut Records,
+        identifier: &str,
+        new: Option<StoredRecord>,
+    ) -> io::Result<()> {
+        let mut updated = records.clone();
+        apply(&mut updated, identifier, new);
+        self.persist(&updated)?;
+        *records = updated;
+        Ok(())
+    }
+}
+
+impl CredentialStore for FileStore {
+    fn get(&self, identifier: &str) -> io::Result<Option<StoredRecord>> {
+        Ok(lock(&self.records)?.get(identifier).cloned())
+    }
+
+    fn put(&self, identifier: &str, record: StoredRecord) -> io::Result<()> {
+        validate(identifier, Some(&record))?;
+        self.update(identifier, Some(record))
+    }
+
+    fn delete(&self, identifier: &str) -> io::Result<bool> {
+        let mut records = lock(&self.records)?;
+        if !records.contains_key(identifier) {
+            return Ok(false);
+        }
+
+        self.update_locked(&mut records, identifier, None)?;
+        Ok(true)
+    }
+
+    fn compare_and_swap(
+        &self,
+        identifier: &str,
+        current: Option<&StoredRecord>,
+        new: Option<StoredRecord>,
+    ) -> io::Result<bool> {
+        validate(identifier, new.as_ref())?;
+        let mut records = lock(&self.records)?;
+        if records.get(identifier) != current {
+            return Ok(false);
+        }
+
+        self.update_locked(&mut records, identifier, new)?;
+        Ok(true)
+    }
+
+    fn identifiers(&self) -> io::Result<Vec<String>> {
+        Ok(lock(&self.records)?.keys().cloned().collect())
+    }
+}
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+
+/// Locks the records, turning a poisoned lock (a thread panicked while holding it) into an error.
+fn lock(records: &Mutex<Records>) -> io::Result<MutexGuard<'_, Records>> {
+    records.lock().map_err(|_| io::Error::from(io::ErrorKind::Other))
+}
+
+/// Checks `identifier` and `record` fit the 2 bytes length prefixes of the [`FileStore`] format.
+/// Checks the limits of [`CredentialStore`], and that the metadata ids are the ones of the record (bytes 1 to 4
+/// of `RegistrationUpload::serialize_record`).
+fn validate(identifier: &str, record: Option<&StoredRecord>) -> io::Result<()> {
+    let record_len = record.map_or(0, |record| record.record.len());
+    if identifier.len() > u16::MAX as usize || record_len > u16::MAX as usize {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+
+    if let Some(record) = record {
+        let ids = [record.metadata.server_key_id.to_be_bytes(), record.metadata.oprf_seed_id.to_be_bytes()].concat();
+        if record.record.get(1..5) != Some(&ids[..]) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+    }
+    Ok(())
+}
+
+fn apply(records: &mut Records, identifier: &str, new: Option<StoredRecord>) {
+    match new {
+        Some(record) => {
+            records.insert(identifier.to_string(), record);
+        }
+        None => {
+            records.remove(identifier);
+        }
+    }
+}
+
+fn encode(records: &Records) -> io::Result<Vec<u8>> {
+    let mut output = vec![FILE_STORE_VERSION];
+    output.extend_from_slice(&(records.len() as u32).to_be_bytes());
+    for (identifier, record) in records {
+        write_field(&mut output, identifier.as_bytes())?;
+        write_field(&mut output, &record.record)?;
+        output.extend_from_slice(&record.metadata.server_key_id.to_be_bytes());
+        output.extend_from_slice(&record.metadata.oprf_seed_id.to_be_bytes());
+        output.extend_from_slice(&record.metadata.registered_at.to_be_bytes());
+        output.extend_from_slice(&record.metadata.updated_at.to_be_bytes());
+    }
+    Ok(output)
+}
+
+fn decode(input: &[u8]) -> io::Result<Records> {
+    let mut reader = input;
+    if read_u8(&mut reader)? != FILE_STORE_VERSION {
+        return Err(invalid_data());
+    }
+
+    let count = u32::from_be_bytes(read_array(&mut reader)?);
+    let mut records = BTreeMap::new();
+    for _ in 0..count {
+        let identifier = String::from_utf8(read_field(&mut reader)?).map_err(|_| invalid_data())?;
+        let record = read_field(&mut reader)?;
+        let metadata = RecordMetadata {
+            server_key_id: u16::from_be_bytes(read_array(&mut reader)?),
+            oprf_seed_id: u16::from_be_bytes(read_array(&mut reader)?),
+            registered_at: u64::from_be_bytes(read_array(&mut reader)?),
+            updated_at: u64::from_be_bytes(read_array(&mut reader)?),
+        };
+        if records.insert(identifier, StoredRecord { record, metadata }).is_some() {
+            return Err(invalid_data());
+        }
+    }
+
+    if !reader.is_empty() {
+        return Err(invalid_data());
+    }
+    Ok(records)
+}
+
+fn read_array<const N: usize>(reader: &mut &[u8]) -> io::Result<[u8; N]> {
+    let mut array = [0u8; N];
+    for byte in array.iter_mut() {
+        *byte = read_u8(reader)?;
+    }
+    Ok(array)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use sha2::Sha512;
+    use crate::config::{CipherSuite, OpaqueConfig};
+    use crate::envelope::EnvelopeMode;
+    use crate::server_setup::ServerSetup;
+
+    fn record(byte: u8, server_key_id: u16) -> StoredRecord {
+        let header = [&[0x01][..], &server_key_id.to_be_bytes(), &1u16.to_be_bytes()].concat();
+        StoredRecord {
+            record: [header, vec![byte; 32]].concat(),
+            metadata: RecordMetadata { server_key_id, oprf_seed_id: 1, registered_at: 10, updated_at: 20 },
+        }
+    }
+
+    fn round_trip(store: &dyn CredentialStore) {
+        assert_eq!(store.get("alice").unwrap(), None);
+        store.put("alice", record(1, 1)).unwrap();
+        store.put("bob", record(2, 2)).unwrap();
+        assert_eq!(store.get("alice").unwrap(), Some(record(1, 1)));
+        assert_eq!(store.identifiers().unwrap(), vec!["alice".to_string(), "bob".to_string()]);
+        assert_eq!(store.oprf_seed_ids().unwrap(), vec![1, 1]);
+
+        store.put("alice", record(3, 1)).unwrap();
+        assert_eq!(store.get("alice").unwrap(), Some(record(3, 1)));
+        assert!(store.delete("alice").unwrap());
+        assert!(!store.delete("alice").unwrap());
+        assert_eq!(store.get("alice").unwrap(), None);
+    }
+
+    fn compare_and_swap(store: &dyn CredentialStore) {
+        // Registration: only succeeds while no record exists.
+        assert!(store.compare_and_swap("alice", None, Some(record(1, 1))).unwrap());
+        assert!(!store.compare_and_swap("alice", None, Some(record(2, 1))).unwrap());
+        assert_eq!(store.get("alice").unwrap(), Some(record(1, 1)));
+
+        // Two logins upgrading the same record: the second one sees a stale record.
+        assert!(store.compare_and_swap("alice", Some(&record(1, 1)), Some(record(1, 2))).unwrap());
+        assert!(!store.compare_and_swap("alice", Some(&record(1, 1)), Some(record(1, 3))).unwrap());
+        assert_eq!(store.get("alice").unwrap(), Some(record(1, 2)));
+
+        assert!(!store.compare_and_swap("alice", Some(&record(1, 1)), None).unwrap());
+        assert!(store.compare_and_swap("alice", Some(&record(1, 2)), None).unwrap());
+        assert_eq!(store.get("alice").unwrap(), None);
+    }
+
+    fn oversize_input(store: &dyn CredentialStore) {
+        let long_identifier = "a".repeat(u16::MAX as usize + 1);
+        let mut long_record = record(1, 1);
+        long_record.record = vec![0; u16::MAX as usize + 1];
+
+        let error = store.put(&long_identifier, record(1, 1)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = store.put("alice", long_record.clone()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = store.compare_and_swap("alice", None, Some(long_record)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(store.identifiers().unwrap().is_empty());
+    }
+
+    fn mismatched_metadata(store: &dyn CredentialStore) {
+        let mut wrong_key = record(1, 1);
+        wrong_key.metadata.server_key_id = 2;
+        let mut wrong_seed = record(1, 1);
+        wrong_seed.metadata.oprf_seed_id = 0;
+        let mut truncated = record(1, 1);
+        truncated.record.truncate(4);
+
+        for invalid in [wrong_key, wrong_seed, truncated].iter() {
+            assert_eq!(store.put("alice", invalid.clone()).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            let error = store.compare_and_swap("alice", None, Some(invalid.clone())).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(store.identifiers().unwrap().is_empty());
+    }
+
+    #[test]
+    fn stored_records_take_the_ids_of_the_record() {
+        let config = OpaqueConfig::new(CipherSuite::Ristretto255Sha512, EnvelopeMode::Internal, Vec::new(), None, None);
+        let mut setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+        setup.rotate_server_key(&config, &mut OsRng).unwrap();
+        setup.rotate_oprf_seed(&mut OsRng).unwrap();
+        setup.rotate_oprf_seed(&mut OsRng).unwrap();
+        let record = RegistrationUpload::fake_record::<Sha512>(&config, &setup, "alice").unwrap();
+
+        let stored = StoredRecord::new(&record, 10, 20).unwrap();
+        let ids = setup.fake_record_ids::<Sha512>(b"alice").unwrap();
+        assert_eq!((stored.metadata.server_key_id, stored.metadata.oprf_seed_id), ids);
+        assert_eq!(stored.record, record.serialize_record().unwrap());
+        let store = MemoryStore::new();
+        store.put("alice", stored.clone()).unwrap();
+        assert_eq!(store.get("alice").unwrap(), Some(stored));
+    }
+
+    #[test]
+    fn memory_store() {
+        round_trip(&MemoryStore::new());
+        compare_and_swap(&MemoryStore::new());
+        oversize_input(&MemoryStore::new());
+        mismatched_metadata(&MemoryStore::new());
+    }
+
+    #[test]
+    fn file_store() {
+        let directory = tempfile::tempdir().unwrap();
+        round_trip(&FileStore::open(directory.path().join("round_trip")).unwrap());
+        compare_and_swap(&FileStore::open(directory.path().join("compare_and_swap")).unwrap());
+        oversize_input(&FileStore::open(directory.path().join("oversize_input")).unwrap());
+        mismatched_metadata(&FileStore::open(directory.path().join("mismatched_metadata")).unwrap());
+    }
+
+    #[test]
+    fn file_store_persists_changes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("records");
+        {
+            let store = FileStore::open(&path).unwrap();
+            store.put("alice", record(1, 1)).unwrap();
+            store.put("bob", record(2, 1)).unwrap();
+            store.delete("bob").unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.get("alice").unwrap(), Some(record(1, 1)));
+        assert_eq!(store.get("bob").unwrap(), None);
+
+        // No temporary file is left behind.
+        let files: Vec<_> = fs::read_dir(directory.path()).unwrap().collect();
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn file_store_rejects_malformed_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("records");
+        FileStore::open(&path).unwrap().put("alice", record(1, 1)).unwrap();
+        let contents = fs::read(&path).unwrap();
+
+        for len in 0..contents.len() {
+            fs::write(&path, &contents[..len]).unwrap();
+            assert_eq!(FileStore::open(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut trailing = contents.clone();
+        trailing.push(0);
+        fs::write(&path, &trailing).unwrap();
+        assert_eq!(FileStore::open(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let mut unknown_version = contents;
+        unknown_version[0] = 0xff;
+        fs::write(&path, &unknown_version).unwrap();
+        assert_eq!(FileStore::open(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn temp_paths_are_unique() {
+        let directory = tempfile::tempdir().unwrap();
+        let first = FileStore::open(directory.path().join("records")).unwrap();
+        let second = FileStore::open(directory.path().join("records")).unwrap();
+        assert_ne!(first.temp_path(), second.temp_path());
+        assert_ne!(first.temp_path(), first.temp_path());
+    }
+}
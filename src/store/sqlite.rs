@@ -0,0 +1,517 @@
+//! # SQLite store
+//!
+//! A [`CredentialStore`] on a SQLite database, for single-node deployments (requires the `sqlite` feature).
+//!
+//! The schema is created and migrated by [`SqliteStore::open`]: the database's `user_version` holds the number
+//! of migrations already applied, and the missing ones are applied in a single transaction, so a crash while
+//! migrating leaves the database as it was. Databases migrated by a newer version of this crate are refused.
+//!
+//! Every record has a revision, starting at 1 and increased on every change (see [`SqliteStore::revision`]), so
+//! applications can tell whether a record changed between two reads (i.e. when auditing record updates).
+//!
+//! ## Registration
+//!
+//! The record of a registration is only known once the client sends its `RegistrationUpload`, after the server
+//! already answered with a `RegistrationResponse`. [`SqliteStore::start_registration`] marks the identifier as
+//! pending when the response is created, and [`SqliteStore::finalize_registration`] stores the record and clears
+//! the mark in a single transaction, failing if the identifier already has a record. So an abandoned, interrupted
+//! or concurrent registration never overwrites a valid record.
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+use crate::store::{validate, CredentialStore, RecordMetadata, StoredRecord};
+
+/// Schema migrations, applied in order. The database's `user_version` is the number of migrations applied, so
+/// schema changes MUST be appended as new migrations, never made to the existing ones.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE records (
+        identifier TEXT PRIMARY KEY NOT NULL,
+        record BLOB NOT NULL,
+        server_key_id INTEGER NOT NULL,
+        oprf_seed_id INTEGER NOT NULL,
+        registered_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        revision INTEGER NOT NULL
+    );
+    CREATE TABLE pending_registrations (
+        identifier TEXT PRIMARY KEY NOT NULL,
+        started_at INTEGER NOT NULL
+    );",
+];
+
+/// How long a write waits for another connection to release the database (i.e. another server sharing it)
+/// before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A [`CredentialStore`] on a SQLite database.
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at `path`, and applies the missing migrations.
+    ///
+    /// # Exceptions
+    ///
+    /// * `InvalidData`: When the database was migrated by a newer version (it has unknown migrations).
+    /// * `Other`: Any error of SQLite.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut connection = Connection::open(path).map_err(sql_error)?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(sql_error)?;
+        migrate(&mut connection)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    /// Number of migrations applied to the database (its schema version).
+    pub fn schema_version(&self) -> io::Result<usize> {
+        schema_version(&*self.lock()?)
+    }
+
+    /// Revision of the record of `identifier`, or ```None``` when `identifier` isn't registered.
+    pub fn revision(&self, identifier: &str) -> io::Result<Option<u64>> {
+        self.lock()?
+            .query_row("SELECT revision FROM records WHERE identifier = ?1", params![identifier], |row| {
+                row.get::<_, i64>(0)
+            })
+            .optional()
+            .map(|revision| revision.map(|revision| revision as u64))
+            .map_err(sql_error)
+    }
+
+    /// Marks a registration of `identifier` as pending, to be called along with `CreateRegistrationResponse`.
+    /// Starting it again (i.e. when the client retries) just refreshes it.
+    ///
+    /// # Arguments
+    ///
+    /// * `identifier`: User's credential identifier.
+    /// * `now`: The current time, in seconds since the UNIX epoch.
+    ///
+    /// # Exceptions
+    ///
+    /// * `AlreadyExists`: When `identifier` already has a record.
+    pub fn start_registration(&self, identifier: &str, now: u64) -> io::Result<()> {
+        let mut connection = self.lock()?;
+        let transaction = begin(&mut connection)?;
+        if get_record(&transaction, identifier)?.is_some() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+
+        transaction
+            .execute(
+                "INSERT OR REPLACE INTO pending_registrations (identifier, started_at) VALUES (?1, ?2)",
+                params![identifier, now as i64],
+            )
+            .map_err(sql_error)?;
+        transaction.commit().map_err(sql_error)
+    }
+
+    /// Stores the record of a pending registration of `identifier`, and clears the pending mark, atomically.
+    ///
+    /// # Arguments
+    ///
+    /// * `identifier`: User's credential identifier.
+    /// * `record`: The record built from the client's `RegistrationUpload`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `NotFound`: When no registration of `identifier` is pending (it was never started, or already finalized
+    ///   or aborted).
+    /// * `AlreadyExists`: When `identifier` already has a record. Nothing is changed.
+    /// * `InvalidInput`: When `identifier` or the record is longer than 65535 bytes, or the metadata ids aren't
+    ///   the ones of the record. Nothing is changed.
+    pub fn finalize_registration(&self, identifier: &str, record: StoredRecord) -> io::Result<()> {
+        validate(identifier, Some(&record))?;
+        let mut connection = self.lock()?;
+        let transaction = begin(&mut connection)?;
+        let pending = transaction
+            .execute("DELETE FROM pending_registrations WHERE identifier = ?1", params![identifier])
+            .map_err(sql_error)?;
+        if pending == 0 {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        if get_record(&transaction, identifier)?.is_some() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+
+        write_record(&transaction, identifier, &record, 1)?;
+        transaction.commit().map_err(sql_error)
+    }
+
+    /// Drops the pending registration of `identifier`, if any.
+    pub fn abort_registration(&self, identifier: &str) -> io::Result<()> {
+        self.lock()?
+            .execute("DELETE FROM pending_registrations WHERE identifier = ?1", params![identifier])
+            .map_err(sql_error)?;
+        Ok(())
+    }
+
+    /// Drops the pending registrations started before `before` (seconds since the UNIX epoch).
+    ///
+    /// # Returns
+    ///
+    /// * `dropped`: The number of registrations dropped.
+    pub fn expire_registrations(&self, before: u64) -> io::Result<usize> {
+        self.lock()?
+            .execute("DELETE FROM pending_registrations WHERE started_at < ?1", params![before as i64])
+            .map_err(sql_error)
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, Connection>> {
+        self.connection.lock().map_err(|_| io::Error::from(io::ErrorKind::Other))
+    }
+}
+
+impl CredentialStore for SqliteStore {
+    fn get(&self, identifier: &str) -> io::Result<Option<StoredRecord>> {
+        Ok(get_record(&*self.lock()?, identifier)?.map(|(record, _)| record))
+    }
+
+    fn put(&self, identifier: &str, record: StoredRecord) -> io::Result<()> {
+        validate(identifier, Some(&record))?;
+        let mut connection = self.lock()?;
+        let transaction = begin(&mut connection)?;
+        let revision = get_record(&transaction, identifier)?.map_or(1, |(_, revision)| revision + 1);
+        write_record(&transaction, identifier, &record, revision)?;
+        transaction.commit().map_err(sql_error)
+    }
+
+    fn delete(&self, identifier: &str) -> io::Result<bool> {
+        let deleted = self
+            .lock()?
+            .execute("DELETE FROM records WHERE identifier = ?1", params![identifier])
+            .map_err(sql_error)?;
+        Ok(deleted > 0)
+    }
+
+    fn compare_and_swap(
+        &self,
+        identifier: &str,
+        current: Option<&StoredRecord>,
+        new: Option<StoredRecord>,
+    ) -> io::Result<bool> {
+        validate(identifier, new.as_ref())?;
+        let mut connection = self.lock()?;
+        let transaction = begin(&mut connection)?;
+        let stored = get_record(&transaction, identifier)?;
+        if stored.as_ref().map(|(record, _)| record) != current {
+            return Ok(false);
+        }
+
+        match new {
+            Some(record) => {
+                let revision = stored.map_or(1, |(_, revision)| revision + 1);
+                write_record(&transaction, identifier, &record, revision)?;
+            }
+            None => {
+                transaction
+                    .execute("DELETE FROM records WHERE identifier = ?1", params![identifier])
+                    .map_err(sql_error)?;
+            }
+        }
+        transaction.commit().map_err(sql_error)?;
+        Ok(true)
+    }
+
+    fn identifiers(&self) -> io::Result<Vec<String>> {
+        let connection = self.lock()?;
+        let mut statement = connection
+            .prepare("SELECT identifier FROM records ORDER BY identifier")
+            .map_err(sql_error)?;
+        let identifiers = statement
+            .query_map([], |row| row.get(0))
+            .map_err(sql_error)?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(sql_error)?;
+        Ok(identifiers)
+    }
+
+    fn oprf_seed_ids(&self) -> io::Result<Vec<u16>> {
+        let connection = self.lock()?;
+        let mut statement = connection
+            .prepare("SELECT oprf_seed_id FROM records ORDER BY identifier")
+            .map_err(sql_error)?;
+        let seed_ids = statement
+            .query_map([], |row| row.get(0))
+            .map_err(sql_error)?
+            .collect::<Result<Vec<u16>, _>>()
+            .map_err(sql_error)?;
+        Ok(seed_ids)
+    }
+}
+
+// ||===============================================================================================
+// || Helper Methods ||
+// ||===============================================================================================
+
+fn sql_error(error: rusqlite::Error) -> io::Error {
+    io::Error::other(error)
+}
+
+/// Starts a transaction holding the write lock from its first statement, so the records it reads can't be
+/// changed by another connection before it commits (a deferred transaction would only take the lock on its first
+/// write, and fail with `SQLITE_BUSY` instead of waiting when another connection wrote meanwhile).
+fn begin(connection: &mut Connection) -> io::Result<Transaction<'_>> {
+    connection.transaction_with_behavior(TransactionBehavior::Immediate).map_err(sql_error)
+}
+
+fn schema_version(connection: &Connection) -> io::Result<usize> {
+    let version: i64 = connection
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(sql_error)?;
+    Ok(version as usize)
+}
+
+fn migrate(connection: &mut Connection) -> io::Result<()> {
+    let transaction = begin(connection)?;
+    let version = schema_version(&transaction)?;
+    if version > MIGRATIONS.len() {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    for migration in &MIGRATIONS[version..] {
+        transaction.execute_batch(migration).map_err(sql_error)?;
+    }
+    transaction
+        .pragma_update(None, "user_version", MIGRATIONS.len() as i64)
+        .map_err(sql_error)?;
+    transaction.commit().map_err(sql_error)
+}
+
+/// Reads the record of `identifier` along with its revision.
+fn get_record(connection: &Connection, identifier: &str) -> io::Result<Option<(StoredRecord, u64)>> {
+    connection
+        .query_row(
+            "SELECT record, server_key_id, oprf_seed_id, registered_at, updated_at, revision
+             FROM records WHERE identifier = ?1",
+            params![identifier],
+            |row| {
+                let record = StoredRecord {
+                    record: row.get(0)?,
+                    metadata: RecordMetadata {
+                        server_key_id: row.get(1)?,
+                        oprf_seed_id: row.get(2)?,
+                        registered_at: row.get::<_, i64>(3)? as u64,
+                        updated_at: row.get::<_, i64>(4)? as u64,
+                    },
+                };
+                Ok((record, row.get::<_, i64>(5)? as u64))
+            },
+        )
+        .optional()
+        .map_err(sql_error)
+}
+
+fn write_record(transaction: &Transaction, identifier: &str, record: &StoredRecord, revision: u64) -> io::Result<()> {
+    transaction
+        .execute(
+            "INSERT OR REPLACE INTO records
+             (identifier, record, server_key_id, oprf_seed_id, registered_at, updated_at, revision)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                identifier,
+                record.record,
+                record.metadata.server_key_id,
+                record.metadata.oprf_seed_id,
+                record.metadata.registered_at as i64,
+                record.metadata.updated_at as i64,
+                revision as i64,
+            ],
+        )
+        .map_err(sql_error)?;
+    Ok(())
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn record(record: &[u8], oprf_seed_id: u16) -> StoredRecord {
+        let header = [&[0x01, 0, 0][..], &oprf_seed_id.to_be_bytes()].concat();
+        StoredRecord {
+            record: [&header[..], record].concat(),
+            metadata: RecordMetadata { server_key_id: 0, oprf_seed_id, registered_at: 1_000, updated_at: 1_000 },
+        }
+    }
+
+    fn database() -> (TempDir, PathBuf) {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("records.sqlite");
+        (directory, path)
+    }
+
+    #[test]
+    fn migrations() {
+        let (_directory, path) = database();
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        store.put("alice", record(b"alice", 0)).unwrap();
+        drop(store);
+
+        // Reopening doesn't migrate again, and keeps the records.
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        assert_eq!(store.get("alice").unwrap(), Some(record(b"alice", 0)));
+        drop(store);
+
+        // A database migrated by a newer version is refused, and left untouched.
+        let connection = Connection::open(&path).unwrap();
+        connection.pragma_update(None, "user_version", 99).unwrap();
+        drop(connection);
+        assert_eq!(SqliteStore::open(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(schema_version(&Connection::open(&path).unwrap()).unwrap(), 99);
+    }
+
+    #[test]
+    fn failed_migrations_are_rolled_back() {
+        let (_directory, path) = database();
+        let connection = Connection::open(&path).unwrap();
+        // A table clashing with the first migration makes it fail half-way.
+        connection.execute_batch("CREATE TABLE pending_registrations (identifier TEXT);").unwrap();
+        drop(connection);
+
+        assert!(SqliteStore::open(&path).is_err());
+        let connection = Connection::open(&path).unwrap();
+        assert_eq!(schema_version(&connection).unwrap(), 0);
+        let records_table: Option<String> = connection
+            .query_row("SELECT name FROM sqlite_master WHERE name = 'records'", [], |row| row.get(0))
+            .optional()
+            .unwrap();
+        assert_eq!(records_table, None);
+    }
+
+    #[test]
+    fn revisions() {
+        let (_directory, path) = database();
+        let store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.revision("alice").unwrap(), None);
+
+        store.put("alice", record(b"alice", 0)).unwrap();
+        assert_eq!(store.revision("alice").unwrap(), Some(1));
+        store.put("alice", record(b"alice again", 0)).unwrap();
+        assert_eq!(store.revision("alice").unwrap(), Some(2));
+        assert!(store.compare_and_swap("alice", Some(&record(b"alice again", 0)), Some(record(b"alice", 1))).unwrap());
+        assert_eq!(store.revision("alice").unwrap(), Some(3));
+
+        // A failed compare-and-swap doesn't change the revision.
+        assert!(!store.compare_and_swap("alice", None, Some(record(b"mallory", 0))).unwrap());
+        assert_eq!(store.revision("alice").unwrap(), Some(3));
+
+        assert!(store.delete("alice").unwrap());
+        assert!(!store.delete("alice").unwrap());
+        assert_eq!(store.revision("alice").unwrap(), None);
+        store.put("alice", record(b"alice", 0)).unwrap();
+        assert_eq!(store.revision("alice").unwrap(), Some(1));
+    }
+
+    #[test]
+    fn registration() {
+        let (_directory, path) = database();
+        let store = SqliteStore::open(&path).unwrap();
+        let alice = record(b"alice", 0);
+
+        // Only finalized once started.
+        let error = store.finalize_registration("alice", alice.clone()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        store.start_registration("alice", 1_000).unwrap();
+        store.start_registration("alice", 1_001).unwrap();
+        store.finalize_registration("alice", alice.clone()).unwrap();
+        assert_eq!(store.get("alice").unwrap(), Some(alice.clone()));
+        assert_eq!(store.revision("alice").unwrap(), Some(1));
+
+        // Finalizing clears the pending mark, so it can't be finalized twice.
+        let error = store.finalize_registration("alice", record(b"mallory", 0)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let error = store.start_registration("alice", 1_002).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+
+        // A record stored meanwhile (i.e. by another server) is never overwritten.
+        store.start_registration("bob", 1_000).unwrap();
+        store.put("bob", record(b"bob", 0)).unwrap();
+        let error = store.finalize_registration("bob", record(b"mallory", 0)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(store.get("bob").unwrap(), Some(record(b"bob", 0)));
+        assert_eq!(store.revision("bob").unwrap(), Some(1));
+    }
+
+    #[test]
+    fn abort_and_expire_registrations() {
+        let (_directory, path) = database();
+        let store = SqliteStore::open(&path).unwrap();
+
+        store.start_registration("alice", 1_000).unwrap();
+        store.abort_registration("alice").unwrap();
+        store.abort_registration("alice").unwrap();
+        let error = store.finalize_registration("alice", record(b"alice", 0)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+
+        store.start_registration("bob", 1_000).unwrap();
+        store.start_registration("carol", 2_000).unwrap();
+        assert_eq!(store.expire_registrations(1_500).unwrap(), 1);
+        assert_eq!(store.expire_registrations(1_500).unwrap(), 0);
+        let error = store.finalize_registration("bob", record(b"bob", 0)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        store.finalize_registration("carol", record(b"carol", 0)).unwrap();
+        assert_eq!(store.identifiers().unwrap(), vec!["carol".to_string()]);
+    }
+
+    #[test]
+    fn compare_and_swap_conflicts() {
+        let (_directory, path) = database();
+        // Two servers sharing the database.
+        let first = SqliteStore::open(&path).unwrap();
+        let second = SqliteStore::open(&path).unwrap();
+
+        assert!(first.compare_and_swap("alice", None, Some(record(b"alice", 0))).unwrap());
+        assert!(!second.compare_and_swap("alice", None, Some(record(b"mallory", 0))).unwrap());
+
+        // Both read the record and upgrade it, only the first upgrade is kept.
+        let current = second.get("alice").unwrap().unwrap();
+        assert!(first.compare_and_swap("alice", Some(&current), Some(record(b"alice", 1))).unwrap());
+        assert!(!second.compare_and_swap("alice", Some(&current), Some(record(b"alice", 2))).unwrap());
+        assert!(!second.compare_and_swap("alice", Some(&current), None).unwrap());
+        assert_eq!(second.get("alice").unwrap(), Some(record(b"alice", 1)));
+        assert_eq!(second.oprf_seed_ids().unwrap(), vec![1]);
+
+        assert!(second.compare_and_swap("alice", Some(&record(b"alice", 1)), None).unwrap());
+        assert_eq!(first.get("alice").unwrap(), None);
+    }
+
+    #[test]
+    fn writers_wait_for_each_other() {
+        let (_directory, path) = database();
+        let store = SqliteStore::open(&path).unwrap();
+        store.put("alice", record(b"alice", 0)).unwrap();
+
+        // Another server holds the write lock for a while: writes wait for it instead of failing.
+        let mut connection = Connection::open(&path).unwrap();
+        let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate).unwrap();
+        transaction.execute("UPDATE records SET revision = revision + 1", []).unwrap();
+        std::thread::scope(|scope| {
+            let swap = scope.spawn(|| {
+                store.compare_and_swap("alice", Some(&record(b"alice", 0)), Some(record(b"alice", 1)))
+            });
+            std::thread::sleep(Duration::from_millis(200));
+            transaction.commit().unwrap();
+            assert!(swap.join().unwrap().unwrap());
+        });
+        assert_eq!(store.revision("alice").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn oversize_records_are_rejected() {
+        let (_directory, path) = database();
+        let store = SqliteStore::open(&path).unwrap();
+        let long_record = record(&vec![0; u16::MAX as usize + 1], 0);
+
+        store.start_registration("alice", 1_000).unwrap();
+        let error = store.finalize_registration("alice", long_record.clone()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        let error = store.put("alice", long_record).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        store.finalize_registration("alice", record(b"alice", 0)).unwrap();
+    }
+}
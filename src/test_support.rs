@@ -0,0 +1,164 @@
+//! Fixtures shared by the unit tests: a registration of `"alice"` and logins against its record.
+use std::io;
+use rand::rngs::OsRng;
+use crate::ake::{AkeProtocol, ClientState, ServerState};
+use crate::config::{CipherSuite, OpaqueConfig};
+use crate::envelope::EnvelopeMode;
+use crate::kdf::SuiteHash;
+use crate::messages::ake::{KE1, KE2, KE3};
+use crate::messages::registration::{RegistrationRequest, RegistrationResponse, RegistrationUpload};
+use crate::server_setup::ServerSetup;
+use crate::session::SessionKey;
+
+/// The identifier every fixture registers.
+pub(crate) const IDENTIFIER: &str = "alice";
+
+/// A Ristretto255-SHA512 config with internal keys and the default AKE.
+pub(crate) fn config() -> OpaqueConfig {
+    OpaqueConfig::new(CipherSuite::Ristretto255Sha512, EnvelopeMode::Internal, Vec::new(), None, None)
+}
+
+/// Registers [`IDENTIFIER`] with `pwd`, every message going through its serialization, and the record
+/// through the store's.
+///
+/// # Returns
+///
+/// * `record`: The record stored by the server.
+/// * `export_key`: The client's export key.
+pub(crate) fn register<D>(
+    config: &OpaqueConfig,
+    setup: &ServerSetup,
+    pwd: &[u8],
+    client_pri_key: Option<&[u8]>,
+) -> (RegistrationUpload, Vec<u8>)
+where
+    D: SuiteHash,
+{
+    let (request, blind) = RegistrationRequest::create_registration_request::<D, _>(config, pwd, &mut OsRng).unwrap();
+    let request = RegistrationRequest::deserialize(config, &request.serialize().unwrap()).unwrap();
+    let response = RegistrationResponse::create_registration_response::<D>(config, &request, setup, IDENTIFIER).unwrap();
+    let response = RegistrationResponse::deserialize(config, &response.serialize().unwrap()).unwrap();
+    let (record, export_key) = RegistrationUpload::finalize_request::<D, _>(
+        config, pwd, &blind, &response, client_pri_key, None, &mut OsRng,
+    ).unwrap();
+    let record = RegistrationUpload::deserialize::<D>(config, &record.serialize().unwrap()).unwrap();
+    (RegistrationUpload::deserialize_record::<D>(config, &record.serialize_record().unwrap()).unwrap(), export_key)
+}
+
+/// The server extensions sent by [`start_login`], none with KEM which can't authenticate them.
+pub(crate) fn server_extensions(ake_protocol: AkeProtocol) -> Option<&'static [u8]> {
+    match ake_protocol {
+        AkeProtocol::Kem(_) => None,
+        _ => Some(b"challenge"),
+    }
+}
+
+/// A server holding the record of [`IDENTIFIER`], registered with `"password"`.
+pub(crate) struct Server {
+    pub(crate) config: OpaqueConfig,
+    pub(crate) setup: ServerSetup,
+    pub(crate) record: RegistrationUpload,
+}
+
+/// Sets up a server under `config` and registers [`IDENTIFIER`] on it.
+pub(crate) fn server<D>(config: OpaqueConfig) -> Server
+where
+    D: SuiteHash,
+{
+    let setup = ServerSetup::new(&config, &mut OsRng).unwrap();
+    let (record, _) = register::<D>(&config, &setup, b"password", None);
+    Server { config, setup, record }
+}
+
+/// A login waiting for `ServerFinish`: the server's state, the client's `KE3` and the client's outputs.
+pub(crate) struct PendingLogin {
+    pub(crate) ake_protocol: AkeProtocol,
+    pub(crate) server_state: ServerState,
+    pub(crate) ke3: KE3,
+    pub(crate) client_session_key: SessionKey,
+    pub(crate) export_key: Vec<u8>,
+    pub(crate) server_extensions: Option<Vec<u8>>,
+    pub(crate) client_info: Option<Vec<u8>>,
+    pub(crate) new_export_key: Option<Vec<u8>>,
+}
+
+/// The outputs of a login: the client's session key and export key, the server's session key, the
+/// extensions received by each side, and the re-bound record.
+pub(crate) struct Login {
+    pub(crate) ake_protocol: AkeProtocol,
+    pub(crate) client_session_key: SessionKey,
+    pub(crate) export_key: Vec<u8>,
+    pub(crate) server_session_key: SessionKey,
+    pub(crate) server_extensions: Option<Vec<u8>>,
+    pub(crate) client_extensions: Option<Vec<u8>>,
+    pub(crate) client_info: Option<Vec<u8>>,
+    pub(crate) record_update: Option<RegistrationUpload>,
+    pub(crate) new_export_key: Option<Vec<u8>>,
+}
+
+/// Runs a login with `pwd` against `record` up to `KE3`, every message going through its serialization.
+///
+/// The client sends `"device"` as client info and `"answer"` as extensions, and the server sends
+/// [`server_extensions`].
+pub(crate) fn start_login<D>(
+    config: &OpaqueConfig,
+    setup: &ServerSetup,
+    record: &RegistrationUpload,
+    pwd: &[u8],
+) -> io::Result<PendingLogin>
+where
+    D: SuiteHash,
+{
+    let (client_state, ke1) = ClientState::client_init::<D, _>(config, &mut OsRng, pwd, Some(b"device"), None)?;
+    let ke1 = KE1::deserialize(config, &ke1.serialize()?)?;
+
+    let (server_state, ke2, client_info) = ServerState::server_init::<D, _>(
+        config, &mut OsRng, setup, record, IDENTIFIER, None, &ke1, server_extensions(config.ake_protocol), None, None, None,
+    )?;
+    let ke2 = KE2::deserialize(config, &ke2.serialize()?)?;
+
+    let (ke3, client_session_key, export_key, server_extensions, new_export_key) =
+        client_state.client_finish::<D, _>(config, &mut OsRng, pwd, &ke2, None, Some(b"answer"))?;
+    Ok(PendingLogin {
+        ake_protocol: config.ake_protocol,
+        server_state: ServerState::deserialize(&server_state.serialize()?)?,
+        ke3: KE3::deserialize(config, &ke3.serialize()?)?,
+        client_session_key,
+        export_key,
+        server_extensions,
+        client_info,
+        new_export_key,
+    })
+}
+
+/// Runs a full login with `pwd` against `record`, every message going through its serialization.
+pub(crate) fn login<D>(config: &OpaqueConfig, setup: &ServerSetup, record: &RegistrationUpload, pwd: &[u8]) -> io::Result<Login>
+where
+    D: SuiteHash,
+{
+    let pending = start_login::<D>(config, setup, record, pwd)?;
+    let (server_session_key, client_extensions, record_update) =
+        pending.server_state.server_finish::<D>(config, setup, &pending.ke3, None)?;
+    Ok(Login {
+        ake_protocol: pending.ake_protocol,
+        client_session_key: pending.client_session_key,
+        export_key: pending.export_key,
+        server_session_key,
+        server_extensions: pending.server_extensions,
+        client_extensions,
+        client_info: pending.client_info,
+        record_update,
+        new_export_key: pending.new_export_key,
+    })
+}
+
+/// Registers [`IDENTIFIER`] with `"password"` under `config` and logs in, returning the client's and the
+/// server's session keys.
+pub(crate) fn session_keys<D>(config: &OpaqueConfig) -> (SessionKey, SessionKey)
+where
+    D: SuiteHash,
+{
+    let server = server::<D>(config.clone());
+    let login = login::<D>(&server.config, &server.setup, &server.record, b"password").unwrap();
+    (login.client_session_key, login.server_session_key)
+}
@@ -0,0 +1,396 @@
+//! Online guessing limits.
+//!
+//! OPAQUE keeps the password safe from offline attacks, but every login is still one online guess. A
+//! [`Throttle`] limits those guesses per credential identifier, and is driven by the server functions themselves,
+//! so every login goes through it in the same way:
+//!
+//! - `ServerInit` calls [`Throttle::check`] before the OPRF evaluation, and fails without any computation when
+//!   the identifier is throttled, then [`Throttle::record_failure`]: the client can check its guess against
+//!   `KE2` alone, so the attempt is a failure whether `KE3` is sent or not;
+//! - `ServerFinish` calls [`Throttle::record_success`] when it authenticates the client, clearing the failure.
+//!
+//! Logins of unknown identifiers, answered with a fake record (see `CreateCredentialResponse`), go through the
+//! same calls with the identifier as given by the client, and never succeed. So they're throttled
+//! exactly like the logins of registered clients, and the throttle doesn't reveal whether an identifier is
+//! registered.
+//!
+//! [`TokenBucketThrottle`] allows bursts of attempts refilled over time, delays the next attempt exponentially
+//! after each failure and locks the identifier out for a while after too many consecutive failures.
+//!
+//! A throttled attempt is reported as a `PermissionDenied` error holding a [`Throttled`], see [`retry_after`].
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The error held by the `PermissionDenied` error of a throttled attempt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Throttled {
+    /// How long until the identifier can be tried again.
+    pub retry_after: Duration,
+    /// Whether the identifier is locked out (after too many failures), rather than rate limited.
+    pub locked_out: bool,
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.locked_out {
+            write!(f, "locked out, retry after {}s", self.retry_after.as_secs())
+        } else {
+            write!(f, "throttled, retry after {}s", self.retry_after.as_secs())
+        }
+    }
+}
+
+impl Error for Throttled {}
+
+/// How long until the identifier can be tried again, when `error` reports a throttled attempt.
+pub fn retry_after(error: &io::Error) -> Option<Duration> {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<Throttled>())
+        .map(|throttled| throttled.retry_after)
+}
+
+/// Limits the login attempts per credential identifier.
+pub trait Throttle {
+    /// Called before each login attempt of `identifier`.
+    ///
+    /// # Exceptions
+    ///
+    /// * `PermissionDenied`: When the attempt is throttled. The error holds a [`Throttled`].
+    fn check(&self, identifier: &str) -> io::Result<()>;
+
+    /// Called after a successful login of `identifier`, whose attempt was recorded as a failure by `ServerInit`.
+    fn record_success(&self, identifier: &str) -> io::Result<()>;
+
+    /// Called on every login attempt of `identifier` that passed [`Throttle::check`], until the login succeeds.
+    fn record_failure(&self, identifier: &str) -> io::Result<()>;
+}
+
+/// Parameters of a [`TokenBucketThrottle`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThrottleConfig {
+    /// Attempts allowed in a burst (the bucket's size).
+    pub burst: u32,
+    /// Time to refill one attempt.
+    pub refill: Duration,
+    /// Delay before the next attempt after the first failure, doubled on every consecutive failure.
+    pub backoff: Duration,
+    /// Longest delay between attempts due to failures.
+    pub max_backoff: Duration,
+    /// Consecutive failures locking the identifier out, zero to never lock it out.
+    pub lockout_threshold: u32,
+    /// How long an identifier stays locked out.
+    pub lockout: Duration,
+}
+
+impl Default for ThrottleConfig {
+    /// Five attempts refilled one per minute, one second of backoff up to a minute, and a fifteen minutes lockout
+    /// after ten consecutive failures.
+    fn default() -> Self {
+        ThrottleConfig {
+            burst: 5,
+            refill: Duration::from_secs(60),
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            lockout_threshold: 10,
+            lockout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+/// A [`Throttle`] keeping a token bucket, and the consecutive failures, of every identifier in memory.
+///
+/// At most `capacity` identifiers are tracked: when a new identifier comes in and the throttle is full, the
+/// identifier touched least recently is dropped (and starts over from a full bucket when it comes back), so every
+/// call takes a bounded time and memory stays bounded whatever the number of identifiers tried. The capacity
+/// SHOULD cover the identifiers attacked at once, since an attacker trying more identifiers than that within a
+/// lockout can have the oldest lockouts dropped.
+pub struct TokenBucketThrottle {
+    config: ThrottleConfig,
+    capacity: usize,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_identifier: HashMap<String, Bucket>,
+    /// The same identifiers as `by_identifier`, from the least to the most recently touched, by touch counter.
+    by_touch: BTreeMap<u64, String>,
+    touches: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+    failures: u32,
+    blocked_until: Option<Instant>,
+    locked_out: bool,
+    touched: u64,
+}
+
+impl Bucket {
+    fn new(config: &ThrottleConfig, now: Instant) -> Self {
+        Bucket {
+            tokens: config.burst as f64,
+            refilled_at: now,
+            failures: 0,
+            blocked_until: None,
+            locked_out: false,
+            touched: 0,
+        }
+    }
+
+    fn refill(&mut self, config: &ThrottleConfig, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        let refilled = if config.refill.is_zero() {
+            config.burst as f64
+        } else {
+            elapsed / config.refill.as_secs_f64()
+        };
+        self.tokens = (self.tokens + refilled).min(config.burst as f64);
+        self.refilled_at = now;
+        if matches!(self.blocked_until, Some(until) if until <= now) {
+            self.blocked_until = None;
+            self.locked_out = false;
+        }
+    }
+}
+
+impl TokenBucketThrottle {
+    /// Creates a throttle.
+    ///
+    /// # Arguments
+    ///
+    /// * `config`: See [`ThrottleConfig`].
+    /// * `capacity`: The maximum number of identifiers tracked at once.
+    pub fn new(config: ThrottleConfig, capacity: usize) -> Self {
+        TokenBucketThrottle {
+            config,
+            capacity,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Lifts the throttling of `identifier` (i.e. after an administrator unlocked the account).
+    pub fn reset(&self, identifier: &str) -> io::Result<()> {
+        let mut buckets = self.lock()?;
+        if let Some(bucket) = buckets.by_identifier.remove(identifier) {
+            buckets.by_touch.remove(&bucket.touched);
+        }
+        Ok(())
+    }
+
+    /// Number of identifiers tracked.
+    pub fn len(&self) -> io::Result<usize> {
+        Ok(self.lock()?.by_identifier.len())
+    }
+
+    /// Whether no identifier is tracked.
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn check_at(&self, identifier: &str, now: Instant) -> io::Result<()> {
+        let config = self.config;
+        let throttled = self.update(identifier, now, |bucket, now| {
+            if let Some(until) = bucket.blocked_until {
+                return Some(Throttled { retry_after: until - now, locked_out: bucket.locked_out });
+            }
+            if bucket.tokens < 1.0 {
+                let missing = (1.0 - bucket.tokens) * config.refill.as_secs_f64();
+                return Some(Throttled { retry_after: Duration::from_secs_f64(missing), locked_out: false });
+            }
+
+            bucket.tokens -= 1.0;
+            None
+        })?;
+
+        match throttled {
+            Some(throttled) => Err(io::Error::new(io::ErrorKind::PermissionDenied, throttled)),
+            None => Ok(()),
+        }
+    }
+
+    fn record_success_at(&self, identifier: &str, now: Instant) -> io::Result<()> {
+        self.update(identifier, now, |bucket, _| {
+            bucket.failures = 0;
+            bucket.blocked_until = None;
+            bucket.locked_out = false;
+        })
+    }
+
+    fn record_failure_at(&self, identifier: &str, now: Instant) -> io::Result<()> {
+        let config = self.config;
+        self.update(identifier, now, |bucket, now| {
+            bucket.failures = bucket.failures.saturating_add(1);
+            if config.lockout_threshold > 0 && bucket.failures >= config.lockout_threshold {
+                bucket.failures = 0;
+                bucket.blocked_until = Some(now + config.lockout);
+                bucket.locked_out = true;
+                return;
+            }
+
+            let factor = 1u32.checked_shl(bucket.failures - 1).unwrap_or(u32::MAX);
+            let delay = config.backoff.checked_mul(factor).unwrap_or(config.max_backoff).min(config.max_backoff);
+            bucket.blocked_until = Some(now + delay);
+        })
+    }
+
+    /// Runs `update` on the refilled bucket of `identifier`, marking it as the most recently touched, and drops
+    /// the least recently touched bucket when a new one doesn't fit.
+    fn update<T, F: FnOnce(&mut Bucket, Instant) -> T>(&self, identifier: &str, now: Instant, update: F) -> io::Result<T> {
+        let config = self.config;
+        let mut buckets = self.lock()?;
+        let Buckets { by_identifier, by_touch, touches } = &mut *buckets;
+
+        *touches += 1;
+        if let Some(bucket) = by_identifier.get(identifier) {
+            by_touch.remove(&bucket.touched);
+        } else {
+            while by_identifier.len() >= self.capacity.max(1) {
+                match by_touch.pop_first() {
+                    Some((_, oldest)) => by_identifier.remove(&oldest),
+                    None => break,
+                };
+            }
+        }
+        by_touch.insert(*touches, identifier.to_string());
+
+        let bucket = by_identifier
+            .entry(identifier.to_string())
+            .or_insert_with(|| Bucket::new(&config, now));
+        bucket.touched = *touches;
+        bucket.refill(&config, now);
+        Ok(update(bucket, now))
+    }
+
+    fn lock(&self) -> io::Result<MutexGuard<'_, Buckets>> {
+        self.buckets.lock().map_err(|_| io::Error::from(io::ErrorKind::Other))
+    }
+}
+
+impl Throttle for TokenBucketThrottle {
+    fn check(&self, identifier: &str) -> io::Result<()> {
+        self.check_at(identifier, Instant::now())
+    }
+
+    fn record_success(&self, identifier: &str) -> io::Result<()> {
+        self.record_success_at(identifier, Instant::now())
+    }
+
+    fn record_failure(&self, identifier: &str) -> io::Result<()> {
+        self.record_failure_at(identifier, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ThrottleConfig {
+        ThrottleConfig {
+            burst: 2,
+            refill: Duration::from_secs(10),
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(4),
+            lockout_threshold: 4,
+            lockout: Duration::from_secs(60),
+        }
+    }
+
+    fn throttled(result: io::Result<()>) -> Throttled {
+        let error = result.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        *error.get_ref().unwrap().downcast_ref::<Throttled>().unwrap()
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let throttle = TokenBucketThrottle::new(config(), 16);
+        let start = Instant::now();
+        throttle.check_at("alice", start).unwrap();
+        throttle.check_at("alice", start).unwrap();
+
+        let error = throttle.check_at("alice", start + Duration::from_secs(4));
+        assert_eq!(retry_after(error.as_ref().unwrap_err()), Some(Duration::from_secs(6)));
+        assert!(!throttled(error).locked_out);
+        throttle.check_at("alice", start + Duration::from_secs(10)).unwrap();
+        throttle.check_at("bob", start).unwrap();
+    }
+
+    #[test]
+    fn failures_back_off_exponentially() {
+        let throttle = TokenBucketThrottle::new(ThrottleConfig { burst: 100, ..config() }, 16);
+        let mut now = Instant::now();
+        for expected in &[1, 2, 4] {
+            throttle.check_at("alice", now).unwrap();
+            throttle.record_failure_at("alice", now).unwrap();
+            let throttled = throttled(throttle.check_at("alice", now));
+            assert_eq!(throttled.retry_after, Duration::from_secs(*expected));
+            now += throttled.retry_after;
+        }
+
+        throttle.check_at("alice", now).unwrap();
+        throttle.record_success_at("alice", now).unwrap();
+        throttle.check_at("alice", now).unwrap();
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let throttle = TokenBucketThrottle::new(ThrottleConfig { lockout_threshold: 0, ..config() }, 16);
+        let now = Instant::now();
+        for _ in 0..40 {
+            throttle.record_failure_at("alice", now).unwrap();
+        }
+        assert_eq!(throttled(throttle.check_at("alice", now)).retry_after, config().max_backoff);
+    }
+
+    #[test]
+    fn consecutive_failures_lock_out() {
+        let throttle = TokenBucketThrottle::new(config(), 16);
+        let now = Instant::now();
+        for _ in 0..config().lockout_threshold {
+            throttle.record_failure_at("alice", now).unwrap();
+        }
+
+        let throttled = throttled(throttle.check_at("alice", now + Duration::from_secs(1)));
+        assert!(throttled.locked_out);
+        assert_eq!(throttled.retry_after, Duration::from_secs(59));
+        throttle.check_at("alice", now + config().lockout).unwrap();
+
+        throttle.record_failure_at("bob", now).unwrap();
+        throttle.reset("bob").unwrap();
+        throttle.check_at("bob", now).unwrap();
+    }
+
+    #[test]
+    fn capacity_evicts_least_recently_touched() {
+        let throttle = TokenBucketThrottle::new(config(), 2);
+        let now = Instant::now();
+        throttle.record_failure_at("alice", now).unwrap();
+        throttle.record_failure_at("bob", now).unwrap();
+        throttle.check_at("alice", now).unwrap_err();
+        throttle.check_at("carol", now).unwrap();
+        assert_eq!(throttle.len().unwrap(), 2);
+
+        // "bob" was the least recently touched, so it was dropped, "alice" wasn't.
+        throttle.check_at("bob", now).unwrap();
+        assert_eq!(throttle.len().unwrap(), 2);
+        throttle.check_at("carol", now).unwrap();
+        throttle.check_at("alice", now).unwrap();
+    }
+
+    #[test]
+    fn many_identifiers_stay_bounded() {
+        let throttle = TokenBucketThrottle::new(config(), 64);
+        let now = Instant::now();
+        for index in 0..10_000 {
+            throttle.record_failure_at(&index.to_string(), now).unwrap();
+        }
+        assert_eq!(throttle.len().unwrap(), 64);
+    }
+}